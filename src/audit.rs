@@ -0,0 +1,244 @@
+//! Audit trail of the proxy, one json line per closed tcp connection.
+//!
+//! `--audit-file` turns it on. Each line holds the close time, the client
+//! address, the destination as sniffed name or address, its port, the
+//! bytes each way and the duration, never any payload. With `--audit-salt`
+//! the client shows as a salted hash, stable for a device without keeping
+//! its address. Lines go through their own writer thread instead of the
+//! log, so the log level never hides them, and are dropped, counted in
+//! `audit_records_dropped`, if the disk falls behind. The file is rotated
+//! at `--audit-max-size` MB keeping `--audit-keep` old ones, file.1 being
+//! the newest.
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use crossbeam::channel::{Receiver, Sender};
+use sha2::{Digest, Sha256};
+
+use crate::{config::OPTIONS, metrics::AUDIT_DROPPED};
+
+/// Lines the queue holds
+const QUEUE_LEN: usize = 4096;
+
+lazy_static::lazy_static! {
+    static ref SENDER: Mutex<Option<Sender<String>>> = Mutex::new(None);
+}
+
+/// Audit file with size based rotation.
+struct Sink {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    /// Bytes before rotating, 0 for never
+    max_size: u64,
+    keep: usize,
+}
+
+fn open(path: &Path) -> std::io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((BufWriter::new(file), size))
+}
+
+impl Sink {
+    fn new(path: PathBuf, max_size: u64, keep: usize) -> std::io::Result<Sink> {
+        let (file, size) = open(path.as_path())?;
+        Ok(Sink {
+            path,
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            std::fs::remove_file(self.path.as_path())?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(self.path.as_path(), self.rotated(1))?;
+        }
+        let (file, size) = open(self.path.as_path())?;
+        self.file = file;
+        self.size = size;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn write_loop(receiver: Receiver<String>, mut sink: Sink) {
+    while let Ok(line) = receiver.recv() {
+        if let Err(err) = sink.write(line.as_str()) {
+            log::error!("write audit file {} failed:{}", sink.path.display(), err);
+        }
+        if receiver.is_empty() {
+            let _ = sink.file.flush();
+        }
+    }
+}
+
+/// Opens the audit file unless `--audit-file` is empty, panics if it
+/// can't be opened like other startup options.
+pub fn init() {
+    let args = OPTIONS.proxy_args();
+    if args.audit_file.is_empty() {
+        return;
+    }
+    let sink = Sink::new(
+        PathBuf::from(args.audit_file.as_str()),
+        args.audit_max_size * 1024 * 1024,
+        args.audit_keep,
+    )
+    .unwrap_or_else(|err| panic!("open audit file {} failed:{}", args.audit_file, err));
+    let (sender, receiver) = crossbeam::channel::bounded(QUEUE_LEN);
+    thread::Builder::new()
+        .name("audit".to_owned())
+        .spawn(move || write_loop(receiver, sink))
+        .unwrap();
+    *SENDER.lock().unwrap() = Some(sender);
+}
+
+/// The client as written, a salted hash of its address with a salt.
+fn client_id(ip: IpAddr, salt: &str) -> String {
+    if salt.is_empty() {
+        return ip.to_string();
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(ip.to_string().as_bytes());
+    hex::encode(&hasher.finalize()[..8])
+}
+
+fn line(
+    time: &str,
+    client: &str,
+    name: Option<&str>,
+    dst_addr: SocketAddr,
+    sent: u64,
+    received: u64,
+    duration: Duration,
+) -> String {
+    let destination = match name {
+        Some(name) => name.to_owned(),
+        None => dst_addr.ip().to_string(),
+    };
+    format!(
+        "{{\"time\":{:?},\"client\":{:?},\"destination\":{:?},\"port\":{},\"sent\":{},\"received\":{},\"duration_ms\":{}}}\n",
+        time,
+        client,
+        destination,
+        dst_addr.port(),
+        sent,
+        received,
+        duration.as_millis()
+    )
+}
+
+/// Writes the line of a closed connection, `sent` toward the destination.
+pub fn record(
+    src_addr: SocketAddr,
+    name: Option<&str>,
+    dst_addr: SocketAddr,
+    sent: u64,
+    received: u64,
+    duration: Duration,
+) {
+    let guard = SENDER.lock().unwrap();
+    let sender = match guard.as_ref() {
+        Some(sender) => sender,
+        None => return,
+    };
+    let time = chrono::Local::now()
+        .format("%Y-%m-%dT%H:%M:%S%.3f%:z")
+        .to_string();
+    let client = client_id(src_addr.ip(), OPTIONS.proxy_args().audit_salt.as_str());
+    let line = line(
+        time.as_str(),
+        client.as_str(),
+        name,
+        dst_addr,
+        sent,
+        received,
+        duration,
+    );
+    if sender.try_send(line).is_err() {
+        AUDIT_DROPPED.inc();
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::{net::IpAddr, time::Duration};
+
+    use crate::audit::{client_id, line, Sink};
+
+    #[test]
+    fn test_line() {
+        let ip: IpAddr = "192.168.1.20".parse().unwrap();
+        assert_eq!(client_id(ip, ""), "192.168.1.20");
+        let hashed = client_id(ip, "salt");
+        assert_eq!(hashed.len(), 16);
+        assert_eq!(hashed, client_id(ip, "salt"));
+        assert_ne!(hashed, client_id(ip, "other"));
+        assert_eq!(
+            line(
+                "2024-01-02T03:04:05.006+08:00",
+                "192.168.1.20",
+                Some("example.com"),
+                "93.184.216.34:443".parse().unwrap(),
+                100,
+                2000,
+                Duration::from_millis(1500)
+            ),
+            "{\"time\":\"2024-01-02T03:04:05.006+08:00\",\"client\":\"192.168.1.20\",\"destination\":\"example.com\",\"port\":443,\"sent\":100,\"received\":2000,\"duration_ms\":1500}\n"
+        );
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("trojan-audit-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(dir.as_path());
+        std::fs::create_dir_all(dir.as_path()).unwrap();
+        let path = dir.join("audit.log");
+        let mut sink = Sink::new(path.clone(), 10, 2).unwrap();
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            sink.write(line).unwrap();
+        }
+        drop(sink);
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("audit.log"), "dddddd\n");
+        assert_eq!(read("audit.log.1"), "cccccc\n");
+        assert_eq!(read("audit.log.2"), "bbbbbb\n");
+        assert!(!dir.join("audit.log.3").exists());
+        let _ = std::fs::remove_dir_all(dir.as_path());
+    }
+}
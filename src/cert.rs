@@ -0,0 +1,256 @@
+//! Details of X.509 certificates for logs, read with a minimal DER parser
+//! which only knows the fields shown.
+use std::{
+    convert::TryInto,
+    fmt::{Display, Formatter},
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{NaiveDate, TimeZone, Utc};
+
+/// Days before `notAfter` from which the expiry is warned about
+const EXPIRY_WARN_DAYS: i64 = 14;
+const SECS_PER_DAY: i64 = 86400;
+/// Time between expiry checks of the server certificate
+pub const EXPIRY_CHECK_DURATION: Duration = Duration::from_secs(SECS_PER_DAY as u64);
+
+const SEQUENCE: u8 = 0x30;
+const OID: u8 = 0x06;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+/// explicit tag of the version field
+const VERSION: u8 = 0xa0;
+/// explicit tag of the extensions field
+const EXTENSIONS: u8 = 0xa3;
+/// context tags of the dNSName and iPAddress general names
+const DNS_NAME: u8 = 0x82;
+const IP_ADDRESS: u8 = 0x87;
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const NAME_ATTRIBUTES: &[(&[u8], &str)] = &[
+    (&[0x55, 0x04, 0x03], "CN"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x06], "C"),
+];
+
+struct Der<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(data: &'a [u8]) -> Der<'a> {
+        Der { data }
+    }
+
+    /// Reads the next element, returns its tag and value.
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        let tag = *self.data.first()?;
+        let first = *self.data.get(1)? as usize;
+        let (len, head) = if first < 0x80 {
+            (first, 2)
+        } else {
+            let size = first & 0x7f;
+            if size == 0 || size > 4 {
+                return None;
+            }
+            let bytes = self.data.get(2..2 + size)?;
+            let len = bytes
+                .iter()
+                .fold(0usize, |len, byte| len << 8 | *byte as usize);
+            (len, 2 + size)
+        };
+        let value = self.data.get(head..head.checked_add(len)?)?;
+        self.data = &self.data[head + len..];
+        Some((tag, value))
+    }
+}
+
+pub struct CertInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub sans: Vec<String>,
+    /// Unix time in seconds
+    pub not_before: i64,
+    pub not_after: i64,
+}
+
+impl CertInfo {
+    pub fn parse(der: &[u8]) -> Option<CertInfo> {
+        let (_, cert) = Der::new(der).next()?;
+        let (_, tbs) = Der::new(cert).next()?;
+        let mut tbs = Der::new(tbs);
+        if tbs.next()?.0 == VERSION {
+            // serial number
+            tbs.next()?;
+        }
+        // signature algorithm
+        tbs.next()?;
+        let issuer = name(tbs.next()?.1);
+        let mut validity = Der::new(tbs.next()?.1);
+        let not_before = time(validity.next()?)?;
+        let not_after = time(validity.next()?)?;
+        let subject = name(tbs.next()?.1);
+        // subject public key
+        tbs.next()?;
+        let mut sans = Vec::new();
+        while let Some((tag, value)) = tbs.next() {
+            if tag == EXTENSIONS {
+                sans = alt_names(value)?;
+            }
+        }
+        Some(CertInfo {
+            subject,
+            issuer,
+            sans,
+            not_before,
+            not_after,
+        })
+    }
+
+    pub fn days_left(&self, now: SystemTime) -> i64 {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        (self.not_after - now).div_euclid(SECS_PER_DAY)
+    }
+
+    /// Warns if the certificate expires within two weeks.
+    pub fn check_expiry(&self, now: SystemTime) {
+        let days = self.days_left(now);
+        if days < 0 {
+            log::error!("certificate {} expired at {}", self.subject, self.expiry());
+        } else if days < EXPIRY_WARN_DAYS {
+            log::warn!(
+                "certificate {} expires in {} days at {}",
+                self.subject,
+                days,
+                self.expiry()
+            );
+        }
+    }
+
+    fn expiry(&self) -> String {
+        format_time(self.not_after)
+    }
+}
+
+pub fn format_time(time: i64) -> String {
+    Utc.timestamp_opt(time, 0)
+        .single()
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_default()
+}
+
+impl Display for CertInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "subject:{} issuer:{} san:{} not_after:{}",
+            self.subject,
+            self.issuer,
+            self.sans.join(","),
+            self.expiry()
+        )
+    }
+}
+
+/// Formats the attributes of a distinguished name we know about.
+fn name(value: &[u8]) -> String {
+    let mut parts = Vec::new();
+    let mut sets = Der::new(value);
+    while let Some((_, set)) = sets.next() {
+        let mut attributes = Der::new(set);
+        while let Some((_, attribute)) = attributes.next() {
+            let mut attribute = Der::new(attribute);
+            if let (Some((OID, oid)), Some((_, text))) = (attribute.next(), attribute.next()) {
+                if let Some((_, key)) = NAME_ATTRIBUTES.iter().find(|(id, _)| *id == oid) {
+                    parts.push(format!("{}={}", key, String::from_utf8_lossy(text)));
+                }
+            }
+        }
+    }
+    parts.join(",")
+}
+
+fn time((tag, value): (u8, &[u8])) -> Option<i64> {
+    let text = std::str::from_utf8(value).ok()?;
+    let (year, rest) = match tag {
+        UTC_TIME => {
+            // two digit years below 50 are in this century
+            let year: i32 = text.get(..2)?.parse().ok()?;
+            let century = if year < 50 { 2000 } else { 1900 };
+            (century + year, &text[2..])
+        }
+        GENERALIZED_TIME => (text.get(..4)?.parse().ok()?, &text[4..]),
+        _ => return None,
+    };
+    let field = |index: usize| -> Option<u32> { rest.get(index * 2..index * 2 + 2)?.parse().ok() };
+    let time = NaiveDate::from_ymd_opt(year, field(0)?, field(1)?)?.and_hms_opt(
+        field(2)?,
+        field(3)?,
+        field(4)?,
+    )?;
+    Some(Utc.from_utc_datetime(&time).timestamp())
+}
+
+/// Returns the dns names and ip addresses of the subject alternative name
+/// extension.
+fn alt_names(value: &[u8]) -> Option<Vec<String>> {
+    let mut names = Vec::new();
+    let (_, extensions) = Der::new(value).next()?;
+    let mut extensions = Der::new(extensions);
+    while let Some((SEQUENCE, extension)) = extensions.next() {
+        let mut extension = Der::new(extension);
+        if extension.next()? != (OID, SUBJECT_ALT_NAME) {
+            continue;
+        }
+        // the critical flag is optional, the value comes last
+        let mut value = extension.next()?.1;
+        if let Some((_, last)) = extension.next() {
+            value = last;
+        }
+        let (_, general_names) = Der::new(value).next()?;
+        let mut general_names = Der::new(general_names);
+        while let Some((tag, name)) = general_names.next() {
+            match (tag, name.len()) {
+                (DNS_NAME, _) => names.push(String::from_utf8_lossy(name).into_owned()),
+                (IP_ADDRESS, 4) => {
+                    let octets: [u8; 4] = name.try_into().ok()?;
+                    names.push(IpAddr::from(octets).to_string());
+                }
+                (IP_ADDRESS, 16) => {
+                    let octets: [u8; 16] = name.try_into().ok()?;
+                    names.push(IpAddr::from(octets).to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+    Some(names)
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::{
+        fs::File,
+        io::BufReader,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use crate::cert::CertInfo;
+
+    #[test]
+    fn test_parse() {
+        let mut reader = BufReader::new(File::open("tests/certs/server.pem").unwrap());
+        let der = rustls_pemfile::certs(&mut reader).unwrap().remove(0);
+        let info = CertInfo::parse(der.as_slice()).unwrap();
+        assert_eq!(info.subject, "CN=localhost");
+        assert_eq!(info.issuer, "CN=trojan test ca");
+        assert_eq!(info.sans, ["localhost"]);
+        assert_eq!(info.expiry(), "2126-09-20 04:39:05 UTC");
+        let before = UNIX_EPOCH + Duration::from_secs(info.not_after as u64 - 86400 * 3);
+        assert_eq!(info.days_left(before), 3);
+        assert!(CertInfo::parse(&der[..100]).is_none());
+    }
+}
@@ -0,0 +1,44 @@
+//! Address ranges shared by the egress policy and the proxy routes.
+use std::net::IpAddr;
+
+/// An address range, ipv4 ranges are kept in their ipv4 mapped form.
+pub struct Cidr {
+    ip: u128,
+    prefix: u32,
+}
+
+impl Cidr {
+    pub fn parse(text: &str) -> Option<Cidr> {
+        let (ip, prefix) = match text.split_once('/') {
+            Some((ip, prefix)) => (ip.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (text.parse().ok()?, None),
+        };
+        let (ip, prefix) = match ip {
+            IpAddr::V4(v4) => (v4.to_ipv6_mapped(), prefix.unwrap_or(32) + 96),
+            IpAddr::V6(v6) => (v6, prefix.unwrap_or(128)),
+        };
+        if prefix > 128 {
+            return None;
+        }
+        Some(Cidr {
+            ip: u128::from(ip) & Self::mask(prefix),
+            prefix,
+        })
+    }
+
+    fn mask(prefix: u32) -> u128 {
+        u128::MAX.checked_shl(128 - prefix).unwrap_or(0)
+    }
+
+    pub fn contains(&self, ip: u128) -> bool {
+        ip & Self::mask(self.prefix) == self.ip
+    }
+}
+
+/// Maps an address into the space of [`Cidr`].
+pub fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
@@ -0,0 +1,250 @@
+//! Latency based control of the data queued toward a tunnel, CoDel style.
+//!
+//! Every write into a tls session is timestamped, and its sojourn time is
+//! taken once the socket took as many bytes as were queued up to its end.
+//! Record headers make that a little early, the queue starts over each time
+//! the session drains. When the sojourn time of sent writes stays above the
+//! target for an [`INTERVAL`] the queue is standing: tcp readers pause as if
+//! the session was full, until it drained, and udp associations drop
+//! datagrams at the rate of the CoDel control law. Bytes inside the session
+//! are encrypted already, a drop hits the oldest datagram not written yet.
+//! The byte limit of the session still applies underneath.
+use std::{
+    collections::VecDeque,
+    fmt::{Display, Formatter},
+    time::{Duration, Instant},
+};
+
+use crate::{config::OPTIONS, metrics::AQM_CONGESTED};
+
+/// Time the sojourn has to stay above the target
+pub const INTERVAL: Duration = Duration::from_millis(100);
+/// log2 buckets of sojourn times in microseconds, the last one open ended
+const BUCKETS: usize = 24;
+
+/// Histogram of durations for their percentiles, like sojourn times of
+/// sent writes.
+#[derive(Default)]
+pub struct Delays {
+    buckets: [u64; BUCKETS],
+    total: u64,
+}
+
+impl Delays {
+    pub fn add(&mut self, delay: Duration) {
+        let micros = delay.as_micros().max(1) as u64;
+        let bucket = (63 - micros.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.total += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Upper bound of the bucket holding the `percent` percentile.
+    pub fn percentile(&self, percent: u64) -> Duration {
+        let rank = (self.total * percent).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(1 << (bucket + 1));
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+impl Display for Delays {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.total == 0 {
+            return f.write_str("delay:-");
+        }
+        write!(
+            f,
+            "delay p50:{:?} p90:{:?} p99:{:?}",
+            self.percentile(50),
+            self.percentile(90),
+            self.percentile(99)
+        )
+    }
+}
+
+pub struct Codel {
+    target: Duration,
+    /// Writes not sent yet, their size and when they were written
+    queue: VecDeque<(usize, Instant)>,
+    /// Bytes of the front write sent already
+    head_sent: usize,
+    /// When the sojourn time has been above target for an interval
+    first_above: Option<Instant>,
+    standing: bool,
+    dropping: bool,
+    drop_next: Instant,
+    /// Drops since dropping started, spacing them by the control law
+    count: u32,
+    delays: Delays,
+}
+
+impl Codel {
+    pub fn new(target: Duration) -> Codel {
+        Codel {
+            target,
+            queue: VecDeque::new(),
+            head_sent: 0,
+            first_above: None,
+            standing: false,
+            dropping: false,
+            drop_next: Instant::now(),
+            count: 0,
+            delays: Delays::default(),
+        }
+    }
+
+    /// Returns the queue of a new proxy connection, None with `--aqm-target 0`.
+    pub fn from_options() -> Option<Codel> {
+        match OPTIONS.proxy_args().aqm_target {
+            0 => None,
+            target => Some(Codel::new(Duration::from_millis(target))),
+        }
+    }
+
+    pub fn written(&mut self, len: usize, now: Instant) {
+        if len > 0 {
+            self.queue.push_back((len, now));
+        }
+    }
+
+    /// Takes `size` bytes the socket took off the front.
+    pub fn sent(&mut self, mut size: usize, now: Instant) {
+        while let Some((len, time)) = self.queue.front().copied() {
+            if self.head_sent + size < len {
+                self.head_sent += size;
+                return;
+            }
+            size -= len - self.head_sent;
+            self.head_sent = 0;
+            self.queue.pop_front();
+            self.dequeued(now - time, now);
+        }
+    }
+
+    /// Everything written was sent.
+    pub fn drained(&mut self, now: Instant) {
+        while let Some((_, time)) = self.queue.pop_front() {
+            self.dequeued(now - time, now);
+        }
+        self.head_sent = 0;
+        self.first_above = None;
+        self.standing = false;
+    }
+
+    fn dequeued(&mut self, sojourn: Duration, now: Instant) {
+        self.delays.add(sojourn);
+        if sojourn < self.target {
+            self.first_above = None;
+            self.standing = false;
+            return;
+        }
+        match self.first_above {
+            None => {
+                self.first_above.replace(now + INTERVAL);
+            }
+            Some(first_above) if now >= first_above && !self.standing => {
+                self.standing = true;
+                AQM_CONGESTED.inc();
+            }
+            Some(_) => {}
+        }
+    }
+
+    /// Whether the sojourn time stayed above target for an interval.
+    pub fn standing(&self) -> bool {
+        self.standing
+    }
+
+    /// Whether the next datagram is dropped, by the control law.
+    pub fn drop(&mut self, now: Instant) -> bool {
+        if !self.standing {
+            self.dropping = false;
+            return false;
+        }
+        if !self.dropping {
+            self.dropping = true;
+            // dropping again soon starts near the rate it left off
+            self.count = if self.count > 2 && now < self.drop_next + INTERVAL * 16 {
+                self.count - 2
+            } else {
+                1
+            };
+            self.drop_next = now + Self::spacing(self.count);
+            return true;
+        }
+        if now < self.drop_next {
+            return false;
+        }
+        self.count += 1;
+        self.drop_next += Self::spacing(self.count);
+        true
+    }
+
+    fn spacing(count: u32) -> Duration {
+        INTERVAL.div_f64((count as f64).sqrt())
+    }
+
+    pub fn delays(&self) -> &Delays {
+        &self.delays
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::time::{Duration, Instant};
+
+    use crate::codel::{Codel, Delays, INTERVAL};
+
+    #[test]
+    fn test_delays() {
+        let mut delays = Delays::default();
+        for _ in 0..90 {
+            delays.add(Duration::from_micros(700));
+        }
+        for _ in 0..10 {
+            delays.add(Duration::from_millis(40));
+        }
+        assert_eq!(delays.percentile(50), Duration::from_micros(1024));
+        assert_eq!(delays.percentile(90), Duration::from_micros(1024));
+        assert_eq!(delays.percentile(99), Duration::from_micros(65536));
+    }
+
+    #[test]
+    fn test_standing() {
+        let start = Instant::now();
+        let mut codel = Codel::new(Duration::from_millis(5));
+        let ms = |ms: u64| start + Duration::from_millis(ms);
+        // a burst leaving within the target never stands
+        codel.written(1000, ms(0));
+        codel.written(1000, ms(0));
+        codel.sent(1500, ms(2));
+        codel.drained(ms(3));
+        assert!(!codel.standing());
+
+        // every write waits 20ms, standing once that lasted an interval
+        for i in 0..20 {
+            codel.written(1000, ms(10 + i * 10));
+            codel.sent(1000, ms(30 + i * 10));
+        }
+        assert!(codel.standing());
+        assert!(codel.drop(ms(230)));
+        assert!(!codel.drop(ms(240)));
+        assert!(codel.drop(ms(230) + INTERVAL));
+        // the next drop comes sooner
+        assert!(codel.drop(ms(230) + INTERVAL + INTERVAL.div_f64(2f64.sqrt())));
+
+        codel.drained(ms(400));
+        assert!(!codel.standing());
+        assert!(!codel.drop(ms(400)));
+    }
+}
@@ -0,0 +1,251 @@
+//! Optional payload compression between proxy and server.
+//!
+//! A compressed request sets [`COMPRESSED`](crate::proto::COMPRESSED) on its
+//! command. The payload the proxy sends after it is framed, each frame a
+//! type byte, a big endian u16 length and the value:
+//!
+//! * `RAW` carries payload which didn't shrink
+//! * `DEFLATE` carries a raw deflate stream of at most [`CHUNK_LEN`] bytes
+//! * `END` has no value, raw payload follows it
+//!
+//! The server answers with one byte before anything else, [`ACK_FRAMED`] if
+//! its payload is framed as well, [`ACK_RAW`] if not. Padding applies on top
+//! of the frames. A stream whose first KB looks random sends `END` right
+//! away, so encrypted or already compressed data only costs three bytes.
+use bytes::{BufMut, BytesMut};
+use miniz_oxide::{deflate::compress_to_vec, inflate::decompress_to_vec_with_limit};
+
+/// answer of a server which sends raw payload
+pub const ACK_RAW: u8 = 0x00;
+/// answer of a server which frames its payload
+pub const ACK_FRAMED: u8 = 0x01;
+const RAW: u8 = 0x00;
+const DEFLATE: u8 = 0x01;
+const END: u8 = 0x02;
+const FRAME_HEAD_LEN: usize = 3;
+/// max payload of a frame, also the inflate limit
+const CHUNK_LEN: usize = 16 * 1024;
+/// bytes sampled for the entropy check
+const SAMPLE_LEN: usize = 1024;
+/// bits per byte above which the sample is taken as incompressible
+const MAX_ENTROPY: f64 = 7.5;
+/// deflate level, fast rather than small
+const LEVEL: u8 = 1;
+
+/// Shannon entropy of `data` in bits per byte.
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[derive(Default)]
+pub struct Compressor {
+    buffer: BytesMut,
+    checked: bool,
+    done: bool,
+    input: u64,
+    output: u64,
+}
+
+impl Compressor {
+    /// Returns `data` as frames, or as is once finished.
+    pub fn frame<'a>(&'a mut self, data: &'a [u8]) -> &'a [u8] {
+        self.input += data.len() as u64;
+        if self.done {
+            self.output += data.len() as u64;
+            return data;
+        }
+        self.buffer.clear();
+        if !self.checked && !data.is_empty() {
+            self.checked = true;
+            if entropy(&data[..data.len().min(SAMPLE_LEN)]) > MAX_ENTROPY {
+                log::debug!("payload looks incompressible, stop compressing");
+                self.done = true;
+                self.buffer.put_u8(END);
+                self.buffer.put_u16(0);
+                self.buffer.extend_from_slice(data);
+                self.output += self.buffer.len() as u64;
+                return self.buffer.as_ref();
+            }
+        }
+        for chunk in data.chunks(CHUNK_LEN) {
+            let packed = compress_to_vec(chunk, LEVEL);
+            let (kind, value) = if packed.len() < chunk.len() {
+                (DEFLATE, packed.as_slice())
+            } else {
+                (RAW, chunk)
+            };
+            self.buffer.put_u8(kind);
+            self.buffer.put_u16(value.len() as u16);
+            self.buffer.extend_from_slice(value);
+        }
+        self.output += self.buffer.len() as u64;
+        self.buffer.as_ref()
+    }
+
+    /// Bytes sent per payload byte.
+    pub fn ratio(&self) -> f64 {
+        ratio(self.output, self.input)
+    }
+}
+
+fn ratio(wire: u64, payload: u64) -> f64 {
+    if payload == 0 {
+        1.0
+    } else {
+        wire as f64 / payload as f64
+    }
+}
+
+/// Restores the payload of a framed stream, data may split frames anywhere.
+#[derive(Default)]
+pub struct Decompressor {
+    /// The answer byte of the server is still to come
+    ack: bool,
+    head: [u8; FRAME_HEAD_LEN],
+    head_len: usize,
+    /// Value bytes left in the current frame
+    remain: usize,
+    packed: Vec<u8>,
+    done: bool,
+    input: u64,
+    output: u64,
+}
+
+impl Decompressor {
+    /// Returns a decompressor for the stream from the server, which starts
+    /// with its answer.
+    pub fn await_ack() -> Decompressor {
+        Decompressor {
+            ack: true,
+            ..Default::default()
+        }
+    }
+
+    /// Appends the payload in `input` to `output`, returns false for an
+    /// invalid frame.
+    pub fn strip(&mut self, mut input: &[u8], output: &mut BytesMut) -> bool {
+        self.input += input.len() as u64;
+        let offset = output.len();
+        while !input.is_empty() {
+            if self.ack {
+                self.ack = false;
+                match input[0] {
+                    ACK_RAW => self.done = true,
+                    ACK_FRAMED => {}
+                    _ => return false,
+                }
+                input = &input[1..];
+                continue;
+            }
+            if self.done {
+                output.extend_from_slice(input);
+                break;
+            }
+            if self.head_len < FRAME_HEAD_LEN {
+                let size = (FRAME_HEAD_LEN - self.head_len).min(input.len());
+                self.head[self.head_len..self.head_len + size].copy_from_slice(&input[..size]);
+                self.head_len += size;
+                input = &input[size..];
+                if self.head_len < FRAME_HEAD_LEN {
+                    break;
+                }
+                self.remain = u16::from_be_bytes([self.head[1], self.head[2]]) as usize;
+                match self.head[0] {
+                    RAW | DEFLATE => {}
+                    END if self.remain == 0 => self.done = true,
+                    _ => return false,
+                }
+            } else {
+                let size = self.remain.min(input.len());
+                if self.head[0] == RAW {
+                    output.extend_from_slice(&input[..size]);
+                } else {
+                    self.packed.extend_from_slice(&input[..size]);
+                }
+                self.remain -= size;
+                input = &input[size..];
+                if self.remain == 0 && self.head[0] == DEFLATE {
+                    match decompress_to_vec_with_limit(self.packed.as_slice(), CHUNK_LEN) {
+                        Ok(data) => output.extend_from_slice(data.as_slice()),
+                        Err(_) => return false,
+                    }
+                    self.packed.clear();
+                }
+            }
+            if self.remain == 0 {
+                self.head_len = 0;
+            }
+        }
+        self.output += (output.len() - offset) as u64;
+        true
+    }
+
+    /// Bytes received per payload byte.
+    pub fn ratio(&self) -> f64 {
+        ratio(self.input, self.output)
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+
+    use bytes::BytesMut;
+
+    use crate::compress::{entropy, Compressor, Decompressor, ACK_FRAMED, ACK_RAW};
+
+    #[test]
+    fn test_compress() {
+        let mut compressor = Compressor::default();
+        let mut decompressor = Decompressor::await_ack();
+        let mut output = BytesMut::new();
+        assert!(decompressor.strip(&[ACK_FRAMED], &mut output));
+        let text = b"{\"name\":\"value\",\"list\":[1,2,3]}".repeat(1000);
+        for data in [&text[..10], &text[..], &[]] {
+            let framed = compressor.frame(data).to_vec();
+            output.clear();
+            // split frames across reads
+            for chunk in framed.chunks(7) {
+                assert!(decompressor.strip(chunk, &mut output));
+            }
+            assert_eq!(output.as_ref(), data);
+        }
+        assert!(!compressor.done);
+        assert!(compressor.ratio() < 0.2);
+        assert!(decompressor.ratio() < 0.2);
+
+        // random looking data is sent raw after END
+        let random: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        assert!(entropy(&random[..1024]) > 7.5);
+        let mut compressor = Compressor::default();
+        let mut decompressor = Decompressor::default();
+        let framed = compressor.frame(random.as_slice()).to_vec();
+        assert_eq!(framed.len(), random.len() + 3);
+        assert!(compressor.done);
+        output.clear();
+        assert!(decompressor.strip(framed.as_slice(), &mut output));
+        assert_eq!(compressor.frame(b"raw"), b"raw");
+        assert!(decompressor.strip(b"raw", &mut output));
+        assert_eq!(&output[random.len()..], b"raw");
+
+        let mut decompressor = Decompressor::await_ack();
+        output.clear();
+        assert!(decompressor.strip(&[ACK_RAW, 1, 2], &mut output));
+        assert_eq!(output.as_ref(), [1, 2]);
+        assert!(!Decompressor::default().strip(&[0x09, 0, 0], &mut output));
+        assert!(!Decompressor::default().strip(&[0x01, 0, 2, 0xff, 0xff], &mut output));
+    }
+}
@@ -4,10 +4,21 @@ use std::{
     time::Duration,
 };
 
-use clap::Parser;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
 use sha2::{Digest, Sha224};
 
-use crate::{types::TrojanError, utils::resolve};
+use crate::{
+    cidr::{to_u128, Cidr},
+    endpoint,
+    hello::{self, HelloProfile},
+    logger,
+    proto::{self, Metadata},
+    sys,
+    tuning::{self, SocketTuning},
+    types::TrojanError,
+    utils::resolve,
+    worker::{self, Priority},
+};
 
 #[derive(Parser)]
 #[clap(
@@ -23,7 +34,7 @@ pub struct Opts {
     #[clap(short, long, default_value = "")]
     pub log_file: String,
 
-    /// Listen address for server, format like 0.0.0.0:443
+    /// Listen address, format like 0.0.0.0:443 or [::]:443, the port defaults to 443 for the server
     #[clap(short = 'a', long)]
     pub local_addr: String,
 
@@ -31,6 +42,10 @@ pub struct Opts {
     #[clap(short, long)]
     pub password: String,
 
+    /// State dump file path for SIGUSR2, empty for writing to log. SIGUSR2 also reopens the log file, no dump is written when the log file was replaced since the last signal, like by logrotate
+    #[clap(long, default_value = "")]
+    pub dump_file: String,
+
     /// Log level, 0 for trace, 1 for debug, 2 for info, 3 for warning, 4 for error, 5 for off
     #[clap(short = 'L', long, default_value = "2")]
     pub log_level: u8,
@@ -43,6 +58,118 @@ pub struct Opts {
     #[clap(short, long, default_value = "600")]
     pub tcp_idle_timeout: u64,
 
+    /// Time in seconds before closing a tcp connection still connecting to the server, 0 for no separate limit
+    #[clap(long, default_value = "0")]
+    pub connect_timeout: u64,
+
+    /// Time in seconds before closing a tcp connection which never got a response byte, 0 for no separate limit
+    #[clap(long, default_value = "0")]
+    pub first_byte_timeout: u64,
+
+    /// Time in seconds a tcp connection of the proxy may live however active it is, 0 for unlimited
+    #[clap(long, default_value = "0")]
+    pub tcp_max_lifetime: u64,
+
+    /// Time in seconds an udp association of the proxy may live however active it is, 0 for unlimited
+    #[clap(long, default_value = "0")]
+    pub udp_max_lifetime: u64,
+
+    /// Upstream dns servers like 8.8.8.8 or [2001:4860:4860::8888]:53 for resolving targets, the port defaults to 53, empty for the system resolver
+    #[clap(long)]
+    pub dns_server: Vec<String>,
+
+    /// Connections the listeners queue before they are accepted, 1 to 65535, the kernel caps it at net.core.somaxconn
+    #[clap(long, default_value = "1024")]
+    pub listen_backlog: u32,
+
+    /// Time in milliseconds to wait for an upstream dns answer
+    #[clap(long, default_value = "3000")]
+    pub dns_timeout: u64,
+
+    /// Tries of a dns query before giving up
+    #[clap(long, default_value = "2")]
+    pub dns_tries: usize,
+
+    /// Retry a timed out dns query on the same upstream instead of rotating to the next one
+    #[clap(long)]
+    pub dns_stick: bool,
+
+    /// Query the nameservers of /etc/resolv.conf when no --dns-server is given. Only in proxy mode. The file is checked for changes every 30 seconds. The hosts file and search domains are not used
+    #[clap(long)]
+    pub dns_resolv_conf: bool,
+
+    /// Time in seconds between re-resolving the server hostname of the idle pool, 0 for only after connect failures
+    #[clap(long, default_value = "300")]
+    pub resolve_interval: u64,
+
+    /// Log the routing and policy decisions of every connection when it closes
+    #[clap(long)]
+    pub trace_decisions: bool,
+
+    /// Log the decisions of connections from this range only, like 10.0.0.0/24
+    #[clap(long)]
+    pub trace_source: Vec<String>,
+
+    /// Addresses or ranges like 203.0.113.0/24 the server hostname may resolve to, other answers are rejected, empty for any
+    #[clap(long)]
+    pub server_ip_allowlist: Vec<String>,
+
+    /// Options of sockets facing local clients in proxy mode, like sndbuf=4194304,rcvbuf=4194304,congestion=bbr
+    #[clap(long, default_value = "")]
+    pub client_socket: String,
+
+    /// Options of tunnel sockets between proxy and server, same format as --client-socket
+    #[clap(long, default_value = "")]
+    pub tunnel_socket: String,
+
+    /// Options of sockets to targets in server mode, same format as --client-socket
+    #[clap(long, default_value = "")]
+    pub backend_socket: String,
+
+    /// Time in seconds a closing connection may take to flush its pending data
+    #[clap(long, default_value = "10")]
+    pub drain_timeout: u64,
+
+    /// Maximum connections accepted per poll iteration, the rest wait for the next iteration
+    #[clap(long, default_value = "64")]
+    pub accept_burst: usize,
+
+    /// ClientHello preset of connections to the server, one of default, chrome-like and minimal
+    #[clap(long, default_value = "default")]
+    pub tls_hello: String,
+
+    /// Cipher suites offered to the server in order, like TLS13_AES_128_GCM_SHA256,TLS13_CHACHA20_POLY1305_SHA256, empty for the preset
+    #[clap(long, default_value = "")]
+    pub tls_ciphers: String,
+
+    /// Key share groups offered to the server in order, like x25519,secp256r1, empty for the preset
+    #[clap(long, default_value = "")]
+    pub tls_groups: String,
+
+    /// TLS versions offered to the server, like 1.3,1.2, empty for the preset
+    #[clap(long, default_value = "")]
+    pub tls_versions: String,
+
+    /// ALPN protocols offered to the server, like h2,http/1.1, none for no ALPN, empty for the preset
+    #[clap(long, default_value = "")]
+    pub tls_alpn: String,
+
+    /// Extra CA certificates trusted for the server besides the webpki roots, in pem format
+    #[clap(long, default_value = "")]
+    pub ca_file: String,
+
+    /// Check that the server certificate is issued for the server name, disabling it lets any trusted certificate pass
+    #[clap(long, default_value = "true", action = clap::ArgAction::Set)]
+    pub verify_hostname: bool,
+
+    /// Cores the worker threads are pinned to in order, the poll loop first and then the handshake workers, like 2,3
+    #[clap(long, default_value = "")]
+    pub worker_affinity: String,
+
+    /// Priority of the worker threads, a nice value like -5, or fifo:10 for SCHED_FIFO which needs root
+    #[clap(long, default_value = "")]
+    pub worker_priority: String,
+
     #[clap(skip)]
     sha_pass: String,
     #[clap(skip)]
@@ -50,16 +177,49 @@ pub struct Opts {
     #[clap(skip)]
     pub back_addr: Option<SocketAddr>,
     #[clap(skip)]
-    pub udp_header_len: usize,
-    #[clap(skip)]
     pub empty_addr: Option<SocketAddr>,
+    /// Host and port of a `--remote-addr` given by name, resolved by each
+    /// connection once the cached address is stale
+    #[clap(skip)]
+    pub back_host: Option<(String, u16)>,
     #[clap(skip)]
     pub udp_idle_duration: Duration,
     #[clap(skip)]
     pub tcp_idle_duration: Duration,
+    #[clap(skip)]
+    pub drain_duration: Duration,
+    #[clap(skip)]
+    pub connect_duration: Option<Duration>,
+    #[clap(skip)]
+    pub first_byte_duration: Option<Duration>,
+    #[clap(skip)]
+    pub tcp_lifetime: Option<Duration>,
+    #[clap(skip)]
+    pub udp_lifetime: Option<Duration>,
+    #[clap(skip)]
+    server_allowlist: Vec<Cidr>,
+    #[clap(skip)]
+    pub client_tuning: SocketTuning,
+    #[clap(skip)]
+    pub tunnel_tuning: SocketTuning,
+    #[clap(skip)]
+    pub backend_tuning: SocketTuning,
+    #[clap(skip)]
+    pub hello: HelloProfile,
+    #[clap(skip)]
+    pub worker_cores: Vec<usize>,
+    #[clap(skip)]
+    pub worker_sched: Option<Priority>,
+    /// Metadata of `--request-metadata`, empty for none
+    #[clap(skip)]
+    pub request_metadata: Metadata,
+    /// Effective options for describe, secrets redacted
+    #[clap(skip)]
+    settings: Vec<String>,
 }
 
 #[derive(Parser)]
+#[allow(clippy::large_enum_variant)]
 pub enum Mode {
     #[clap(version, name = "proxy", about = "run in proxy mode")]
     Proxy(ProxyArgs),
@@ -69,6 +229,30 @@ pub enum Mode {
     Wintun(WintunArgs),
     #[clap(version, name = "dns", about = "run in dns mode")]
     Dns(DnsArgs),
+    #[clap(version, name = "ctl", about = "run a management command on a server")]
+    Ctl(CtlArgs),
+}
+
+#[derive(Parser, Debug)]
+pub struct CtlArgs {
+    /// Trojan server hostname
+    #[clap(short = 'H', long)]
+    pub hostname: String,
+
+    /// Trojan server port
+    #[clap(short = 'o', long, default_value = "443")]
+    pub port: u16,
+
+    /// Server name for tls, the hostname if not given
+    #[clap(long)]
+    pub sni: Option<String>,
+
+    /// CA certificates to verify the server with instead of the webpki roots
+    #[clap(long, default_value = "")]
+    pub ca: String,
+
+    /// Command to run, like stats or reload
+    pub command: String,
 }
 
 #[derive(Parser, Debug)]
@@ -133,7 +317,7 @@ pub struct WintunArgs {
     #[clap(long)]
     pub inverse_route: bool,
 
-    /// DNS server address used for query trojan server ip
+    /// DNS server address used for query trojan server ip, the port defaults to 53
     #[clap(long)]
     pub dns_server_addr: Option<String>,
 }
@@ -151,6 +335,270 @@ pub struct ProxyArgs {
     /// Pool size, 0 for disable
     #[clap(short = 'P', long, default_value = "0")]
     pub pool_size: usize,
+
+    /// Pooled connections of an endpoint connecting or handshaking at a time, 0 for no limit
+    #[clap(long, default_value = "4")]
+    pub pool_handshakes: usize,
+
+    /// Send the first flight of pooled connections in the SYN with TCP fast open, linux only
+    #[clap(long)]
+    pub tcp_fast_open: bool,
+
+    /// Address actually dialed, like a CDN edge in host:port format, defaults to hostname and port, the port alone to port
+    #[clap(long)]
+    pub connect_addr: Option<String>,
+
+    /// Server name sent in TLS ClientHello and verified against the certificate, defaults to hostname
+    #[clap(long)]
+    pub sni: Option<String>,
+
+    /// Listen address for DNS queries redirected to the proxy, like 0.0.0.0:5353, the port defaults to 53
+    #[clap(long)]
+    pub dns_redirect_addr: Option<String>,
+
+    /// DNS server receiving the redirected queries at the other end of the tunnel, the port defaults to 53
+    #[clap(long, default_value = "8.8.8.8:53")]
+    pub dns_redirect_resolver: String,
+
+    /// Local udp ports forwarded to a fixed remote through the tunnel, format like 127.0.0.1:51820=203.0.113.5:51820, the remote port defaults to the local one
+    #[clap(long)]
+    pub udp_forward: Vec<String>,
+
+    /// Read the SNI or Host of connections to ports 443 and 80 for domain routes and logs before opening the tunnel
+    #[clap(long)]
+    pub sniff: bool,
+
+    /// Send the sniffed name to the server instead of the destination ip
+    #[clap(long)]
+    pub sniff_request_domain: bool,
+
+    /// Ask the server for the cause of closing a connection it rejected, the server must be trojan-rs
+    #[clap(long)]
+    pub close_notice: bool,
+
+    /// Metadata sent with each request for the server to route on, format like 1=acct-42 for the account or 2=eu-west for a routing hint, the server must be trojan-rs
+    #[clap(long)]
+    pub request_metadata: Vec<String>,
+
+    /// Pass a reset of the client on to the target and one of the target back, instead of closing gracefully, the server must be trojan-rs
+    #[clap(long)]
+    pub propagate_reset: bool,
+
+    /// Hold back the request header until the first payload joins it, instead of sending both alone
+    #[clap(long)]
+    pub initial_cork: bool,
+
+    /// Address families the server reaches targets with, requests to another one go by the sniffed name or are refused
+    #[clap(long, value_enum, default_value = "any")]
+    pub remote_prefers: RemotePrefers,
+
+    /// Time in seconds a tunnel may hold unacked data without a byte back before counting against its endpoint, 0 for disable
+    #[clap(long, default_value = "15")]
+    pub stall_threshold: u64,
+
+    /// Connect failures in a row to an endpoint before its new clients fail at once, until a probe gets through, 0 for never
+    #[clap(long, default_value = "0")]
+    pub breaker_failures: usize,
+
+    /// Worker threads for handshakes of pooled connections, 0 for handshaking in the poll loop
+    #[clap(long, default_value = "1")]
+    pub handshake_workers: usize,
+
+    /// SO_MARK set on client and pooled server sockets, 0 for no mark
+    #[clap(long, default_value = "0")]
+    pub marker: u8,
+
+    /// Fail when the mark can't be set instead of continuing without it
+    #[clap(long)]
+    pub require_mark: bool,
+
+    /// Extra server endpoints for routes, format like name=host:port, the port defaults to port, the main server is named default
+    #[clap(long)]
+    pub endpoint: Vec<String>,
+
+    /// Route file pinning destinations to endpoints, empty for sending everything to the main server
+    #[clap(long, default_value = "")]
+    pub route_file: String,
+
+    /// Only tunnel destinations in --allowlist-file, an empty list denies all
+    #[clap(long)]
+    pub strict_allowlist: bool,
+
+    /// Addresses and names which may be tunneled with --strict-allowlist, reloaded on SIGHUP
+    #[clap(long, default_value = "")]
+    pub allowlist_file: String,
+
+    /// Pad the first records to a multiple of this size, 0 for no padding, the server must support padding
+    #[clap(long, default_value = "0")]
+    pub pad_block: u16,
+
+    /// Number of padded records of a connection, including the request
+    #[clap(long, default_value = "8")]
+    pub pad_records: usize,
+
+    /// Max length of a random dummy record sent after the request, 0 for none
+    #[clap(long, default_value = "0")]
+    pub pad_dummy: u16,
+
+    /// Destinations tracked for traffic accounting, the least used ones are replaced when full, 0 for disable
+    #[clap(long, default_value = "1024")]
+    pub top_destinations: usize,
+
+    /// Top destinations logged with each metrics report, 0 for none because of the many values
+    #[clap(long, default_value = "0")]
+    pub destination_metrics: usize,
+
+    /// Check at startup that a connection through the redirect keeps its original destination
+    #[clap(long, default_value = "true", action = clap::ArgAction::Set)]
+    pub self_test: bool,
+
+    /// Datagrams per second a udp association may send into the tunnel, 0 for no limit
+    #[clap(long, default_value = "0")]
+    pub udp_pace_packets: u64,
+
+    /// Bytes per second a udp association may send into the tunnel, 0 for no limit
+    #[clap(long, default_value = "0")]
+    pub udp_pace_bytes: u64,
+
+    /// Move a udp association to a new tunnel when its tunnel breaks, instead of closing it
+    #[clap(long)]
+    pub udp_session_resume: bool,
+
+    /// Seconds a udp association may stay quiet toward the server before an empty datagram refreshes the nat on the way, 0 for none
+    ///
+    /// The keepalive goes to the destination the client sent to last, only while it got a
+    /// datagram within --udp-idle-timeout. This server discards empty datagrams, servers
+    /// which don't forward them to the destination, only enable it with servers of this
+    /// version.
+    #[clap(long, default_value = "0")]
+    pub udp_keepalive: u64,
+
+    /// Milliseconds data may wait toward the server before reads pause and udp datagrams drop, 0 to only limit by bytes
+    #[clap(long, default_value = "5")]
+    pub aqm_target: u64,
+
+    /// Datagrams over the pace waiting for their turn, the oldest is dropped when full
+    #[clap(long, default_value = "64")]
+    pub udp_pace_queue: usize,
+
+    /// Time in milliseconds between releases of paced datagrams, only while any are waiting
+    #[clap(long, default_value = "10")]
+    pub pace_tick: u64,
+
+    /// File keeping endpoint health across restarts, empty for none
+    #[clap(long, default_value = "")]
+    pub health_file: String,
+
+    /// Compress tcp payload in the tunnel, the server must support compression
+    #[clap(long)]
+    pub compress: bool,
+
+    /// Destination ports never compressed, like TLS on 443 which doesn't shrink
+    #[clap(long, default_values_t = vec![443])]
+    pub compress_skip_ports: Vec<u16>,
+
+    /// Seconds the poll loop may stay busy with connections open before the watchdog acts, 0 for no watchdog
+    #[clap(long, default_value = "30")]
+    pub watchdog_timeout: u64,
+
+    /// What the watchdog does after logging a stuck poll loop
+    #[clap(long, value_enum, default_value = "abort")]
+    pub watchdog_action: WatchdogAction,
+
+    /// Milliseconds the 95th percentile of accept to request sent may reach in a minute before a warning, 0 for none
+    #[clap(long, default_value = "0")]
+    pub tunnel_latency_alarm: u64,
+
+    /// File the audit trail of tcp connections is appended to in json lines, empty for none
+    #[clap(long, default_value = "")]
+    pub audit_file: String,
+
+    /// Megabytes the audit file may reach before it is rotated, 0 for never
+    #[clap(long, default_value = "64")]
+    pub audit_max_size: u64,
+
+    /// Rotated audit files kept
+    #[clap(long, default_value = "5")]
+    pub audit_keep: usize,
+
+    /// Salt the client addresses of audit lines are hashed with, empty to write them as is
+    #[clap(long, default_value = "")]
+    pub audit_salt: String,
+
+    /// Seconds between self checks through the tunnel, 0 for none
+    #[clap(long, default_value = "0")]
+    pub healthcheck_interval: u64,
+
+    /// Host and port the self check connects through the tunnel, it must answer a http HEAD request
+    #[clap(long, default_value = "connectivitycheck.gstatic.com:80")]
+    pub healthcheck_target: String,
+
+    /// Seconds a self check may take before it fails
+    #[clap(long, default_value = "10")]
+    pub healthcheck_timeout: u64,
+
+    /// Self checks failing in a row before the alert
+    #[clap(long, default_value = "3")]
+    pub healthcheck_failures: u32,
+
+    /// File rewritten with the self check status after each check, empty for none
+    #[clap(long, default_value = "")]
+    pub healthcheck_status_file: String,
+
+    /// Url like http://host:port/path the alert and the recovery are posted to in json, outside the tunnel, empty for none
+    #[clap(long, default_value = "")]
+    pub healthcheck_webhook: String,
+
+    /// Tcp connections of the transparent listener at a time, the ones accepted beyond are closed right away, 0 for no limit
+    #[clap(long, default_value = "0")]
+    pub max_connections: usize,
+
+    /// Udp associations of the transparent listener at a time, datagrams of new clients beyond are dropped, 0 for no limit
+    #[clap(long, default_value = "0")]
+    pub max_udp_connections: usize,
+
+    /// How new connections go whatever the routes say: tunnel as routed, direct to their destination without the tunnel, or reject, changed at runtime with the route-mode command of --control-socket
+    #[clap(long, value_enum, default_value = "tunnel")]
+    pub route_mode: RouteMode,
+
+    /// Unix socket path for local management commands, one command line per connection answered in json, empty for none
+    #[clap(long, default_value = "")]
+    pub control_socket: String,
+}
+
+impl ProxyArgs {
+    /// Host and port to dial, from `connect_addr` if present.
+    pub fn connect_host(&self) -> (String, u16) {
+        if let Some(addr) = &self.connect_addr {
+            let endpoint = endpoint::parse_option("connect-addr", addr, Some(self.port));
+            (endpoint.host.to_string(), endpoint.port)
+        } else {
+            (self.hostname.clone(), self.port)
+        }
+    }
+
+    pub fn sni(&self) -> &str {
+        self.sni.as_deref().unwrap_or(&self.hostname)
+    }
+
+    /// Warns about server name settings that break certificate verification,
+    /// called once the logger is ready.
+    pub fn check_sni(&self) {
+        if self.sni() != self.hostname {
+            log::warn!(
+                "tls server name {} differs from hostname {}, server certificate is verified against {}",
+                self.sni(),
+                self.hostname,
+                self.sni()
+            );
+        }
+        if self.sni().parse::<IpAddr>().is_ok() {
+            log::error!(
+                "tls server name {} is an ip address, certificate verification will fail",
+                self.sni()
+            );
+        }
+    }
 }
 
 #[derive(Parser)]
@@ -163,15 +611,15 @@ pub struct DnsArgs {
     #[clap(long, default_value = "ipset/domain.txt")]
     pub blocked_domain_list: String,
 
-    /// Listen address for DNS server, like 127.0.0.1:53
+    /// Listen address for DNS server, like 127.0.0.1:53, the port defaults to 53
     #[clap(long, default_value = "127.0.0.1:53")]
     pub dns_listen_address: String,
 
-    /// Trusted DNS server
+    /// Trusted DNS server, the port defaults to 53
     #[clap(long, default_value = "8.8.8.8")]
     pub trusted_dns: String,
 
-    /// Poisoned DNS server
+    /// Poisoned DNS server, the port defaults to 53
     #[clap(long, default_value = "114.114.114.114")]
     pub poisoned_dns: String,
 
@@ -184,6 +632,57 @@ pub struct DnsArgs {
     pub add_route: bool,
 }
 
+/// What the watchdog does about a stuck poll loop, for `--watchdog-action`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchdogAction {
+    /// Abort the process so its supervisor restarts it
+    Abort,
+    /// Have the loop dump its state and register all connections again once it returns
+    Recover,
+}
+
+/// Egress address families of the server, for `--remote-prefers`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemotePrefers {
+    /// Both families, requests go as they are
+    Any,
+    Ipv4,
+    Ipv6,
+    /// Told by the server once connected, it must be trojan-rs
+    Auto,
+}
+
+/// How the proxy takes new connections, for `--route-mode`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteMode {
+    /// Through the tunnel the routes pick
+    Tunnel,
+    /// Relayed to their destination locally, unencrypted
+    Direct,
+    /// Refused, nothing leaves
+    Reject,
+}
+
+/// What the server does when a udp target send is shorter than the datagram
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UdpTruncate {
+    /// Discard the datagram and continue
+    Drop,
+    /// Send the datagram once more, discard it if that is short again
+    Retry,
+    /// Close the udp session
+    Shutdown,
+}
+
+/// How strictly the server parses trojan requests
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolCompat {
+    /// Requests off the protocol are passed to the http backend
+    Strict,
+    /// Requests with one of the deviations in `proto::Deviation` are accepted
+    Lenient,
+}
+
 #[derive(Parser)]
 pub struct ServerArgs {
     /// Certificate file path, This should contain PEM-format certificates in the right order (the first certificate should certify KEYFILE, the last should be a root CA
@@ -194,7 +693,7 @@ pub struct ServerArgs {
     #[clap(short, long)]
     pub key: String,
 
-    /// Http backend server address
+    /// Http backend server address, the port defaults to 80, a host name is resolved again once the dns cache entry expires
     #[clap(short, long, default_value = "127.0.0.1:80")]
     pub remote_addr: String,
 
@@ -203,15 +702,220 @@ pub struct ServerArgs {
     pub dns_cache_time: u64,
 
     /// Check client auth
-    #[clap(short, long)]
+    #[clap(long)]
     pub check_auth: bool,
 
     /// ALPN protocol supported
     #[clap(short = 'n', long)]
     pub alpn: Vec<String>,
+
+    /// More listen addresses sharing the connections, bans and quotas of local-addr, like 0.0.0.0:8443 or [::]:8443
+    #[clap(long)]
+    pub extra_local_addr: Vec<String>,
+
+    /// UDP destination ports relayed as length prefixed TCP streams, like DNS on 53
+    #[clap(long)]
+    pub udp_via_tcp_ports: Vec<u16>,
+
+    /// Idle timeout of udp associations by destination port, format like 53=3 in seconds, other ports use udp-idle-timeout
+    #[clap(long)]
+    pub udp_port_timeout: Vec<String>,
+
+    /// Local ports given in turn to new udp associations, like 20000-30000, empty for ephemeral ports
+    #[clap(long, default_value = "")]
+    pub udp_port_range: String,
+
+    /// Bind udp backend sockets to the local address the tls connection came in on, for servers with several addresses
+    #[clap(long)]
+    pub udp_bind_ingress_ip: bool,
+
+    /// Source address of tcp target connections, given several times they take turns per family, each adding its own ephemeral ports
+    #[clap(long)]
+    pub egress_source_ip: Vec<IpAddr>,
+
+    /// Set IP_BIND_ADDRESS_NO_PORT on target connections bound to --egress-source-ip, so ports are shared across destinations, linux only
+    #[clap(long)]
+    pub bind_address_no_port: bool,
+
+    /// Issue TLS 1.3 session tickets so clients can resume sessions
+    #[clap(long)]
+    pub session_ticket: bool,
+
+    /// File holding the ticket master secret shared by all server processes, re-read on SIGHUP, empty for a random key
+    #[clap(long, default_value = "")]
+    pub ticket_key_file: String,
+
+    /// Egress rule file with allow and deny lines, re-read on SIGHUP, empty for allowing all targets
+    #[clap(long, default_value = "")]
+    pub egress_acl_file: String,
+
+    /// Deny targets in loopback, private and link local ranges
+    #[clap(long)]
+    pub block_private_targets: bool,
+
+    /// Idle seconds before sending TCP keepalive probes on client connections, 0 for disabled
+    #[clap(long, default_value = "0")]
+    pub tcp_keepalive: u64,
+
+    /// Queue length of TCP fast open requests on the listener, 0 for disabled
+    #[clap(long, default_value = "0")]
+    pub tcp_fast_open_backlog: u32,
+
+    /// Seconds the kernel holds new connections until their first data, the ClientHello, before the server is woken for them, up to 600, 0 for disabled. A client silent that long is passed on anyway once the kernel gives up, it then gets as long again from accept to send its first byte instead of --tcp-idle-timeout, the idle clock starts at accept
+    #[clap(long, default_value = "0")]
+    pub defer_accept_secs: u32,
+
+    /// Answer management commands like stats from authenticated clients
+    #[clap(long)]
+    pub control: bool,
+
+    /// Answer the echo and discard test commands, which never reach a target, for checking a tunnel without a server to test against
+    #[clap(long)]
+    pub test_backends: bool,
+
+    /// Allow management commands which change the server, like reload, and the history and targets commands showing addresses
+    #[clap(long)]
+    pub control_mutating: bool,
+
+    /// Closed connections kept for the history command, 0 for remembering none
+    #[clap(long, default_value = "1024")]
+    pub history_size: usize,
+
+    /// Targets tracked for the connect statistics of the targets command, the least used ones are replaced when full, 0 for disable
+    #[clap(long, default_value = "1024")]
+    pub target_stats: usize,
+
+    /// Handling of udp target sends shorter than the datagram
+    #[clap(long, value_enum, default_value = "drop")]
+    pub udp_truncate: UdpTruncate,
+
+    /// Compress the payload sent to proxies which ask for compression
+    #[clap(long)]
+    pub compression: bool,
+
+    /// Monthly traffic quota in GB of the password, new requests over it are closed, 0 for no quota
+    #[clap(long, default_value = "0")]
+    pub quota_gb: u64,
+
+    /// Day of month from 1 to 28 on which the quota usage starts again
+    #[clap(long, default_value = "1")]
+    pub quota_reset_day: u8,
+
+    /// File keeping the quota usage across restarts, empty for none
+    #[clap(long, default_value = "")]
+    pub usage_file: String,
+
+    /// Unix socket path for handing the listener to a new process on SIGUSR1, empty for no upgrades
+    #[clap(long, default_value = "")]
+    pub upgrade_socket: String,
+
+    /// Accept the request deviations of some third party clients with lenient
+    #[clap(long, value_enum, default_value = "strict")]
+    pub protocol_compat: ProtocolCompat,
+
+    /// User to switch to once the listeners are bound and the certificates read, empty for staying
+    #[clap(long, default_value = "")]
+    pub user: String,
+
+    /// Group to switch to with --user, empty for the primary group of the user
+    #[clap(long, default_value = "")]
+    pub group: String,
+
+    /// Sample accept queues of the listeners and drops of the udp sockets from the kernel every 10s
+    #[clap(long)]
+    pub kernel_stats: bool,
+}
+
+/// Options whose values describe leaves out
+const SECRET_OPTIONS: &[&str] = &["password"];
+
+/// Bounds of --listen-backlog and --defer-accept-secs
+pub const MAX_LISTEN_BACKLOG: u32 = 65535;
+pub const MAX_DEFER_ACCEPT_SECS: u32 = 600;
+const CAP_NET_BIND_SERVICE: u64 = 1 << 10;
+const CAP_NET_ADMIN: u64 = 1 << 12;
+
+/// Lines of the options in `matches` and its subcommand, whatever set them.
+fn settings(matches: &ArgMatches) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut matches = Some(("", matches));
+    while let Some((name, current)) = matches {
+        if !name.is_empty() {
+            lines.push(format!("{}:", name));
+        }
+        let mut ids: Vec<_> = current.ids().map(|id| id.as_str()).collect();
+        ids.sort_unstable();
+        for id in ids {
+            let value = if SECRET_OPTIONS.contains(&id) {
+                "<redacted>".to_owned()
+            } else {
+                current
+                    .get_raw(id)
+                    .map(|values| {
+                        values
+                            .map(|value| value.to_string_lossy())
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    })
+                    .unwrap_or_default()
+            };
+            let default = match current.value_source(id) {
+                Some(clap::parser::ValueSource::DefaultValue) => " (default)",
+                _ => "",
+            };
+            lines.push(format!(
+                "  {} = {:?}{}",
+                id.replace('_', "-"),
+                value,
+                default
+            ));
+        }
+        matches = current.subcommand();
+    }
+    lines
+}
+
+fn on(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
 }
 
 impl Opts {
+    fn from_matches(matches: &ArgMatches) -> Opts {
+        let mut opts = Opts::from_arg_matches(matches).unwrap_or_else(|err| err.exit());
+        opts.settings = settings(matches);
+        opts
+    }
+
+    /// The effective options and what this build and host support, for the
+    /// startup log and the config command.
+    pub fn describe(&self) -> String {
+        let mut lines = vec!["effective configuration:".to_owned()];
+        lines.extend(self.settings.iter().cloned());
+        lines.push(format!(
+            "features: profiling:{} redirect:{} wintun:{}",
+            on(cfg!(feature = "profiling")),
+            on(cfg!(unix)),
+            on(cfg!(windows))
+        ));
+        let caps = sys::capabilities();
+        lines.push(match caps {
+            Some(caps) => format!(
+                "capabilities: net_admin:{} net_bind_service:{}",
+                on(caps & CAP_NET_ADMIN != 0),
+                on(caps & CAP_NET_BIND_SERVICE != 0)
+            ),
+            None => "capabilities: unknown".to_owned(),
+        });
+        // the transparent listener sets IP_TRANSPARENT, which needs net_admin
+        let net_admin = caps.is_some_and(|caps| caps & CAP_NET_ADMIN != 0);
+        lines.push(format!("tproxy available:{}", on(cfg!(unix) && net_admin)));
+        lines.join("\n")
+    }
+
     pub fn server_args(&self) -> &ServerArgs {
         match self.mode {
             Mode::Server(ref args) => args,
@@ -234,6 +938,13 @@ impl Opts {
         }
     }
 
+    pub fn ctl_args(&self) -> &CtlArgs {
+        match self.mode {
+            Mode::Ctl(ref args) => args,
+            _ => panic!("not in ctl mode"),
+        }
+    }
+
     #[allow(dead_code)]
     pub fn dns_args(&self) -> &DnsArgs {
         match self.mode {
@@ -242,7 +953,32 @@ impl Opts {
         }
     }
 
-    fn resolve(&mut self, hostname: String, port: u16, dns_server: Option<&str>) {
+    /// Whether the server hostname may resolve to `ip`, logs rejected answers.
+    pub fn server_ip_allowed(&self, hostname: &str, ip: IpAddr) -> bool {
+        let ip_value = to_u128(ip);
+        if self.server_allowlist.is_empty()
+            || self
+                .server_allowlist
+                .iter()
+                .any(|cidr| cidr.contains(ip_value))
+        {
+            true
+        } else {
+            log::warn!(
+                "server {} resolved to {} outside of the allowlist, answer rejected",
+                hostname,
+                ip
+            );
+            false
+        }
+    }
+
+    fn resolve(&mut self, hostname: String, port: u16, dns_server: Option<SocketAddr>) {
+        if let Ok(ip) = hostname.parse::<IpAddr>() {
+            self.back_addr.replace(SocketAddr::new(ip, port));
+            log::info!("server address is {}", ip);
+            return;
+        }
         for i in 0..10 {
             if let Ok(response) = if let Some(dns_server) = dns_server {
                 resolve(hostname.as_str(), dns_server)
@@ -250,6 +986,9 @@ impl Opts {
                 dns_lookup::lookup_host(hostname.as_str()).map_err(|_| TrojanError::Dummy(()))
             } {
                 for ip in response {
+                    if !self.server_ip_allowed(hostname.as_str(), ip) {
+                        continue;
+                    }
                     if ip.is_ipv4() {
                         self.back_addr.replace(SocketAddr::new(ip, port));
                         break;
@@ -271,26 +1010,80 @@ impl Opts {
     }
 
     pub fn setup(&mut self) {
+        self.server_allowlist = self
+            .server_ip_allowlist
+            .iter()
+            .map(|text| {
+                Cidr::parse(text)
+                    .unwrap_or_else(|| panic!("invalid server ip allowlist entry:{}", text))
+            })
+            .collect();
+        self.client_tuning = tuning::parse_option("client-socket", self.client_socket.as_str());
+        self.tunnel_tuning = tuning::parse_option("tunnel-socket", self.tunnel_socket.as_str());
+        self.backend_tuning = tuning::parse_option("backend-socket", self.backend_socket.as_str());
+        self.worker_cores = worker::parse_affinity(self.worker_affinity.as_str());
+        self.worker_sched = worker::parse_priority(self.worker_priority.as_str());
+        self.hello = hello::parse_option(
+            self.tls_hello.as_str(),
+            self.tls_ciphers.as_str(),
+            self.tls_groups.as_str(),
+            self.tls_versions.as_str(),
+            self.tls_alpn.as_str(),
+        );
+        if !(1..=MAX_LISTEN_BACKLOG).contains(&self.listen_backlog) {
+            panic!(
+                "invalid --listen-backlog {}, it takes 1 to {}",
+                self.listen_backlog, MAX_LISTEN_BACKLOG
+            );
+        }
         match self.mode {
             Mode::Server(ref args) => {
-                let back_addr: SocketAddr = args.remote_addr.parse().unwrap();
-                self.back_addr = Some(back_addr);
+                if args.defer_accept_secs > MAX_DEFER_ACCEPT_SECS {
+                    panic!(
+                        "invalid --defer-accept-secs {}, it takes at most {}",
+                        args.defer_accept_secs, MAX_DEFER_ACCEPT_SECS
+                    );
+                }
+                let remote = endpoint::parse_option("remote-addr", &args.remote_addr, Some(80));
+                match remote.socket_addr() {
+                    Some(back_addr) => {
+                        self.back_addr = Some(back_addr);
+                    }
+                    None => {
+                        log::info!("remote address {} is resolved by connections", remote.host);
+                        self.back_host = Some((remote.host.to_string(), remote.port));
+                        self.empty_addr =
+                            Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+                    }
+                }
             }
             Mode::Proxy(ref args) => {
-                let hostname = args.hostname.clone();
-                let port = args.port;
+                if args.strict_allowlist == args.allowlist_file.is_empty() {
+                    panic!("--strict-allowlist and --allowlist-file must be set together");
+                }
+                self.tunnel_tuning.fast_open = args.tcp_fast_open;
+                self.request_metadata = proto::parse_metadata_option(&args.request_metadata);
+                let (hostname, port) = args.connect_host();
                 self.resolve(hostname, port, None);
             }
             Mode::Wintun(ref args) => {
                 let hostname = args.hostname.clone();
                 let port = args.port;
-                let dns_server = args.dns_server_addr.clone();
-                self.resolve(hostname, port, dns_server.as_deref());
+                let dns_server = args
+                    .dns_server_addr
+                    .as_deref()
+                    .map(|addr| endpoint::parse_addr("dns-server-addr", addr, Some(53)));
+                self.resolve(hostname, port, dns_server);
             }
             Mode::Dns(_) => {}
+            Mode::Ctl(ref args) => {
+                let hostname = args.hostname.clone();
+                let port = args.port;
+                self.resolve(hostname, port, None);
+            }
         }
-        if self.back_addr.is_some() {
-            let empty_addr = if self.back_addr.as_ref().unwrap().is_ipv4() {
+        if let Some(back_addr) = &self.back_addr {
+            let empty_addr = if back_addr.is_ipv4() {
                 SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
             } else {
                 SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
@@ -299,6 +1092,19 @@ impl Opts {
         }
         self.udp_idle_duration = Duration::new(self.udp_idle_timeout, 0);
         self.tcp_idle_duration = Duration::new(self.tcp_idle_timeout, 0);
+        self.drain_duration = Duration::new(self.drain_timeout, 0);
+        self.connect_duration = Some(self.connect_timeout)
+            .filter(|timeout| *timeout != 0)
+            .map(|timeout| Duration::new(timeout, 0));
+        self.first_byte_duration = Some(self.first_byte_timeout)
+            .filter(|timeout| *timeout != 0)
+            .map(|timeout| Duration::new(timeout, 0));
+        self.tcp_lifetime = Some(self.tcp_max_lifetime)
+            .filter(|lifetime| *lifetime != 0)
+            .map(|lifetime| Duration::new(lifetime, 0));
+        self.udp_lifetime = Some(self.udp_max_lifetime)
+            .filter(|lifetime| *lifetime != 0)
+            .map(|lifetime| Duration::new(lifetime, 0));
         self.digest_pass();
     }
 
@@ -315,14 +1121,6 @@ impl Opts {
         self.sha_pass = result;
     }
 
-    pub fn check_pass(&self, pass: &str) -> Option<&String> {
-        if self.sha_pass.eq(pass) {
-            Some(&self.password)
-        } else {
-            None
-        }
-    }
-
     pub fn get_pass(&self) -> &String {
         &self.sha_pass
     }
@@ -353,11 +1151,12 @@ pub fn setup_logger(logfile: &str, level: u8) {
         cfg_if::cfg_if! {
             if #[cfg(unix)] {
                 let path = std::path::Path::new(logfile);
-                builder = builder.chain(fern::log_reopen(path, Some(libc::SIGUSR2)).unwrap());
+                let file = Box::new(fern::log_reopen(path, Some(libc::SIGUSR2)).unwrap());
             } else {
-                builder = builder.chain(fern::log_file(logfile).unwrap());
+                let file = Box::new(fern::log_file(logfile).unwrap());
             }
         }
+        builder = builder.chain(logger::output(file));
     } else {
         builder = builder.chain(std::io::stdout());
     }
@@ -366,8 +1165,55 @@ pub fn setup_logger(logfile: &str, level: u8) {
 
 lazy_static::lazy_static! {
     pub static ref OPTIONS:Opts = {
-        let mut opts = Opts::parse();
+        let mut opts = Opts::from_matches(&Opts::command().get_matches());
         opts.setup();
         opts
     };
 }
+
+mod test {
+    #![allow(unused_imports)]
+
+    use clap::CommandFactory;
+
+    use crate::config::Opts;
+
+    #[test]
+    fn test_describe() {
+        let matches = Opts::command()
+            .try_get_matches_from([
+                "trojan",
+                "--local-addr",
+                "0.0.0.0:443",
+                "-p",
+                "secret",
+                "server",
+                "--cert",
+                "cert.pem",
+                "--key",
+                "key.pem",
+                "--extra-local-addr",
+                "0.0.0.0:8443",
+                "--extra-local-addr",
+                "[::]:8443",
+            ])
+            .unwrap();
+        let describe = Opts::from_matches(&matches).describe();
+        assert!(!describe.contains("secret"), "{}", describe);
+        assert!(
+            describe.contains("  password = \"<redacted>\"\n"),
+            "{}",
+            describe
+        );
+        assert!(describe.contains("server:\n"), "{}", describe);
+        assert!(describe.contains("  cert = \"cert.pem\"\n"), "{}", describe);
+        assert!(
+            describe.contains("  extra-local-addr = \"0.0.0.0:8443,[::]:8443\"\n"),
+            "{}",
+            describe
+        );
+        assert!(describe.contains("  history-size = \"1024\" (default)\n"));
+        assert!(describe.contains("\nfeatures: "), "{}", describe);
+        assert!(describe.contains("\ntproxy available:"), "{}", describe);
+    }
+}
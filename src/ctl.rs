@@ -0,0 +1,68 @@
+//! Client for management commands, sent over a normal trojan connection.
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{BufReader, Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    time::Duration,
+};
+
+use rustls::{Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore};
+
+use crate::{
+    config::OPTIONS,
+    proto::{TrojanRequest, CONTROL, MAX_REQUEST_LEN},
+    types::Result,
+};
+
+fn root_store(ca: &str) -> Result<RootCertStore> {
+    let mut root_store = RootCertStore::empty();
+    if ca.is_empty() {
+        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+    } else {
+        let mut reader = BufReader::new(File::open(ca)?);
+        for cert in rustls_pemfile::certs(&mut reader)? {
+            root_store.add(&Certificate(cert))?;
+        }
+    }
+    Ok(root_store)
+}
+
+/// Runs the command on the server and prints its json response.
+pub fn run() -> Result<()> {
+    let args = OPTIONS.ctl_args();
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store(args.ca.as_str())?)
+        .with_no_client_auth();
+    let sni = args.sni.as_deref().unwrap_or(args.hostname.as_str());
+    let session = ClientConnection::new(Arc::new(config), sni.try_into()?)?;
+    let stream = TcpStream::connect(OPTIONS.back_addr.unwrap())?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut tls = rustls::StreamOwned::new(session, stream);
+
+    let mut request = [0u8; MAX_REQUEST_LEN];
+    let len = TrojanRequest::write(&mut request, CONTROL, OPTIONS.empty_addr.as_ref().unwrap());
+    let mut request = request[..len].to_vec();
+    request.extend_from_slice(args.command.as_bytes());
+    request.push(b'\n');
+    tls.write_all(request.as_slice())?;
+    tls.flush()?;
+
+    let mut response = String::new();
+    if let Err(err) = tls.read_to_string(&mut response) {
+        // servers may close without close_notify
+        if response.is_empty() {
+            return Err(err.into());
+        }
+    }
+    println!("{}", response.trim_end());
+    Ok(())
+}
@@ -8,8 +8,8 @@ use winapi::{
 
 use server::DnsServer;
 
-use crate::{dns::adapter::get_adapter_index, OPTIONS, types::Result};
 pub use crate::dns::adapter::{get_adapter_ip, get_main_adapter_gwif, set_dns_server};
+use crate::{dns::adapter::get_adapter_index, types::Result, OPTIONS};
 
 mod adapter;
 mod domain;
@@ -17,6 +17,7 @@ use trust_dns_proto::{
 
 use crate::{
     dns::{domain::DomainMap, DNS_LOCAL, DNS_POISONED, DNS_TRUSTED},
+    endpoint,
     proto::MAX_PACKET_SIZE,
     wintun::route_add_with_if,
     OPTIONS,
@@ -46,22 +47,19 @@ struct QueryResult {
 impl DnsServer {
     pub fn new(index: u32) -> Self {
         let default_addr = "0.0.0.0:0".to_owned();
-        let trusted_dns_addr = OPTIONS.dns_args().trusted_dns.clone() + ":53";
-        let poisoned_dns_addr = OPTIONS.dns_args().poisoned_dns.clone() + ":53";
-        let trusted_addr = trusted_dns_addr.as_str().parse().unwrap();
-        let poisoned_addr = poisoned_dns_addr.as_str().parse().unwrap();
+        let args = OPTIONS.dns_args();
+        let trusted_addr = endpoint::parse_addr("trusted-dns", args.trusted_dns.as_str(), Some(53));
+        let poisoned_addr =
+            endpoint::parse_addr("poisoned-dns", args.poisoned_dns.as_str(), Some(53));
 
         Self {
             trusted_addr,
             poisoned_addr,
-            listener: UdpSocket::bind(
-                OPTIONS
-                    .dns_args()
-                    .dns_listen_address
-                    .as_str()
-                    .parse()
-                    .unwrap(),
-            )
+            listener: UdpSocket::bind(endpoint::parse_addr(
+                "dns-listen-address",
+                args.dns_listen_address.as_str(),
+                Some(53),
+            ))
             .unwrap(),
             trusted: UdpSocket::bind(default_addr.as_str().parse().unwrap()).unwrap(),
             poisoned: UdpSocket::bind(default_addr.as_str().parse().unwrap()).unwrap(),
@@ -0,0 +1,133 @@
+//! State snapshot of all connections, requested with SIGUSR2.
+//!
+//! The signal handler only sets a flag, the dump itself is written by the
+//! poll loop, so it sees a consistent state without any locking.
+//!
+//! SIGUSR2 also reopens the log file, like from the postrotate hook of
+//! logrotate. A signal which finds the log file replaced since the last
+//! one is taken for that and writes no dump, rotations with copytruncate
+//! keep the file and dump as well.
+use std::{
+    fmt::Arguments,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::config::OPTIONS;
+
+lazy_static::lazy_static! {
+    static ref REQUESTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    /// Device and inode of the log file at the last signal
+    static ref LOG_FILE: Mutex<Option<(u64, u64)>> = Mutex::new(None);
+}
+
+pub struct Dump {
+    file: Option<BufWriter<File>>,
+}
+
+impl Dump {
+    /// A dump written right away, like by a thread other than the poll loop.
+    pub fn new() -> Dump {
+        let file = if OPTIONS.dump_file.is_empty() {
+            None
+        } else {
+            match OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(OPTIONS.dump_file.as_str())
+            {
+                Ok(file) => Some(BufWriter::new(file)),
+                Err(err) => {
+                    log::error!("open dump file {} failed:{}", OPTIONS.dump_file, err);
+                    None
+                }
+            }
+        };
+        let mut dump = Dump { file };
+        dump.line(format_args!(
+            "state dump at {}",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.6f")
+        ));
+        dump
+    }
+
+    pub fn line(&mut self, args: Arguments) {
+        if let Some(file) = &mut self.file {
+            if let Err(err) = writeln!(file, "{}", args) {
+                log::error!("write dump file failed:{}", err);
+                self.file.take();
+            }
+        } else {
+            log::warn!("{}", args);
+        }
+    }
+}
+
+impl Drop for Dump {
+    fn drop(&mut self) {
+        if let Some(file) = &mut self.file {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// Installs the SIGUSR2 handler. The same signal also reopens the log file.
+pub fn init() {
+    *LOG_FILE.lock().unwrap() = log_identity();
+    #[cfg(unix)]
+    if let Err(err) = signal_hook::flag::register(libc::SIGUSR2, REQUESTED.clone()) {
+        log::error!("register SIGUSR2 for state dump failed:{}", err);
+    }
+}
+
+/// Has the poll loop write a dump as if SIGUSR2 came.
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Returns a dump to be filled if one was requested since the last call.
+pub fn take() -> Option<Dump> {
+    if REQUESTED.swap(false, Ordering::SeqCst) {
+        if rotated() {
+            log::info!(
+                "log file {} was replaced, SIGUSR2 reopened it without a state dump",
+                OPTIONS.log_file
+            );
+            return None;
+        }
+        Some(Dump::new())
+    } else {
+        None
+    }
+}
+
+/// Device and inode of the file at the log path, None without one.
+#[cfg(unix)]
+fn log_identity() -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    if OPTIONS.log_file.is_empty() {
+        return None;
+    }
+    std::fs::metadata(OPTIONS.log_file.as_str())
+        .ok()
+        .map(|metadata| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn log_identity() -> Option<(u64, u64)> {
+    None
+}
+
+/// Whether the log file was replaced since the last signal.
+fn rotated() -> bool {
+    let identity = log_identity();
+    let mut last = LOG_FILE.lock().unwrap();
+    let rotated = *last != identity;
+    *last = identity;
+    rotated
+}
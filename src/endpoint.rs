@@ -0,0 +1,281 @@
+//! Host and port options, parsed the same way for every option.
+//!
+//! An option takes `host:port`, `192.0.2.1:port` or `[2001:db8::1]:port`,
+//! options with a default port also take the host alone, an IPv6 address
+//! then without brackets too. Errors name the option and the forms.
+use std::{
+    fmt::{Display, Formatter},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+};
+
+/// Forms listed in errors
+const FORMS: &str = "host:port, 192.0.2.1:port or [2001:db8::1]:port";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Host {
+    Ip(IpAddr),
+    Name(String),
+}
+
+impl Display for Host {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Host::Ip(ip) => ip.fmt(f),
+            Host::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    pub host: Host,
+    pub port: u16,
+}
+
+impl Endpoint {
+    /// The address of an ip host, None for a name.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self.host {
+            Host::Ip(ip) => Some(SocketAddr::new(ip, self.port)),
+            Host::Name(_) => None,
+        }
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.host {
+            Host::Ip(IpAddr::V6(ip)) => write!(f, "[{}]:{}", ip, self.port),
+            _ => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EndpointError {
+    Empty,
+    MissingPort,
+    InvalidPort(String),
+    InvalidHost(String),
+    /// `[` without `]`
+    UnclosedBracket,
+    /// An IPv6 address followed by a port without brackets
+    Unbracketed,
+}
+
+impl Display for EndpointError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EndpointError::Empty => f.write_str("empty"),
+            EndpointError::MissingPort => f.write_str("missing port"),
+            EndpointError::InvalidPort(port) => write!(f, "invalid port {}", port),
+            EndpointError::InvalidHost(host) => write!(f, "invalid host {}", host),
+            EndpointError::UnclosedBracket => f.write_str("unclosed bracket"),
+            EndpointError::Unbracketed => f.write_str("an ipv6 address with a port needs brackets"),
+        }
+    }
+}
+
+fn parse_port(text: &str) -> Result<u16, EndpointError> {
+    text.parse()
+        .map_err(|_| EndpointError::InvalidPort(text.to_owned()))
+}
+
+fn valid_name(name: &str) -> bool {
+    let labels = name.strip_suffix('.').unwrap_or(name);
+    name.len() <= 253
+        // digits and dots only is a broken ipv4 address
+        && !labels.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && labels.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+/// Parses a host with optional port, `default_port` is taken without one,
+/// the port is required without a default.
+pub fn parse_endpoint(text: &str, default_port: Option<u16>) -> Result<Endpoint, EndpointError> {
+    let text = text.trim();
+    let missing = || default_port.ok_or(EndpointError::MissingPort);
+    if text.is_empty() {
+        return Err(EndpointError::Empty);
+    }
+    if let Some(rest) = text.strip_prefix('[') {
+        let (ip, after) = rest.split_once(']').ok_or(EndpointError::UnclosedBracket)?;
+        let ip: Ipv6Addr = ip
+            .parse()
+            .map_err(|_| EndpointError::InvalidHost(ip.to_owned()))?;
+        let port = match after.strip_prefix(':') {
+            Some(text) => parse_port(text)?,
+            None if after.is_empty() => missing()?,
+            None => return Err(EndpointError::InvalidPort(after.to_owned())),
+        };
+        return Ok(Endpoint {
+            host: Host::Ip(IpAddr::V6(ip)),
+            port,
+        });
+    }
+    if text.matches(':').count() > 1 {
+        let ip: Ipv6Addr = text
+            .parse()
+            .map_err(|_| EndpointError::InvalidHost(text.to_owned()))?;
+        let port = default_port.ok_or(EndpointError::Unbracketed)?;
+        return Ok(Endpoint {
+            host: Host::Ip(IpAddr::V6(ip)),
+            port,
+        });
+    }
+    let (host, port) = match text.rsplit_once(':') {
+        Some((host, text)) => (host, parse_port(text)?),
+        None => (text, missing()?),
+    };
+    let host = if let Ok(ip) = host.parse::<IpAddr>() {
+        Host::Ip(ip)
+    } else if valid_name(host) {
+        Host::Name(host.to_owned())
+    } else {
+        return Err(EndpointError::InvalidHost(host.to_owned()));
+    };
+    Ok(Endpoint { host, port })
+}
+
+/// The error of option `option` with `text`.
+fn invalid(option: &str, text: &str, err: impl Display, default_port: Option<u16>) -> String {
+    let mut message = format!(
+        "invalid --{} {}: {}, accepted forms are {}",
+        option, text, err, FORMS
+    );
+    if let Some(port) = default_port {
+        message.push_str(&format!(" with the port defaulting to {}", port));
+    }
+    message
+}
+
+/// Parses option `option`, panics if invalid like other startup options.
+pub fn parse_option(option: &str, text: &str, default_port: Option<u16>) -> Endpoint {
+    parse_endpoint(text, default_port)
+        .unwrap_or_else(|err| panic!("{}", invalid(option, text, err, default_port)))
+}
+
+/// Parses option `option` which takes an ip address, panics for names too.
+pub fn parse_addr(option: &str, text: &str, default_port: Option<u16>) -> SocketAddr {
+    parse_option(option, text, default_port)
+        .socket_addr()
+        .unwrap_or_else(|| {
+            let err = "an ip address is required";
+            panic!("{}", invalid(option, text, err, default_port))
+        })
+}
+
+/// Parses each value of option `option` with [`parse_addr`].
+pub fn parse_addrs(option: &str, texts: &[String], default_port: Option<u16>) -> Vec<SocketAddr> {
+    texts
+        .iter()
+        .map(|text| parse_addr(option, text.as_str(), default_port))
+        .collect()
+}
+
+mod test {
+    #![allow(unused_imports, dead_code)]
+
+    use std::net::IpAddr;
+
+    use crate::endpoint::{invalid, parse_endpoint, Endpoint, EndpointError, Host};
+
+    fn parsed(text: &str, default_port: Option<u16>) -> Result<String, EndpointError> {
+        parse_endpoint(text, default_port).map(|endpoint| match endpoint.host {
+            Host::Ip(_) => format!("ip {}", endpoint),
+            Host::Name(_) => format!("name {}", endpoint),
+        })
+    }
+
+    #[test]
+    fn test_forms() {
+        let ok = |text: &str, default_port, expected: &str| {
+            assert_eq!(
+                parsed(text, default_port).as_deref(),
+                Ok(expected),
+                "{}",
+                text
+            );
+        };
+        for default_port in [None, Some(53)] {
+            ok("example.com:443", default_port, "name example.com:443");
+            ok("192.0.2.1:443", default_port, "ip 192.0.2.1:443");
+            ok("[2001:db8::1]:443", default_port, "ip [2001:db8::1]:443");
+            ok(
+                "[::ffff:192.0.2.1]:0",
+                default_port,
+                "ip [::ffff:192.0.2.1]:0",
+            );
+            ok(" localhost:80 ", default_port, "name localhost:80");
+            ok("my_host.lan.:8080", default_port, "name my_host.lan.:8080");
+        }
+        ok("example.com", Some(53), "name example.com:53");
+        ok("192.0.2.1", Some(53), "ip 192.0.2.1:53");
+        ok("[2001:db8::1]", Some(53), "ip [2001:db8::1]:53");
+        ok("2001:db8::1", Some(53), "ip [2001:db8::1]:53");
+        ok("::", Some(53), "ip [::]:53");
+    }
+
+    #[test]
+    fn test_errors() {
+        use EndpointError::*;
+        let err = |text: &str, default_port| parsed(text, default_port).unwrap_err();
+        assert_eq!(err("", Some(53)), Empty);
+        assert_eq!(err("   ", None), Empty);
+        assert_eq!(err("example.com", None), MissingPort);
+        assert_eq!(err("192.0.2.1", None), MissingPort);
+        assert_eq!(err("[2001:db8::1]", None), MissingPort);
+        assert_eq!(err("2001:db8::1", None), Unbracketed);
+        // a bare ipv6 address never takes a port, the last group is a group
+        assert_eq!(err("2001:db8::1:443", None), Unbracketed);
+        assert_eq!(err("example.com:", None), InvalidPort("".to_owned()));
+        assert_eq!(
+            err("example.com:65536", None),
+            InvalidPort("65536".to_owned())
+        );
+        assert_eq!(
+            err("example.com:https", None),
+            InvalidPort("https".to_owned())
+        );
+        assert_eq!(err("[2001:db8::1]443", None), InvalidPort("443".to_owned()));
+        assert_eq!(err("[2001:db8::1:443", None), UnclosedBracket);
+        assert_eq!(
+            err("[192.0.2.1]:443", None),
+            InvalidHost("192.0.2.1".to_owned())
+        );
+        assert_eq!(
+            err("2001:db8:::1", Some(53)),
+            InvalidHost("2001:db8:::1".to_owned())
+        );
+        assert_eq!(
+            err("192.0.2.300:53", None),
+            InvalidHost("192.0.2.300".to_owned())
+        );
+        assert_eq!(err(":443", None), InvalidHost("".to_owned()));
+        assert_eq!(
+            err("exa mple.com:443", None),
+            InvalidHost("exa mple.com".to_owned())
+        );
+        assert_eq!(err("a..b:443", None), InvalidHost("a..b".to_owned()));
+        assert_eq!(
+            err("http://example.com", Some(80)),
+            InvalidPort("//example.com".to_owned())
+        );
+
+        assert_eq!(
+            invalid(
+                "dns-server",
+                "8.8.8.8:x",
+                InvalidPort("x".to_owned()),
+                Some(53)
+            ),
+            "invalid --dns-server 8.8.8.8:x: invalid port x, accepted forms are host:port, \
+             192.0.2.1:port or [2001:db8::1]:port with the port defaulting to 53"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Address families a server can reach targets with.
+//!
+//! A server without ipv4 egress can't serve a request to an ipv4 address,
+//! and the failure only shows in its own log. `--remote-prefers` tells the
+//! proxy which families the server has, requests to another one go in the
+//! domain form if the name was sniffed or are refused by the proxy.
+//!
+//! With `--remote-prefers auto` the proxy learns them instead: it sets
+//! [`FAMILIES`](crate::proto::FAMILIES) along with the close notice flag,
+//! and the server answers with [`Notice::Families`](crate::notice::Notice)
+//! instead of a plain ok. The server finds its families by whether it has a
+//! route for each. Servers without this extension refuse such requests.
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::Mutex,
+};
+
+use crate::config::{RemotePrefers, OPTIONS};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Families {
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
+
+impl Families {
+    pub const ALL: Families = Families {
+        ipv4: true,
+        ipv6: true,
+    };
+
+    pub fn bits(self) -> u8 {
+        self.ipv4 as u8 | (self.ipv6 as u8) << 1
+    }
+
+    /// Families of `bits`, None if there are none.
+    pub fn from_bits(bits: u8) -> Option<Families> {
+        let families = Families {
+            ipv4: bits & 0x01 != 0,
+            ipv6: bits & 0x02 != 0,
+        };
+        if families.ipv4 || families.ipv6 {
+            Some(families)
+        } else {
+            None
+        }
+    }
+
+    pub fn supports(self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(_) => self.ipv4,
+            IpAddr::V6(_) => self.ipv6,
+        }
+    }
+}
+
+impl Display for Families {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (self.ipv4, self.ipv6) {
+            (true, true) => f.write_str("ipv4,ipv6"),
+            (true, false) => f.write_str("ipv4"),
+            (false, true) => f.write_str("ipv6"),
+            (false, false) => f.write_str("none"),
+        }
+    }
+}
+
+pub fn name(ip: IpAddr) -> &'static str {
+    if ip.is_ipv4() {
+        "ipv4"
+    } else {
+        "ipv6"
+    }
+}
+
+/// Whether a datagram to `addr` has a route, nothing is sent.
+fn routed(addr: &str) -> bool {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let local = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    UdpSocket::bind(local)
+        .and_then(|socket| socket.connect(addr))
+        .is_ok()
+}
+
+lazy_static::lazy_static! {
+    /// Families of the server, found on the first use
+    static ref EGRESS: Families = {
+        let families = Families {
+            ipv4: routed("192.0.2.1:53"),
+            ipv6: routed("[2001:db8::1]:53"),
+        };
+        log::warn!("server egress families:{}", families);
+        families
+    };
+    /// Families of the endpoints learned by the proxy
+    static ref LEARNED: Mutex<HashMap<usize, Families>> = Mutex::new(HashMap::new());
+}
+
+/// Families the server reaches targets with.
+pub fn egress() -> Families {
+    *EGRESS
+}
+
+/// Families the server of `endpoint` has, all of them until known.
+pub fn remote(endpoint: usize) -> Families {
+    match OPTIONS.proxy_args().remote_prefers {
+        RemotePrefers::Any => Families::ALL,
+        RemotePrefers::Ipv4 => Families {
+            ipv4: true,
+            ipv6: false,
+        },
+        RemotePrefers::Ipv6 => Families {
+            ipv4: false,
+            ipv6: true,
+        },
+        RemotePrefers::Auto => LEARNED
+            .lock()
+            .unwrap()
+            .get(&endpoint)
+            .copied()
+            .unwrap_or(Families::ALL),
+    }
+}
+
+/// Stores the families told by the server of `endpoint`.
+pub fn learn(endpoint: usize, families: Families) {
+    let previous = LEARNED.lock().unwrap().insert(endpoint, families);
+    if previous != Some(families) {
+        log::warn!("endpoint:{} egress families:{}", endpoint, families);
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use crate::family::Families;
+
+    #[test]
+    fn test_bits() {
+        for bits in 1..=3 {
+            assert_eq!(Families::from_bits(bits).unwrap().bits(), bits);
+        }
+        assert_eq!(Families::from_bits(0), None);
+        let ipv6 = Families::from_bits(2).unwrap();
+        assert!(ipv6.supports("2001:db8::1".parse().unwrap()));
+        assert!(!ipv6.supports("192.0.2.1".parse().unwrap()));
+        assert_eq!(ipv6.to_string(), "ipv6");
+    }
+}
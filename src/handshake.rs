@@ -0,0 +1,93 @@
+//! Worker threads running the crypto of client TLS handshakes.
+//!
+//! The poll loop keeps doing all the socket I/O, it only hands raw TLS
+//! records together with the session over to a worker, and gets back the
+//! session with the records to be written.
+use std::sync::{
+    mpsc::{channel, Receiver, Sender},
+    Arc,
+};
+
+use mio::{Token, Waker};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use rustls::ClientConnection;
+
+use crate::{types::Result, worker};
+
+/// Session returned from a worker.
+pub struct Handshaken {
+    pub token: Token,
+    pub session: ClientConnection,
+    pub output: Vec<u8>,
+    pub error: Option<rustls::Error>,
+}
+
+pub struct Handshaker {
+    workers: ThreadPool,
+    waker: Arc<Waker>,
+    sender: Sender<Handshaken>,
+    receiver: Receiver<Handshaken>,
+}
+
+impl Handshaker {
+    pub fn new(workers: usize, waker: Arc<Waker>) -> Result<Handshaker> {
+        let workers = ThreadPoolBuilder::new()
+            .num_threads(workers)
+            .thread_name(|i| format!("handshake-{}", i))
+            .start_handler(|_| {
+                worker::start("handshake");
+                worker::idle();
+            })
+            .build()?;
+        let (sender, receiver) = channel();
+        Ok(Handshaker {
+            workers,
+            waker,
+            sender,
+            receiver,
+        })
+    }
+
+    pub fn submit(&self, token: Token, mut session: ClientConnection, input: Vec<u8>) {
+        let sender = self.sender.clone();
+        let waker = self.waker.clone();
+        self.workers.spawn(move || {
+            worker::busy();
+            let mut error = None;
+            let mut input = input.as_slice();
+            while !input.is_empty() {
+                if let Err(err) = session.read_tls(&mut input) {
+                    log::error!("read tls records failed:{}", err);
+                    error.replace(rustls::Error::General(err.to_string()));
+                    break;
+                }
+                if let Err(err) = session.process_new_packets() {
+                    error.replace(err);
+                    break;
+                }
+            }
+            let mut output = Vec::new();
+            while error.is_none() && session.wants_write() {
+                if let Err(err) = session.write_tls(&mut output) {
+                    error.replace(rustls::Error::General(err.to_string()));
+                }
+            }
+            let result = Handshaken {
+                token,
+                session,
+                output,
+                error,
+            };
+            if sender.send(result).is_err() {
+                log::error!("send handshake result failed");
+            } else if let Err(err) = waker.wake() {
+                log::error!("wake failed {}", err);
+            }
+            worker::idle();
+        });
+    }
+
+    pub fn consume<F: FnMut(Handshaken)>(&self, f: F) {
+        self.receiver.try_iter().for_each(f);
+    }
+}
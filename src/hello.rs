@@ -0,0 +1,266 @@
+//! The visible parts of the ClientHello the proxy sends to the server.
+//!
+//! `--tls-hello` picks a preset:
+//!
+//! * `default` is what rustls offers on its own, no ALPN
+//! * `chrome-like` orders suites and groups like Chrome and offers
+//!   `h2,http/1.1`
+//! * `minimal` only offers TLS 1.3 with AES-128-GCM, ChaCha20 and X25519
+//!
+//! `--tls-ciphers`, `--tls-groups`, `--tls-versions` and `--tls-alpn`
+//! replace single parts of the preset, each a comma separated list like
+//! `TLS13_AES_128_GCM_SHA256,TLS13_CHACHA20_POLY1305_SHA256`, `x25519,secp256r1`,
+//! `1.3,1.2` and `h2,http/1.1`, with `none` for no ALPN. A server started
+//! with `--alpn` needs to accept one of the offered ALPN protocols.
+//!
+//! rustls decides the extensions and their order, and sends no GREASE, so
+//! a JA3 of the proxy won't match a browser even with the same suites. The
+//! parts which can be set are logged at startup in JA3 notation, to compare
+//! with the fronting site.
+use std::sync::Arc;
+
+use rustls::{
+    client::ServerCertVerifier,
+    version::{TLS12, TLS13},
+    CipherSuite, ClientConfig, SupportedCipherSuite, SupportedKxGroup, SupportedProtocolVersion,
+    ALL_CIPHER_SUITES, ALL_KX_GROUPS,
+};
+
+/// Chrome's order of the suites rustls has
+const CHROME_SUITES: &[CipherSuite] = &[
+    CipherSuite::TLS13_AES_128_GCM_SHA256,
+    CipherSuite::TLS13_AES_256_GCM_SHA384,
+    CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+    CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+];
+const MINIMAL_SUITES: &[CipherSuite] = &[
+    CipherSuite::TLS13_AES_128_GCM_SHA256,
+    CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+];
+
+#[derive(Default)]
+pub struct HelloProfile {
+    suites: Vec<SupportedCipherSuite>,
+    groups: Vec<&'static SupportedKxGroup>,
+    versions: Vec<&'static SupportedProtocolVersion>,
+    alpn: Vec<String>,
+}
+
+fn suites(names: &[CipherSuite]) -> Vec<SupportedCipherSuite> {
+    names
+        .iter()
+        .filter_map(|name| {
+            ALL_CIPHER_SUITES
+                .iter()
+                .find(|suite| suite.suite() == *name)
+        })
+        .copied()
+        .collect()
+}
+
+/// Splits a comma separated override, None if it is not given.
+fn items(text: &str) -> Option<Vec<&str>> {
+    let items: Vec<_> = text
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .collect();
+    if items.is_empty() {
+        None
+    } else {
+        Some(items)
+    }
+}
+
+fn parse_list<T: Copy>(
+    text: &str,
+    all: &[T],
+    name: impl Fn(&T) -> String,
+) -> Result<Option<Vec<T>>, String> {
+    let items = if let Some(items) = items(text) {
+        items
+    } else {
+        return Ok(None);
+    };
+    items
+        .into_iter()
+        .map(|item| {
+            all.iter()
+                .find(|value| name(value).eq_ignore_ascii_case(item))
+                .copied()
+                .ok_or_else(|| item.to_owned())
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+impl HelloProfile {
+    pub fn preset(name: &str) -> Option<HelloProfile> {
+        let profile = match name {
+            "default" => HelloProfile {
+                suites: ALL_CIPHER_SUITES.to_vec(),
+                groups: ALL_KX_GROUPS.to_vec(),
+                versions: vec![&TLS13, &TLS12],
+                alpn: Vec::new(),
+            },
+            "chrome-like" => HelloProfile {
+                suites: suites(CHROME_SUITES),
+                groups: ALL_KX_GROUPS.to_vec(),
+                versions: vec![&TLS13, &TLS12],
+                alpn: vec!["h2".to_owned(), "http/1.1".to_owned()],
+            },
+            "minimal" => HelloProfile {
+                suites: suites(MINIMAL_SUITES),
+                groups: ALL_KX_GROUPS[..1].to_vec(),
+                versions: vec![&TLS13],
+                alpn: Vec::new(),
+            },
+            _ => return None,
+        };
+        Some(profile)
+    }
+
+    /// Takes `preset` with the overrides given, checking that rustls can
+    /// build a client out of them.
+    pub fn parse(
+        preset: &str,
+        ciphers: &str,
+        groups: &str,
+        versions: &str,
+        alpn: &str,
+    ) -> Result<HelloProfile, String> {
+        let mut profile =
+            HelloProfile::preset(preset).ok_or_else(|| format!("unknown preset {}", preset))?;
+        if let Some(suites) = parse_list(ciphers, ALL_CIPHER_SUITES, |suite| {
+            format!("{:?}", suite.suite())
+        })
+        .map_err(|item| format!("unknown cipher suite {}", item))?
+        {
+            profile.suites = suites;
+        }
+        if let Some(groups) =
+            parse_list(groups, &ALL_KX_GROUPS, |group| format!("{:?}", group.name))
+                .map_err(|item| format!("unknown group {}", item))?
+        {
+            profile.groups = groups;
+        }
+        let all_versions: [&'static SupportedProtocolVersion; 2] = [&TLS13, &TLS12];
+        if let Some(versions) =
+            parse_list(versions, &all_versions, |version| match version.version {
+                rustls::ProtocolVersion::TLSv1_3 => "1.3".to_owned(),
+                _ => "1.2".to_owned(),
+            })
+            .map_err(|item| format!("unknown tls version {}", item))?
+        {
+            profile.versions = versions;
+        }
+        match items(alpn) {
+            Some(items) if items == ["none"] => profile.alpn.clear(),
+            Some(items) => profile.alpn = items.into_iter().map(str::to_owned).collect(),
+            None => {}
+        }
+        ClientConfig::builder()
+            .with_cipher_suites(profile.suites.as_slice())
+            .with_kx_groups(profile.groups.as_slice())
+            .with_protocol_versions(profile.versions.as_slice())
+            .map_err(|err| err.to_string())?;
+        Ok(profile)
+    }
+
+    pub fn client_config(&self, verifier: Arc<dyn ServerCertVerifier>) -> Arc<ClientConfig> {
+        let mut config = ClientConfig::builder()
+            .with_cipher_suites(self.suites.as_slice())
+            .with_kx_groups(self.groups.as_slice())
+            .with_protocol_versions(self.versions.as_slice())
+            .unwrap()
+            .with_custom_certificate_verifier(verifier)
+            .with_no_client_auth();
+        config.alpn_protocols = self
+            .alpn
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        Arc::new(config)
+    }
+
+    /// The version, suites and groups fields of JA3 and the ALPN list.
+    pub fn fingerprint(&self) -> String {
+        let join = |codes: Vec<u16>| {
+            codes
+                .iter()
+                .map(u16::to_string)
+                .collect::<Vec<_>>()
+                .join("-")
+        };
+        // a TLS 1.3 hello still says 1.2 in its version field
+        format!(
+            "771,{},{},alpn:{}",
+            join(
+                self.suites
+                    .iter()
+                    .filter(|suite| self.versions.contains(&suite.version()))
+                    .map(|suite| suite.suite().get_u16())
+                    .collect()
+            ),
+            join(
+                self.groups
+                    .iter()
+                    .map(|group| group.name.get_u16())
+                    .collect()
+            ),
+            if self.alpn.is_empty() {
+                "none".to_owned()
+            } else {
+                self.alpn.join(",")
+            }
+        )
+    }
+}
+
+/// Parses the `--tls-*` options, panics for invalid ones like other startup
+/// options.
+pub fn parse_option(
+    preset: &str,
+    ciphers: &str,
+    groups: &str,
+    versions: &str,
+    alpn: &str,
+) -> HelloProfile {
+    HelloProfile::parse(preset, ciphers, groups, versions, alpn)
+        .unwrap_or_else(|err| panic!("invalid client hello options, {}", err))
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use crate::hello::HelloProfile;
+
+    #[test]
+    fn test_profile() {
+        let chrome = HelloProfile::parse("chrome-like", "", "", "", "").unwrap();
+        assert_eq!(
+            chrome.fingerprint(),
+            "771,4865-4866-4867-49195-49199-49196-49200-52393-52392,29-23-24,alpn:h2,http/1.1"
+        );
+        let minimal = HelloProfile::parse("minimal", "", "", "", "none").unwrap();
+        assert_eq!(minimal.fingerprint(), "771,4865-4867,29,alpn:none");
+        // tls 1.2 suites are left out of a tls 1.3 only hello
+        let custom = HelloProfile::parse(
+            "default",
+            "TLS13_AES_256_GCM_SHA384, tls_ecdhe_rsa_with_aes_128_gcm_sha256",
+            "secp256r1",
+            "1.3",
+            "h2",
+        )
+        .unwrap();
+        assert_eq!(custom.fingerprint(), "771,4866,23,alpn:h2");
+        assert!(HelloProfile::parse("firefox", "", "", "", "").is_err());
+        assert!(HelloProfile::parse("default", "TLS13_AES_128_CCM", "", "", "").is_err());
+        assert!(HelloProfile::parse("minimal", "", "", "1.2", "").is_err());
+    }
+}
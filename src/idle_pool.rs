@@ -1,19 +1,220 @@
 use std::{
-    net::{IpAddr, SocketAddr},
+    cell::RefCell,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::{ErrorKind, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr},
+    ops::{Deref, DerefMut},
+    rc::Rc,
     sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
+use bytes::BytesMut;
 use itertools::Itertools;
-use mio::{event::Event, net::TcpStream, Poll, Token};
+use mio::{event::Event, net::TcpStream, Interest, Poll, Token};
+use ring::rand::{SecureRandom, SystemRandom};
 use rustls::{ClientConfig, ClientConnection, Connection, ServerName};
 
 use crate::{
-    config::OPTIONS, resolver::DnsResolver, status::StatusProvider, tls_conn::TlsConn,
+    config::OPTIONS,
+    dump::Dump,
+    handshake::{Handshaken, Handshaker},
+    metrics::{POOL_CLOSED_AT_CHECKOUT, POOL_RETURNED},
+    refill::Refill,
+    resolver::DnsResolver,
+    status::StatusProvider,
+    sys,
+    tls_conn::TlsConn,
     types::Result,
 };
 
+/// A connection from [`IdlePool::get`], it goes back to the pool when
+/// dropped unless [`commit`](Checkout::commit)ted or
+/// [`keep`](Checkout::keep)t, so a setup failing before the session was
+/// used doesn't waste its handshake. Until then it may take another token,
+/// the pool gives it its own back, but its session must stay untouched.
+pub struct Checkout {
+    conn: Option<TlsConn>,
+    /// Pool index and token, restored when it is returned
+    pooled: (usize, Token),
+    /// None once committed
+    returned: Option<Returned>,
+}
+
+/// Connections of dropped checkouts, with their pool index and token.
+type Returned = Rc<RefCell<Vec<(TlsConn, (usize, Token))>>>;
+
+impl Checkout {
+    /// Wraps a connection which goes nowhere when dropped.
+    pub fn detached(conn: TlsConn) -> Checkout {
+        Checkout {
+            pooled: (conn.index(), conn.token()),
+            conn: Some(conn),
+            returned: None,
+        }
+    }
+
+    pub fn commit(mut self) -> TlsConn {
+        self.conn.take().unwrap()
+    }
+
+    /// Commits in place, the connection is no longer returned.
+    pub fn keep(&mut self) {
+        self.returned = None;
+    }
+
+    pub fn committed(&self) -> bool {
+        self.returned.is_none()
+    }
+}
+
+impl Deref for Checkout {
+    type Target = TlsConn;
+
+    fn deref(&self) -> &TlsConn {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Checkout {
+    fn deref_mut(&mut self) -> &mut TlsConn {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        if let (Some(conn), Some(returned)) = (self.conn.take(), self.returned.take()) {
+            returned.borrow_mut().push((conn, self.pooled));
+        }
+    }
+}
+
+/// Connection handshaking with the help of a [`Handshaker`].
+struct Pending {
+    index: usize,
+    token: Token,
+    stream: TcpStream,
+    // None while the session is on a worker
+    session: Option<ClientConnection>,
+    recv_buffer: Vec<u8>,
+    send_buffer: Vec<u8>,
+    create_time: Instant,
+}
+
+impl Pending {
+    /// Returns false if the stream is broken.
+    fn flush(&mut self) -> bool {
+        while !self.send_buffer.is_empty() {
+            match self.stream.write(self.send_buffer.as_slice()) {
+                Ok(0) => return false,
+                Ok(size) => {
+                    self.send_buffer.drain(..size);
+                }
+                Err(err)
+                    if err.kind() == ErrorKind::WouldBlock
+                        || err.kind() == ErrorKind::NotConnected =>
+                {
+                    break
+                }
+                Err(err) => {
+                    log::info!("pending connection:{} write failed:{}", self.index, err);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Reads all available data, returns false if the stream is closed.
+    fn fill(&mut self) -> bool {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return false,
+                Ok(size) => self.recv_buffer.extend_from_slice(&buffer[..size]),
+                Err(err)
+                    if err.kind() == ErrorKind::WouldBlock
+                        || err.kind() == ErrorKind::NotConnected =>
+                {
+                    return true
+                }
+                Err(err) => {
+                    log::info!("pending connection:{} read failed:{}", self.index, err);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Hands received records over to a worker unless one is busy already.
+    fn submit(&mut self, handshaker: &Handshaker) {
+        if self.session.is_some() && !self.recv_buffer.is_empty() {
+            let input = std::mem::take(&mut self.recv_buffer);
+            handshaker.submit(self.token, self.session.take().unwrap(), input);
+        }
+    }
+
+    fn close(mut self, poll: &Poll) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+        let _ = poll.registry().deregister(&mut self.stream);
+    }
+
+    /// Turns the handshaken session into a pooled connection.
+    fn complete(self) -> Option<TlsConn> {
+        let mut session = self.session?;
+        let mut input = self.recv_buffer.as_slice();
+        while !input.is_empty() {
+            if session.read_tls(&mut input).is_err() || session.process_new_packets().is_err() {
+                log::info!("pending connection:{} got invalid records", self.index);
+                return None;
+            }
+        }
+        let mut conn = TlsConn::new(
+            self.index,
+            self.token,
+            Connection::Client(session),
+            self.stream,
+        );
+        conn.set_registered();
+        conn.established();
+        Some(conn)
+    }
+}
+
+/// Logs the parameters of a finished handshake, at info level for the
+/// first one of the pool.
+fn log_negotiated(conn: &mut TlsConn, domain: &str, negotiated: &mut bool) {
+    if !conn.take_negotiated() {
+        return;
+    }
+    let info = conn.tls_info();
+    let cert = conn
+        .peer_cert()
+        .map_or_else(|| "unknown".to_owned(), |cert| cert.to_string());
+    if *negotiated {
+        log::debug!("server {} negotiated {} cert {}", domain, info, cert);
+    } else {
+        *negotiated = true;
+        log::info!("server {} negotiated {} cert {}", domain, info, cert);
+    }
+}
+
+/// Random draw for the refill backoff.
+fn jitter() -> u32 {
+    let mut random = [0u8; 4];
+    let _ = SystemRandom::new().fill(&mut random);
+    u32::from_be_bytes(random)
+}
+
+/// Connect failures in a row before resolving the server host again
+const RESOLVE_FAILURES: usize = 3;
+
 pub struct IdlePool {
     pool: Vec<TlsConn>,
+    pending: Vec<Pending>,
+    handshaker: Option<Handshaker>,
     next_index: usize,
     size: usize,
     addr: SocketAddr,
@@ -25,6 +226,36 @@ pub struct IdlePool {
     channel_idle: usize,
     min_index: usize,
     max_index: usize,
+    marker: u8,
+    resolve_token: Option<Token>,
+    resolve_time: Instant,
+    /// Connections failed before their handshake since the last success
+    failures: usize,
+    /// Like `failures`, but not reset by resolving the server again
+    streak: usize,
+    health: Health,
+    /// Whether a handshake of this pool finished already
+    negotiated: bool,
+    /// Fingerprint of the settings the pooled connections were made with
+    pooled: u64,
+    /// A reload waits for the server host to be resolved again
+    reloading: bool,
+    /// The fingerprint is compared with the next timeout check
+    check_flush: bool,
+    /// Whether the last [`get`](IdlePool::get) had to connect directly
+    fresh: bool,
+    refill: Refill,
+    /// Dropped checkouts, taken back on the next call with a poll
+    returned: Returned,
+}
+
+/// Health of an endpoint, kept across restarts by the health file.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Health {
+    pub failures: usize,
+    /// Moving average of handshake times
+    pub latency: Option<Duration>,
+    pub last_failure: Option<SystemTime>,
 }
 
 impl IdlePool {
@@ -34,6 +265,18 @@ impl IdlePool {
         size: usize,
         port: u16,
         domain: String,
+    ) -> IdlePool {
+        let addr = OPTIONS.back_addr.unwrap();
+        IdlePool::with_addr(config, hostname, size, port, domain, addr)
+    }
+
+    fn with_addr(
+        config: Arc<ClientConfig>,
+        hostname: ServerName,
+        size: usize,
+        port: u16,
+        domain: String,
+        addr: SocketAddr,
     ) -> IdlePool {
         IdlePool {
             size,
@@ -45,12 +288,54 @@ impl IdlePool {
             channel_idle: 0,
             min_index: 0,
             max_index: 0,
-            addr: OPTIONS.back_addr.unwrap(),
+            addr,
             pool: Vec::new(),
+            pending: Vec::new(),
+            handshaker: None,
             next_index: 0,
+            marker: 0,
+            resolve_token: None,
+            resolve_time: Instant::now(),
+            failures: 0,
+            streak: 0,
+            health: Health::default(),
+            negotiated: false,
+            pooled: 0,
+            reloading: false,
+            check_flush: false,
+            fresh: false,
+            refill: Refill::new(0),
+            returned: Rc::new(RefCell::new(Vec::new())),
         }
     }
 
+    /// Connects to `addr` instead of the resolved main server address.
+    pub fn set_addr(&mut self, addr: SocketAddr) {
+        self.addr = addr;
+    }
+
+    /// Resolves the server host with `token` instead of the resolver's own.
+    pub fn set_resolve_token(&mut self, token: Token) {
+        self.resolve_token.replace(token);
+    }
+
+    /// Marks new server sockets with `marker`, 0 for no mark.
+    pub fn set_marker(&mut self, marker: u8) {
+        self.marker = marker;
+    }
+
+    /// Limits the connections the pool opens by itself which connect or
+    /// handshake at a time, 0 for no limit.
+    pub fn set_max_handshakes(&mut self, max_handshakes: usize) {
+        self.refill = Refill::new(max_handshakes);
+    }
+
+    /// Runs the handshakes of new pooled connections on `handshaker`,
+    /// connections required right away still handshake in the poll loop.
+    pub fn set_handshaker(&mut self, handshaker: Handshaker) {
+        self.handshaker.replace(handshaker);
+    }
+
     pub fn init_index(
         &mut self,
         channel_cnt: usize,
@@ -66,39 +351,171 @@ impl IdlePool {
     }
 
     pub fn init(&mut self, poll: &Poll, resolver: &DnsResolver) {
+        self.pooled = self.fingerprint();
         if self.size > 1 {
             self.alloc(poll, resolver);
         }
     }
 
-    pub fn get(&mut self, poll: &Poll, resolver: &DnsResolver) -> Option<TlsConn> {
-        // in case we got all the cached connections disconnected
-        for _ in 0..self.size {
-            self.alloc(poll, resolver);
-            if let Some(conn) = self.pool.pop() {
+    pub fn get(&mut self, poll: &Poll, resolver: &DnsResolver) -> Option<Checkout> {
+        self.reclaim(poll);
+        self.alloc(poll, resolver);
+        let conn = if let Some(conn) = self.take_open(poll) {
+            self.fresh = false;
+            conn
+        } else {
+            // the refills are still handshaking or wait for their turn
+            self.fresh = true;
+            self.direct(poll, resolver)?
+        };
+        Some(Checkout {
+            pooled: (conn.index(), conn.token()),
+            conn: Some(conn),
+            returned: Some(self.returned.clone()),
+        })
+    }
+
+    /// Whether the last connection given out was made for it instead of
+    /// taken from the pool.
+    pub fn fresh(&self) -> bool {
+        self.fresh
+    }
+
+    /// Pops the newest connection the server didn't close, a close_notify
+    /// may be pending since no event was handled for it yet.
+    fn take_open(&mut self, poll: &Poll) -> Option<TlsConn> {
+        while let Some(mut conn) = self.pool.pop() {
+            if !conn.check_remote_closed() {
                 return Some(conn);
             }
+            POOL_CLOSED_AT_CHECKOUT.inc();
+            log::info!("idle token:{} closed by server in the pool", conn.token().0);
+            conn.shutdown();
+            conn.check_status(poll);
         }
         None
     }
 
+    /// Takes back the connections of dropped checkouts.
+    fn reclaim(&mut self, poll: &Poll) {
+        let returned = std::mem::take(&mut *self.returned.borrow_mut());
+        for (mut conn, (index, token)) in returned {
+            if conn.token() != token && !conn.reset_index(index, token, poll) {
+                conn.check_status(poll);
+                continue;
+            }
+            self.put_back(conn, poll);
+        }
+    }
+
+    /// Keeps a connection which was not used if it is still healthy, it
+    /// must have its pool token again.
+    fn put_back(&mut self, mut conn: TlsConn, poll: &Poll) {
+        if self.pool.len() < self.size && conn.writable() && !conn.check_remote_closed() {
+            POOL_RETURNED.inc();
+            log::debug!("idle token:{} returned to the pool", conn.token().0);
+            self.pool.push(conn);
+        } else {
+            conn.shutdown();
+            conn.check_status(poll);
+        }
+    }
+
+    fn direct(&mut self, poll: &Poll, resolver: &DnsResolver) -> Option<TlsConn> {
+        match self.new_conn() {
+            Ok(mut conn) => {
+                if conn.register(poll) {
+                    Some(conn)
+                } else {
+                    None
+                }
+            }
+            Err(err) => {
+                log::error!("new connection to remote server failed:{:?}", err);
+                self.streak += 1;
+                self.update_dns(resolver);
+                None
+            }
+        }
+    }
+
+    /// Opens the connections the pool lacks, as far as the refill pacing
+    /// allows.
     fn alloc(&mut self, poll: &Poll, resolver: &DnsResolver) {
-        let size = self.pool.len();
-        for _ in size..self.size {
-            match self.new_conn() {
-                Ok(mut conn) => {
+        let open = self.pool.len() + self.pending.len();
+        let in_flight =
+            self.pending.len() + self.pool.iter().filter(|conn| conn.handshaking()).count();
+        let allowed = self
+            .refill
+            .allowance(Instant::now(), self.size, open, in_flight);
+        for _ in 0..allowed {
+            let result = if self.handshaker.is_some() {
+                self.new_pending(poll)
+                    .map(|pending| self.pending.push(pending))
+            } else {
+                self.new_conn().map(|mut conn| {
                     if conn.register(poll) {
                         self.pool.push(conn);
                     }
-                }
-                Err(err) => {
-                    log::error!("new connection to remote server failed:{:?}", err);
-                    self.update_dns(resolver);
-                }
+                })
+            };
+            if let Err(err) = result {
+                log::error!("new connection to remote server failed:{:?}", err);
+                self.streak += 1;
+                self.update_dns(resolver);
+                self.refill.failed(Instant::now(), jitter());
+                break;
             }
         }
     }
 
+    /// Hash of the settings which decide where new connections go.
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.addr.hash(&mut hasher);
+        self.domain.hash(&mut hasher);
+        self.port.hash(&mut hasher);
+        self.marker.hash(&mut hasher);
+        format!("{:?}", self.hostname).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Resolves the server host again, the pool is flushed if anything
+    /// deciding where connections go changed.
+    pub fn reload(&mut self, resolver: &DnsResolver) {
+        if self.domain.parse::<IpAddr>().is_ok() {
+            self.check_flush = true;
+        } else {
+            self.reloading = true;
+            self.update_dns(resolver);
+        }
+    }
+
+    /// Closes the idle and handshaking connections and opens new ones,
+    /// connections handed out by [`get`](Self::get) are not touched.
+    pub fn flush(&mut self, poll: &Poll, resolver: &DnsResolver) {
+        let idle = self.pool.len();
+        let handshaking = self.pending.len();
+        for mut conn in self.pool.drain(..) {
+            conn.shutdown();
+            conn.check_status(poll);
+        }
+        for pending in std::mem::take(&mut self.pending) {
+            pending.close(poll);
+        }
+        self.pooled = self.fingerprint();
+        if self.size > 1 {
+            self.alloc(poll, resolver);
+        }
+        log::warn!(
+            "pool of {} flushed, closed {} idle and {} handshaking connections, opened {}",
+            self.domain,
+            idle,
+            handshaking,
+            self.pool.len() + self.pending.len()
+        );
+    }
+
     fn next_index(&mut self) -> usize {
         let index = self.next_index;
         self.next_index += 1;
@@ -109,8 +526,10 @@ impl IdlePool {
     }
 
     fn new_conn(&mut self) -> Result<TlsConn> {
-        let server = TcpStream::connect(self.addr)?;
-        //sys::set_mark(&server, self.marker)?;
+        let server = OPTIONS.tunnel_tuning.connect("tunnel", self.addr)?;
+        if self.marker != 0 {
+            sys::set_mark(&server, self.marker)?;
+        }
         #[cfg(not(target_os = "windows"))]
         server.set_nodelay(true)?;
 
@@ -126,27 +545,233 @@ impl IdlePool {
         Ok(conn)
     }
 
+    fn new_pending(&mut self, poll: &Poll) -> Result<Pending> {
+        let mut stream = OPTIONS.tunnel_tuning.connect("tunnel", self.addr)?;
+        if self.marker != 0 {
+            sys::set_mark(&stream, self.marker)?;
+        }
+        #[cfg(not(target_os = "windows"))]
+        stream.set_nodelay(true)?;
+
+        let mut session = ClientConnection::new(self.config.clone(), self.hostname.clone())?;
+        let mut send_buffer = Vec::new();
+        while session.wants_write() {
+            session.write_tls(&mut send_buffer)?;
+        }
+        let index = self.next_index();
+        let token = Token(index * self.channel_cnt + self.channel_idle);
+        poll.registry()
+            .register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)?;
+        Ok(Pending {
+            index,
+            token,
+            stream,
+            session: Some(session),
+            recv_buffer: Vec::new(),
+            send_buffer,
+            create_time: Instant::now(),
+        })
+    }
+
+    /// Takes back sessions from the handshake workers.
+    pub fn handshaken(&mut self, poll: &Poll) {
+        let mut results = Vec::new();
+        if let Some(handshaker) = &self.handshaker {
+            handshaker.consume(|result| results.push(result));
+        }
+        for result in results {
+            self.take_back(result, poll);
+        }
+    }
+
+    fn take_back(&mut self, result: Handshaken, poll: &Poll) {
+        let position = self
+            .pending
+            .iter()
+            .position(|pending| pending.token == result.token);
+        let position = if let Some(position) = position {
+            position
+        } else {
+            log::debug!("pending token:{} closed during handshake", result.token.0);
+            return;
+        };
+        let mut pending = self.pending.swap_remove(position);
+        if let Some(err) = result.error {
+            log::error!(
+                "pending connection:{} handshake failed:{}",
+                pending.index,
+                err
+            );
+            self.close_pending(pending, poll);
+            return;
+        }
+        pending
+            .send_buffer
+            .extend_from_slice(result.output.as_slice());
+        let handshaking = result.session.is_handshaking();
+        pending.session.replace(result.session);
+        if !pending.flush() {
+            self.close_pending(pending, poll);
+        } else if handshaking {
+            pending.submit(self.handshaker.as_ref().unwrap());
+            self.pending.push(pending);
+        } else {
+            let index = pending.index;
+            let elapsed = pending.create_time.elapsed();
+            if let Some(mut conn) = pending.complete() {
+                log::debug!("connection:{} handshaken by worker", index);
+                log_negotiated(&mut conn, self.domain.as_str(), &mut self.negotiated);
+                self.failures = 0;
+                self.streak = 0;
+                self.refill.succeeded(Instant::now());
+                self.add_latency(elapsed);
+                self.pool.push(conn);
+            }
+        }
+    }
+
+    fn pending_ready(&mut self, event: &Event, poll: &Poll) -> bool {
+        let position = if let Some(position) = self
+            .pending
+            .iter()
+            .position(|pending| pending.token == event.token())
+        {
+            position
+        } else {
+            return false;
+        };
+        let pending = &mut self.pending[position];
+        let alive =
+            (!event.is_writable() || pending.flush()) && (!event.is_readable() || pending.fill());
+        if alive {
+            pending.submit(self.handshaker.as_ref().unwrap());
+        } else {
+            let pending = self.pending.swap_remove(position);
+            self.close_pending(pending, poll);
+        }
+        true
+    }
+
+    fn close_pending(&mut self, pending: Pending, poll: &Poll) {
+        self.failed();
+        pending.close(poll);
+    }
+
+    fn failed(&mut self) {
+        self.failures += 1;
+        self.streak += 1;
+        self.health.last_failure.replace(SystemTime::now());
+        self.refill.failed(Instant::now(), jitter());
+    }
+
+    /// Adds a handshake time to the moving average, weighted 1/8.
+    fn add_latency(&mut self, elapsed: Duration) {
+        let latency = match self.health.latency {
+            Some(latency) => (latency * 7 + elapsed) / 8,
+            None => elapsed,
+        };
+        self.health.latency.replace(latency);
+    }
+
+    /// Connect failures in a row, for the breaker of the endpoint.
+    pub fn streak(&self) -> usize {
+        self.streak
+    }
+
+    /// Opens a connection into the pool to see whether the server is back,
+    /// unless the pool is full.
+    pub fn probe(&mut self, poll: &Poll, resolver: &DnsResolver) {
+        self.reclaim(poll);
+        if self.pool.len() + self.pending.len() >= self.size {
+            return;
+        }
+        if let Some(conn) = self.direct(poll, resolver) {
+            self.pool.push(conn);
+        }
+    }
+
+    pub fn health(&self) -> Health {
+        Health {
+            failures: self.failures,
+            ..self.health
+        }
+    }
+
+    /// Starts from the health saved by an earlier run, so an endpoint
+    /// which was failing is resolved again right away.
+    pub fn seed(&mut self, health: Health) {
+        if health.failures > 0 {
+            log::warn!(
+                "server {} had {} connect failures before restart",
+                self.domain,
+                health.failures
+            );
+        }
+        self.failures = health.failures;
+        self.health = health;
+    }
+
     fn update_dns(&mut self, resolver: &DnsResolver) {
-        resolver.resolve(self.domain.clone(), None);
+        self.resolve_time = Instant::now();
+        if self.domain.parse::<IpAddr>().is_err() {
+            resolver.resolve_server(self.domain.clone(), self.resolve_token);
+        }
+    }
+
+    /// Re-resolves the server host every `--resolve-interval` and after
+    /// repeated failures, so a changed address is picked up by new connections.
+    fn check_dns(&mut self, resolver: &DnsResolver) {
+        if self.domain.parse::<IpAddr>().is_ok() {
+            return;
+        }
+        if self.failures >= RESOLVE_FAILURES {
+            log::warn!(
+                "{} connections to {} failed, resolve it again",
+                self.failures,
+                self.domain
+            );
+            self.failures = 0;
+            self.update_dns(resolver);
+        } else if OPTIONS.resolve_interval > 0
+            && self.resolve_time.elapsed() > Duration::from_secs(OPTIONS.resolve_interval)
+        {
+            self.update_dns(resolver);
+        }
     }
 
     pub fn resolve(&mut self, ip: Option<IpAddr>) {
-        if let Some(address) = ip {
+        if let Some(address) = ip.filter(|ip| OPTIONS.server_ip_allowed(&self.domain, *ip)) {
             log::debug!("idle_pool got resolve result {} = {}", self.domain, address);
             let addr = SocketAddr::new(address, self.port);
+            if addr != self.addr {
+                log::info!(
+                    "server {} address changed from {} to {}",
+                    self.domain,
+                    self.addr.ip(),
+                    address
+                );
+            }
             self.addr = addr;
         } else {
             log::error!("idle_pool resolve host:{} failed", self.domain);
         }
+        if self.reloading {
+            self.reloading = false;
+            self.check_flush = true;
+        }
     }
 
     pub fn ready(&mut self, event: &Event, poll: &Poll) {
+        self.reclaim(poll);
+        if self.pending_ready(event, poll) {
+            return;
+        }
         if let Some((index, conn)) = self
             .pool
             .iter_mut()
             .find_position(|conn| conn.token() == event.token())
         {
-            if event.is_readable() && conn.do_read().is_some() {
+            if event.is_readable() && conn.do_read_into(&mut BytesMut::new()) > 0 {
                 log::error!("found data in https handshake phase");
             }
             if event.is_writable() {
@@ -155,24 +780,75 @@ impl IdlePool {
             }
             conn.check_status(poll);
             if conn.deregistered() {
+                if conn.handshaking() {
+                    self.failed();
+                }
                 self.pool.swap_remove(index);
+            } else if !conn.handshaking() {
+                log_negotiated(conn, self.domain.as_str(), &mut self.negotiated);
+                self.failures = 0;
+                self.streak = 0;
+                self.refill.succeeded(Instant::now());
             }
         } else {
             log::error!("idle token:{} not found", event.token().0);
         }
     }
 
-    pub fn check_timeout(&mut self, poll: &Poll) {
+    /// Returns when [`check_timeout`](Self::check_timeout) has something to
+    /// do, None while the pool only waits for events.
+    pub fn next_deadline(&self, now: Instant) -> Option<Instant> {
+        let resolvable = self.domain.parse::<IpAddr>().is_err();
+        if self.check_flush
+            || (resolvable && self.failures >= RESOLVE_FAILURES)
+            || self.pool.iter().any(|conn| conn.handshaking())
+        {
+            return Some(now);
+        }
+        let limit = OPTIONS
+            .connect_duration
+            .unwrap_or(OPTIONS.tcp_idle_duration);
+        let pending = self
+            .pending
+            .iter()
+            .map(|pending| pending.create_time + limit)
+            .min();
+        let resolve = if resolvable && OPTIONS.resolve_interval > 0 {
+            Some(self.resolve_time + Duration::from_secs(OPTIONS.resolve_interval))
+        } else {
+            None
+        };
+        pending.into_iter().chain(resolve).min()
+    }
+
+    pub fn check_timeout(&mut self, poll: &Poll, resolver: &DnsResolver) {
+        self.reclaim(poll);
+        let limit = OPTIONS
+            .connect_duration
+            .unwrap_or(OPTIONS.tcp_idle_duration);
+        let (expired, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|pending| pending.create_time.elapsed() > limit);
+        self.pending = pending;
+        for pending in expired {
+            log::warn!("pending connection:{} handshake timeout", pending.index);
+            self.close_pending(pending, poll);
+        }
+
+        let mut failed = 0;
         let mut closed: Vec<_> = self
             .pool
             .iter_mut()
             .enumerate()
             .filter_map(|(index, conn)| {
-                if !conn.deregistered() && conn.do_read().is_some() {
+                if !conn.deregistered() && conn.do_read_into(&mut BytesMut::new()) > 0 {
                     log::error!("found data in https handshake phase");
                 }
                 conn.check_status(poll);
                 if conn.deregistered() {
+                    if conn.handshaking() {
+                        failed += 1;
+                    }
                     Some(index)
                 } else {
                     None
@@ -183,5 +859,136 @@ impl IdlePool {
         for index in closed.iter().rev() {
             self.pool.swap_remove(*index);
         }
+        if failed > 0 {
+            self.failures += failed;
+            self.streak += failed;
+            self.refill.failed(Instant::now(), jitter());
+        }
+        self.check_dns(resolver);
+        if self.check_flush {
+            self.check_flush = false;
+            if self.fingerprint() != self.pooled {
+                self.flush(poll, resolver);
+            } else {
+                log::info!("pool of {} is unchanged by reload", self.domain);
+            }
+        }
+    }
+
+    pub fn dump(&self, dump: &mut Dump) {
+        dump.line(format_args!(
+            "idle connections:{} handshaking:{} handshake latency:{}ms fast open:{}",
+            self.pool.len(),
+            self.pending.len(),
+            self.health.latency.map_or(0, |latency| latency.as_millis()),
+            OPTIONS.tunnel_tuning.fast_open
+        ));
+        for conn in &self.pool {
+            dump.line(format_args!(
+                "idle token:{} server:{}",
+                conn.token().0,
+                conn.dump_state()
+            ));
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports, dead_code)]
+
+    use std::{convert::TryInto, sync::Arc, thread, time::Duration};
+
+    use mio::{Poll, Token, Waker};
+    use rustls::{ClientConfig, RootCertStore};
+
+    use crate::{
+        idle_pool::IdlePool, metrics::POOL_RETURNED, resolver::DnsResolver, sim::loopback_pair,
+        tls_conn::TlsConn,
+    };
+
+    /// A pool of `size` holding one handshaken connection, it never
+    /// connects by itself while it isn't short of connections.
+    fn pool(size: usize) -> (IdlePool, TlsConn) {
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let mut pool = IdlePool::with_addr(
+            Arc::new(config),
+            "localhost".try_into().unwrap(),
+            size,
+            443,
+            "localhost".to_owned(),
+            "127.0.0.1:1".parse().unwrap(),
+        );
+        let (conn, server) = loopback_pair();
+        pool.pool.push(conn);
+        (pool, server)
+    }
+
+    fn resolver(poll: &Poll) -> DnsResolver {
+        let waker = Arc::new(Waker::new(poll.registry(), Token(1)).unwrap());
+        DnsResolver::new(waker, Token(1), Vec::new())
+    }
+
+    #[test]
+    fn test_checkout_returned() {
+        let poll = Poll::new().unwrap();
+        let resolver = resolver(&poll);
+        let (mut pool, _server) = pool(1);
+        let returned = POOL_RETURNED.get();
+        let checkout = pool.get(&poll, &resolver).unwrap();
+        assert!(pool.pool.is_empty());
+        // the client registration failed, the setup gave up before commit
+        drop(checkout);
+        pool.reclaim(&poll);
+        assert_eq!(POOL_RETURNED.get() - returned, 1);
+        assert_eq!(pool.pool.len(), 1);
+        assert!(pool.returned.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_moved_checkout_returned() {
+        let poll = Poll::new().unwrap();
+        let resolver = resolver(&poll);
+        let (mut pool, _server) = pool(1);
+        assert!(pool.pool[0].register(&poll));
+        let pooled = pool.pool[0].token();
+        let mut checkout = pool.get(&poll, &resolver).unwrap();
+        // the client was registered, writing the request failed
+        assert!(checkout.reset_index(7, Token(7 * 4 + 3), &poll));
+        drop(checkout);
+        pool.reclaim(&poll);
+        assert_eq!(pool.pool.len(), 1);
+        assert_eq!(pool.pool[0].token(), pooled);
+        pool.pool[0].close(&poll);
+    }
+
+    #[test]
+    fn test_kept_checkout_not_returned() {
+        let poll = Poll::new().unwrap();
+        let resolver = resolver(&poll);
+        let (mut pool, _server) = pool(1);
+        let mut checkout = pool.get(&poll, &resolver).unwrap();
+        checkout.keep();
+        assert!(checkout.committed());
+        drop(checkout);
+        assert!(pool.returned.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_closed_checkout_shut_down() {
+        let poll = Poll::new().unwrap();
+        let resolver = resolver(&poll);
+        let (mut pool, server) = pool(1);
+        let checkout = pool.get(&poll, &resolver).unwrap();
+        // the server closes it while the setup runs
+        drop(server);
+        thread::sleep(Duration::from_millis(50));
+        drop(checkout);
+        assert_eq!(pool.returned.borrow().len(), 1);
+        pool.reclaim(&poll);
+        assert!(pool.pool.is_empty());
+        assert!(pool.returned.borrow().is_empty());
     }
 }
@@ -0,0 +1,84 @@
+//! Log file output through a writer thread, so a slow disk never stalls the
+//! poll loops.
+//!
+//! Formatted records wait in a bounded queue. Debug and trace records are
+//! dropped once it is half full, the others only when it is full. Dropped
+//! records are counted in the `log_records_dropped` metric.
+use std::{
+    io::{BufWriter, Write},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use crossbeam::channel::{Receiver, Sender};
+
+use crate::metrics::LOG_DROPPED;
+
+/// Records the queue holds
+const QUEUE_LEN: usize = 4096;
+/// Max wait of a flush for the writer thread
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+enum Message {
+    Line(String),
+    Flush(Sender<()>),
+}
+
+lazy_static::lazy_static! {
+    static ref SENDER: Mutex<Option<Sender<Message>>> = Mutex::new(None);
+}
+
+/// Returns an output which passes records to a thread writing `writer`.
+pub fn output(writer: Box<dyn Write + Send>) -> fern::Output {
+    let (sender, receiver) = crossbeam::channel::bounded(QUEUE_LEN);
+    thread::Builder::new()
+        .name("logger".to_owned())
+        .spawn(move || write_loop(receiver, writer))
+        .unwrap();
+    *SENDER.lock().unwrap() = Some(sender.clone());
+    fern::Output::call(move |record| {
+        if record.level() >= log::Level::Debug && sender.len() >= QUEUE_LEN / 2 {
+            LOG_DROPPED.inc();
+            return;
+        }
+        let line = format!("{}\n", record.args());
+        if sender.try_send(Message::Line(line)).is_err() {
+            LOG_DROPPED.inc();
+        }
+    })
+}
+
+fn write_loop(receiver: Receiver<Message>, writer: Box<dyn Write + Send>) {
+    let mut writer = BufWriter::new(writer);
+    while let Ok(message) = receiver.recv() {
+        match message {
+            Message::Line(line) => {
+                let _ = writer.write_all(line.as_bytes());
+            }
+            Message::Flush(done) => {
+                let _ = writer.flush();
+                let _ = done.send(());
+            }
+        }
+        if receiver.is_empty() {
+            let _ = writer.flush();
+        }
+    }
+    let _ = writer.flush();
+}
+
+/// Waits until the queued records are written, called before exit.
+pub fn flush() {
+    let sender = match SENDER.lock().unwrap().as_ref() {
+        Some(sender) => sender.clone(),
+        None => return,
+    };
+    let (done, wait) = crossbeam::channel::bounded(1);
+    if sender
+        .send_timeout(Message::Flush(done), FLUSH_TIMEOUT)
+        .is_ok()
+    {
+        let _ = wait.recv_timeout(FLUSH_TIMEOUT);
+    }
+}
@@ -5,26 +5,57 @@ use std::panic;
 
 use backtrace::Backtrace;
 
-use crate::config::{Mode, OPTIONS};
+use crate::{
+    config::{Mode, OPTIONS},
+    types::TrojanError,
+};
 
+mod audit;
+mod cert;
+mod cidr;
+mod codel;
+mod compress;
 mod config;
+mod ctl;
+mod dump;
+mod endpoint;
+mod family;
 cfg_if::cfg_if! {
     if #[cfg(windows)] {
         mod dns;
         mod wintun;
     }
 }
+mod handshake;
+mod hello;
 mod idle_pool;
+mod logger;
+mod metrics;
+mod notice;
+mod padding;
+mod profile;
 mod proto;
 mod proxy;
+mod refill;
+mod reload;
 mod resolver;
 mod server;
+mod sim;
+mod stale;
+mod startup;
 mod status;
+mod summary;
 mod sys;
 mod tcp_util;
 mod tls_conn;
+mod trace;
+mod tuning;
 mod types;
+mod udp_loss;
 mod utils;
+mod verify;
+mod wheel;
+mod worker;
 
 fn main() {
     #[cfg(debug_assertions)]
@@ -37,6 +68,7 @@ fn main() {
         let trace = Backtrace::new();
         let message = info.to_string();
         log::error!("application exit with error:{}\n{:?}", message, trace);
+        logger::flush();
         cfg_if::cfg_if! {
           if #[cfg(windows)] {
             if let Mode::Dns(_) = OPTIONS.mode {
@@ -45,6 +77,9 @@ fn main() {
             }
         }
     }));
+    if !matches!(OPTIONS.mode, Mode::Ctl(_)) {
+        log::info!("{}", OPTIONS.describe());
+    }
     if let Err(err) = match OPTIONS.mode {
         Mode::Proxy(_) => {
             log::warn!(
@@ -79,7 +114,20 @@ fn main() {
                 }
             }
         }
+        Mode::Ctl(_) => ctl::run(),
     } {
-        log::error!("trojan exited with error:{:?}", err);
+        let code = if let TrojanError::Startup(failure, hint) = &err {
+            log::error!("{}", hint);
+            if !OPTIONS.log_file.is_empty() {
+                eprintln!("{}", hint);
+            }
+            failure.exit_code()
+        } else {
+            log::error!("trojan exited with error:{:?}", err);
+            1
+        };
+        logger::flush();
+        std::process::exit(code);
     }
+    logger::flush();
 }
@@ -0,0 +1,172 @@
+//! Process wide counters, reported periodically by the poll loops.
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// Interval between two metrics reports in the poll loops
+pub const REPORT_DURATION: Duration = Duration::from_secs(60);
+
+pub struct Counter {
+    name: &'static str,
+    value: AtomicUsize,
+}
+
+impl Counter {
+    const fn new(name: &'static str) -> Counter {
+        Counter {
+            name,
+            value: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: usize) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+macro_rules! counters {
+    ($($(#[$meta:meta])* $ident:ident => $name:literal,)*) => {
+        $($(#[$meta])* pub static $ident: Counter = Counter::new($name);)*
+
+        /// All the counters defined in this module, in declaration order.
+        pub static COUNTERS: &[&Counter] = &[$(&$ident,)*];
+    };
+}
+
+counters! {
+    /// Connections retried with a fresh pooled connection after an early failure
+    EARLY_RETRIES => "early_retries",
+    /// Pooled connections found closed by the server when taken from the pool
+    POOL_CLOSED_AT_CHECKOUT => "pool_closed_at_checkout",
+    /// Pooled connections taken back after a setup failed before using them
+    POOL_RETURNED => "pool_returned",
+    /// Pooled connections closed by the server before answering their first request
+    POOL_CLOSED_AT_FIRST_USE => "pool_closed_at_first_use",
+    /// Server handshakes without session resumption
+    FULL_HANDSHAKES => "full_handshakes",
+    /// Server handshakes resumed from a session ticket
+    RESUMED_HANDSHAKES => "resumed_handshakes",
+    /// Requests and udp packets dropped by the egress policy
+    EGRESS_DENIED => "egress_denied",
+    /// Requests closed because the traffic quota was used up
+    QUOTA_REJECTED => "quota_rejected",
+    /// Server requests rejected in maintenance mode
+    MAINTENANCE_REJECTED => "maintenance_rejected",
+    /// Server connections visited by the timeout sweep as they came due
+    SWEEP_VISITS => "sweep_visits",
+    /// Poll iterations which stopped accepting at --accept-burst with a backlog left
+    ACCEPT_BACKLOG => "accept_backlog_iterations",
+    /// Accepted connections without a usable pooled server connection
+    SETUP_POOL_FAILURES => "setup_pool_failures",
+    /// Accepted connections whose request could not be written
+    SETUP_REQUEST_FAILURES => "setup_request_failures",
+    /// Accepted connections whose client socket could not be registered
+    SETUP_REGISTER_FAILURES => "setup_register_failures",
+    /// Proxy connections refused because their destination is our own listener
+    SELF_LOOPS => "self_loops_refused",
+    /// Proxy connections whose server name was sniffed, and ones without
+    SNIFFED => "sniffed",
+    SNIFF_MISSES => "sniff_misses",
+    /// Proxy connections to an address family the server lacks, without a name for it
+    FAMILY_REFUSED => "family_refused",
+    /// Proxy connections and datagrams to destinations not in the allowlist
+    ALLOWLIST_DENIED => "allowlist_denied",
+    /// Proxy connections whose tunnel stalled with data on the way
+    TUNNEL_STALLS => "tunnel_stalls",
+    /// Endpoints marked degraded by stalled tunnels
+    ENDPOINTS_DEGRADED => "endpoints_degraded",
+    /// Proxy connections closed by the server with a notice of the cause
+    CLOSE_NOTICES => "close_notices",
+    /// Proxy tcp events whose token is none of its connection's current ones
+    MISMATCHED_EVENTS => "mismatched_events",
+    /// Proxy udp associations moved to a new tunnel after theirs broke
+    UDP_RESUMED => "udp_resumed",
+    /// Proxy udp associations closed without a new tunnel in time
+    UDP_RESUME_FAILED => "udp_resume_failed",
+    /// Proxy udp datagrams over the pace, queued or dropped
+    UDP_PACED => "udp_paced",
+    /// Proxy udp datagrams dropped from a full pace queue
+    UDP_PACE_DROPPED => "udp_pace_dropped",
+    /// Udp datagrams discarded after a short send to the target
+    UDP_TRUNCATED => "udp_truncated",
+    /// Udp frames from the tunnel which could not be parsed
+    UDP_DROPPED_PARSE => "udp_dropped_parse",
+    /// Udp datagrams too long for the tunnel or cut short by a socket
+    UDP_DROPPED_OVERSIZE => "udp_dropped_oversize",
+    /// Proxy tunnels rejected by a server in maintenance
+    MAINTENANCE_REFUSALS => "maintenance_refusals",
+    /// Udp datagrams dropped without room left in the tunnel
+    UDP_DROPPED_BUFFER_FULL => "udp_dropped_buffer_full",
+    /// Proxy udp datagrams dropped while the tunnel queue stood above --aqm-target
+    UDP_DROPPED_QUEUE_DELAY => "udp_dropped_queue_delay",
+    /// Proxy tunnel queues found standing above --aqm-target
+    AQM_CONGESTED => "aqm_congested",
+    /// Proxy poll loops found busy for --watchdog-timeout
+    WATCHDOG_STUCK => "watchdog_stuck",
+    /// Audit lines dropped because the writer fell behind, with --audit-file
+    AUDIT_DROPPED => "audit_records_dropped",
+    /// Udp datagrams dropped by the kernel on server sockets, with --kernel-stats
+    UDP_SOCKET_DROPS => "udp_socket_drops",
+    /// Target connects which found no free local port, pausing new ones
+    PORT_EXHAUSTION => "port_exhaustion",
+    /// Udp associations bound to an ephemeral port without a free one in --udp-port-range
+    UDP_PORT_RANGE_EXHAUSTED => "udp_port_range_exhausted",
+    /// Udp datagrams whose send to a socket failed
+    UDP_DROPPED_SEND_ERROR => "udp_dropped_send_error",
+    /// Dns lookups which waited for the same lookup in flight
+    DNS_COALESCED => "dns_coalesced",
+    /// Log records dropped because the log file writer fell behind
+    LOG_DROPPED => "log_records_dropped",
+    /// Proxy self checks which got an answer of --healthcheck-target
+    HEALTHCHECK_PASSED => "healthcheck_passed",
+    /// Proxy self checks which failed or timed out
+    HEALTHCHECK_FAILED => "healthcheck_failed",
+    /// Proxy tcp connections closed at accept for --max-connections
+    TCP_LISTENER_OVERFLOW => "tcp_listener_overflow",
+    /// Udp datagrams of new proxy clients dropped for --max-udp-connections
+    UDP_LISTENER_OVERFLOW => "udp_listener_overflow",
+    /// Proxy tcp connections relayed to their destination with --route-mode direct
+    DIRECT_CONNECTIONS => "direct_connections",
+    /// Proxy udp associations relayed to their destinations with --route-mode direct
+    DIRECT_UDP => "direct_udp",
+    /// Proxy connections and udp associations refused with --route-mode reject
+    ROUTE_MODE_REJECTED => "route_mode_rejected",
+    /// Empty keepalive datagrams the proxy sent through idle udp associations
+    UDP_KEEPALIVES => "udp_keepalives",
+    /// Empty udp datagrams the server discarded instead of forwarding
+    UDP_EMPTY_DISCARDED => "udp_empty_discarded",
+    /// Bytes the server sent back to echo test commands
+    TEST_ECHOED => "test_echoed_bytes",
+    /// Bytes the server dropped for discard test commands
+    TEST_DISCARDED => "test_discarded_bytes",
+    /// Endpoint breakers opened by connect failures in a row
+    BREAKER_OPENED => "breaker_opened",
+    /// Proxy clients failed fast by the open breaker of their endpoint
+    BREAKER_REJECTED => "breaker_rejected",
+}
+
+/// Logs every counter which is not zero.
+pub fn report() {
+    let line = COUNTERS
+        .iter()
+        .filter(|counter| counter.get() != 0)
+        .map(|counter| format!("{}={}", counter.name(), counter.get()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if !line.is_empty() {
+        log::info!("metrics: {}", line);
+    }
+}
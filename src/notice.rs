@@ -0,0 +1,100 @@
+//! Close notices of the server, a trojan-rs extension.
+//!
+//! A proxy run with `--close-notice` sets [`NOTICE`](crate::proto::NOTICE)
+//! on the command of its CONNECT requests. Vanilla trojan peers never set
+//! it, so they never get a notice. The first payload byte the server sends
+//! on such a connection is a notice: [`Notice::Ok`] once the target is
+//! connected, or the cause of a rejection right before the server closes.
+//! The byte passes through compression and padding like any payload.
+//!
+//! A proxy which also sets [`FAMILIES`](crate::proto::FAMILIES) gets
+//! [`Notice::Families`] instead of [`Notice::Ok`], see [`crate::family`].
+//!
+//! The proxy strips the byte, logs a rejection and counts it. It only
+//! fronts redirected connections, so there is no SOCKS reply or HTTP
+//! status to map the code to.
+use std::fmt::{Display, Formatter};
+
+use crate::family::Families;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Notice {
+    Ok,
+    /// Target denied by the egress policy
+    Denied,
+    /// Traffic quota of the server used up
+    Quota,
+    /// Target not resolved or not connected
+    Unreachable,
+    /// Target not connected in time
+    Timeout,
+    /// The server takes no new sessions, see [`crate::server`] maintenance
+    Maintenance,
+    /// Ok, with the egress families of the server
+    Families(Families),
+}
+
+impl Notice {
+    pub fn code(self) -> u8 {
+        match self {
+            Notice::Ok => 0x00,
+            Notice::Denied => 0x01,
+            Notice::Quota => 0x02,
+            Notice::Unreachable => 0x03,
+            Notice::Timeout => 0x04,
+            Notice::Maintenance => 0x05,
+            Notice::Families(families) => 0x10 | families.bits(),
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Notice> {
+        match code {
+            0x00 => Some(Notice::Ok),
+            0x01 => Some(Notice::Denied),
+            0x02 => Some(Notice::Quota),
+            0x03 => Some(Notice::Unreachable),
+            0x04 => Some(Notice::Timeout),
+            0x05 => Some(Notice::Maintenance),
+            0x11..=0x13 => Families::from_bits(code & 0x03).map(Notice::Families),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Notice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Notice::Families(families) => return write!(f, "ok, egress {}", families),
+            Notice::Ok => "ok",
+            Notice::Denied => "denied by policy",
+            Notice::Quota => "quota exceeded",
+            Notice::Unreachable => "target unreachable",
+            Notice::Timeout => "target timed out",
+            Notice::Maintenance => "server in maintenance",
+        };
+        f.write_str(text)
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use crate::{family::Families, notice::Notice};
+
+    #[test]
+    fn test_code() {
+        for notice in [
+            Notice::Ok,
+            Notice::Denied,
+            Notice::Quota,
+            Notice::Unreachable,
+            Notice::Timeout,
+            Notice::Maintenance,
+            Notice::Families(Families::ALL),
+        ] {
+            assert_eq!(Notice::from_code(notice.code()), Some(notice));
+        }
+        assert_eq!(Notice::from_code(0x06), None);
+        assert_eq!(Notice::from_code(0x10), None);
+    }
+}
@@ -0,0 +1,224 @@
+//! Optional record padding between proxy and server.
+//!
+//! A padded request sets [`PADDED`](crate::proto::PADDED) on its command.
+//! The rest of the stream then starts with frames, each a type byte, a big
+//! endian u16 length and the value:
+//!
+//! * `DATA` carries payload
+//! * `PAD` carries ignored bytes
+//! * `END` has no value, raw payload follows it
+//!
+//! The proxy pads the request record and the next records to a multiple of
+//! `--pad-block`, the last padded record carries `END`. Only the direction
+//! to the server is padded, so servers without it keep working with
+//! proxies which don't ask for it.
+use bytes::{BufMut, BytesMut};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::config::OPTIONS;
+
+const DATA: u8 = 0x00;
+const PAD: u8 = 0x01;
+const END: u8 = 0x02;
+const FRAME_HEAD_LEN: usize = 3;
+const MAX_FRAME_LEN: usize = u16::MAX as usize;
+
+pub struct Padder {
+    block: usize,
+    /// Padded records left, including the request
+    records: usize,
+    max_dummy: usize,
+    buffer: BytesMut,
+}
+
+impl Padder {
+    pub fn new(block: usize, records: usize, max_dummy: usize) -> Padder {
+        Padder {
+            block,
+            records: records.max(1),
+            max_dummy,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    /// Returns a padder for a new connection if padding is enabled.
+    pub fn from_options() -> Option<Padder> {
+        let args = OPTIONS.proxy_args();
+        if args.pad_block == 0 {
+            None
+        } else {
+            Some(Padder::new(
+                args.pad_block as usize,
+                args.pad_records,
+                args.pad_dummy as usize,
+            ))
+        }
+    }
+
+    /// Whether all padded records are framed, later data is sent raw.
+    pub fn finished(&self) -> bool {
+        self.records == 0
+    }
+
+    /// Frames `head` and `data` as one record padded to a multiple of the
+    /// block, `head` is sent before any frame and only used for the request.
+    pub fn frame(&mut self, head: &[u8], data: &[u8]) -> &[u8] {
+        self.buffer.clear();
+        self.buffer.extend_from_slice(head);
+        for chunk in data.chunks(MAX_FRAME_LEN) {
+            self.buffer.put_u8(DATA);
+            self.buffer.put_u16(chunk.len() as u16);
+            self.buffer.extend_from_slice(chunk);
+        }
+        self.records = self.records.saturating_sub(1);
+        let tail = if self.finished() {
+            FRAME_HEAD_LEN * 2
+        } else {
+            FRAME_HEAD_LEN
+        };
+        let pad = (self.block - (self.buffer.len() + tail) % self.block) % self.block;
+        self.put_pad(pad);
+        if self.finished() {
+            self.buffer.put_u8(END);
+            self.buffer.put_u16(0);
+        }
+        self.buffer.as_ref()
+    }
+
+    /// Returns a dummy record of random length, if enabled and not done yet.
+    pub fn dummy(&mut self) -> Option<&[u8]> {
+        if self.max_dummy == 0 || self.finished() {
+            return None;
+        }
+        let mut random = [0u8; 2];
+        SystemRandom::new().fill(&mut random).ok()?;
+        let len = u16::from_be_bytes(random) as usize % self.max_dummy.min(MAX_FRAME_LEN) + 1;
+        self.buffer.clear();
+        self.put_pad(len);
+        self.max_dummy = 0;
+        Some(self.buffer.as_ref())
+    }
+
+    fn put_pad(&mut self, len: usize) {
+        self.buffer.put_u8(PAD);
+        self.buffer.put_u16(len as u16);
+        self.buffer.put_bytes(0, len);
+    }
+}
+
+/// Strips frames from a padded stream, data may split frames anywhere.
+#[derive(Default)]
+pub struct Unpadder {
+    head: [u8; FRAME_HEAD_LEN],
+    head_len: usize,
+    /// Value bytes left in the current frame
+    remain: usize,
+    done: bool,
+}
+
+impl Unpadder {
+    /// Whether `END` was received, the rest of the stream is raw.
+    pub fn finished(&self) -> bool {
+        self.done
+    }
+
+    /// Appends the payload in `input` to `output`, returns false for an
+    /// invalid frame.
+    pub fn strip(&mut self, mut input: &[u8], output: &mut BytesMut) -> bool {
+        while !input.is_empty() {
+            if self.done {
+                output.extend_from_slice(input);
+                break;
+            }
+            if self.head_len < FRAME_HEAD_LEN {
+                let size = (FRAME_HEAD_LEN - self.head_len).min(input.len());
+                self.head[self.head_len..self.head_len + size].copy_from_slice(&input[..size]);
+                self.head_len += size;
+                input = &input[size..];
+                if self.head_len < FRAME_HEAD_LEN {
+                    break;
+                }
+                self.remain = u16::from_be_bytes([self.head[1], self.head[2]]) as usize;
+                match self.head[0] {
+                    DATA | PAD => {}
+                    END if self.remain == 0 => self.done = true,
+                    _ => return false,
+                }
+            } else {
+                let size = self.remain.min(input.len());
+                if self.head[0] == DATA {
+                    output.extend_from_slice(&input[..size]);
+                }
+                self.remain -= size;
+                input = &input[size..];
+            }
+            if self.remain == 0 {
+                self.head_len = 0;
+            }
+        }
+        true
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    extern crate test;
+
+    use bytes::BytesMut;
+    use test::Bencher;
+
+    use crate::padding::{Padder, Unpadder};
+
+    fn round_trip(padder: &mut Padder, unpadder: &mut Unpadder, data: &[u8]) -> BytesMut {
+        let mut output = BytesMut::new();
+        if padder.finished() {
+            assert!(unpadder.strip(data, &mut output));
+        } else {
+            let framed = padder.frame(&[], data);
+            assert_eq!(framed.len() % 256, 0);
+            // split frames across reads
+            for chunk in framed.chunks(7) {
+                assert!(unpadder.strip(chunk, &mut output));
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn test_padding() {
+        let mut padder = Padder::new(256, 3, 100);
+        let mut unpadder = Unpadder::default();
+        let mut output = BytesMut::new();
+        let request = padder.frame(b"request", b"hello").to_vec();
+        assert_eq!(request.len() % 256, 0);
+        assert_eq!(&request[..7], b"request");
+        assert!(unpadder.strip(&request[7..], &mut output));
+        let dummy = padder.dummy().unwrap().to_vec();
+        assert!(unpadder.strip(dummy.as_slice(), &mut output));
+        assert!(padder.dummy().is_none());
+        assert_eq!(output.as_ref(), b"hello");
+
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        for len in [0, 1, 253, 1000] {
+            let output = round_trip(&mut padder, &mut unpadder, &data[..len]);
+            assert_eq!(output.as_ref(), &data[..len]);
+        }
+        assert!(padder.finished() && unpadder.finished());
+        assert!(!Unpadder::default().strip(&[0x09, 0, 0], &mut output));
+    }
+
+    #[bench]
+    fn bench_padding(b: &mut Bencher) {
+        let data = vec![1u8; 1400];
+        let mut output = BytesMut::new();
+        b.iter(|| {
+            let mut padder = Padder::new(256, 8, 0);
+            let mut unpadder = Unpadder::default();
+            output.clear();
+            for _ in 0..8 {
+                assert!(unpadder.strip(padder.frame(&[], data.as_slice()), &mut output));
+            }
+            output.len()
+        });
+    }
+}
@@ -0,0 +1,105 @@
+//! Scoped timers around the hot paths of the poll loops.
+//!
+//! Built with the `profiling` feature, every [`scope`] adds its elapsed time
+//! to the totals of its category, which are logged with the metrics report
+//! and written to the state dump. Without the feature the timers are empty.
+use crate::dump::Dump;
+
+#[derive(Copy, Clone)]
+pub enum Category {
+    Accept,
+    ClientRead,
+    ClientWrite,
+    TlsRead,
+    TlsWrite,
+    UdpRead,
+    UdpSend,
+    TimeoutSweep,
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "profiling")] {
+        use std::{
+            sync::atomic::{AtomicU64, Ordering},
+            time::Instant,
+        };
+
+        const CATEGORY_CNT: usize = 8;
+        const NAMES: [&str; CATEGORY_CNT] = [
+            "accept",
+            "client_read",
+            "client_write",
+            "tls_read",
+            "tls_write",
+            "udp_read",
+            "udp_send",
+            "timeout_sweep",
+        ];
+
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        static NANOS: [AtomicU64; CATEGORY_CNT] = [ZERO; CATEGORY_CNT];
+        static COUNTS: [AtomicU64; CATEGORY_CNT] = [ZERO; CATEGORY_CNT];
+
+        pub struct Scope {
+            category: Category,
+            start: Instant,
+        }
+
+        impl Drop for Scope {
+            fn drop(&mut self) {
+                let index = self.category as usize;
+                NANOS[index].fetch_add(self.start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                COUNTS[index].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        /// Starts timing `category` until the returned scope is dropped.
+        #[inline]
+        pub fn scope(category: Category) -> Scope {
+            Scope {
+                category,
+                start: Instant::now(),
+            }
+        }
+
+        fn totals() -> String {
+            NAMES
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| COUNTS[*index].load(Ordering::Relaxed) != 0)
+                .map(|(index, name)| {
+                    let count = COUNTS[index].load(Ordering::Relaxed);
+                    let nanos = NANOS[index].load(Ordering::Relaxed);
+                    format!("{}={}us/{}", name, nanos / 1000, count)
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+
+        /// Logs the totals of every category since start.
+        pub fn report() {
+            let line = totals();
+            if !line.is_empty() {
+                log::info!("profile: {}", line);
+            }
+        }
+
+        pub fn dump(dump: &mut Dump) {
+            dump.line(format_args!("profile: {}", totals()));
+        }
+    } else {
+        pub struct Scope;
+
+        #[inline(always)]
+        pub fn scope(_: Category) -> Scope {
+            Scope
+        }
+
+        #[inline(always)]
+        pub fn report() {}
+
+        #[inline(always)]
+        pub fn dump(_: &mut Dump) {}
+    }
+}
@@ -2,15 +2,45 @@
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address, Ipv6Address};
 
-use crate::config::OPTIONS;
+use crate::config::{ProtocolCompat, OPTIONS};
 
 /// protocol code for CONNECT command
 pub const CONNECT: u8 = 0x01;
 /// protocol code for UDP_ASSOCIATE command
 pub const UDP_ASSOCIATE: u8 = 0x03;
+/// protocol code for management commands, answered by servers with --control
+pub const CONTROL: u8 = 0x10;
+/// protocol code for the echo test command, answered by servers with
+/// --test-backends, the payload comes back as it is, udp frames included
+pub const ECHO: u8 = 0x11;
+/// protocol code for the discard test command, answered by servers with
+/// --test-backends, the payload is counted and dropped
+pub const DISCARD: u8 = 0x12;
+/// flag on the command of a request followed by padding frames
+pub const PADDED: u8 = 0x80;
+/// flag on the command of a request followed by compression frames
+pub const COMPRESSED: u8 = 0x40;
+/// flag on the command of a request whose proxy takes a close notice
+pub const NOTICE: u8 = 0x20;
+/// flag on the command of a request whose proxy takes the egress families in the ok notice
+pub const FAMILIES: u8 = 0x04;
+/// flag on the command of a request whose peers pass on a reset, a tls
+/// stream closed without close_notify stands for an RST of its client or target
+pub const RESET: u8 = 0x08;
+/// flag on the address type of a request followed by a metadata block, see
+/// [`TrojanRequest::write_metadata`]
+pub const METADATA: u8 = 0x80;
+/// metadata type of the account a request is made for
+pub const METADATA_ACCOUNT: u8 = 0x01;
+/// metadata type of a routing hint for the server
+pub const METADATA_ROUTE: u8 = 0x02;
+/// max length of the tlvs of a metadata block
+pub const MAX_METADATA_LEN: usize = 256;
+/// Metadata tlvs of a request by type
+pub type Metadata = Vec<(u8, Bytes)>;
 /// max packet size for udp, MTU = 1500 minus IP head size
 pub const MAX_PACKET_SIZE: usize = 1450;
 /// protocol code for IPV4 type
@@ -19,6 +49,18 @@ const IPV4: u8 = 0x01;
 const DOMAIN: u8 = 0x03;
 /// protocol code for IPV6 type
 const IPV6: u8 = 0x04;
+/// length of hex encoded sha224 password
+const PASS_LEN: usize = 56;
+/// max length of an ip address, type + ipv6 + port
+pub const MAX_ADDRESS_LEN: usize = 19;
+/// max length of the head before each udp packet
+pub const MAX_UDP_HEAD_LEN: usize = MAX_ADDRESS_LEN + 4;
+/// max length of a trojan request with ip address
+pub const MAX_REQUEST_LEN: usize = PASS_LEN + 2 + 1 + MAX_ADDRESS_LEN + 2;
+/// max length of a trojan request with any address, type + domain + port,
+/// and a metadata block
+pub const MAX_HEADER_LEN: usize = PASS_LEN + 2 + 1 + 1 + 256 + 2 + 2 + 2 + MAX_METADATA_LEN;
+const CRLF: &[u8] = b"\r\n";
 
 /// Trojan Socks5 address enum
 pub enum Sock5Address {
@@ -30,81 +72,243 @@ pub enum Sock5Address {
     None, // Invalid
 }
 
+/// Deviations from the protocol accepted with `--protocol-compat lenient`,
+/// requests with anything else off the protocol are still refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Deviation {
+    /// No CRLF after the address, the payload follows it right away
+    MissingCrlf,
+    /// Zero bytes between the address of an UDP_ASSOCIATE request and its CRLF
+    UdpAddressPadding,
+}
+
+impl Deviation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Deviation::MissingCrlf => "missing_crlf",
+            Deviation::UdpAddressPadding => "udp_address_padding",
+        }
+    }
+}
+
 /// Trojan protocol for a request
 pub struct TrojanRequest<'a> {
     pub command: u8,
+    pub padded: bool,
+    pub compressed: bool,
+    pub notice: bool,
+    /// The ok notice carries the egress families
+    pub families: bool,
+    /// Resets pass through the tunnel, see [`RESET`]
+    pub reset: bool,
     pub address: Sock5Address,
+    /// Deviation the request was accepted with in lenient mode
+    pub deviation: Option<Deviation>,
+    /// Metadata of the known types, see [`METADATA`]
+    pub metadata: Metadata,
     pub payload: &'a [u8],
 }
 
+pub enum RequestParseResult<'a> {
+    Request(TrojanRequest<'a>),
+    InvalidProtocol,
+    /// The data so far is the front of a valid request
+    Continued,
+}
+
 impl<'a> TrojanRequest<'a> {
-    pub fn parse(mut buffer: &'a [u8]) -> Option<TrojanRequest<'a>> {
-        if buffer.len() < OPTIONS.pass_len {
-            log::debug!(
-                "data length:{} is too short for a trojan request",
-                buffer.len()
-            );
-            return None;
-        }
+    pub fn parse(buffer: &'a [u8], compat: ProtocolCompat) -> RequestParseResult<'a> {
+        Self::parse_with(buffer, OPTIONS.get_pass().as_bytes(), compat)
+    }
 
-        let pass = String::from_utf8_lossy(&buffer[..OPTIONS.pass_len]);
-        if let Some(orig) = OPTIONS.check_pass(&pass) {
-            log::debug!("request using password:{}", &orig);
-        } else {
+    /// Parses a request of password `pass`, a request which is still
+    /// incomplete at [`MAX_HEADER_LEN`] bytes is invalid.
+    fn parse_with(buffer: &'a [u8], pass: &[u8], compat: ProtocolCompat) -> RequestParseResult<'a> {
+        if buffer.len() < pass.len() {
+            if pass.starts_with(buffer) {
+                log::debug!("got {} bytes of a trojan request", buffer.len());
+                return RequestParseResult::Continued;
+            }
+            log::debug!("request didn't find matched password");
+            return RequestParseResult::InvalidProtocol;
+        }
+        if &buffer[..pass.len()] != pass {
             log::debug!("request didn't find matched password");
-            return None;
+            return RequestParseResult::InvalidProtocol;
         }
 
-        buffer = &buffer[OPTIONS.pass_len..];
-        if buffer.len() < 2 || buffer[0] != b'\r' || buffer[1] != b'\n' {
+        match Self::parse_command(&buffer[pass.len()..], compat) {
+            RequestParseResult::Continued if buffer.len() >= MAX_HEADER_LEN => {
+                log::error!(
+                    "unknown protocol, request longer than {} bytes",
+                    MAX_HEADER_LEN
+                );
+                RequestParseResult::InvalidProtocol
+            }
+            result => result,
+        }
+    }
+
+    /// Parses the request after the password.
+    fn parse_command(mut buffer: &'a [u8], compat: ProtocolCompat) -> RequestParseResult<'a> {
+        let len = buffer.len();
+        if CRLF.starts_with(buffer) {
+            return RequestParseResult::Continued;
+        }
+        if !buffer.starts_with(CRLF) {
             log::error!(
-                "unknown protocol, expected CRLF, {:#X}{:#X}",
-                buffer[0],
-                buffer[1]
+                "unknown protocol, expected CRLF, {:X?}",
+                &buffer[..buffer.len().min(2)]
             );
-            return None;
+            return RequestParseResult::InvalidProtocol;
         }
 
         buffer = &buffer[2..];
-        if buffer.len() < 3 {
-            log::error!("unknown protocol, invalid size");
-            return None;
+        if buffer.is_empty() {
+            return RequestParseResult::Continued;
         }
-        if buffer[0] != CONNECT && buffer[0] != UDP_ASSOCIATE {
+        let command = buffer[0] & !(PADDED | COMPRESSED | NOTICE | FAMILIES | RESET);
+        if !matches!(command, CONNECT | UDP_ASSOCIATE | CONTROL | ECHO | DISCARD) {
             log::error!(
                 "unknown protocol, expected valid command, found:{}",
                 buffer[0]
             );
-            return None;
+            return RequestParseResult::InvalidProtocol;
+        }
+        if buffer.len() < 2 {
+            return RequestParseResult::Continued;
         }
 
-        let command = buffer[0];
-        let atyp = buffer[1];
+        let padded = buffer[0] & PADDED != 0;
+        let compressed = buffer[0] & COMPRESSED != 0;
+        let notice = buffer[0] & NOTICE != 0;
+        let families = buffer[0] & FAMILIES != 0;
+        let reset = buffer[0] & RESET != 0;
+        let has_metadata = buffer[1] & METADATA != 0;
+        let atyp = buffer[1] & !METADATA;
         buffer = &buffer[2..];
-        if let Some((size, address)) = parse_address(atyp, buffer) {
-            buffer = &buffer[size..];
-            if buffer[0] != b'\r' || buffer[1] != b'\n' {
-                log::error!("unknown protocol, expected CRLF after address");
-                return None;
+        match address_len(atyp, buffer) {
+            Some(len) if buffer.len() < len => return RequestParseResult::Continued,
+            Some(_) => {}
+            None => {
+                log::warn!("unknown protocol, invalid address type:{}", atyp);
+                return RequestParseResult::InvalidProtocol;
             }
-            Some(TrojanRequest {
-                command,
-                address,
-                payload: &buffer[2..],
-            })
-        } else {
-            None
         }
+        let (size, address) = match parse_address(atyp, buffer) {
+            // an ipv4 target sent as ipv6 is connected over ipv4
+            Some((size, Sock5Address::Socket(addr))) => (size, Sock5Address::Socket(unmap(addr))),
+            Some(address) => address,
+            None => return RequestParseResult::InvalidProtocol,
+        };
+        buffer = &buffer[size..];
+        let lenient = compat == ProtocolCompat::Lenient;
+        let mut deviation = None;
+        if lenient && command == UDP_ASSOCIATE {
+            // only padding which keeps the request within MAX_HEADER_LEN,
+            // so the decision doesn't depend on how the request was split
+            let limit = (MAX_HEADER_LEN - PASS_LEN - 2).saturating_sub(len - buffer.len());
+            let zeros = buffer
+                .iter()
+                .take(limit)
+                .take_while(|byte| **byte == 0)
+                .count();
+            let rest = &buffer[zeros..];
+            if zeros > 0 && rest.starts_with(CRLF) {
+                deviation.replace(Deviation::UdpAddressPadding);
+                buffer = rest;
+            } else if zeros > 0 && CRLF.starts_with(rest) {
+                return RequestParseResult::Continued;
+            }
+        }
+        let payload = if buffer.starts_with(CRLF) {
+            &buffer[2..]
+        } else if CRLF.starts_with(buffer) {
+            return RequestParseResult::Continued;
+        } else if lenient {
+            deviation.replace(Deviation::MissingCrlf);
+            buffer
+        } else {
+            log::error!("unknown protocol, expected CRLF after address");
+            return RequestParseResult::InvalidProtocol;
+        };
+        let (metadata, payload) = if has_metadata {
+            match split_metadata(payload) {
+                Ok(Some(split)) => split,
+                Ok(None) => return RequestParseResult::Continued,
+                Err(()) => return RequestParseResult::InvalidProtocol,
+            }
+        } else {
+            (Vec::new(), payload)
+        };
+        RequestParseResult::Request(TrojanRequest {
+            command,
+            padded,
+            compressed,
+            notice,
+            families,
+            reset,
+            address,
+            deviation,
+            metadata,
+            payload,
+        })
     }
 
     pub fn generate(buffer: &mut BytesMut, cmd: u8, addr: &SocketAddr) {
-        buffer.extend_from_slice(OPTIONS.get_pass().as_bytes());
-        buffer.put_u8(b'\r');
-        buffer.put_u8(b'\n');
-        buffer.put_u8(cmd);
-        Sock5Address::generate(buffer, addr);
-        buffer.put_u8(b'\r');
-        buffer.put_u8(b'\n');
+        let mut head = [0u8; MAX_REQUEST_LEN];
+        let len = Self::write(&mut head, cmd, addr);
+        buffer.extend_from_slice(&head[..len]);
+    }
+
+    /// Writes the request into the front of `buffer`, which must hold
+    /// [`MAX_REQUEST_LEN`] bytes, returns the length written. An ipv4
+    /// address in ipv6 form goes as ipv4, servers on ipv4 only hosts fail
+    /// to connect the ipv6 form.
+    pub fn write(buffer: &mut [u8], cmd: u8, addr: &SocketAddr) -> usize {
+        let pass = OPTIONS.get_pass().as_bytes();
+        let mut len = pass.len();
+        buffer[..len].copy_from_slice(pass);
+        buffer[len..len + 3].copy_from_slice(&[b'\r', b'\n', cmd]);
+        len += 3;
+        len += Sock5Address::write(&mut buffer[len..], &unmap(*addr));
+        buffer[len..len + 2].copy_from_slice(b"\r\n");
+        len + 2
+    }
+
+    /// Writes a request to `host`, which is at most 255 bytes, into the
+    /// front of `buffer`, which must hold [`MAX_HEADER_LEN`] bytes.
+    pub fn write_domain(buffer: &mut [u8], cmd: u8, host: &str, port: u16) -> usize {
+        let pass = OPTIONS.get_pass().as_bytes();
+        let mut len = pass.len();
+        buffer[..len].copy_from_slice(pass);
+        buffer[len..len + 3].copy_from_slice(&[b'\r', b'\n', cmd]);
+        len += 3;
+        len += Sock5Address::write_domain(&mut buffer[len..], host, port);
+        buffer[len..len + 2].copy_from_slice(b"\r\n");
+        len + 2
+    }
+
+    /// Flags the request of `len` bytes at the front of `buffer` and appends
+    /// the metadata block, returns the new length. The block is a u16 length
+    /// and tlvs of a type byte, a length byte and the value. Servers without
+    /// this extension refuse flagged requests, nothing changes for empty
+    /// `metadata`.
+    pub fn write_metadata(buffer: &mut [u8], len: usize, metadata: &[(u8, Bytes)]) -> usize {
+        if metadata.is_empty() {
+            return len;
+        }
+        buffer[PASS_LEN + 3] |= METADATA;
+        let mut written = len + 2;
+        for (kind, value) in metadata {
+            buffer[written] = *kind;
+            buffer[written + 1] = value.len() as u8;
+            buffer[written + 2..written + 2 + value.len()].copy_from_slice(value);
+            written += 2 + value.len();
+        }
+        let block = (written - len - 2) as u16;
+        buffer[len..len + 2].copy_from_slice(&block.to_be_bytes());
+        written
     }
 
     pub fn generate_endpoint(buffer: &mut BytesMut, cmd: u8, addr: &IpEndpoint) {
@@ -119,6 +323,103 @@ impl<'a> TrojanRequest<'a> {
     }
 }
 
+/// Splits the metadata block off the front of `buffer`, None while it is
+/// incomplete. Tlvs of unknown types are skipped.
+fn split_metadata(buffer: &[u8]) -> Result<Option<(Metadata, &[u8])>, ()> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let len = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+    if len > MAX_METADATA_LEN {
+        log::error!("unknown protocol, metadata of {} bytes", len);
+        return Err(());
+    }
+    if buffer.len() < 2 + len {
+        return Ok(None);
+    }
+    let mut block = &buffer[2..2 + len];
+    let mut metadata = Vec::new();
+    while !block.is_empty() {
+        if block.len() < 2 || block.len() < 2 + block[1] as usize {
+            log::error!("unknown protocol, truncated metadata item");
+            return Err(());
+        }
+        let (kind, value) = (block[0], &block[2..2 + block[1] as usize]);
+        if kind == METADATA_ACCOUNT || kind == METADATA_ROUTE {
+            metadata.push((kind, Bytes::copy_from_slice(value)));
+        } else {
+            log::debug!("skipped metadata of unknown type:{}", kind);
+        }
+        block = &block[2 + value.len()..];
+    }
+    Ok(Some((metadata, &buffer[2 + len..])))
+}
+
+/// Parses `--request-metadata` entries like `1=acct-42`, panics on an
+/// invalid entry or when they don't fit [`MAX_METADATA_LEN`].
+pub fn parse_metadata_option(entries: &[String]) -> Metadata {
+    let metadata: Metadata = entries
+        .iter()
+        .map(|entry| {
+            let (kind, value) = entry
+                .split_once('=')
+                .unwrap_or_else(|| panic!("invalid --request-metadata {}, need TYPE=VALUE", entry));
+            let kind = kind
+                .parse::<u8>()
+                .unwrap_or_else(|_| panic!("invalid --request-metadata type {}", kind));
+            if value.len() > u8::MAX as usize {
+                panic!(
+                    "invalid --request-metadata {}, value longer than 255 bytes",
+                    entry
+                );
+            }
+            (kind, Bytes::copy_from_slice(value.as_bytes()))
+        })
+        .collect();
+    let len: usize = metadata.iter().map(|(_, value)| 2 + value.len()).sum();
+    if len > MAX_METADATA_LEN {
+        panic!(
+            "--request-metadata takes {} bytes, at most {} fit",
+            len, MAX_METADATA_LEN
+        );
+    }
+    metadata
+}
+
+/// The ipv4 address in a v4 mapped `::ffff:a.b.c.d` or v4 compatible
+/// `::a.b.c.d` address. `::` and `::1` are ipv6 addresses of their own,
+/// like every compatible one below 1.0.0.0.
+pub fn embedded_ipv4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return Some(v4);
+    }
+    ip.to_ipv4().filter(|v4| v4.octets()[0] != 0)
+}
+
+/// `addr` with the ipv4 address embedded in its ipv6 one, see
+/// [`embedded_ipv4`].
+pub fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match embedded_ipv4(v6.ip()) {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), v6.port()),
+            None => addr,
+        },
+        addr => addr,
+    }
+}
+
+/// Length of the address of type `atyp` at the front of `buffer`, at least
+/// one more byte than there is for a domain without its length byte. None
+/// for an unknown type.
+fn address_len(atyp: u8, buffer: &[u8]) -> Option<usize> {
+    match atyp {
+        IPV4 => Some(6),
+        DOMAIN => Some(buffer.first().map_or(1, |length| *length as usize + 3)),
+        IPV6 => Some(18),
+        _ => None,
+    }
+}
+
 fn parse_address(atyp: u8, buffer: &[u8]) -> Option<(usize, Sock5Address)> {
     match atyp {
         IPV4 => {
@@ -137,7 +438,7 @@ fn parse_address(atyp: u8, buffer: &[u8]) -> Option<(usize, Sock5Address)> {
         DOMAIN => {
             log::debug!("domain address found");
             let length = buffer[0] as usize;
-            if buffer.len() < length + 3 {
+            if length == 0 || buffer.len() < length + 3 {
                 log::error!("unknown protocol, invalid domain address");
                 return None;
             }
@@ -198,7 +499,7 @@ fn parse_address_endpoint(atyp: u8, buffer: &[u8]) -> Option<(usize, Sock5Addres
         DOMAIN => {
             log::debug!("domain address found");
             let length = buffer[0] as usize;
-            if buffer.len() < length + 3 {
+            if length == 0 || buffer.len() < length + 3 {
                 log::error!("unknown protocol, invalid domain address");
                 return None;
             }
@@ -220,7 +521,7 @@ fn parse_address_endpoint(atyp: u8, buffer: &[u8]) -> Option<(usize, Sock5Addres
                 log::error!("unknown protocol, invalid ipv6 address");
                 return None;
             }
-            let addr = Ipv6Address::from_bytes(buffer);
+            let addr = Ipv6Address::from_bytes(&buffer[..16]);
             let endpoint = IpEndpoint::new(IpAddress::Ipv6(addr), to_u16(&buffer[16..]));
             Some((18, Sock5Address::Endpoint(endpoint)))
         }
@@ -339,10 +640,18 @@ impl<'a> UdpAssociate<'a> {
     }
 
     pub fn generate(buffer: &mut BytesMut, address: &SocketAddr, length: u16) {
-        Sock5Address::generate(buffer, address);
-        buffer.put_u16(length);
-        buffer.put_u8(b'\r');
-        buffer.put_u8(b'\n');
+        let mut head = [0u8; MAX_UDP_HEAD_LEN];
+        let len = Self::write(&mut head, address, length);
+        buffer.extend_from_slice(&head[..len]);
+    }
+
+    /// Writes the packet head into the front of `buffer`, which must hold
+    /// [`MAX_UDP_HEAD_LEN`] bytes, returns the length written.
+    pub fn write(buffer: &mut [u8], address: &SocketAddr, length: u16) -> usize {
+        let len = Sock5Address::write(buffer, address);
+        buffer[len..len + 2].copy_from_slice(&length.to_be_bytes());
+        buffer[len + 2..len + 4].copy_from_slice(b"\r\n");
+        len + 4
     }
 
     pub fn generate_endpoint(buffer: &mut BytesMut, endpoint: &IpEndpoint, length: u16) {
@@ -360,19 +669,37 @@ fn to_u16(buffer: &[u8]) -> u16 {
 
 impl Sock5Address {
     pub fn generate(buffer: &mut BytesMut, address: &SocketAddr) {
-        let port = match address {
+        let mut head = [0u8; MAX_ADDRESS_LEN];
+        let len = Self::write(&mut head, address);
+        buffer.extend_from_slice(&head[..len]);
+    }
+
+    /// Writes the address into the front of `buffer`, which must hold
+    /// [`MAX_ADDRESS_LEN`] bytes, returns the length written.
+    pub fn write(buffer: &mut [u8], address: &SocketAddr) -> usize {
+        let len = match address {
             SocketAddr::V4(v4) => {
-                buffer.put_u8(IPV4);
-                buffer.extend_from_slice(&v4.ip().octets()[..]);
-                v4.port()
+                buffer[0] = IPV4;
+                buffer[1..5].copy_from_slice(&v4.ip().octets()[..]);
+                5
             }
             SocketAddr::V6(v6) => {
-                buffer.put_u8(IPV6);
-                buffer.extend_from_slice(&v6.ip().octets()[..]);
-                v6.port()
+                buffer[0] = IPV6;
+                buffer[1..17].copy_from_slice(&v6.ip().octets()[..]);
+                17
             }
         };
-        buffer.put_u16(port);
+        buffer[len..len + 2].copy_from_slice(&address.port().to_be_bytes());
+        len + 2
+    }
+
+    pub fn write_domain(buffer: &mut [u8], host: &str, port: u16) -> usize {
+        let len = host.len();
+        buffer[0] = DOMAIN;
+        buffer[1] = len as u8;
+        buffer[2..2 + len].copy_from_slice(host.as_bytes());
+        buffer[2 + len..4 + len].copy_from_slice(&port.to_be_bytes());
+        len + 4
     }
 
     pub fn generate_endpoint(buffer: &mut BytesMut, endpoint: &IpEndpoint) {
@@ -392,3 +719,401 @@ impl Sock5Address {
         buffer.put_u16(endpoint.port);
     }
 }
+
+mod tests {
+    #![allow(unused_imports)]
+    extern crate test;
+
+    use bytes::Bytes;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+    use test::Bencher;
+
+    use crate::{
+        config::ProtocolCompat,
+        proto::{
+            embedded_ipv4, unmap, Deviation, RequestParseResult, Sock5Address, TrojanRequest,
+            UdpAssociate, UdpParseResult, UdpParseResultEndpoint, CONNECT, CONTROL,
+            MAX_ADDRESS_LEN, MAX_HEADER_LEN, MAX_METADATA_LEN, MAX_PACKET_SIZE, MAX_UDP_HEAD_LEN,
+            METADATA_ACCOUNT, METADATA_ROUTE, RESET, UDP_ASSOCIATE,
+        },
+        sim::allocations,
+    };
+
+    fn round_trip(buffer: &mut [u8], addr: &SocketAddr, payload_len: usize) -> usize {
+        let len = UdpAssociate::write(buffer, addr, payload_len as u16);
+        match UdpAssociate::parse(&buffer[..len + payload_len]) {
+            UdpParseResult::Packet(packet) => packet.length,
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn test_udp_head_no_alloc() {
+        let addrs: Vec<SocketAddr> = vec![
+            "1.2.3.4:53".parse().unwrap(),
+            "[2001:db8::1]:443".parse().unwrap(),
+        ];
+        let mut buffer = vec![0u8; MAX_UDP_HEAD_LEN + MAX_PACKET_SIZE];
+        for addr in &addrs {
+            let before = allocations();
+            for _ in 0..1000 {
+                assert_eq!(round_trip(&mut buffer, addr, 100), 100);
+            }
+            assert_eq!(allocations(), before);
+        }
+    }
+
+    type Parsed = Result<(Option<Deviation>, Vec<u8>), &'static str>;
+
+    fn parse(request: &[u8], compat: ProtocolCompat) -> Parsed {
+        match TrojanRequest::parse_command(request, compat) {
+            RequestParseResult::Request(request) => {
+                Ok((request.deviation, request.payload.to_vec()))
+            }
+            RequestParseResult::InvalidProtocol => Err("invalid"),
+            RequestParseResult::Continued => Err("continued"),
+        }
+    }
+
+    #[test]
+    fn test_deviations() {
+        use ProtocolCompat::{Lenient, Strict};
+        let connect = b"\r\n\x01\x01\x01\x02\x03\x04\x00\x50";
+        let request = [&connect[..], b"\r\nGET"].concat();
+        for compat in [Strict, Lenient] {
+            assert_eq!(parse(&request, compat), Ok((None, b"GET".to_vec())));
+        }
+
+        // missing CRLF after the address
+        let request = [&connect[..], b"GET"].concat();
+        assert_eq!(parse(&request, Strict), Err("invalid"));
+        let expected = (Some(Deviation::MissingCrlf), b"GET".to_vec());
+        assert_eq!(parse(&request, Lenient), Ok(expected));
+        // the CRLF may still come
+        assert_eq!(parse(connect, Lenient), Err("continued"));
+
+        // padding after the address of an udp associate request
+        let associate = b"\r\n\x03\x01\x00\x00\x00\x00\x00\x00\x00\x00\r\n";
+        assert_eq!(parse(associate, Strict), Err("invalid"));
+        let expected = (Some(Deviation::UdpAddressPadding), vec![]);
+        assert_eq!(parse(associate, Lenient), Ok(expected));
+        assert_eq!(parse(&associate[..12], Lenient), Err("continued"));
+        // only for udp associate, zeros of a connect request are payload
+        let mut connect = associate.to_vec();
+        connect[2] = CONNECT;
+        let expected = (Some(Deviation::MissingCrlf), b"\0\0\r\n".to_vec());
+        assert_eq!(parse(&connect, Lenient), Ok(expected));
+
+        // anything else is still refused
+        let request = b"\r\n\x02\x01\x01\x02\x03\x04\x00\x50\r\n";
+        assert_eq!(parse(request, Lenient), Err("invalid"));
+        let request = b"\n\x01\x01\x01\x02\x03\x04\x00\x50\r\n";
+        assert_eq!(parse(request, Lenient), Err("invalid"));
+    }
+
+    #[test]
+    fn test_v4_mapped() {
+        let v4 = |text: &str| embedded_ipv4(&text.parse::<Ipv6Addr>().unwrap());
+        assert_eq!(
+            v4("::ffff:93.184.216.34"),
+            Some(Ipv4Addr::new(93, 184, 216, 34))
+        );
+        assert_eq!(v4("::93.184.216.34"), Some(Ipv4Addr::new(93, 184, 216, 34)));
+        assert_eq!(v4("::ffff:127.0.0.1"), Some(Ipv4Addr::LOCALHOST));
+        assert_eq!(v4("::ffff:0.0.0.0"), Some(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(v4("::1"), None);
+        assert_eq!(v4("::"), None);
+        assert_eq!(v4("2001:db8::1"), None);
+        assert_eq!(v4("64:ff9b::93.184.216.34"), None);
+
+        let addr = |text: &str| unmap(text.parse().unwrap()).to_string();
+        assert_eq!(addr("[::ffff:93.184.216.34]:443"), "93.184.216.34:443");
+        assert_eq!(addr("[::1]:53"), "[::1]:53");
+        assert_eq!(addr("93.184.216.34:80"), "93.184.216.34:80");
+
+        let mapped = |text: &str| {
+            let mut buffer = [0u8; MAX_ADDRESS_LEN];
+            let addr: SocketAddr = text.parse().unwrap();
+            let len = Sock5Address::write(&mut buffer, &addr);
+            // the request as an old proxy sends it, the address as ipv6
+            assert_eq!(buffer[0], 0x04);
+            let request = [&b"\r\n\x01"[..], &buffer[..len], b"\r\n"].concat();
+            match TrojanRequest::parse_command(&request, ProtocolCompat::Strict) {
+                RequestParseResult::Request(TrojanRequest {
+                    address: Sock5Address::Socket(addr),
+                    ..
+                }) => addr.to_string(),
+                _ => panic!("{} not parsed", text),
+            }
+        };
+        assert_eq!(mapped("[::ffff:93.184.216.34]:443"), "93.184.216.34:443");
+        assert_eq!(mapped("[::93.184.216.34]:443"), "93.184.216.34:443");
+        assert_eq!(mapped("[::1]:443"), "[::1]:443");
+        assert_eq!(mapped("[::]:443"), "[::]:443");
+        assert_eq!(mapped("[2001:db8::1]:443"), "[2001:db8::1]:443");
+    }
+
+    #[test]
+    fn test_split_request() {
+        let addresses: [&[u8]; 3] = [
+            b"\x01\x01\x02\x03\x04\x00\x50",
+            b"\x03\x0bexample.com\x01\xbb",
+            b"\x04\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x35",
+        ];
+        let mut domain = [0u8; MAX_ADDRESS_LEN];
+        let len = Sock5Address::write_domain(&mut domain, "example.com", 443);
+        assert_eq!(&domain[..len], addresses[1]);
+        for command in [CONNECT, UDP_ASSOCIATE] {
+            for address in addresses {
+                let head = [b"\r\n", &[command][..], address, b"\r\n"].concat();
+                let request = [&head[..], b"data"].concat();
+                for compat in [ProtocolCompat::Strict, ProtocolCompat::Lenient] {
+                    // every split position of the head is waited on
+                    for len in 0..head.len() {
+                        assert_eq!(parse(&request[..len], compat), Err("continued"));
+                    }
+                    assert_eq!(parse(&head, compat), Ok((None, vec![])));
+                    assert_eq!(parse(&request, compat), Ok((None, b"data".to_vec())));
+                }
+                // a wrong byte anywhere ends the wait
+                for len in [1, 2, 3, head.len() - 1] {
+                    let mut request = head.clone();
+                    request[len - 1] = 0x02;
+                    let parsed = parse(&request[..len], ProtocolCompat::Strict);
+                    assert_eq!(parsed, Err("invalid"), "{:X?}", &request[..len]);
+                }
+            }
+        }
+        let request = [&b"\r\n\x01"[..], addresses[1], b"\r\n"].concat();
+        match TrojanRequest::parse_command(&request, ProtocolCompat::Strict) {
+            RequestParseResult::Request(TrojanRequest {
+                address: Sock5Address::Domain(domain, 443),
+                reset: false,
+                ..
+            }) => assert_eq!(domain, "example.com"),
+            _ => panic!("domain not parsed"),
+        }
+        let request = [&[b'\r', b'\n', CONNECT | RESET][..], addresses[1], b"\r\n"].concat();
+        match TrojanRequest::parse_command(&request, ProtocolCompat::Strict) {
+            RequestParseResult::Request(TrojanRequest {
+                command: CONNECT,
+                reset: true,
+                ..
+            }) => {}
+            _ => panic!("reset flag not parsed"),
+        }
+        // an empty domain is no target
+        let request = b"\r\n\x01\x03\x00\x00\x50\r\n";
+        assert_eq!(parse(request, ProtocolCompat::Strict), Err("invalid"));
+    }
+
+    #[test]
+    fn test_metadata() {
+        let metadata = vec![
+            (METADATA_ACCOUNT, Bytes::from_static(b"acct-42")),
+            (0x7f, Bytes::from_static(b"unknown")),
+            (METADATA_ROUTE, Bytes::from_static(b"eu-west")),
+        ];
+        let mut request = [0u8; MAX_HEADER_LEN];
+        let head = [PASS, b"\r\n\x01\x01\x01\x02\x03\x04\x00\x50\r\n"].concat();
+        request[..head.len()].copy_from_slice(&head);
+        assert_eq!(
+            TrojanRequest::write_metadata(&mut request, head.len(), &[]),
+            head.len()
+        );
+        let len = TrojanRequest::write_metadata(&mut request, head.len(), &metadata);
+        assert_eq!(len, head.len() + 2 + 27);
+        let request = [&request[..len], b"data"].concat();
+        for end in PASS.len()..len {
+            assert_eq!(
+                parse(&request[PASS.len()..end], ProtocolCompat::Strict),
+                Err("continued")
+            );
+        }
+        match TrojanRequest::parse_with(&request, PASS, ProtocolCompat::Strict) {
+            RequestParseResult::Request(request) => {
+                assert_eq!(request.payload, b"data");
+                // the unknown type is skipped
+                assert_eq!(
+                    request.metadata,
+                    vec![metadata[0].clone(), metadata[2].clone()]
+                );
+            }
+            _ => panic!("metadata not parsed"),
+        }
+
+        // too long or an item beyond the block
+        let head = &request[PASS.len()..head.len()];
+        let long = [head, &(MAX_METADATA_LEN as u16 + 1).to_be_bytes()].concat();
+        assert_eq!(parse(&long, ProtocolCompat::Strict), Err("invalid"));
+        let truncated = [head, b"\x00\x03\x01\x05a"].concat();
+        assert_eq!(parse(&truncated, ProtocolCompat::Strict), Err("invalid"));
+        let empty = [head, b"\x00\x00GET"].concat();
+        assert_eq!(
+            parse(&empty, ProtocolCompat::Strict),
+            Ok((None, b"GET".to_vec()))
+        );
+    }
+
+    /// xorshift, the fuzz tests below are random but repeatable
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 as usize
+        }
+
+        fn below(&mut self, max: usize) -> usize {
+            self.next() % max
+        }
+
+        fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+            items[self.below(items.len())]
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    const PASS: &[u8] = b"0123456789abcdef0123456789abcdef0123456789abcdef01234567";
+
+    /// Returns a request close enough to a valid one to get deep into the
+    /// parser, with the edge cases of the fallback decision.
+    fn fuzz_request(rng: &mut Rng) -> Vec<u8> {
+        let mut request = PASS.to_vec();
+        if rng.below(8) == 0 {
+            // looks valid, but the hash is wrong
+            let index = rng.below(PASS.len());
+            request[index] = rng.pick(b"0123456789abcdef");
+        }
+        request.extend_from_slice(b"\r\n");
+        let commands = [CONNECT, UDP_ASSOCIATE, CONTROL, CONNECT | 0xc0, 0x00, 0xff];
+        request.push(rng.pick(&commands));
+        let random = rng.next() as u8;
+        let atyp = rng.pick(&[0x01, 0x03, 0x04, 0x00, 0xff, random]);
+        request.push(atyp);
+        let len = match atyp {
+            0x01 => 4,
+            0x04 => 16,
+            _ => {
+                let random = rng.below(256);
+                let len = rng.pick(&[0, 1, 255, random]);
+                request.push(len as u8);
+                len
+            }
+        };
+        let address = rng.bytes(len);
+        request.extend_from_slice(address.as_slice());
+        let random = rng.next() as u16;
+        let port = rng.pick(&[0, 80, random]);
+        request.extend_from_slice(&port.to_be_bytes());
+        let random = rng.below(400);
+        let zeros = rng.pick(&[0, 0, 2, random]);
+        request.extend_from_slice(vec![0; zeros].as_slice());
+        request.extend_from_slice(&b"\r\n"[..rng.below(3)]);
+        let len = rng.below(16);
+        let payload = rng.bytes(len);
+        request.extend_from_slice(payload.as_slice());
+        if rng.below(4) == 0 {
+            let index = rng.below(request.len());
+            request[index] = rng.next() as u8;
+        }
+        if rng.below(4) == 0 {
+            request.truncate(rng.below(request.len()));
+        }
+        request
+    }
+
+    type Decision = Option<Result<(Option<Deviation>, Vec<u8>), ()>>;
+
+    fn decide(buffer: &[u8], compat: ProtocolCompat) -> Decision {
+        match TrojanRequest::parse_with(buffer, PASS, compat) {
+            RequestParseResult::Request(request) => {
+                Some(Ok((request.deviation, request.payload.to_vec())))
+            }
+            RequestParseResult::InvalidProtocol => Some(Err(())),
+            RequestParseResult::Continued => {
+                assert!(buffer.len() < MAX_HEADER_LEN, "{:X?}", buffer);
+                assert!(buffer.starts_with(PASS) || PASS.starts_with(buffer));
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_request() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for round in 0..20000 {
+            let request = if round % 4 == 0 {
+                let len = rng.below(2 * MAX_HEADER_LEN);
+                [&PASS[..rng.below(PASS.len() + 1)], &rng.bytes(len)].concat()
+            } else {
+                fuzz_request(&mut rng)
+            };
+            for compat in [ProtocolCompat::Strict, ProtocolCompat::Lenient] {
+                let whole = decide(request.as_slice(), compat);
+                if request.len() >= MAX_HEADER_LEN {
+                    assert!(whole.is_some(), "{:X?}", request);
+                }
+                // read in random pieces, like the server does after a
+                // split, until there is a decision
+                let mut len = 0;
+                let split = loop {
+                    if len == request.len() {
+                        break None;
+                    }
+                    let max = rng.pick(&[1, 8, 64]);
+                    len = (len + 1 + rng.below(max)).min(request.len());
+                    if let Some(decision) = decide(&request[..len], compat) {
+                        break Some(decision);
+                    }
+                };
+                match (split, whole) {
+                    (Some(Ok((deviation, payload))), Some(Ok(whole))) => {
+                        assert_eq!(deviation, whole.0, "{:X?}", request);
+                        assert!(whole.1.starts_with(payload.as_slice()));
+                    }
+                    (split, whole) => assert_eq!(split, whole, "{:X?}", request),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_udp() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        let max_len = 1 + 1 + 255 + 2 + 4 + MAX_PACKET_SIZE;
+        for _ in 0..20000 {
+            let mut packet = vec![rng.pick(&[0x01, 0x03, 0x04, 0x00, 0xff])];
+            let len = rng.below(64);
+            packet.extend(rng.bytes(len));
+            if rng.below(2) == 0 {
+                let length = rng.below(MAX_PACKET_SIZE + 8) as u16;
+                packet.extend_from_slice(&length.to_be_bytes());
+                packet.extend_from_slice(b"\r\n");
+                let len = rng.below(MAX_PACKET_SIZE);
+                packet.extend(rng.bytes(len));
+            }
+            for len in [packet.len(), rng.below(packet.len() + 1)] {
+                let packet = &packet[..len];
+                if let UdpParseResult::Continued = UdpAssociate::parse(packet) {
+                    assert!(packet.len() < max_len);
+                }
+                if let UdpParseResultEndpoint::Continued = UdpAssociate::parse_endpoint(packet) {
+                    assert!(packet.len() < max_len);
+                }
+            }
+        }
+    }
+
+    #[bench]
+    fn bench_udp_head(b: &mut Bencher) {
+        let addr: SocketAddr = "1.2.3.4:53".parse().unwrap();
+        let mut buffer = vec![0u8; MAX_UDP_HEAD_LEN + MAX_PACKET_SIZE];
+        b.iter(|| round_trip(&mut buffer, &addr, 100));
+    }
+}
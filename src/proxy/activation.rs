@@ -0,0 +1,71 @@
+//! Systemd socket activation of the dns redirect socket.
+//!
+//! A unit with a `ListenDatagram=` socket hands it to the proxy from fd 3
+//! on, announced by LISTEN_PID and LISTEN_FDS. The datagram socket bound
+//! to `--dns-redirect-addr` is taken instead of binding one, so it stays
+//! open with the queries queued while the service restarts. Inherited fds
+//! of other addresses are left alone. A SIGHUP reload never rebinds the
+//! socket or forgets the pending queries, the address is only read at
+//! startup.
+use std::net::SocketAddr;
+
+use mio::net::UdpSocket;
+
+/// First fd passed by systemd
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Number of fds passed to this process by systemd.
+#[cfg(unix)]
+fn listen_fds() -> Option<i32> {
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != std::process::id() {
+        return None;
+    }
+    std::env::var("LISTEN_FDS")
+        .ok()?
+        .parse()
+        .ok()
+        .filter(|fds| *fds > 0)
+}
+
+/// Takes the datagram socket bound to `addr` from systemd, None if it
+/// passed none.
+#[cfg(unix)]
+pub fn inherit_udp(addr: SocketAddr) -> Option<UdpSocket> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    use socket2::{Socket, Type};
+
+    let fds = listen_fds()?;
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds {
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        let bound = socket.r#type().is_ok_and(|typ| typ == Type::DGRAM)
+            && socket.local_addr().ok().and_then(|local| local.as_socket()) == Some(addr);
+        if !bound {
+            // not ours to close
+            let _ = socket.into_raw_fd();
+            continue;
+        }
+        if let Err(err) = socket.set_nonblocking(true) {
+            log::error!(
+                "set inherited dns redirect socket nonblocking failed:{}",
+                err
+            );
+            let _ = socket.into_raw_fd();
+            return None;
+        }
+        log::warn!("dns redirect socket {} inherited from systemd", addr);
+        return Some(UdpSocket::from_std(socket.into()));
+    }
+    log::warn!(
+        "systemd passed no datagram socket bound to {}, binding one",
+        addr
+    );
+    None
+}
+
+#[cfg(not(unix))]
+pub fn inherit_udp(_: SocketAddr) -> Option<UdpSocket> {
+    None
+}
@@ -0,0 +1,190 @@
+//! Destinations the proxy may tunnel to, with --strict-allowlist.
+//!
+//! Entries are read from `--allowlist-file`, one per line:
+//!
+//! ```text
+//! # comments and empty lines are ignored
+//! 203.0.113.0/24
+//! 192.0.2.10 443
+//! example.com
+//! *.example.org 443
+//! ```
+//!
+//! `example.com` matches that name only, `*.example.org` its subdomains.
+//! Names are sniffed with `--sniff`, without a sniffed name only addresses
+//! can match. Connections to anything else are closed and datagrams are
+//! dropped, logged with the client address. An empty list denies all. The
+//! file is read again on SIGHUP, an invalid one keeps the current list.
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    net::SocketAddr,
+    sync::RwLock,
+};
+
+use crate::{
+    cidr::{to_u128, Cidr},
+    config::OPTIONS,
+    types::{Result, TrojanError},
+};
+
+enum Target {
+    Cidr(Cidr),
+    Name(String),
+    Suffix(String),
+}
+
+struct Entry {
+    target: Target,
+    // None for any port
+    port: Option<u16>,
+}
+
+impl Entry {
+    fn parse(line: &str) -> Option<Entry> {
+        let mut items = line.split_whitespace();
+        let target = items.next()?;
+        let target = if let Some(cidr) = Cidr::parse(target) {
+            Target::Cidr(cidr)
+        } else {
+            let name = target.trim_end_matches('.').to_ascii_lowercase();
+            match name.strip_prefix("*.") {
+                Some(suffix) if !suffix.is_empty() => Target::Suffix(format!(".{}", suffix)),
+                Some(_) => return None,
+                None if name.contains(['*', '/']) || name.is_empty() => return None,
+                None => Target::Name(name),
+            }
+        };
+        let port = match items.next() {
+            Some(port) => Some(port.parse().ok()?),
+            None => None,
+        };
+        if items.next().is_some() {
+            return None;
+        }
+        Some(Entry { target, port })
+    }
+
+    fn matches(&self, ip: u128, port: u16, name: Option<&str>) -> bool {
+        let target = match &self.target {
+            Target::Cidr(cidr) => cidr.contains(ip),
+            Target::Name(exact) => name.is_some_and(|name| name == exact),
+            Target::Suffix(suffix) => name.is_some_and(|name| name.ends_with(suffix.as_str())),
+        };
+        target && self.port.is_none_or(|p| p == port)
+    }
+}
+
+#[derive(Default)]
+struct Allowlist {
+    entries: Vec<Entry>,
+}
+
+lazy_static::lazy_static! {
+    static ref ALLOWLIST: RwLock<Allowlist> = RwLock::new(Allowlist::default());
+}
+
+impl Allowlist {
+    fn parse(reader: impl BufRead) -> Result<Allowlist> {
+        let mut allowlist = Allowlist::default();
+        for (no, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(entry) = Entry::parse(line) {
+                allowlist.entries.push(entry);
+            } else {
+                log::error!("invalid allowlist entry at line {}:{}", no + 1, line);
+                return Err(TrojanError::Dummy(()));
+            }
+        }
+        Ok(allowlist)
+    }
+
+    fn allowed(&self, addr: &SocketAddr, name: Option<&str>) -> bool {
+        let ip = to_u128(addr.ip());
+        let name = name.map(|name| name.trim_end_matches('.').to_ascii_lowercase());
+        self.entries
+            .iter()
+            .any(|entry| entry.matches(ip, addr.port(), name.as_deref()))
+    }
+}
+
+fn load() -> Result<Allowlist> {
+    let file = File::open(OPTIONS.proxy_args().allowlist_file.as_str())?;
+    Allowlist::parse(BufReader::new(file))
+}
+
+/// Loads the list at startup, panics if the file is invalid.
+pub fn init() {
+    let args = OPTIONS.proxy_args();
+    if !args.strict_allowlist {
+        return;
+    }
+    let allowlist = load()
+        .unwrap_or_else(|err| panic!("load allowlist {} failed:{:?}", args.allowlist_file, err));
+    if allowlist.entries.is_empty() {
+        log::warn!("allowlist is empty, every destination is denied");
+    }
+    let names = allowlist
+        .entries
+        .iter()
+        .any(|entry| !matches!(entry.target, Target::Cidr(_)));
+    if names && !args.sniff {
+        log::warn!("allowlist has names, which only match with --sniff");
+    }
+    *ALLOWLIST.write().unwrap() = allowlist;
+}
+
+/// Reloads the list, keeps the current one if the file is invalid.
+pub fn reload() {
+    if !OPTIONS.proxy_args().strict_allowlist {
+        return;
+    }
+    match load() {
+        Ok(allowlist) => {
+            log::warn!("allowlist reloaded, {} entries", allowlist.entries.len());
+            *ALLOWLIST.write().unwrap() = allowlist;
+        }
+        Err(err) => log::error!("reload allowlist failed:{:?}", err),
+    }
+}
+
+/// Whether `addr`, with the sniffed `name` if any, may be tunneled.
+pub fn allowed(addr: &SocketAddr, name: Option<&str>) -> bool {
+    !OPTIONS.proxy_args().strict_allowlist || ALLOWLIST.read().unwrap().allowed(addr, name)
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::net::SocketAddr;
+
+    use crate::proxy::allowlist::Allowlist;
+
+    #[test]
+    fn test_allowlist() {
+        let entries = "# ci\n203.0.113.0/24\n192.0.2.10 443\nexample.com\n*.example.org 443\n";
+        let allowlist = Allowlist::parse(entries.as_bytes()).unwrap();
+        let allowed = |addr: &str, name: Option<&str>| {
+            allowlist.allowed(&addr.parse::<SocketAddr>().unwrap(), name)
+        };
+        assert!(allowed("203.0.113.7:22", None));
+        assert!(allowed("192.0.2.10:443", None));
+        assert!(!allowed("192.0.2.10:80", None));
+        assert!(allowed("1.2.3.4:80", Some("Example.com.")));
+        assert!(!allowed("1.2.3.4:80", Some("www.example.com")));
+        assert!(allowed("1.2.3.4:443", Some("a.b.example.org")));
+        assert!(!allowed("1.2.3.4:443", Some("example.org")));
+        assert!(!allowed("1.2.3.4:443", Some("badexample.org")));
+        assert!(!allowed("1.2.3.4:443", None));
+
+        let empty = Allowlist::parse("# nothing\n".as_bytes()).unwrap();
+        assert!(!empty.allowed(&"203.0.113.7:22".parse().unwrap(), None));
+        assert!(Allowlist::parse("*.".as_bytes()).is_err());
+        assert!(Allowlist::parse("10.0.0.0/40".as_bytes()).is_err());
+        assert!(Allowlist::parse("example.com http".as_bytes()).is_err());
+    }
+}
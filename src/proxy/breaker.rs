@@ -0,0 +1,187 @@
+//! Circuit breaker of an endpoint whose server is down.
+//!
+//! After `--breaker-failures` connect failures in a row the breaker of the
+//! endpoint opens. New clients routed to it fail at once instead of waiting
+//! on a connect which fails like the ones before, tcp clients are reset
+//! and udp datagrams dropped. The open breaker logs one error line per
+//! [`LOG_INTERVAL`] with the clients failed meanwhile.
+//!
+//! Every [`PROBE_INTERVAL`] an open breaker is half open, a connection is
+//! opened into the pool as probe. The first handshake of the endpoint
+//! which completes closes it, a probe which fails keeps it open. Tunnels
+//! already open are not touched. Breaker states are shown by the `stats`
+//! command of the control socket and in `--healthcheck-status-file`.
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::OPTIONS;
+
+/// Time between two probes of an open breaker
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+/// Time between two log lines of an open breaker
+pub const LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    /// States of the breakers for the control thread, by endpoint
+    static ref STATES: Mutex<Vec<(&'static str, &'static str)>> = Mutex::new(Vec::new());
+}
+
+/// A change of a breaker, see [`Breaker::update`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Change {
+    Opened,
+    Closed,
+}
+
+#[derive(Default)]
+pub struct Breaker {
+    /// Since when the breaker is open
+    opened: Option<Instant>,
+    next_probe: Option<Instant>,
+    /// Failures in a row when the last probe was opened, while it runs
+    probing: Option<usize>,
+    /// Clients failed fast since the last log line
+    rejected: usize,
+    logged: Option<Instant>,
+}
+
+impl Breaker {
+    pub fn open(&self) -> bool {
+        self.opened.is_some()
+    }
+
+    pub fn state(&self) -> &'static str {
+        match (self.opened, self.probing) {
+            (None, _) => "closed",
+            (Some(_), None) => "open",
+            (Some(_), Some(_)) => "half-open",
+        }
+    }
+
+    /// Takes the connect failures in a row of the endpoint, `threshold`
+    /// opens the breaker, 0 never does.
+    pub fn update(&mut self, failures: usize, threshold: usize, now: Instant) -> Option<Change> {
+        match self.opened {
+            None if threshold > 0 && failures >= threshold => {
+                self.opened.replace(now);
+                self.next_probe.replace(now + PROBE_INTERVAL);
+                self.probing = None;
+                self.logged = None;
+                self.rejected = 0;
+                Some(Change::Opened)
+            }
+            Some(_) if failures == 0 => {
+                self.opened = None;
+                self.next_probe = None;
+                self.probing = None;
+                Some(Change::Closed)
+            }
+            Some(_) => {
+                if self.probing.is_some_and(|probing| failures > probing) {
+                    self.probing = None;
+                }
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Whether a probe is due at `now`, the next one is due an interval later.
+    pub fn probe(&mut self, failures: usize, now: Instant) -> bool {
+        match self.next_probe {
+            Some(next) if self.opened.is_some() && now >= next => {
+                self.next_probe.replace(now + PROBE_INTERVAL);
+                self.probing.replace(failures);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn next_probe(&self) -> Option<Instant> {
+        self.next_probe
+    }
+
+    /// Counts a client failed fast, returns the clients to log if the line
+    /// is due.
+    pub fn reject(&mut self, now: Instant) -> Option<usize> {
+        self.rejected += 1;
+        if self
+            .logged
+            .is_some_and(|logged| now.duration_since(logged) < LOG_INTERVAL)
+        {
+            return None;
+        }
+        self.logged.replace(now);
+        Some(std::mem::take(&mut self.rejected))
+    }
+
+    /// Time the breaker is open, for the dump.
+    pub fn open_for(&self, now: Instant) -> Duration {
+        self.opened.map_or(Duration::ZERO, |opened| {
+            now.saturating_duration_since(opened)
+        })
+    }
+}
+
+pub fn enabled() -> bool {
+    OPTIONS.proxy_args().breaker_failures > 0
+}
+
+/// Publishes the states of the breakers of all endpoints.
+pub fn publish(states: Vec<(&'static str, &'static str)>) {
+    *STATES.lock().unwrap() = states;
+}
+
+/// The published states as a json object, by endpoint name.
+pub fn json() -> String {
+    let states = STATES.lock().unwrap();
+    let states = states
+        .iter()
+        .map(|(name, state)| format!("\"{}\":\"{}\"", name, state))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{{{}}}", states)
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::time::{Duration, Instant};
+
+    use crate::proxy::breaker::{Breaker, Change, LOG_INTERVAL, PROBE_INTERVAL};
+
+    #[test]
+    fn test_breaker() {
+        let start = Instant::now();
+        let mut breaker = Breaker::default();
+        assert_eq!(breaker.update(9, 0, start), None);
+        assert_eq!(breaker.update(2, 3, start), None);
+        assert_eq!(breaker.update(3, 3, start), Some(Change::Opened));
+        assert_eq!(breaker.state(), "open");
+        assert_eq!(breaker.update(4, 3, start), None);
+
+        // one log line per interval
+        assert_eq!(breaker.reject(start), Some(1));
+        assert_eq!(breaker.reject(start + Duration::from_secs(1)), None);
+        assert_eq!(breaker.reject(start + Duration::from_secs(2)), None);
+        assert_eq!(breaker.reject(start + LOG_INTERVAL), Some(3));
+
+        // a failed probe keeps it open, a handshake closes it
+        assert!(!breaker.probe(4, start + Duration::from_secs(1)));
+        assert!(breaker.probe(4, start + PROBE_INTERVAL));
+        assert_eq!(breaker.state(), "half-open");
+        assert!(!breaker.probe(4, start + PROBE_INTERVAL));
+        assert_eq!(breaker.update(5, 3, start + PROBE_INTERVAL * 2), None);
+        assert_eq!(breaker.state(), "open");
+        assert!(breaker.probe(5, start + PROBE_INTERVAL * 2));
+        assert_eq!(
+            breaker.update(0, 3, start + PROBE_INTERVAL * 2),
+            Some(Change::Closed)
+        );
+        assert_eq!(breaker.state(), "closed");
+        assert!(!breaker.probe(0, start + PROBE_INTERVAL * 3));
+    }
+}
@@ -0,0 +1,139 @@
+//! Local management commands of the proxy on `--control-socket`, one
+//! command line per connection answered with a json line, like
+//! `echo route-mode direct | nc -U /run/trojan.sock`.
+//!
+//! The socket is served by its own thread, commands only touch state the
+//! poll loop reads atomically. Only our user may connect, the socket file
+//! is created with mode 0600 and peers of other users besides root are
+//! refused.
+// commands are only served on unix sockets
+#![cfg_attr(not(unix), allow(dead_code))]
+use crate::{
+    config::OPTIONS,
+    metrics::COUNTERS,
+    proxy::{breaker, route_mode},
+};
+
+/// Longest command line accepted
+const MAX_COMMAND_LEN: usize = 256;
+
+fn stats() -> String {
+    let counters = COUNTERS
+        .iter()
+        .map(|counter| format!("\"{}\":{}", counter.name(), counter.get()))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"route_mode\":\"{}\",\"breakers\":{},\"counters\":{{{}}}}}",
+        route_mode::name(route_mode::get()),
+        breaker::json(),
+        counters
+    )
+}
+
+/// Returns the json response of `command`.
+pub fn handle(command: &str) -> String {
+    match command {
+        "stats" => stats(),
+        "route-mode" => format!(
+            "{{\"route_mode\":\"{}\"}}",
+            route_mode::name(route_mode::get())
+        ),
+        command if command.starts_with("route-mode ") => {
+            match route_mode::parse(command[11..].trim()) {
+                Some(mode) => format!(
+                    "{{\"route_mode\":\"{}\",\"previous\":\"{}\"}}",
+                    route_mode::name(mode),
+                    route_mode::name(route_mode::set(mode))
+                ),
+                None => "{\"error\":\"route mode is one of tunnel, direct or reject\"}".to_owned(),
+            }
+        }
+        _ => "{\"error\":\"unknown command\"}".to_owned(),
+    }
+}
+
+/// Serves `--control-socket` if set, a stale socket file is replaced.
+#[cfg(unix)]
+pub fn start() -> crate::types::Result<()> {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        time::Duration,
+    };
+
+    let path = OPTIONS.proxy_args().control_socket.as_str();
+    if path.is_empty() {
+        return Ok(());
+    }
+    let _ = std::fs::remove_file(path);
+    let listener = crate::sys::bind_private(path).map_err(|err| {
+        log::error!("bind --control-socket {} failed:{}", path, err);
+        err
+    })?;
+    log::info!("control socket listening on {}", path);
+    let own = unsafe { libc::geteuid() };
+    std::thread::Builder::new()
+        .name("control".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::warn!("control socket accept failed:{}", err);
+                        continue;
+                    }
+                };
+                match crate::sys::peer_cred(&stream) {
+                    Ok((_, uid)) if uid == own || uid == 0 => {}
+                    Ok((pid, uid)) => {
+                        log::warn!("control socket refused pid:{} uid:{}", pid, uid);
+                        let _ = stream.write_all(b"{\"error\":\"permission denied\"}\n");
+                        continue;
+                    }
+                    Err(err) => {
+                        log::warn!("control socket peer unknown:{}", err);
+                        continue;
+                    }
+                }
+                let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                let mut command = String::new();
+                let result =
+                    BufReader::new((&stream).take(MAX_COMMAND_LEN as u64)).read_line(&mut command);
+                if let Err(err) = result {
+                    log::warn!("control command read failed:{}", err);
+                    continue;
+                }
+                let command = command.trim();
+                log::info!("control command {:?}", command);
+                let mut response = handle(command);
+                response.push('\n');
+                if let Err(err) = stream.write_all(response.as_bytes()) {
+                    log::warn!("control response write failed:{}", err);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn start() -> crate::types::Result<()> {
+    if !OPTIONS.proxy_args().control_socket.is_empty() {
+        log::warn!("--control-socket needs unix sockets, ignored");
+    }
+    Ok(())
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use crate::proxy::control::handle;
+
+    #[test]
+    fn test_handle() {
+        assert_eq!(
+            handle("route-mode sideways"),
+            "{\"error\":\"route mode is one of tunnel, direct or reject\"}"
+        );
+        assert_eq!(handle("reload"), "{\"error\":\"unknown command\"}");
+    }
+}
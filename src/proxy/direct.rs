@@ -0,0 +1,438 @@
+//! Local relays of `--route-mode direct`, the destination is connected
+//! from the proxy with the marker instead of through the tunnel.
+//!
+//! Tcp relays both ways with half closes, udp keeps one socket per client
+//! like an association does, answers go back from the address they came
+//! from.
+use std::{
+    io::ErrorKind,
+    net::{Shutdown, SocketAddr},
+    rc::Rc,
+    time::Instant,
+};
+
+use bytes::BytesMut;
+use mio::{
+    event::Event,
+    net::{TcpStream, UdpSocket},
+    Interest, Poll, Token,
+};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+use crate::{
+    config::OPTIONS,
+    dump::Dump,
+    proto::MAX_PACKET_SIZE,
+    proxy::{udp_cache::UdpSvrCache, CHANNEL_CLIENT, CHANNEL_CNT, CHANNEL_TCP, CHANNEL_UDP},
+    sys,
+    tcp_util::{self, Outcome, TcpIo},
+    types::Result,
+};
+
+/// One direction of a relay
+#[derive(Default)]
+struct Half {
+    /// Read from the source, not yet taken by the destination
+    pending: BytesMut,
+    /// The source finished sending
+    eof: bool,
+    /// The destination got the FIN
+    shut: bool,
+    bytes: usize,
+}
+
+impl Half {
+    /// Moves what `from` has to `to` until either blocks, false if one
+    /// of them broke.
+    fn relay(&mut self, index: usize, from: &TcpStream, to: &TcpStream, buffer: &mut [u8]) -> bool {
+        loop {
+            if !self.pending.is_empty() {
+                let data = self.pending.split();
+                let transfer = tcp_util::tcp_send(index, to, &mut self.pending, &data);
+                self.bytes += transfer.bytes;
+                match transfer.outcome {
+                    Outcome::Ok => {}
+                    Outcome::WouldBlock => return true,
+                    _ => return false,
+                }
+            }
+            if self.eof {
+                if !self.shut {
+                    self.shut = true;
+                    let _ = TcpIo::shutdown(to, Shutdown::Write);
+                }
+                return true;
+            }
+            match TcpIo::read(from, buffer) {
+                Ok(0) => self.eof = true,
+                Ok(size) => self.pending.extend_from_slice(&buffer[..size]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return true,
+                Err(err) => {
+                    log::debug!("direct connection:{} read failed:{}", index, err);
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+pub struct DirectTcp {
+    index: usize,
+    pub src_addr: SocketAddr,
+    pub dst_addr: SocketAddr,
+    client: TcpStream,
+    target: TcpStream,
+    connected: bool,
+    up: Half,
+    down: Half,
+    pub created: Instant,
+    last_active: Instant,
+    closed: bool,
+}
+
+/// Opens a nonblocking socket marked before it connects, so the
+/// redirect rules let it pass.
+fn marked(addr: SocketAddr, marker: u8, typ: Type) -> std::io::Result<Socket> {
+    let protocol = if typ == Type::STREAM {
+        Protocol::TCP
+    } else {
+        Protocol::UDP
+    };
+    let socket = Socket::new(Domain::for_address(addr), typ, Some(protocol))?;
+    if marker != 0 {
+        sys::set_mark(&socket, marker)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+impl DirectTcp {
+    pub fn open(
+        index: usize,
+        mut client: TcpStream,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        marker: u8,
+        poll: &Poll,
+    ) -> Result<DirectTcp> {
+        let socket = marked(dst_addr, marker, Type::STREAM)?;
+        match socket.connect(&SockAddr::from(dst_addr)) {
+            Ok(()) => {}
+            #[cfg(unix)]
+            Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err.into()),
+        }
+        let mut target = TcpStream::from_std(socket.into());
+        target.set_nodelay(true)?;
+        let interest = Interest::READABLE | Interest::WRITABLE;
+        poll.registry().register(
+            &mut target,
+            Token(index * CHANNEL_CNT + CHANNEL_TCP),
+            interest,
+        )?;
+        if let Err(err) = poll.registry().register(
+            &mut client,
+            Token(index * CHANNEL_CNT + CHANNEL_CLIENT),
+            interest,
+        ) {
+            let _ = poll.registry().deregister(&mut target);
+            return Err(err.into());
+        }
+        Ok(DirectTcp {
+            index,
+            src_addr,
+            dst_addr,
+            client,
+            target,
+            connected: false,
+            up: Half::default(),
+            down: Half::default(),
+            created: Instant::now(),
+            last_active: Instant::now(),
+            closed: false,
+        })
+    }
+
+    pub fn ready(&mut self, event: &Event, poll: &Poll) {
+        if self.closed {
+            return;
+        }
+        self.last_active = Instant::now();
+        if !self.connected {
+            if event.token().0 % CHANNEL_CNT != CHANNEL_TCP || !event.is_writable() {
+                return;
+            }
+            if let Ok(Some(err)) | Err(err) = self.target.take_error() {
+                log::warn!(
+                    "direct connection:{} to:{} failed:{}",
+                    self.index,
+                    self.dst_addr,
+                    err
+                );
+                self.close(poll);
+                return;
+            }
+            self.connected = true;
+        }
+        self.relay(poll);
+    }
+
+    fn relay(&mut self, poll: &Poll) {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let index = self.index;
+        let open = self
+            .up
+            .relay(index, &self.client, &self.target, &mut buffer)
+            && self
+                .down
+                .relay(index, &self.target, &self.client, &mut buffer);
+        if !open || (self.up.shut && self.down.shut) {
+            self.close(poll);
+        }
+    }
+
+    /// Closes a relay which failed to connect or stayed idle for the
+    /// timeouts of tunnel connections.
+    pub fn check_timeout(&mut self, now: Instant, poll: &Poll) {
+        if self.closed {
+            return;
+        }
+        let connect = OPTIONS.connect_duration;
+        let expired = if self.connected {
+            now - self.last_active > OPTIONS.tcp_idle_duration
+        } else {
+            connect.is_some_and(|connect| now - self.created > connect)
+        };
+        if expired {
+            log::info!(
+                "direct connection:{} to:{} timed out",
+                self.index,
+                self.dst_addr
+            );
+            self.close(poll);
+        }
+    }
+
+    pub fn reregister(&mut self, poll: &Poll) {
+        if self.closed {
+            return;
+        }
+        let interest = Interest::READABLE | Interest::WRITABLE;
+        let client = Token(self.index * CHANNEL_CNT + CHANNEL_CLIENT);
+        let target = Token(self.index * CHANNEL_CNT + CHANNEL_TCP);
+        if let Err(err) = poll
+            .registry()
+            .reregister(&mut self.client, client, interest)
+            .and_then(|_| {
+                poll.registry()
+                    .reregister(&mut self.target, target, interest)
+            })
+        {
+            log::warn!("direct connection:{} reregister failed:{}", self.index, err);
+            self.close(poll);
+        }
+    }
+
+    fn close(&mut self, poll: &Poll) {
+        self.closed = true;
+        let _ = poll.registry().deregister(&mut self.client);
+        let _ = poll.registry().deregister(&mut self.target);
+        let _ = self.client.shutdown(Shutdown::Both);
+        let _ = self.target.shutdown(Shutdown::Both);
+    }
+
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    /// When [`check_timeout`](DirectTcp::check_timeout) closes it if
+    /// nothing happens first.
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.closed {
+            None
+        } else if self.connected {
+            Some(self.last_active + OPTIONS.tcp_idle_duration)
+        } else {
+            OPTIONS
+                .connect_duration
+                .map(|connect| self.created + connect)
+        }
+    }
+
+    /// Bytes sent to and received from the destination.
+    pub fn bytes(&self) -> (usize, usize) {
+        (self.up.bytes, self.down.bytes)
+    }
+
+    pub fn dump(&self, dump: &mut Dump) {
+        dump.line(format_args!(
+            "direct tcp:{} {}->{} connected:{} sent:{} recv:{} idle:{}s",
+            self.index,
+            self.src_addr,
+            self.dst_addr,
+            self.connected,
+            self.up.bytes,
+            self.down.bytes,
+            self.last_active.elapsed().as_secs()
+        ));
+    }
+}
+
+pub struct DirectUdp {
+    index: usize,
+    pub src_addr: SocketAddr,
+    socket: UdpSocket,
+    last_active: Instant,
+    sent: usize,
+    received: usize,
+}
+
+impl DirectUdp {
+    /// Opens the socket of client `src_addr`, of the family of its first
+    /// destination.
+    pub fn open(
+        index: usize,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        marker: u8,
+        poll: &Poll,
+    ) -> Result<DirectUdp> {
+        let socket = marked(dst_addr, marker, Type::DGRAM)?;
+        let any: SocketAddr = if dst_addr.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        socket.bind(&SockAddr::from(any))?;
+        let mut socket = UdpSocket::from_std(socket.into());
+        poll.registry().register(
+            &mut socket,
+            Token(index * CHANNEL_CNT + CHANNEL_UDP),
+            Interest::READABLE,
+        )?;
+        Ok(DirectUdp {
+            index,
+            src_addr,
+            socket,
+            last_active: Instant::now(),
+            sent: 0,
+            received: 0,
+        })
+    }
+
+    pub fn send(&mut self, payload: &[u8], dst_addr: SocketAddr) {
+        self.last_active = Instant::now();
+        match self.socket.send_to(payload, dst_addr) {
+            Ok(size) => self.sent += size,
+            Err(err) => log::debug!(
+                "direct udp:{} send to:{} failed:{}",
+                self.index,
+                dst_addr,
+                err
+            ),
+        }
+    }
+
+    /// Passes the answers to the client from the address of their sender.
+    pub fn ready(&mut self, udp_cache: &mut UdpSvrCache, buffer: &mut [u8]) {
+        loop {
+            let (size, from) = match self.socket.recv_from(buffer) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    log::debug!("direct udp:{} receive failed:{}", self.index, err);
+                    return;
+                }
+            };
+            self.last_active = Instant::now();
+            self.received += size;
+            let socket: Option<Rc<UdpSocket>> = udp_cache.get_socket(from);
+            if let Some(socket) = socket {
+                if let Err(err) = socket.send_to(&buffer[..size], self.src_addr) {
+                    log::debug!(
+                        "direct udp:{} send to client:{} failed:{}",
+                        self.index,
+                        self.src_addr,
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn idle(&self, now: Instant) -> bool {
+        now - self.last_active > OPTIONS.udp_idle_duration
+    }
+
+    pub fn close(&mut self, poll: &Poll) {
+        let _ = poll.registry().deregister(&mut self.socket);
+    }
+
+    pub fn dump(&self, dump: &mut Dump) {
+        dump.line(format_args!(
+            "direct udp:{} {} sent:{} recv:{} idle:{}s",
+            self.index,
+            self.src_addr,
+            self.sent,
+            self.received,
+            self.last_active.elapsed().as_secs()
+        ));
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::{
+        io::{Read, Write},
+        net::{Shutdown, TcpListener},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use mio::{net::TcpStream, Events, Poll};
+
+    use crate::proxy::direct::DirectTcp;
+
+    #[test]
+    fn test_tcp_relay() {
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst_addr = origin.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = origin.accept().unwrap();
+            let mut ping = Vec::new();
+            stream.read_to_end(&mut ping).unwrap();
+            stream.write_all(b"pong").unwrap();
+            ping
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, src_addr) = listener.accept().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        let mut poll = Poll::new().unwrap();
+        let accepted = TcpStream::from_std(accepted);
+        let mut direct = DirectTcp::open(2, accepted, src_addr, dst_addr, 0, &poll).unwrap();
+        client.write_all(b"ping").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let mut events = Events::with_capacity(16);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !direct.closed() && Instant::now() < deadline {
+            poll.poll(&mut events, Some(Duration::from_millis(100)))
+                .unwrap();
+            for event in &events {
+                direct.ready(event, &poll);
+            }
+        }
+        assert!(direct.closed());
+        assert_eq!(server.join().unwrap(), b"ping");
+        let mut pong = Vec::new();
+        client.read_to_end(&mut pong).unwrap();
+        assert_eq!(pong, b"pong");
+        assert_eq!(direct.bytes(), (4, 4));
+    }
+}
@@ -0,0 +1,292 @@
+//! Forwards DNS queries redirected to a plain UDP port through the tunnel.
+//!
+//! All the queries share one pooled connection in UDP_ASSOCIATE mode, each
+//! query gets a new transaction id so answers can be matched with the
+//! client and the original id even if clients reuse ids.
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use mio::{event::Event, net::UdpSocket, Poll, Token};
+
+use crate::{
+    config::OPTIONS,
+    dump::Dump,
+    idle_pool::IdlePool,
+    proto::{
+        TrojanRequest, UdpAssociate, UdpParseResult, MAX_PACKET_SIZE, MAX_REQUEST_LEN,
+        MAX_UDP_HEAD_LEN, UDP_ASSOCIATE,
+    },
+    proxy::DNS_TUNNEL,
+    resolver::DnsResolver,
+    status::StatusProvider,
+    tls_conn::TlsConn,
+};
+
+/// Time before forgetting a query without answer
+const SESSION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Index of the tunnel connection in logs, below the indexes of the pool
+const TUNNEL_INDEX: usize = 1;
+/// Length of the DNS message header
+const DNS_HEAD_LEN: usize = 12;
+
+struct Session {
+    client: SocketAddr,
+    id: u16,
+    time: Instant,
+}
+
+pub struct DnsRedirect {
+    socket: UdpSocket,
+    resolver_addr: SocketAddr,
+    tunnel: Option<TlsConn>,
+    recv_buffer: Vec<u8>,
+    tunnel_buffer: BytesMut,
+    sessions: HashMap<u16, Session>,
+    next_id: u16,
+    last_active: Instant,
+}
+
+impl DnsRedirect {
+    pub fn new(socket: UdpSocket, resolver_addr: SocketAddr) -> DnsRedirect {
+        DnsRedirect {
+            socket,
+            resolver_addr,
+            tunnel: None,
+            recv_buffer: vec![0u8; MAX_PACKET_SIZE],
+            tunnel_buffer: BytesMut::new(),
+            sessions: HashMap::new(),
+            next_id: 0,
+            last_active: Instant::now(),
+        }
+    }
+
+    pub fn accept(&mut self, poll: &Poll, pool: &mut IdlePool, resolver: &DnsResolver) {
+        loop {
+            match self.socket.recv_from(self.recv_buffer.as_mut_slice()) {
+                Ok((size, client)) => self.forward(size, client, poll, pool, resolver),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("dns redirect receive failed:{}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn tunnel(&mut self, poll: &Poll, pool: &mut IdlePool, resolver: &DnsResolver) -> bool {
+        if let Some(tunnel) = &self.tunnel {
+            if tunnel.alive() || tunnel.is_connecting() {
+                return true;
+            }
+        }
+        self.close_tunnel(poll);
+        if let Some(conn) = pool.get(poll, resolver) {
+            let mut conn = conn.commit();
+            if !conn.reset_index(TUNNEL_INDEX, Token(DNS_TUNNEL), poll) {
+                conn.check_status(poll);
+                return false;
+            }
+            let mut request = [0u8; MAX_REQUEST_LEN];
+            let len = TrojanRequest::write(
+                &mut request,
+                UDP_ASSOCIATE,
+                OPTIONS.empty_addr.as_ref().unwrap(),
+            );
+            if !conn.write_session(&request[..len]).accepted() {
+                conn.check_status(poll);
+                return false;
+            }
+            log::debug!("dns redirect tunnel is ready");
+            self.tunnel.replace(conn);
+            true
+        } else {
+            log::error!("alloc dns redirect tunnel failed");
+            false
+        }
+    }
+
+    fn close_tunnel(&mut self, poll: &Poll) {
+        if let Some(mut tunnel) = self.tunnel.take() {
+            tunnel.shutdown();
+            tunnel.check_status(poll);
+        }
+        self.tunnel_buffer.clear();
+    }
+
+    fn next_id(&mut self) -> Option<u16> {
+        for _ in 0..=u16::MAX {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.sessions.contains_key(&id) {
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    fn forward(
+        &mut self,
+        size: usize,
+        client: SocketAddr,
+        poll: &Poll,
+        pool: &mut IdlePool,
+        resolver: &DnsResolver,
+    ) {
+        if size < DNS_HEAD_LEN {
+            log::debug!("dns redirect got {} bytes from {}, too short", size, client);
+            return;
+        }
+        if !self.tunnel(poll, pool, resolver) {
+            return;
+        }
+        self.last_active = Instant::now();
+        let tunnel = self.tunnel.as_ref().unwrap();
+        if !tunnel.is_connecting() && !tunnel.writable() {
+            log::warn!("dns redirect tunnel is blocked, drop query from {}", client);
+            return;
+        }
+        let id = if let Some(id) = self.next_id() {
+            id
+        } else {
+            log::warn!(
+                "dns redirect has too many queries, drop query from {}",
+                client
+            );
+            return;
+        };
+        let query = &mut self.recv_buffer[..size];
+        let orig = u16::from_be_bytes([query[0], query[1]]);
+        query[..2].copy_from_slice(&id.to_be_bytes());
+        self.sessions.insert(
+            id,
+            Session {
+                client,
+                id: orig,
+                time: Instant::now(),
+            },
+        );
+
+        let mut head = [0u8; MAX_UDP_HEAD_LEN];
+        let len = UdpAssociate::write(&mut head, &self.resolver_addr, size as u16);
+        let tunnel = self.tunnel.as_mut().unwrap();
+        if tunnel
+            .write_frame(&head[..len], &self.recv_buffer[..size])
+            .accepted()
+        {
+            tunnel.do_send();
+        }
+        tunnel.check_status(poll);
+    }
+
+    pub fn ready(&mut self, event: &Event, poll: &Poll) {
+        let tunnel = if let Some(tunnel) = self.tunnel.as_mut() {
+            tunnel
+        } else {
+            log::error!("dns redirect tunnel not found, check deregister");
+            return;
+        };
+        if event.is_readable() && tunnel.do_read_into(&mut self.tunnel_buffer) > 0 {
+            self.answer();
+        }
+        let tunnel = self.tunnel.as_mut().unwrap();
+        if event.is_writable() {
+            tunnel.established();
+            tunnel.do_send();
+        }
+        if tunnel.is_shutdown() {
+            tunnel.peer_closed();
+        }
+        tunnel.check_status(poll);
+        if tunnel.deregistered() {
+            log::debug!("dns redirect tunnel closed");
+            self.tunnel.take();
+            self.tunnel_buffer.clear();
+        }
+    }
+
+    fn answer(&mut self) {
+        let data = self.tunnel_buffer.split();
+        let mut buffer = data.as_ref();
+        loop {
+            match UdpAssociate::parse(buffer) {
+                UdpParseResult::Packet(packet) => {
+                    self.reply(&packet.payload[..packet.length]);
+                    buffer = &packet.payload[packet.length..];
+                }
+                UdpParseResult::InvalidProtocol => {
+                    log::error!("dns redirect tunnel got invalid udp protocol");
+                    if let Some(tunnel) = self.tunnel.as_mut() {
+                        tunnel.shutdown();
+                    }
+                    return;
+                }
+                UdpParseResult::Continued => break,
+            }
+        }
+        self.tunnel_buffer.extend_from_slice(buffer);
+    }
+
+    fn reply(&mut self, answer: &[u8]) {
+        if answer.len() < DNS_HEAD_LEN {
+            log::debug!("dns redirect got {} bytes answer, too short", answer.len());
+            return;
+        }
+        let id = u16::from_be_bytes([answer[0], answer[1]]);
+        let session = if let Some(session) = self.sessions.remove(&id) {
+            session
+        } else {
+            log::debug!("dns redirect got answer:{} without query", id);
+            return;
+        };
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let buffer = &mut buffer[..answer.len()];
+        buffer.copy_from_slice(answer);
+        buffer[..2].copy_from_slice(&session.id.to_be_bytes());
+        if let Err(err) = self.socket.send_to(buffer, session.client) {
+            log::warn!("dns redirect send to {} failed:{}", session.client, err);
+        }
+    }
+
+    /// Returns when the next session expires, or without sessions when the
+    /// tunnel is idle.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.sessions
+            .values()
+            .map(|session| session.time + SESSION_TIMEOUT)
+            .min()
+            .or_else(|| {
+                self.tunnel
+                    .as_ref()
+                    .filter(|tunnel| tunnel.alive())
+                    .map(|_| self.last_active + OPTIONS.udp_idle_duration)
+            })
+    }
+
+    pub fn check_timeout(&mut self, poll: &Poll) {
+        self.sessions
+            .retain(|_, session| session.time.elapsed() < SESSION_TIMEOUT);
+        if self.sessions.is_empty() {
+            if let Some(tunnel) = &self.tunnel {
+                if tunnel.alive() && self.last_active.elapsed() > OPTIONS.udp_idle_duration {
+                    log::debug!("dns redirect tunnel idle, close now");
+                    self.close_tunnel(poll);
+                }
+            }
+        }
+    }
+
+    pub fn dump(&self, dump: &mut Dump) {
+        dump.line(format_args!(
+            "dns redirect sessions:{} tunnel:{}",
+            self.sessions.len(),
+            self.tunnel
+                .as_ref()
+                .map_or_else(|| "none".to_owned(), |tunnel| tunnel.dump_state())
+        ));
+    }
+}
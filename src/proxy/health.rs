@@ -0,0 +1,142 @@
+//! Endpoint health kept across restarts in `--health-file`.
+//!
+//! The file is rewritten every few minutes and when the proxy is stopped
+//! with SIGTERM or SIGINT, and read at startup. It is json with one
+//! endpoint a line:
+//!
+//! ```text
+//! {"version":1,"saved":1700000000,"endpoints":[
+//! {"name":"default","failures":0,"latency_ms":42,"last_failure":0}
+//! ]}
+//! ```
+//!
+//! Readers skip fields they don't know, so new fields keep the version,
+//! which only changes with incompatible formats. Missing, corrupt or stale
+//! files are ignored silently, there is nothing to lose by starting fresh.
+use std::{
+    fmt::Write as _,
+    fs,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::idle_pool::Health;
+
+const VERSION: u64 = 1;
+/// Time in seconds after which a saved state is not trusted anymore
+const MAX_AGE: u64 = 3600;
+/// Interval between two saves in the poll loop
+pub const SAVE_DURATION: Duration = Duration::from_secs(180);
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn format(endpoints: &[(&str, Health)], now: SystemTime) -> String {
+    let mut text = format!(
+        "{{\"version\":{},\"saved\":{},\"endpoints\":[\n",
+        VERSION,
+        secs(now)
+    );
+    for (i, (name, health)) in endpoints.iter().enumerate() {
+        let _ = writeln!(
+            text,
+            "{{\"name\":\"{}\",\"failures\":{},\"latency_ms\":{},\"last_failure\":{}}}{}",
+            name,
+            health.failures,
+            health.latency.map_or(0, |latency| latency.as_millis()),
+            health.last_failure.map_or(0, secs),
+            if i + 1 == endpoints.len() { "" } else { "," }
+        );
+    }
+    text.push_str("]}\n");
+    text
+}
+
+/// Returns the raw value of `key` in a flat json object.
+fn field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(&format!("\"{}\":", key))? + key.len() + 3;
+    let value = &line[start..];
+    let end = value.find([',', '}']).unwrap_or(value.len());
+    Some(value[..end].trim().trim_matches('"'))
+}
+
+fn number(line: &str, key: &str) -> Option<u64> {
+    field(line, key)?.parse().ok()
+}
+
+fn parse(text: &str, now: SystemTime) -> Option<Vec<(String, Health)>> {
+    let mut lines = text.lines();
+    let head = lines.next()?;
+    let version = number(head, "version")?;
+    let saved = number(head, "saved")?;
+    if version != VERSION || secs(now).saturating_sub(saved) > MAX_AGE {
+        return None;
+    }
+    let mut endpoints = Vec::new();
+    for line in lines.filter(|line| line.starts_with('{')) {
+        let since_epoch = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let health = Health {
+            failures: number(line, "failures")? as usize,
+            latency: Some(number(line, "latency_ms")?)
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            last_failure: Some(number(line, "last_failure")?)
+                .filter(|secs| *secs > 0)
+                .map(since_epoch),
+        };
+        endpoints.push((field(line, "name")?.to_owned(), health));
+    }
+    Some(endpoints)
+}
+
+pub fn save(path: &str, endpoints: &[(&str, Health)]) {
+    let temp = format!("{}.tmp", path);
+    let text = format(endpoints, SystemTime::now());
+    if let Err(err) = fs::write(&temp, text).and_then(|_| fs::rename(&temp, path)) {
+        log::warn!("save health file {} failed:{}", path, err);
+    }
+}
+
+/// Returns the saved health of endpoints, empty if there is none usable.
+pub fn load(path: &str) -> Vec<(String, Health)> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|text| parse(text.as_str(), SystemTime::now()))
+        .unwrap_or_default()
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::{
+        idle_pool::Health,
+        proxy::health::{format, parse},
+    };
+
+    #[test]
+    fn test_health_file() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let health = Health {
+            failures: 3,
+            latency: Some(Duration::from_millis(42)),
+            last_failure: Some(now - Duration::from_secs(10)),
+        };
+        let text = format(&[("default", Health::default()), ("us", health)], now);
+        let endpoints = parse(text.as_str(), now).unwrap();
+        assert_eq!(endpoints.len(), 2);
+        assert_eq!(endpoints[0], ("default".to_owned(), Health::default()));
+        assert_eq!(endpoints[1], ("us".to_owned(), health));
+
+        // unknown fields are skipped
+        let future = text.replace("\"failures\"", "\"weight\":5,\"failures\"");
+        assert_eq!(parse(future.as_str(), now).unwrap()[1].1, health);
+        assert!(parse(text.as_str(), now + Duration::from_secs(7200)).is_none());
+        assert!(parse(text.replace("\"version\":1", "\"version\":2").as_str(), now).is_none());
+        assert!(parse("{\"version\":1,\"sav", now).is_none());
+        assert!(parse(&text[..text.len() / 2], now).is_none());
+    }
+}
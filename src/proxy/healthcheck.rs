@@ -0,0 +1,483 @@
+//! Periodic self check through the tunnel, with an alert once it fails.
+//!
+//! Every `--healthcheck-interval` seconds a connection is taken from the
+//! pool like for any client and sends a HEAD request to
+//! `--healthcheck-target`, any answer within `--healthcheck-timeout` passes.
+//! So an expired certificate, a wrong password or a dead server fail it
+//! just as they fail users. After `--healthcheck-failures` failures in a row
+//! the check is down: an error is logged and the alert is posted to
+//! `--healthcheck-webhook`, a passing check posts the recovery. While down
+//! the interval doubles per failure up to [`MAX_BACKOFF`] times, a pool in
+//! its refill backoff fails right away without connecting at all.
+//! `--healthcheck-status-file` is rewritten after every check, its time
+//! tells monitoring the check still runs. Off by default, the proxy then
+//! never wakes up for it.
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::BytesMut;
+use mio::{event::Event, Poll, Token};
+use socket2::{Domain, Socket, Type};
+
+use crate::{
+    config::OPTIONS,
+    dump::Dump,
+    endpoint,
+    metrics::{HEALTHCHECK_FAILED, HEALTHCHECK_PASSED},
+    proto::{TrojanRequest, CONNECT, MAX_HEADER_LEN},
+    proxy::{breaker, route::Router, HEALTHCHECK},
+    resolver::DnsResolver,
+    status::StatusProvider,
+    sys,
+    tls_conn::TlsConn,
+};
+
+/// Most times the interval is doubled while down
+pub const MAX_BACKOFF: u32 = 16;
+/// Index of the check connection in logs, below the indexes of the pool
+const CHECK_INDEX: usize = 0;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Failures in a row, which decide on the alert and the pace.
+struct Streak {
+    threshold: u32,
+    failures: u32,
+}
+
+impl Streak {
+    fn down(&self) -> bool {
+        self.failures >= self.threshold
+    }
+
+    /// Counts a failure, returns whether the check went down with it.
+    fn failed(&mut self) -> bool {
+        self.failures = self.failures.saturating_add(1);
+        self.failures == self.threshold
+    }
+
+    /// Returns whether the check was down before.
+    fn passed(&mut self) -> bool {
+        let down = self.down();
+        self.failures = 0;
+        down
+    }
+
+    /// Time to the next check.
+    fn wait(&self, interval: Duration) -> Duration {
+        if !self.down() {
+            return interval;
+        }
+        let doubled = (self.failures - self.threshold + 1).min(MAX_BACKOFF.trailing_zeros());
+        interval.saturating_mul(1 << doubled)
+    }
+}
+
+/// Webhook target, plain http as it is meant for the local network
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Webhook {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Webhook {
+    fn parse(url: &str) -> Option<Webhook> {
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let endpoint = endpoint::parse_endpoint(authority, Some(80)).ok()?;
+        Some(Webhook {
+            host: endpoint.host.to_string(),
+            port: endpoint.port,
+            path: path.to_owned(),
+        })
+    }
+
+    fn post(&self, body: &str, marker: u8) -> std::io::Result<()> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        // marked like the tunnels, so the redirect lets it out directly
+        if marker != 0 {
+            sys::set_mark(&socket, marker)?;
+        }
+        socket.connect_timeout(&addr.into(), WEBHOOK_TIMEOUT)?;
+        let mut stream: TcpStream = socket.into();
+        stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+        stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        )?;
+        let mut status = [0u8; 12];
+        stream.read_exact(&mut status)?;
+        match &status[9..10] {
+            b"2" => Ok(()),
+            _ => Err(std::io::Error::other(format!(
+                "webhook answered {}",
+                String::from_utf8_lossy(&status)
+            ))),
+        }
+    }
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// The json of the status file and the webhook, `breakers` the states of
+/// the endpoint breakers as a json object.
+fn status(
+    target: &str,
+    down: bool,
+    failures: u32,
+    latency: Option<Duration>,
+    breakers: &str,
+    time: u64,
+) -> String {
+    format!(
+        "{{\"status\":\"{}\",\"target\":{:?},\"failures\":{},\"latency_ms\":{},\"breakers\":{},\"time\":{}}}\n",
+        if down { "down" } else { "up" },
+        target,
+        failures,
+        latency.map_or(0, |latency| latency.as_millis()),
+        breakers,
+        time
+    )
+}
+
+pub struct Healthcheck {
+    target: String,
+    host: String,
+    port: u16,
+    interval: Duration,
+    timeout: Duration,
+    streak: Streak,
+    webhook: Option<Webhook>,
+    marker: u8,
+    conn: Option<TlsConn>,
+    buffer: BytesMut,
+    /// When the check in flight started
+    started: Option<Instant>,
+    next: Instant,
+    latency: Option<Duration>,
+}
+
+impl Healthcheck {
+    /// Returns None without `--healthcheck-interval`, panics on invalid
+    /// options like other startup options.
+    pub fn new(marker: u8) -> Option<Healthcheck> {
+        let args = OPTIONS.proxy_args();
+        if args.healthcheck_interval == 0 {
+            return None;
+        }
+        let target = args.healthcheck_target.as_str();
+        let endpoint = endpoint::parse_option("healthcheck-target", target, Some(80));
+        let webhook = Some(args.healthcheck_webhook.as_str())
+            .filter(|url| !url.is_empty())
+            .map(|url| {
+                Webhook::parse(url).unwrap_or_else(|| panic!("invalid healthcheck webhook:{}", url))
+            });
+        if args.healthcheck_failures == 0 {
+            panic!("--healthcheck-failures must be at least 1");
+        }
+        let interval = Duration::from_secs(args.healthcheck_interval);
+        Some(Healthcheck {
+            target: target.to_owned(),
+            host: endpoint.host.to_string(),
+            port: endpoint.port,
+            interval,
+            timeout: Duration::from_secs(args.healthcheck_timeout.max(1)),
+            streak: Streak {
+                threshold: args.healthcheck_failures,
+                failures: 0,
+            },
+            webhook,
+            marker,
+            conn: None,
+            buffer: BytesMut::new(),
+            started: None,
+            next: Instant::now() + interval,
+            latency: None,
+        })
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        Some(match self.started {
+            Some(started) => started + self.timeout,
+            None => self.next,
+        })
+    }
+
+    pub fn check_timeout(
+        &mut self,
+        poll: &Poll,
+        now: Instant,
+        router: &mut Router,
+        resolver: &DnsResolver,
+    ) {
+        match self.started {
+            Some(started) if now - started >= self.timeout => {
+                self.finish(poll, now, Err("timed out".to_owned()))
+            }
+            None if now >= self.next => self.start(poll, now, router, resolver),
+            _ => {}
+        }
+    }
+
+    fn start(&mut self, poll: &Poll, now: Instant, router: &mut Router, resolver: &DnsResolver) {
+        let (endpoint, ip) = match self.host.parse::<IpAddr>() {
+            Ok(ip) => (router.route(&SocketAddr::new(ip, self.port)), Some(ip)),
+            Err(_) => {
+                let any = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), self.port);
+                (router.route_name(&any, Some(self.host.as_str())), None)
+            }
+        };
+        self.started.replace(now);
+        let mut conn = match router.pool(endpoint).get(poll, resolver) {
+            Some(conn) => conn.commit(),
+            None => {
+                let reason = format!("endpoint {} has no connection", router.name(endpoint));
+                return self.finish(poll, now, Err(reason));
+            }
+        };
+        if !conn.reset_index(CHECK_INDEX, Token(HEALTHCHECK), poll) {
+            conn.check_status(poll);
+            return self.finish(poll, now, Err("register failed".to_owned()));
+        }
+        let mut request = [0u8; MAX_HEADER_LEN];
+        let len = match ip {
+            Some(ip) => {
+                TrojanRequest::write(&mut request, CONNECT, &SocketAddr::new(ip, self.port))
+            }
+            None => {
+                TrojanRequest::write_domain(&mut request, CONNECT, self.host.as_str(), self.port)
+            }
+        };
+        let head = format!(
+            "HEAD / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            self.host
+        );
+        if !conn.write_session(&request[..len]).accepted()
+            || !conn.write_session(head.as_bytes()).accepted()
+        {
+            conn.check_status(poll);
+            return self.finish(poll, now, Err("write request failed".to_owned()));
+        }
+        conn.do_send();
+        conn.check_status(poll);
+        self.conn.replace(conn);
+    }
+
+    pub fn ready(&mut self, event: &Event, poll: &Poll) {
+        let conn = if let Some(conn) = self.conn.as_mut() {
+            conn
+        } else {
+            log::error!("healthcheck connection not found, check deregister");
+            return;
+        };
+        if event.is_readable() {
+            conn.do_read_into(&mut self.buffer);
+        }
+        if event.is_writable() {
+            conn.established();
+            conn.do_send();
+        }
+        let answered = !self.buffer.is_empty();
+        let closed = conn.is_shutdown() || conn.remote_closed();
+        if self.started.is_some() && (answered || closed) {
+            let result = if answered {
+                Ok(())
+            } else {
+                Err("closed without answer".to_owned())
+            };
+            self.finish(poll, Instant::now(), result);
+        }
+        if let Some(conn) = self.conn.as_mut() {
+            conn.check_status(poll);
+            if conn.deregistered() {
+                self.conn.take();
+            }
+        }
+    }
+
+    fn finish(&mut self, poll: &Poll, now: Instant, result: Result<(), String>) {
+        let started = self.started.take().unwrap_or(now);
+        self.buffer.clear();
+        if let Some(mut conn) = self.conn.take() {
+            conn.shutdown();
+            conn.check_status(poll);
+            if !conn.deregistered() {
+                self.conn.replace(conn);
+            }
+        }
+        match result {
+            Ok(()) => {
+                HEALTHCHECK_PASSED.inc();
+                let latency = now - started;
+                self.latency.replace(latency);
+                log::debug!("healthcheck of {} passed in {:?}", self.target, latency);
+                if self.streak.passed() {
+                    log::warn!("healthcheck of {} recovered", self.target);
+                    self.alert(false);
+                }
+            }
+            Err(reason) => {
+                HEALTHCHECK_FAILED.inc();
+                self.latency = None;
+                log::warn!("healthcheck of {} failed:{}", self.target, reason);
+                if self.streak.failed() {
+                    log::error!(
+                        "healthcheck of {} failed {} times in a row, the tunnel is down",
+                        self.target,
+                        self.streak.failures
+                    );
+                    self.alert(true);
+                }
+            }
+        }
+        self.save();
+        self.next = now + self.streak.wait(self.interval);
+    }
+
+    fn status(&self) -> String {
+        status(
+            self.target.as_str(),
+            self.streak.down(),
+            self.streak.failures,
+            self.latency,
+            breaker::json().as_str(),
+            secs(SystemTime::now()),
+        )
+    }
+
+    fn save(&self) {
+        let path = OPTIONS.proxy_args().healthcheck_status_file.as_str();
+        if path.is_empty() {
+            return;
+        }
+        let temp = format!("{}.tmp", path);
+        let text = self.status();
+        if let Err(err) = std::fs::write(&temp, text).and_then(|_| std::fs::rename(&temp, path)) {
+            log::warn!("save healthcheck status {} failed:{}", path, err);
+        }
+    }
+
+    /// Posts the status to the webhook, in its own thread as it blocks.
+    fn alert(&self, down: bool) {
+        let webhook = match &self.webhook {
+            Some(webhook) => webhook.clone(),
+            None => return,
+        };
+        let body = self.status();
+        let marker = self.marker;
+        let spawned = thread::Builder::new()
+            .name("webhook".to_owned())
+            .spawn(move || {
+                if let Err(err) = webhook.post(body.trim_end(), marker) {
+                    log::error!(
+                        "post healthcheck {} to {}:{} failed:{}",
+                        if down { "alert" } else { "recovery" },
+                        webhook.host,
+                        webhook.port,
+                        err
+                    );
+                }
+            });
+        if let Err(err) = spawned {
+            log::error!("start healthcheck webhook failed:{}", err);
+        }
+    }
+
+    pub fn dump(&self, dump: &mut Dump) {
+        dump.line(format_args!(
+            "healthcheck {} failures:{} latency:{}ms probe:{}",
+            self.target,
+            self.streak.failures,
+            self.latency.map_or(0, |latency| latency.as_millis()),
+            self.conn
+                .as_ref()
+                .map_or_else(|| "none".to_owned(), |conn| conn.dump_state())
+        ));
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::time::Duration;
+
+    use crate::proxy::healthcheck::{status, Streak, Webhook, MAX_BACKOFF};
+
+    #[test]
+    fn test_streak() {
+        let interval = Duration::from_secs(60);
+        let mut streak = Streak {
+            threshold: 3,
+            failures: 0,
+        };
+        assert!(!streak.failed());
+        assert!(!streak.failed());
+        assert_eq!(streak.wait(interval), interval);
+        // the alert comes once, the interval grows while down
+        let waits: Vec<_> = (0..6)
+            .map(|n| {
+                assert_eq!(streak.failed(), n == 0);
+                streak.wait(interval).as_secs() / 60
+            })
+            .collect();
+        assert_eq!(waits, vec![2, 4, 8, 16, 16, 16]);
+        assert_eq!(MAX_BACKOFF, 16);
+        assert!(streak.passed());
+        assert!(!streak.passed());
+        assert_eq!(streak.wait(interval), interval);
+    }
+
+    #[test]
+    fn test_webhook() {
+        let parse = |url| Webhook::parse(url);
+        let webhook = |host: &str, port, path: &str| Webhook {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        };
+        assert_eq!(
+            parse("http://192.168.1.2:8080/hooks/proxy"),
+            Some(webhook("192.168.1.2", 8080, "/hooks/proxy"))
+        );
+        assert_eq!(parse("http://nas.lan"), Some(webhook("nas.lan", 80, "/")));
+        assert_eq!(
+            parse("http://[fd00::2]:81/"),
+            Some(webhook("fd00::2", 81, "/"))
+        );
+        assert_eq!(parse("http://[fd00::2]"), Some(webhook("fd00::2", 80, "/")));
+        assert_eq!(parse("https://nas.lan/"), None);
+        assert_eq!(parse("http://nas.lan:x/"), None);
+        assert_eq!(
+            status(
+                "example.com:80",
+                true,
+                3,
+                None,
+                "{\"default\":\"open\"}",
+                1700000000
+            ),
+            "{\"status\":\"down\",\"target\":\"example.com:80\",\"failures\":3,\"latency_ms\":0,\
+             \"breakers\":{\"default\":\"open\"},\"time\":1700000000}\n"
+        );
+    }
+}
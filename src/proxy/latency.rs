@@ -0,0 +1,85 @@
+//! Time from accepting a client to its trojan request leaving for the
+//! server, the latency the proxy adds to a connection.
+//!
+//! A request left once the tls session handed it to the socket, after the
+//! handshake of a fresh connection and the cork of `--initial-cork`.
+//! Sniffed clients count from their accept, sniffing included. Pool hits
+//! and misses go in separate histograms, kept since the start for the
+//! state dump and per minute for the metrics report. A minute whose 95th
+//! percentile of either is above `--tunnel-latency-alarm` is warned about,
+//! which hints at an undersized pool or a slow path to the server.
+use std::{sync::Mutex, time::Duration};
+
+use crate::{codel::Delays, config::OPTIONS, dump::Dump};
+
+#[derive(Default)]
+struct Latency {
+    /// Hits and misses since the start
+    total: [Delays; 2],
+    /// Hits and misses since the last report
+    minute: [Delays; 2],
+}
+
+lazy_static::lazy_static! {
+    static ref LATENCY: Mutex<Latency> = Mutex::new(Latency::default());
+}
+
+fn case(hit: bool) -> usize {
+    if hit {
+        0
+    } else {
+        1
+    }
+}
+
+const CASES: [&str; 2] = ["hit", "miss"];
+
+fn describe(delays: &Delays) -> String {
+    format!(
+        "count:{} p50:{:?} p95:{:?} p99:{:?}",
+        delays.count(),
+        delays.percentile(50),
+        delays.percentile(95),
+        delays.percentile(99)
+    )
+}
+
+/// Counts a request which left `latency` after its client was accepted,
+/// on a pooled connection if `hit`.
+pub fn record(hit: bool, latency: Duration) {
+    let mut guard = LATENCY.lock().unwrap();
+    guard.total[case(hit)].add(latency);
+    guard.minute[case(hit)].add(latency);
+}
+
+/// Logs the minute since the last report and starts the next one.
+pub fn report() {
+    let alarm = Duration::from_millis(OPTIONS.proxy_args().tunnel_latency_alarm);
+    let mut guard = LATENCY.lock().unwrap();
+    for (delays, name) in guard.minute.iter().zip(CASES) {
+        if delays.count() == 0 {
+            continue;
+        }
+        log::info!("tunnel latency pool {} {}", name, describe(delays));
+        if !alarm.is_zero() && delays.percentile(95) > alarm {
+            log::warn!(
+                "tunnel latency of pool {} p95:{:?} above {:?} for the last minute",
+                name,
+                delays.percentile(95),
+                alarm
+            );
+        }
+    }
+    guard.minute = Default::default();
+}
+
+pub fn dump(dump: &mut Dump) {
+    let guard = LATENCY.lock().unwrap();
+    for (delays, name) in guard.total.iter().zip(CASES) {
+        dump.line(format_args!(
+            "tunnel latency pool {} {}",
+            name,
+            describe(delays)
+        ));
+    }
+}
@@ -1,6 +1,6 @@
 //! This module provides functions used in proxy mod.
 use std::{
-    convert::TryInto,
+    io::ErrorKind,
     net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
@@ -10,32 +10,67 @@ use mio::{
     net::{TcpListener, UdpSocket},
     Events, Interest, Poll, Token, Waker,
 };
-use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
-pub use crate::idle_pool::IdlePool;
 use crate::{
+    audit,
     config::OPTIONS,
-    proxy::{tcp_server::TcpServer, udp_cache::UdpSvrCache, udp_server::UdpServer},
+    dump, endpoint, metrics,
+    profile::{self, Category},
+    proxy::{
+        dns_redirect::DnsRedirect, healthcheck::Healthcheck, route::Router, tcp_server::TcpServer,
+        udp_cache::UdpSvrCache, udp_forward::UdpForwarder, udp_server::UdpServer,
+    },
+    reload,
     resolver::DnsResolver,
-    sys,
+    startup, sys, trace,
     types::Result,
+    verify, worker,
 };
 
+mod activation;
+mod allowlist;
+mod breaker;
+mod control;
+mod direct;
+mod dns_redirect;
+mod health;
+mod healthcheck;
+mod latency;
+mod pacer;
+mod route;
+mod route_mode;
+mod self_test;
+mod sniff;
+mod stall;
 mod tcp_server;
+mod traffic;
 mod udp_cache;
+mod udp_forward;
 mod udp_server;
+mod watchdog;
 
 /// minimal index used in `IdlePool`, `TcpServer` and `UdpServer`
 const MIN_INDEX: usize = 2;
-/// maximum index used in `IdlePool`, `TcpServer` and `UdpServer`
-const MAX_INDEX: usize = usize::MAX / CHANNEL_CNT;
+/// maximum index used in `IdlePool`, `TcpServer` and `UdpServer`, the tokens
+/// above are kept for udp forwards
+const MAX_INDEX: usize = (usize::MAX - UDP_FORWARD_TOKENS) / CHANNEL_CNT - 1;
+/// Tokens kept for udp forward listeners and the tunnels of their peers
+const UDP_FORWARD_TOKENS: usize = 4096;
+/// First token of udp forwards
+const UDP_FORWARD_BASE: usize = (MAX_INDEX + 1) * CHANNEL_CNT;
 /// Token used for TcpListener
 const TCP_LISTENER: usize = 1;
 /// Token used for main Udp Socket
 const UDP_LISTENER: usize = 2;
 /// Token used for dns resolver
 const RESOLVER: usize = 3;
+/// Token used for socket receiving redirected dns queries
+const DNS_LISTENER: usize = 4;
+/// Token used for the tunnel of redirected dns queries
+const DNS_TUNNEL: usize = 5;
+/// Token used for the connection of the self check
+const HEALTHCHECK: usize = 6;
 /// total channel count for Poll
 const CHANNEL_CNT: usize = 4;
 /// channel index  for `IdlePool`
@@ -71,101 +106,295 @@ pub fn new_socket(addr: SocketAddr, is_udp: bool) -> Result<Socket> {
         (Type::STREAM, Protocol::TCP)
     };
     let socket = Socket::new(domain, typ, Some(protocol))?;
-    sys::set_socket_opts(addr.is_ipv4(), is_udp, &socket)?;
+    sys::set_socket_opts(addr.is_ipv4(), is_udp, &socket)
+        .map_err(|err| startup::capability_error("transparent proxy socket", err))?;
     socket.set_nonblocking(true)?;
     socket.set_reuse_address(true)?;
-    socket.bind(&SockAddr::from(addr))?;
+    socket
+        .bind(&SockAddr::from(addr))
+        .map_err(|err| startup::bind_error("local-addr", addr, is_udp, err))?;
     if !is_udp {
-        socket.listen(1024)?;
+        socket.listen(OPTIONS.listen_backlog as i32)?;
     }
     Ok(socket)
 }
 
+/// Returns the mark for sockets, 0 if marking is disabled or, unless
+/// `--require-mark`, not permitted for this process.
+fn probe_mark() -> Result<u8> {
+    let args = OPTIONS.proxy_args();
+    if args.marker == 0 {
+        return Ok(0);
+    }
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+    match sys::set_mark(&socket, args.marker) {
+        Ok(()) => Ok(args.marker),
+        Err(err) if err.kind() == ErrorKind::PermissionDenied && !args.require_mark => {
+            log::warn!(
+                "set mark {} not permitted, sockets stay unmarked:{}",
+                args.marker,
+                err
+            );
+            Ok(0)
+        }
+        Err(err) => Err(startup::capability_error("--marker", err)),
+    }
+}
+
 pub fn run() -> Result<()> {
-    let addr: SocketAddr = OPTIONS.local_addr.parse()?;
-    let mut tcp_listener = TcpListener::from_std(new_socket(addr, false)?.into());
+    worker::start("poll");
+    let addr = endpoint::parse_addr("local-addr", OPTIONS.local_addr.as_str(), None);
+    let tcp_socket = new_socket(addr, false)?;
+    // accepted sockets inherit the options
+    OPTIONS.client_tuning.apply("client", &tcp_socket);
+    let mut tcp_listener = TcpListener::from_std(tcp_socket.into());
     let mut udp_listener = UdpSocket::from_std(new_socket(addr, true)?.into());
     let mut udp_cache = UdpSvrCache::new();
     let mut poll = Poll::new()?;
     let waker = Arc::new(Waker::new(poll.registry(), Token(RESOLVER))?);
-    let mut resolver = DnsResolver::new(waker, Token(RESOLVER), None);
+    let dns_servers = endpoint::parse_addrs("dns-server", &OPTIONS.dns_server, Some(53));
+    let mut resolver = DnsResolver::new(waker.clone(), Token(RESOLVER), dns_servers);
+    resolver.watch_resolv_conf();
     poll.registry()
         .register(&mut tcp_listener, Token(TCP_LISTENER), Interest::READABLE)?;
     poll.registry()
         .register(&mut udp_listener, Token(UDP_LISTENER), Interest::READABLE)?;
 
-    let hostname = OPTIONS.proxy_args().hostname.as_str().try_into()?;
+    OPTIONS.proxy_args().check_sni();
+    allowlist::init();
+    trace::init();
+    audit::init();
+    if OPTIONS.proxy_args().self_test {
+        self_test::run(&tcp_listener, addr);
+    }
+    let dns_redirect = if let Some(addr) = &OPTIONS.proxy_args().dns_redirect_addr {
+        let addr = endpoint::parse_addr("dns-redirect-addr", addr, Some(53));
+        let mut socket = match activation::inherit_udp(addr) {
+            Some(socket) => socket,
+            None => UdpSocket::bind(addr)
+                .map_err(|err| startup::bind_error("dns-redirect-addr", addr, true, err))?,
+        };
+        poll.registry()
+            .register(&mut socket, Token(DNS_LISTENER), Interest::READABLE)?;
+        let resolver_addr = endpoint::parse_addr(
+            "dns-redirect-resolver",
+            OPTIONS.proxy_args().dns_redirect_resolver.as_str(),
+            Some(53),
+        );
+        Some((DnsRedirect::new(socket, resolver_addr), resolver_addr))
+    } else {
+        None
+    };
 
-    let mut root_store = RootCertStore::empty();
-    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-        OwnedTrustAnchor::from_subject_spki_name_constraints(
-            ta.subject,
-            ta.spki,
-            ta.name_constraints,
-        )
-    }));
-    let config = ClientConfig::builder()
-        .with_safe_default_cipher_suites()
-        .with_safe_default_kx_groups()
-        .with_safe_default_protocol_versions()
-        .unwrap()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
-    let config = Arc::new(config);
+    log::info!("client hello {}", OPTIONS.hello.fingerprint());
+    let config = OPTIONS.hello.client_config(verify::verifier()?);
 
-    let mut tcp_server = TcpServer::new(tcp_listener);
-    let mut udp_server = UdpServer::new(udp_listener);
+    let marker = probe_mark()?;
+    let mut tcp_server = TcpServer::new(tcp_listener, marker);
+    let mut udp_server = UdpServer::new(udp_listener, marker);
 
     let mut events = Events::with_capacity(1024);
 
-    let mut pool = IdlePool::new(
-        config,
-        hostname,
-        OPTIONS.proxy_args().pool_size + 1,
-        OPTIONS.proxy_args().port,
-        OPTIONS.proxy_args().hostname.clone(),
-    );
-    pool.init_index(CHANNEL_CNT, CHANNEL_IDLE, MIN_INDEX, MAX_INDEX);
-    pool.init(&poll, &resolver);
+    let mut router = Router::new(config, marker, &waker)?;
+    let mut dns_redirect = dns_redirect.map(|(dns_redirect, resolver_addr)| {
+        let endpoint = router.route(&resolver_addr);
+        (dns_redirect, endpoint)
+    });
+    let mut udp_forwarder = UdpForwarder::new(&OPTIONS.proxy_args().udp_forward, &poll, &router)?;
+    let health_file = OPTIONS.proxy_args().health_file.as_str();
+    if !health_file.is_empty() {
+        router.seed(health::load(health_file));
+        reload::init_stop();
+    }
+    router.init(&poll, &resolver);
+    let mut healthcheck = Healthcheck::new(marker);
+    route_mode::init();
+    control::start()?;
 
+    // the loop sleeps until the nearest deadline, without one until an event
     let mut last_check_time = Instant::now();
     let check_duration = Duration::new(1, 0);
+    let pace_tick = Duration::from_millis(OPTIONS.proxy_args().pace_tick.max(1));
+    let mut last_report_time = Instant::now();
+    let mut last_save_time = Instant::now();
+    // listeners are edge triggered, a capped accept is resumed by the loop
+    let mut accept_pending = false;
+    dump::init();
+    reload::init();
+    reload::wake_on_signals(&waker);
+    watchdog::init();
 
     loop {
-        poll.poll(&mut events, Some(check_duration))?;
+        let now = Instant::now();
+        let sweep = [
+            tcp_server.next_deadline(now),
+            udp_server.next_deadline(),
+            udp_cache.next_deadline(now),
+            router.next_deadline(now),
+            resolver.next_deadline(),
+            dns_redirect
+                .as_ref()
+                .and_then(|(dns_redirect, _)| dns_redirect.next_deadline()),
+            udp_forwarder.next_deadline(),
+            healthcheck
+                .as_ref()
+                .and_then(|healthcheck| healthcheck.next_deadline()),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .map(|deadline| (*deadline).max(last_check_time + check_duration));
+        let timeout = if accept_pending {
+            Some(Duration::ZERO)
+        } else if udp_server.pacing() {
+            Some(sweep.map_or(pace_tick, |sweep| {
+                sweep.saturating_duration_since(now).min(pace_tick)
+            }))
+        } else {
+            sweep.map(|sweep| sweep.saturating_duration_since(now))
+        };
+        // sniffing waits far less than the sweep, the report and the
+        // health file have their own timers
+        let timers = [
+            tcp_server.sniff_deadline(),
+            Some(last_report_time + metrics::REPORT_DURATION),
+            (!health_file.is_empty()).then_some(last_save_time + health::SAVE_DURATION),
+        ];
+        let timeout = match timers.iter().flatten().min() {
+            Some(deadline) => {
+                let timer = deadline.saturating_duration_since(now);
+                Some(timeout.map_or(timer, |timeout| timeout.min(timer)))
+            }
+            None => timeout,
+        };
+        worker::idle();
+        watchdog::idle();
+        let result = poll.poll(&mut events, timeout);
+        worker::busy();
+        watchdog::busy(tcp_server.connections() + udp_server.connections());
+        match result {
+            Ok(()) => {}
+            // a signal, its flag is checked below
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
         for event in &events {
             log::trace!("dispatch token:{}", event.token().0);
+            watchdog::dispatch(event.token().0);
             match event.token() {
                 Token(TCP_LISTENER) => {
-                    tcp_server.accept(&poll, &mut pool, &resolver);
+                    accept_pending = true;
                 }
                 Token(UDP_LISTENER) => {
-                    udp_server.accept(&poll, &mut pool, &mut udp_cache, &resolver);
+                    udp_server.accept(&poll, &mut router, &mut udp_cache, &resolver);
                 }
                 Token(RESOLVER) => {
-                    resolver.consume(|_, ip| {
-                        pool.resolve(ip);
+                    resolver.consume(|token, ip| {
+                        router.resolve(token, ip);
                     });
+                    router.handshaken(&poll);
+                }
+                Token(DNS_LISTENER) => {
+                    if let Some((dns_redirect, endpoint)) = dns_redirect.as_mut() {
+                        dns_redirect.accept(&poll, router.pool(*endpoint), &resolver);
+                    }
+                }
+                Token(DNS_TUNNEL) => {
+                    if let Some((dns_redirect, _)) = dns_redirect.as_mut() {
+                        dns_redirect.ready(event, &poll);
+                    }
+                }
+                Token(HEALTHCHECK) => {
+                    if let Some(healthcheck) = healthcheck.as_mut() {
+                        healthcheck.ready(event, &poll);
+                    }
+                }
+                Token(i) if i >= UDP_FORWARD_BASE => {
+                    udp_forwarder.ready(event, &poll, &mut router, &resolver);
                 }
                 Token(i) if i % CHANNEL_CNT == CHANNEL_IDLE => {
-                    pool.ready(event, &poll);
+                    router.ready(event, &poll);
                 }
                 Token(i) if i % CHANNEL_CNT == CHANNEL_UDP => {
-                    udp_server.ready(event, &poll, &mut udp_cache);
+                    udp_server.ready(event, &poll, &mut udp_cache, &mut router, &resolver);
                 }
                 _ => {
-                    tcp_server.ready(event, &poll);
+                    tcp_server.ready(event, &poll, &mut router, &resolver);
                 }
             }
         }
+        if accept_pending {
+            accept_pending = tcp_server.accept(&poll, &mut router, &resolver);
+        }
+        tcp_server.check_sniffing(&poll, &mut router, &resolver);
+        if udp_server.pacing() {
+            udp_server.release(&poll);
+        }
         udp_server.remove_closed();
         tcp_server.remove_closed();
+        if reload::take() {
+            log::warn!("reset destination traffic");
+            tcp_server.traffic().reset();
+            router.reload(&resolver);
+            allowlist::reload();
+        }
+        if let Some(mut dump) = dump::take() {
+            route_mode::dump(&mut dump);
+            tcp_server.dump(&mut dump);
+            udp_server.dump(&mut dump);
+            router.dump(&mut dump);
+            profile::dump(&mut dump);
+            latency::dump(&mut dump);
+            worker::dump(&mut dump);
+            if let Some((dns_redirect, _)) = &dns_redirect {
+                dns_redirect.dump(&mut dump);
+            }
+            udp_forwarder.dump(&mut dump);
+            if let Some(healthcheck) = &healthcheck {
+                healthcheck.dump(&mut dump);
+            }
+        }
+        if watchdog::take_recover() {
+            tcp_server.reregister(&poll);
+        }
         let now = Instant::now();
-        if now - last_check_time > check_duration {
-            tcp_server.check_timeout(&poll, now);
+        if sweep.is_some_and(|sweep| now >= sweep) {
+            let _scope = profile::scope(Category::TimeoutSweep);
+            tcp_server.check_timeout(&poll, now, &mut router);
+            udp_server.check_timeout(&poll, now);
+            udp_server.resume_broken(&poll, now, &mut router, &resolver);
             udp_cache.check_timeout();
-            pool.check_timeout(&poll);
+            router.check_timeout(&poll, &resolver);
+            resolver.check_resolv_conf(false);
+            if let Some((dns_redirect, _)) = dns_redirect.as_mut() {
+                dns_redirect.check_timeout(&poll);
+            }
+            udp_forwarder.check_timeout(&poll, &mut router, &resolver);
+            if let Some(healthcheck) = healthcheck.as_mut() {
+                healthcheck.check_timeout(&poll, now, &mut router, &resolver);
+            }
             last_check_time = now;
         }
+        if now - last_report_time >= metrics::REPORT_DURATION {
+            metrics::report();
+            route_mode::report();
+            profile::report();
+            latency::report();
+            tcp_server
+                .traffic()
+                .report(OPTIONS.proxy_args().destination_metrics);
+            last_report_time = now;
+        }
+        if !health_file.is_empty() {
+            if reload::stopped() {
+                health::save(health_file, router.health().as_slice());
+                log::warn!("proxy stopped, health saved to {}", health_file);
+                return Ok(());
+            }
+            if now - last_save_time >= health::SAVE_DURATION {
+                health::save(health_file, router.health().as_slice());
+                last_save_time = now;
+            }
+        }
     }
 }
@@ -0,0 +1,182 @@
+//! Token bucket pacing of the datagrams a udp association sends into the
+//! tunnel.
+//!
+//! Datagrams over `--udp-pace-packets` or `--udp-pace-bytes` per second
+//! wait in a short queue, released on the poll loop tick. A full queue
+//! drops its oldest datagram, fresh real time traffic is worth more than
+//! stale one. Drops are counted by the association as rate limit loss.
+use std::{collections::VecDeque, net::SocketAddr, time::Instant};
+
+use crate::{config::OPTIONS, metrics::UDP_PACED};
+
+/// Time in seconds of traffic a bucket holds, allowing short bursts
+const BURST_SECS: f64 = 0.05;
+
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Option<Bucket> {
+        if rate == 0 {
+            None
+        } else {
+            let rate = rate as f64;
+            Some(Bucket {
+                rate,
+                tokens: (rate * BURST_SECS).max(1.0),
+            })
+        }
+    }
+
+    fn refill(&mut self, secs: f64) {
+        self.tokens = (self.tokens + self.rate * secs).min((self.rate * BURST_SECS).max(1.0));
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admit {
+    /// The datagram may be sent now
+    Now,
+    Queued,
+    /// Queued, the oldest datagram was dropped for it
+    Evicted,
+}
+
+pub struct Pacer {
+    packets: Option<Bucket>,
+    bytes: Option<Bucket>,
+    last: Instant,
+    queue: VecDeque<(SocketAddr, Vec<u8>)>,
+    max_queue: usize,
+}
+
+impl Pacer {
+    pub fn new(packets: u64, bytes: u64, max_queue: usize, now: Instant) -> Pacer {
+        Pacer {
+            packets: Bucket::new(packets),
+            bytes: Bucket::new(bytes),
+            last: now,
+            queue: VecDeque::new(),
+            max_queue,
+        }
+    }
+
+    /// Returns a pacer for a new association if pacing is enabled.
+    pub fn from_options() -> Option<Pacer> {
+        let args = OPTIONS.proxy_args();
+        if args.udp_pace_packets == 0 && args.udp_pace_bytes == 0 {
+            None
+        } else {
+            Some(Pacer::new(
+                args.udp_pace_packets,
+                args.udp_pace_bytes,
+                args.udp_pace_queue,
+                Instant::now(),
+            ))
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let secs = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        if let Some(bucket) = &mut self.packets {
+            bucket.refill(secs);
+        }
+        if let Some(bucket) = &mut self.bytes {
+            bucket.refill(secs);
+        }
+    }
+
+    /// Takes the budget of a datagram of `len` bytes if there is any, a
+    /// large datagram may leave the byte bucket in debt.
+    fn take(&mut self, len: usize) -> bool {
+        let ready = self
+            .packets
+            .as_ref()
+            .is_none_or(|bucket| bucket.tokens > 0.0)
+            && self.bytes.as_ref().is_none_or(|bucket| bucket.tokens > 0.0);
+        if ready {
+            if let Some(bucket) = &mut self.packets {
+                bucket.tokens -= 1.0;
+            }
+            if let Some(bucket) = &mut self.bytes {
+                bucket.tokens -= len as f64;
+            }
+        }
+        ready
+    }
+
+    /// Returns whether the datagram may be sent now, otherwise it is queued.
+    pub fn admit(&mut self, dst_addr: &SocketAddr, payload: &[u8], now: Instant) -> Admit {
+        self.refill(now);
+        if self.queue.is_empty() && self.take(payload.len()) {
+            return Admit::Now;
+        }
+        UDP_PACED.inc();
+        let admit = if self.queue.len() >= self.max_queue.max(1) {
+            self.queue.pop_front();
+            Admit::Evicted
+        } else {
+            Admit::Queued
+        };
+        self.queue.push_back((*dst_addr, payload.to_vec()));
+        admit
+    }
+
+    /// Pops the next queued datagram if the budget allows it.
+    pub fn release(&mut self, now: Instant) -> Option<(SocketAddr, Vec<u8>)> {
+        self.refill(now);
+        let len = self.queue.front()?.1.len();
+        if self.take(len) {
+            self.queue.pop_front()
+        } else {
+            None
+        }
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use crate::proxy::pacer::{Admit, Pacer};
+
+    #[test]
+    fn test_pacer() {
+        let addr: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let now = Instant::now();
+        // 100 packets per second, a burst of 5
+        let mut pacer = Pacer::new(100, 0, 3, now);
+        let admits: Vec<_> = (0..10u8).map(|i| pacer.admit(&addr, &[i], now)).collect();
+        assert_eq!(admits[..5], [Admit::Now; 5]);
+        assert_eq!(admits[5..8], [Admit::Queued; 3]);
+        assert_eq!(admits[8..], [Admit::Evicted; 2]);
+        // oldest ones are dropped
+        assert_eq!(pacer.queued(), 3);
+        assert!(pacer.release(now).is_none());
+        let later = now + Duration::from_millis(20);
+        assert_eq!(pacer.release(later).unwrap().1, [7]);
+        assert_eq!(pacer.release(later).unwrap().1, [8]);
+        assert!(pacer.release(later).is_none());
+        let admit = pacer.admit(&addr, &[10], later + Duration::from_secs(1));
+        assert_eq!(admit, Admit::Queued);
+        assert_eq!(pacer.queued(), 2);
+
+        // 1000 bytes per second, a large datagram goes into debt
+        let mut pacer = Pacer::new(0, 1000, 8, now);
+        assert_eq!(pacer.admit(&addr, &[0; 1400], now), Admit::Now);
+        assert_eq!(pacer.admit(&addr, &[0; 10], now), Admit::Queued);
+        assert!(pacer.release(now + Duration::from_secs(1)).is_none());
+        assert!(pacer.release(now + Duration::from_secs(2)).is_some());
+    }
+}
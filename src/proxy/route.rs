@@ -0,0 +1,560 @@
+//! Routing of destinations to server endpoints.
+//!
+//! Every endpoint has its own idle pool. The main server is the endpoint
+//! named `default`, others come from `--endpoint name=host:port`. Rules
+//! are read from `--route-file`, one per line, the first matching rule wins:
+//!
+//! ```text
+//! # comments and empty lines are ignored
+//! default proxy@auto
+//! 203.0.113.0/24 proxy@us
+//! * 5222 proxy@jp
+//! domain:example.com proxy@us
+//! ```
+//!
+//! Domain rules match the name and its subdomains, for connections whose
+//! name was sniffed with `--sniff`.
+//!
+//! `auto` picks the first endpoint not degraded by stalled tunnels, see
+//! [`stall`](crate::proxy::stall), or the main server if all are. An
+//! endpoint whose server stopped answering connects has its
+//! [`breaker`](crate::proxy::breaker) open.
+use std::{
+    cell::Cell,
+    convert::TryInto,
+    fs::File,
+    io::{BufRead, BufReader},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::Instant,
+};
+
+use mio::{event::Event, Poll, Token, Waker};
+use rustls::ClientConfig;
+
+use crate::{
+    cidr::{to_u128, Cidr},
+    config::OPTIONS,
+    dump::Dump,
+    endpoint::{self, Endpoint as Addr},
+    handshake::Handshaker,
+    idle_pool::{Health, IdlePool},
+    metrics::{BREAKER_OPENED, BREAKER_REJECTED, ENDPOINTS_DEGRADED},
+    proxy::{
+        breaker::{self, Breaker, Change},
+        stall::StallHealth,
+        CHANNEL_CNT, CHANNEL_IDLE, MAX_INDEX, MIN_INDEX,
+    },
+    resolver::DnsResolver,
+    types::{Result, TrojanError},
+};
+
+/// Name of the endpoint for the main server
+const DEFAULT_ENDPOINT: &str = "default";
+/// Endpoint name picking the first healthy endpoint
+const AUTO_ENDPOINT: &str = "auto";
+/// Endpoint of rules with `proxy@auto`, resolved by the router
+const AUTO: usize = usize::MAX;
+
+struct Endpoint {
+    name: &'static str,
+    pool: IdlePool,
+    stall: StallHealth,
+    breaker: Breaker,
+}
+
+struct Rule {
+    // None for any address
+    cidr: Option<Cidr>,
+    // Some for a domain rule, which matches any address
+    domain: Option<String>,
+    // None for any port
+    port: Option<u16>,
+    endpoint: usize,
+    /// Line in the route file, for traces
+    line: usize,
+}
+
+#[derive(Default)]
+struct Routes {
+    rules: Vec<Rule>,
+    default: usize,
+}
+
+pub struct Router {
+    endpoints: Vec<Endpoint>,
+    routes: Routes,
+    /// Pool indexes of one endpoint, each pool has its own range
+    span: usize,
+    /// Endpoint `auto` picked last time
+    auto: Cell<usize>,
+}
+
+/// Returns name and address of an `--endpoint` value, without a port it
+/// is `default_port`.
+fn parse_endpoint(text: &'static str, default_port: u16) -> Option<(&'static str, Addr)> {
+    let (name, addr) = text.split_once('=')?;
+    Some((
+        name,
+        endpoint::parse_endpoint(addr, Some(default_port)).ok()?,
+    ))
+}
+
+fn resolve(host: &str, port: u16) -> Result<SocketAddr> {
+    let mut addrs: Vec<_> = (host, port)
+        .to_socket_addrs()?
+        .filter(|addr| OPTIONS.server_ip_allowed(host, addr.ip()))
+        .collect();
+    addrs.sort_by_key(|addr| !addr.is_ipv4());
+    addrs.first().copied().ok_or_else(|| {
+        log::error!("resolve endpoint host {} failed", host);
+        TrojanError::Dummy(())
+    })
+}
+
+impl Routes {
+    fn endpoint(action: &str, names: &[&str]) -> Option<usize> {
+        match action.strip_prefix("proxy@")? {
+            AUTO_ENDPOINT => Some(AUTO),
+            name => names.iter().position(|exist| *exist == name),
+        }
+    }
+
+    fn parse_rule(line: &str, no: usize, names: &[&str]) -> Option<Rule> {
+        let items: Vec<_> = line.split_whitespace().collect();
+        let (cidr, port, action) = match items.as_slice() {
+            [cidr, action] => (*cidr, None, *action),
+            [cidr, port, action] => (*cidr, Some(port.parse().ok()?), *action),
+            _ => return None,
+        };
+        let (cidr, domain) = match cidr {
+            "*" => (None, None),
+            cidr => match cidr.strip_prefix("domain:") {
+                Some(domain) if !domain.is_empty() => (
+                    None,
+                    Some(domain.trim_end_matches('.').to_ascii_lowercase()),
+                ),
+                Some(_) => return None,
+                None => (Some(Cidr::parse(cidr)?), None),
+            },
+        };
+        Some(Rule {
+            cidr,
+            domain,
+            port,
+            endpoint: Self::endpoint(action, names)?,
+            line: no + 1,
+        })
+    }
+
+    /// Parses rules referring to endpoints by `names`, the first one is
+    /// the main server.
+    fn parse(reader: impl BufRead, names: &[&str]) -> Result<Routes> {
+        let mut routes = Routes::default();
+        for (no, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = match line.strip_prefix("default ") {
+                Some(action) => Self::endpoint(action.trim(), names).map(|endpoint| {
+                    routes.default = endpoint;
+                }),
+                None => Self::parse_rule(line, no, names).map(|rule| routes.rules.push(rule)),
+            };
+            if parsed.is_none() {
+                log::error!(
+                    "invalid route at line {}, or its endpoint is not defined:{}",
+                    no + 1,
+                    line
+                );
+                return Err(TrojanError::Dummy(()));
+            }
+        }
+        Ok(routes)
+    }
+
+    fn route(&self, addr: &SocketAddr, name: Option<&str>) -> usize {
+        self.rule(addr, name)
+            .map_or(self.default, |rule| rule.endpoint)
+    }
+
+    /// The first rule matching, None if the default applies.
+    fn rule(&self, addr: &SocketAddr, name: Option<&str>) -> Option<&Rule> {
+        let ip = to_u128(addr.ip());
+        self.rules.iter().find(|rule| {
+            rule.cidr.as_ref().is_none_or(|cidr| cidr.contains(ip))
+                && rule.domain.as_ref().is_none_or(|domain| {
+                    name.is_some_and(|name| {
+                        name == domain
+                            || name
+                                .strip_suffix(domain.as_str())
+                                .is_some_and(|prefix| prefix.ends_with('.'))
+                    })
+                })
+                && rule.port.is_none_or(|port| port == addr.port())
+        })
+    }
+}
+
+impl Router {
+    pub fn new(config: Arc<ClientConfig>, marker: u8, waker: &Arc<Waker>) -> Result<Router> {
+        let args = OPTIONS.proxy_args();
+        let (connect_host, connect_port) = args.connect_host();
+        let mut pools = vec![(
+            DEFAULT_ENDPOINT,
+            IdlePool::new(
+                config.clone(),
+                args.sni().try_into()?,
+                args.pool_size + 1,
+                connect_port,
+                connect_host,
+            ),
+        )];
+        for text in &args.endpoint {
+            let (name, addr) = parse_endpoint(text.as_str(), args.port).ok_or_else(|| {
+                log::error!(
+                    "invalid endpoint {}, format like name=host:port, name=192.0.2.1:port or \
+                     name=[2001:db8::1]:port, the port defaults to --port",
+                    text
+                );
+                TrojanError::Dummy(())
+            })?;
+            let (host, port) = (addr.host.to_string(), addr.port);
+            if name == AUTO_ENDPOINT || pools.iter().any(|(exist, _)| *exist == name) {
+                log::error!("endpoint name {} is reserved or duplicated", name);
+                return Err(TrojanError::Dummy(()));
+            }
+            let mut pool = IdlePool::new(
+                config.clone(),
+                host.as_str().try_into()?,
+                args.pool_size + 1,
+                port,
+                host.clone(),
+            );
+            pool.set_addr(resolve(host.as_str(), port)?);
+            pools.push((name, pool));
+        }
+
+        let span = (MAX_INDEX - MIN_INDEX) / pools.len();
+        let mut endpoints = Vec::new();
+        for (i, (name, mut pool)) in pools.into_iter().enumerate() {
+            let min_index = MIN_INDEX + i * span;
+            pool.init_index(CHANNEL_CNT, CHANNEL_IDLE, min_index, min_index + span);
+            pool.set_marker(marker);
+            pool.set_max_handshakes(args.pool_handshakes);
+            pool.set_resolve_token(Token(i));
+            if args.handshake_workers > 0 {
+                // a poll allows a single waker, which is shared with the resolver
+                pool.set_handshaker(Handshaker::new(args.handshake_workers, waker.clone())?);
+            }
+            endpoints.push(Endpoint {
+                name,
+                pool,
+                stall: StallHealth::default(),
+                breaker: Breaker::default(),
+            });
+        }
+        let routes = if args.route_file.is_empty() {
+            Routes::default()
+        } else {
+            let names: Vec<_> = endpoints.iter().map(|endpoint| endpoint.name).collect();
+            let file = File::open(args.route_file.as_str())?;
+            Routes::parse(BufReader::new(file), names.as_slice())?
+        };
+        let router = Router {
+            endpoints,
+            routes,
+            span,
+            auto: Cell::new(0),
+        };
+        router.publish();
+        Ok(router)
+    }
+
+    /// A router without endpoints, for tests of connections which never
+    /// ask it for one.
+    #[allow(dead_code)]
+    pub fn empty() -> Router {
+        Router {
+            endpoints: Vec::new(),
+            routes: Routes::default(),
+            span: 1,
+            auto: Cell::new(0),
+        }
+    }
+
+    /// Returns the endpoint for connections to `addr`.
+    pub fn route(&self, addr: &SocketAddr) -> usize {
+        self.resolve_auto(self.routes.route(addr, None))
+    }
+
+    /// Returns the endpoint for connections to `addr` with the sniffed
+    /// server `name`.
+    pub fn route_name(&self, addr: &SocketAddr, name: Option<&str>) -> usize {
+        self.resolve_auto(self.routes.route(addr, name))
+    }
+
+    fn resolve_auto(&self, endpoint: usize) -> usize {
+        if endpoint != AUTO {
+            return endpoint;
+        }
+        let now = Instant::now();
+        let endpoint = self
+            .endpoints
+            .iter()
+            .position(|endpoint| !endpoint.stall.degraded(now))
+            .unwrap_or(0);
+        if self.auto.replace(endpoint) != endpoint {
+            log::warn!("auto endpoint is {} now", self.endpoints[endpoint].name);
+        }
+        endpoint
+    }
+
+    /// Why connections to `addr` with `name` go where they go, for traces.
+    pub fn explain(&self, addr: &SocketAddr, name: Option<&str>) -> String {
+        let (mut reason, endpoint) = match self.routes.rule(addr, name) {
+            Some(rule) => (format!("rule at line {}", rule.line), rule.endpoint),
+            None => ("default".to_owned(), self.routes.default),
+        };
+        if endpoint == AUTO {
+            let now = Instant::now();
+            let degraded: Vec<_> = self
+                .endpoints
+                .iter()
+                .take_while(|endpoint| endpoint.stall.degraded(now))
+                .map(|endpoint| endpoint.name)
+                .collect();
+            if !degraded.is_empty() {
+                reason.push_str(", auto skipped degraded ");
+                reason.push_str(degraded.join(",").as_str());
+            }
+        }
+        reason
+    }
+
+    /// Counts a stalled tunnel against `endpoint`.
+    pub fn stalled(&mut self, endpoint: usize, now: Instant) {
+        let endpoint = &mut self.endpoints[endpoint];
+        if endpoint.stall.stalled(now) {
+            ENDPOINTS_DEGRADED.inc();
+            log::error!(
+                "endpoint {} degraded for {}s by stalled tunnels",
+                endpoint.name,
+                endpoint.stall.remaining(now).as_secs()
+            );
+        }
+    }
+
+    /// Degrades `endpoint` for a tunnel its server rejected, in
+    /// maintenance.
+    pub fn refused(&mut self, endpoint: usize, now: Instant) {
+        let endpoint = &mut self.endpoints[endpoint];
+        if endpoint.stall.refused(now) {
+            ENDPOINTS_DEGRADED.inc();
+            log::error!(
+                "endpoint {} degraded for {}s, its server is in maintenance",
+                endpoint.name,
+                endpoint.stall.remaining(now).as_secs()
+            );
+        }
+    }
+
+    /// Whether new clients of `endpoint` fail fast by its open breaker,
+    /// counted and logged once in a while if they do.
+    pub fn broken(&mut self, endpoint: usize, now: Instant) -> bool {
+        if !breaker::enabled() {
+            return false;
+        }
+        self.update_breaker(endpoint, now);
+        let endpoint = &mut self.endpoints[endpoint];
+        if !endpoint.breaker.open() {
+            return false;
+        }
+        BREAKER_REJECTED.inc();
+        if let Some(rejected) = endpoint.breaker.reject(now) {
+            log::error!(
+                "endpoint {} breaker open for {}s, {} connections failed fast since the last line",
+                endpoint.name,
+                endpoint.breaker.open_for(now).as_secs(),
+                rejected
+            );
+        }
+        true
+    }
+
+    /// Opens or closes the breaker of `endpoint` by the connect failures in
+    /// a row of its pool.
+    fn update_breaker(&mut self, endpoint: usize, now: Instant) {
+        let threshold = OPTIONS.proxy_args().breaker_failures;
+        let entry = &mut self.endpoints[endpoint];
+        let streak = entry.pool.streak();
+        let change = entry.breaker.update(streak, threshold, now);
+        match change {
+            Some(Change::Opened) => {
+                BREAKER_OPENED.inc();
+                log::error!(
+                    "endpoint {} breaker open after {} connect failures in a row, new \
+                     connections fail fast until a probe gets through",
+                    entry.name,
+                    streak
+                );
+            }
+            Some(Change::Closed) => log::warn!(
+                "endpoint {} breaker closed after {}s, its server is back",
+                entry.name,
+                entry.breaker.open_for(now).as_secs()
+            ),
+            None => {}
+        }
+        if change.is_some() {
+            self.publish();
+        }
+    }
+
+    fn publish(&self) {
+        breaker::publish(
+            self.endpoints
+                .iter()
+                .map(|endpoint| (endpoint.name, endpoint.breaker.state()))
+                .collect(),
+        );
+    }
+
+    pub fn name(&self, endpoint: usize) -> &'static str {
+        self.endpoints[endpoint].name
+    }
+
+    pub fn pool(&mut self, endpoint: usize) -> &mut IdlePool {
+        &mut self.endpoints[endpoint].pool
+    }
+
+    pub fn init(&mut self, poll: &Poll, resolver: &DnsResolver) {
+        for endpoint in &mut self.endpoints {
+            endpoint.pool.init(poll, resolver);
+        }
+    }
+
+    pub fn ready(&mut self, event: &Event, poll: &Poll) {
+        let index = event.token().0 / CHANNEL_CNT;
+        let endpoint = (index.saturating_sub(MIN_INDEX) / self.span).min(self.endpoints.len() - 1);
+        self.endpoints[endpoint].pool.ready(event, poll);
+    }
+
+    pub fn resolve(&mut self, token: Token, ip: Option<IpAddr>) {
+        if let Some(endpoint) = self.endpoints.get_mut(token.0) {
+            endpoint.pool.resolve(ip);
+        }
+    }
+
+    pub fn handshaken(&mut self, poll: &Poll) {
+        for endpoint in &mut self.endpoints {
+            endpoint.pool.handshaken(poll);
+        }
+    }
+
+    pub fn next_deadline(&self, now: Instant) -> Option<Instant> {
+        self.endpoints
+            .iter()
+            .filter_map(|endpoint| {
+                let probe = endpoint.breaker.next_probe();
+                endpoint
+                    .pool
+                    .next_deadline(now)
+                    .into_iter()
+                    .chain(probe)
+                    .min()
+            })
+            .min()
+    }
+
+    /// Sweeps the pools, then probes the endpoints with an open breaker.
+    pub fn check_timeout(&mut self, poll: &Poll, resolver: &DnsResolver) {
+        for endpoint in &mut self.endpoints {
+            endpoint.pool.check_timeout(poll, resolver);
+        }
+        if !breaker::enabled() {
+            return;
+        }
+        let now = Instant::now();
+        for index in 0..self.endpoints.len() {
+            self.update_breaker(index, now);
+            let endpoint = &mut self.endpoints[index];
+            if endpoint.breaker.probe(endpoint.pool.streak(), now) {
+                log::info!("endpoint {} breaker half open, probing", endpoint.name);
+                endpoint.pool.probe(poll, resolver);
+                self.publish();
+            }
+        }
+    }
+
+    /// Flushes the pools whose server changed, see [`IdlePool::reload`].
+    pub fn reload(&mut self, resolver: &DnsResolver) {
+        for endpoint in &mut self.endpoints {
+            endpoint.pool.reload(resolver);
+        }
+    }
+
+    pub fn health(&self) -> Vec<(&str, Health)> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| (endpoint.name, endpoint.pool.health()))
+            .collect()
+    }
+
+    /// Seeds endpoints with their health saved by an earlier run.
+    pub fn seed(&mut self, saved: Vec<(String, Health)>) {
+        for (name, health) in saved {
+            if let Some(endpoint) = self
+                .endpoints
+                .iter_mut()
+                .find(|endpoint| endpoint.name == name)
+            {
+                endpoint.pool.seed(health);
+            }
+        }
+    }
+
+    pub fn dump(&self, dump: &mut Dump) {
+        let now = Instant::now();
+        for endpoint in &self.endpoints {
+            dump.line(format_args!(
+                "endpoint {} degraded:{}s breaker:{} for {}s failures:{}",
+                endpoint.name,
+                endpoint.stall.remaining(now).as_secs(),
+                endpoint.breaker.state(),
+                endpoint.breaker.open_for(now).as_secs(),
+                endpoint.pool.streak()
+            ));
+            endpoint.pool.dump(dump);
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::net::SocketAddr;
+
+    use crate::proxy::route::{Routes, AUTO};
+
+    #[test]
+    fn test_routes() {
+        let names = ["default", "us", "jp"];
+        let rules = "# test\ndefault proxy@us\n10.0.0.0/8 proxy@auto\n* 5222 proxy@jp\n\
+                     domain:Example.com 443 proxy@auto\n";
+        let routes = Routes::parse(rules.as_bytes(), &names).unwrap();
+        let route = |addr: &str| routes.route(&addr.parse::<SocketAddr>().unwrap(), None);
+        assert_eq!(route("10.1.2.3:80"), AUTO);
+        assert_eq!(route("1.2.3.4:5222"), 2);
+        assert_eq!(route("1.2.3.4:443"), 1);
+        let addr: SocketAddr = "1.2.3.4:443".parse().unwrap();
+        assert_eq!(routes.route(&addr, Some("www.example.com")), AUTO);
+        assert_eq!(routes.route(&addr, Some("example.com")), AUTO);
+        assert_eq!(routes.route(&addr, Some("badexample.com")), 1);
+        assert!(Routes::parse("domain: proxy@us".as_bytes(), &names).is_err());
+        assert!(Routes::parse("* proxy@eu".as_bytes(), &names).is_err());
+        assert!(Routes::parse("default direct".as_bytes(), &names).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+//! Override of the routes for new connections, `--route-mode` at startup
+//! and the `route-mode` command of the control socket later on.
+//!
+//! Direct relays new connections to their destination locally without
+//! the tunnel, for when the server is down, reject refuses them, for when
+//! nothing may leave unencrypted. The mode is only read at accept, open
+//! connections go on the way they started. Reloads keep the mode, a
+//! restart starts again from `--route-mode`.
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{
+    config::{RouteMode, OPTIONS},
+    dump::Dump,
+    metrics::{DIRECT_CONNECTIONS, DIRECT_UDP, ROUTE_MODE_REJECTED},
+};
+
+static MODE: AtomicU8 = AtomicU8::new(0);
+
+fn code(mode: RouteMode) -> u8 {
+    match mode {
+        RouteMode::Tunnel => 0,
+        RouteMode::Direct => 1,
+        RouteMode::Reject => 2,
+    }
+}
+
+fn from_code(code: u8) -> RouteMode {
+    match code {
+        1 => RouteMode::Direct,
+        2 => RouteMode::Reject,
+        _ => RouteMode::Tunnel,
+    }
+}
+
+pub fn name(mode: RouteMode) -> &'static str {
+    match mode {
+        RouteMode::Tunnel => "tunnel",
+        RouteMode::Direct => "direct",
+        RouteMode::Reject => "reject",
+    }
+}
+
+/// What happens to new connections in `mode`.
+fn effect(mode: RouteMode) -> &'static str {
+    match mode {
+        RouteMode::Tunnel => "new connections take the routes",
+        RouteMode::Direct => "new connections bypass the tunnel",
+        RouteMode::Reject => "new connections are refused",
+    }
+}
+
+// parse and set serve the control socket, which is unix only
+#[cfg_attr(not(unix), allow(dead_code))]
+pub fn parse(text: &str) -> Option<RouteMode> {
+    [RouteMode::Tunnel, RouteMode::Direct, RouteMode::Reject]
+        .iter()
+        .copied()
+        .find(|mode| name(*mode) == text)
+}
+
+/// Takes the mode of `--route-mode`, logged for the startup banner.
+pub fn init() {
+    let mode = OPTIONS.proxy_args().route_mode;
+    MODE.store(code(mode), Ordering::SeqCst);
+    if mode == RouteMode::Tunnel {
+        log::info!("route mode:tunnel");
+    } else {
+        log::warn!("route mode:{}, {}", name(mode), effect(mode));
+    }
+}
+
+pub fn get() -> RouteMode {
+    from_code(MODE.load(Ordering::SeqCst))
+}
+
+/// Changes the mode of new connections, returns the one before.
+#[cfg_attr(not(unix), allow(dead_code))]
+pub fn set(mode: RouteMode) -> RouteMode {
+    let previous = from_code(MODE.swap(code(mode), Ordering::SeqCst));
+    if previous != mode {
+        log::warn!(
+            "route mode changed from {} to {}, {}, open connections are kept",
+            name(previous),
+            name(mode),
+            effect(mode)
+        );
+    }
+    previous
+}
+
+/// Logs the mode with the metrics while it overrides the routes.
+pub fn report() {
+    let mode = get();
+    if mode != RouteMode::Tunnel {
+        log::warn!("route mode:{}, {}", name(mode), effect(mode));
+    }
+}
+
+pub fn dump(dump: &mut Dump) {
+    dump.line(format_args!(
+        "route mode:{} direct tcp:{} udp:{} rejected:{}",
+        name(get()),
+        DIRECT_CONNECTIONS.get(),
+        DIRECT_UDP.get(),
+        ROUTE_MODE_REJECTED.get()
+    ));
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use crate::{
+        config::RouteMode,
+        proxy::route_mode::{name, parse},
+    };
+
+    #[test]
+    fn test_parse() {
+        for mode in [RouteMode::Tunnel, RouteMode::Direct, RouteMode::Reject] {
+            assert_eq!(parse(name(mode)), Some(mode));
+        }
+        assert_eq!(parse("Direct"), None);
+        assert_eq!(parse(""), None);
+    }
+}
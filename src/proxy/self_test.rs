@@ -0,0 +1,113 @@
+//! Checks of the transparent redirect in front of the proxy.
+//!
+//! A redirect which rewrites the destination, like REDIRECT or DNAT
+//! instead of TPROXY, makes the original destination of every connection
+//! our own listen address, and the proxy would tunnel to itself.
+use std::{
+    net::{SocketAddr, UdpSocket},
+    thread::sleep,
+    time::Duration,
+};
+
+use mio::net::TcpListener;
+
+use crate::sys;
+
+/// Connected by the startup test, a documentation address which never
+/// answers unless the connection is redirected to us
+const SENTINEL_ADDR: &str = "198.51.100.1:9";
+
+/// Whether the ip of `addr` belongs to this host.
+fn is_local(addr: SocketAddr) -> bool {
+    addr.ip().is_loopback() || UdpSocket::bind(SocketAddr::new(addr.ip(), 0)).is_ok()
+}
+
+/// Whether a connection to `dst` would reach the listener at `listen`.
+pub fn is_self_loop(dst: SocketAddr, listen: SocketAddr) -> bool {
+    if dst.port() != listen.port() {
+        false
+    } else if listen.ip().is_unspecified() {
+        is_local(dst)
+    } else {
+        dst.ip() == listen.ip()
+    }
+}
+
+fn remediation(listen: SocketAddr) -> String {
+    format!(
+        "the redirect rewrites destinations, use TPROXY like \
+         'iptables -t mangle -A PREROUTING -p tcp -j TPROXY --on-port {} --tproxy-mark 1' \
+         with 'ip rule add fwmark 1 lookup 100' and 'ip route add local default dev lo table 100' \
+         instead of REDIRECT or DNAT",
+        listen.port()
+    )
+}
+
+/// Connects to a sentinel address and checks the original destination
+/// the listener sees for it. Runs before the poll loop.
+pub fn run(listener: &TcpListener, listen: SocketAddr) {
+    let sentinel: SocketAddr = SENTINEL_ADDR.parse().unwrap();
+    let client = match std::net::TcpStream::connect_timeout(&sentinel, Duration::from_secs(1)) {
+        Ok(client) => client,
+        Err(err) => {
+            log::warn!(
+                "self test connection to {} failed:{}, traffic of this host is not redirected, \
+                 which is fine if the proxy only serves other hosts",
+                sentinel,
+                err
+            );
+            return;
+        }
+    };
+    let local = client.local_addr().ok();
+    for _ in 0..10 {
+        match listener.accept() {
+            Ok((stream, peer)) if Some(peer) == local => {
+                match sys::get_oridst_addr(&stream) {
+                    Ok(dst) if dst == sentinel => log::info!("self test of the redirect passed"),
+                    Ok(dst) if is_self_loop(dst, listen) => {
+                        log::error!(
+                            "self test got our own address {} as destination, {}",
+                            dst,
+                            remediation(listen)
+                        );
+                    }
+                    Ok(dst) => log::warn!(
+                        "self test connection to {} arrived with destination {}",
+                        sentinel,
+                        dst
+                    ),
+                    Err(err) => log::warn!("self test read original destination failed:{}", err),
+                }
+                return;
+            }
+            Ok((_, peer)) => log::warn!("connection from {} closed during self test", peer),
+            Err(_) => sleep(Duration::from_millis(100)),
+        }
+    }
+    log::warn!(
+        "self test connection to {} was not accepted by the proxy, it went elsewhere",
+        sentinel
+    );
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::net::SocketAddr;
+
+    use crate::proxy::self_test::is_self_loop;
+
+    #[test]
+    fn test_self_loop() {
+        let addr = |text: &str| text.parse::<SocketAddr>().unwrap();
+        assert!(is_self_loop(addr("127.0.0.1:1080"), addr("0.0.0.0:1080")));
+        assert!(is_self_loop(addr("10.0.0.1:1080"), addr("10.0.0.1:1080")));
+        assert!(!is_self_loop(
+            addr("198.51.100.1:1080"),
+            addr("0.0.0.0:1080")
+        ));
+        assert!(!is_self_loop(addr("10.0.0.2:1080"), addr("10.0.0.1:1080")));
+        assert!(!is_self_loop(addr("127.0.0.1:1081"), addr("0.0.0.0:1080")));
+    }
+}
@@ -0,0 +1,258 @@
+//! Server names sniffed from the first bytes of redirected connections.
+//!
+//! With `--sniff` the proxy reads up to [`SNIFF_LIMIT`] bytes of a client
+//! connecting to port 443 or 80 before opening the tunnel, for at most
+//! [`SNIFF_TIMEOUT`]. The SNI of a TLS ClientHello or the Host header of
+//! an HTTP request is used for domain routes and logs, and with
+//! `--sniff-request-domain` sent to the server instead of the ip. The bytes
+//! read are forwarded unchanged. Without a name the connection goes on
+//! with the ip like without sniffing.
+use std::time::Duration;
+
+/// Bytes read at most before giving up
+pub const SNIFF_LIMIT: usize = 4096;
+/// Time waited at most for the client to send enough
+pub const SNIFF_TIMEOUT: Duration = Duration::from_millis(200);
+
+const HANDSHAKE_RECORD: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME: u16 = 0x0000;
+const HOST_NAME: u8 = 0x00;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Sniffed {
+    Name(String),
+    /// The data so far is fine but ends before the name
+    More,
+    /// No name in the data, or a protocol we don't know
+    Unknown,
+}
+
+/// Whether connections to `port` are sniffed.
+pub fn sniffed_port(port: u16) -> bool {
+    port == 443 || port == 80
+}
+
+pub fn sniff(port: u16, data: &[u8]) -> Sniffed {
+    match port {
+        443 => tls_sni(data),
+        80 => http_host(data),
+        _ => Sniffed::Unknown,
+    }
+}
+
+/// Accepts dns names only, an ip is no better than the destination.
+fn checked(name: &[u8]) -> Sniffed {
+    let name = match std::str::from_utf8(name) {
+        Ok(name) => name.trim_end_matches('.').to_ascii_lowercase(),
+        Err(_) => return Sniffed::Unknown,
+    };
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'.')
+        && name.parse::<std::net::IpAddr>().is_err();
+    if valid {
+        Sniffed::Name(name)
+    } else {
+        Sniffed::Unknown
+    }
+}
+
+/// Reads fields in order, `None` once the data ends.
+struct Fields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (field, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(field)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|field| field[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|field| u16::from_be_bytes([field[0], field[1]]))
+    }
+
+    /// A field with a length of `len` bytes in front.
+    fn vector(&mut self, len: usize) -> Option<&'a [u8]> {
+        let size = self
+            .take(len)?
+            .iter()
+            .fold(0usize, |size, byte| size << 8 | *byte as usize);
+        self.take(size)
+    }
+}
+
+/// Finds the SNI in a ClientHello, the first record is enough as long as
+/// the extension is in it.
+fn tls_sni(data: &[u8]) -> Sniffed {
+    let mut record = Fields { data };
+    match record.u8() {
+        Some(HANDSHAKE_RECORD) => {}
+        Some(_) => return Sniffed::Unknown,
+        None => return Sniffed::More,
+    }
+    let length = match (record.u16(), record.u16()) {
+        (Some(_), Some(length)) => length as usize,
+        _ => return Sniffed::More,
+    };
+    let available = record.data.len().min(length);
+    let complete = available == length;
+    let mut hello = Fields {
+        data: &record.data[..available],
+    };
+    let result = (|| {
+        if hello.u8()? != CLIENT_HELLO {
+            return Some(Sniffed::Unknown);
+        }
+        // handshake length, version and random
+        hello.take(3 + 2 + 32)?;
+        // session id, cipher suites and compression methods
+        hello.vector(1)?;
+        hello.vector(2)?;
+        hello.vector(1)?;
+        let mut extensions = Fields {
+            data: hello.vector(2)?,
+        };
+        while !extensions.data.is_empty() {
+            let kind = extensions.u16()?;
+            let extension = extensions.vector(2)?;
+            if kind != SERVER_NAME {
+                continue;
+            }
+            let mut names = Fields {
+                data: Fields { data: extension }.vector(2)?,
+            };
+            while !names.data.is_empty() {
+                let kind = names.u8()?;
+                let name = names.vector(2)?;
+                if kind == HOST_NAME {
+                    return Some(checked(name));
+                }
+            }
+        }
+        Some(Sniffed::Unknown)
+    })();
+    match result {
+        Some(sniffed) => sniffed,
+        // a ClientHello longer than its record is not looked into
+        None if complete => Sniffed::Unknown,
+        None => Sniffed::More,
+    }
+}
+
+/// Finds the Host header of an HTTP/1 request.
+fn http_host(data: &[u8]) -> Sniffed {
+    let mut lines = data.split(|byte| *byte == b'\n');
+    let request = lines.next().unwrap_or_default();
+    let method_len = request
+        .iter()
+        .position(|byte| !byte.is_ascii_uppercase())
+        .unwrap_or(request.len());
+    if method_len == 0 || method_len == request.len() && request.len() > 16 {
+        return Sniffed::Unknown;
+    }
+    if method_len < request.len() && request[method_len] != b' ' {
+        return Sniffed::Unknown;
+    }
+    if data.len() == request.len() {
+        return Sniffed::More;
+    }
+    let mut rest = data.len() - request.len() - 1;
+    for line in lines {
+        // the last piece has no newline yet
+        if rest == line.len() {
+            return Sniffed::More;
+        }
+        rest -= line.len() + 1;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            return Sniffed::Unknown;
+        }
+        if let Some(colon) = line.iter().position(|byte| *byte == b':') {
+            if line[..colon].eq_ignore_ascii_case(b"host") {
+                let value = String::from_utf8_lossy(&line[colon + 1..]);
+                let value = value.trim();
+                // drop the port
+                let host = match value.rsplit_once(':') {
+                    Some((host, port)) if port.bytes().all(|byte| byte.is_ascii_digit()) => host,
+                    _ => value,
+                };
+                return checked(host.as_bytes());
+            }
+        }
+    }
+    Sniffed::More
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use crate::proxy::sniff::{http_host, sniff, tls_sni, Sniffed};
+
+    /// A ClientHello with a padding extension before the SNI.
+    #[allow(dead_code)]
+    fn client_hello(name: &str) -> Vec<u8> {
+        let mut sni = vec![0u8, 0];
+        let list_len = name.len() + 3;
+        sni.extend_from_slice(&((list_len + 2) as u16).to_be_bytes());
+        sni.extend_from_slice(&(list_len as u16).to_be_bytes());
+        sni.push(0);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name.as_bytes());
+        let mut extensions = vec![0u8, 0x15, 0, 4, 0, 0, 0, 0];
+        extensions.extend_from_slice(&sni);
+
+        let mut body = vec![3u8, 3];
+        body.extend_from_slice(&[7u8; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        let mut handshake = vec![1u8, 0];
+        handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&body);
+        let mut record = vec![0x16u8, 3, 1];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_tls_sni() {
+        let hello = client_hello("Www.Example.com");
+        assert_eq!(tls_sni(&hello), Sniffed::Name("www.example.com".to_owned()));
+        for len in [0, 3, 10, hello.len() - 1] {
+            assert_eq!(tls_sni(&hello[..len]), Sniffed::More);
+        }
+        assert_eq!(tls_sni(&client_hello("10.0.0.1")), Sniffed::Unknown);
+        assert_eq!(tls_sni(b"GET / HTTP/1.1\r\n"), Sniffed::Unknown);
+        // a record shorter than the hello in it
+        let mut short = hello.clone();
+        short[4] -= 10;
+        assert_eq!(tls_sni(&short), Sniffed::Unknown);
+    }
+
+    #[test]
+    fn test_http_host() {
+        let request =
+            b"GET /index.html HTTP/1.1\r\nUser-Agent: curl\r\nHOST: example.com:8080\r\n\r\n";
+        assert_eq!(http_host(request), Sniffed::Name("example.com".to_owned()));
+        assert_eq!(http_host(&request[..30]), Sniffed::More);
+        assert_eq!(http_host(&request[..3]), Sniffed::More);
+        assert_eq!(http_host(b"GET / HTTP/1.1\r\n\r\n"), Sniffed::Unknown);
+        assert_eq!(http_host(b"\x16\x03\x01\x02\x00"), Sniffed::Unknown);
+        assert_eq!(http_host(b"get / HTTP/1.1\r\n"), Sniffed::Unknown);
+        assert_eq!(sniff(8080, request), Sniffed::Unknown);
+    }
+}
@@ -0,0 +1,177 @@
+//! Endpoints degraded by stalled tunnels.
+//!
+//! A lossy path to a server rarely fails a connect, tunnels just stop
+//! moving. A tunnel counts as stalled when the server answered before, has
+//! data unacked or waiting to be sent, and nothing came back for
+//! `--stall-threshold` seconds. Each tunnel counts once until it moves
+//! again. [`FAILURES`] stalls within [`WINDOW`] degrade the endpoint, and
+//! `proxy@auto` routes new connections to the next endpoint which is not
+//! degraded. Tunnels already open stay where they are.
+//!
+//! A degraded endpoint is held for [`MIN_HOLD`] and stalls meanwhile are
+//! not counted, its own tunnels are expected to stall. One degraded again
+//! soon after it recovered is held twice as long, up to [`MAX_HOLD`], so
+//! a bad path does not flap between endpoints. An unanswered request
+//! alone does not count, long polls look just like it.
+//!
+//! A server in maintenance rejects new tunnels with a notice, which
+//! degrades its endpoint at once, so `auto` moves to the next one. That
+//! takes `--close-notice`, without notices the tunnels just close.
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Time stalls are counted over
+pub const WINDOW: Duration = Duration::from_secs(60);
+/// Stalls within the window degrading an endpoint
+pub const FAILURES: usize = 3;
+/// Time an endpoint is degraded for the first time
+pub const MIN_HOLD: Duration = Duration::from_secs(60);
+/// Time an endpoint is degraded at most
+pub const MAX_HOLD: Duration = Duration::from_secs(600);
+
+#[derive(Default)]
+pub struct StallHealth {
+    stalls: VecDeque<Instant>,
+    degraded_until: Option<Instant>,
+    hold: Duration,
+}
+
+impl StallHealth {
+    /// Counts a stalled tunnel, returns true if it degrades the endpoint.
+    pub fn stalled(&mut self, now: Instant) -> bool {
+        if self.degraded(now) {
+            return false;
+        }
+        while let Some(first) = self.stalls.front() {
+            if now.duration_since(*first) < WINDOW {
+                break;
+            }
+            self.stalls.pop_front();
+        }
+        self.stalls.push_back(now);
+        if self.stalls.len() < FAILURES {
+            return false;
+        }
+        self.degrade(now);
+        true
+    }
+
+    /// Degrades the endpoint for a rejected tunnel, returns true if it was
+    /// not degraded yet.
+    pub fn refused(&mut self, now: Instant) -> bool {
+        if self.degraded(now) {
+            return false;
+        }
+        self.degrade(now);
+        true
+    }
+
+    fn degrade(&mut self, now: Instant) {
+        self.stalls.clear();
+        self.hold = match self.degraded_until {
+            // degraded again before a clean period as long as the last hold
+            Some(until) if now.duration_since(until) < self.hold => (self.hold * 2).min(MAX_HOLD),
+            _ => MIN_HOLD,
+        };
+        self.degraded_until.replace(now + self.hold);
+    }
+
+    pub fn degraded(&self, now: Instant) -> bool {
+        self.degraded_until.is_some_and(|until| now < until)
+    }
+
+    /// Time left degraded, for the dump.
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.degraded_until
+            .map_or(Duration::ZERO, |until| until.saturating_duration_since(now))
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::time::{Duration, Instant};
+
+    use crate::proxy::stall::{StallHealth, MAX_HOLD, MIN_HOLD};
+
+    /// Feeds stalls at the given seconds, returns the seconds degrading.
+    #[allow(dead_code)]
+    fn simulate(health: &mut StallHealth, start: Instant, stalls: &[u64]) -> Vec<u64> {
+        stalls
+            .iter()
+            .filter(|secs| health.stalled(start + Duration::from_secs(**secs)))
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn test_sporadic() {
+        let start = Instant::now();
+        let mut health = StallHealth::default();
+        // a stall every 31 seconds never fills the window
+        let stalls: Vec<_> = (0..100).map(|i| i * 31).collect();
+        assert!(simulate(&mut health, start, &stalls).is_empty());
+        assert!(!health.degraded(start + Duration::from_secs(3100)));
+    }
+
+    #[test]
+    fn test_burst() {
+        let start = Instant::now();
+        let mut health = StallHealth::default();
+        assert_eq!(simulate(&mut health, start, &[0, 5, 10, 11, 12]), vec![10]);
+        assert!(health.degraded(start + Duration::from_secs(69)));
+        assert_eq!(
+            health.remaining(start + Duration::from_secs(40)),
+            Duration::from_secs(30)
+        );
+        // stalls while degraded are not kept for later
+        assert!(!health.degraded(start + Duration::from_secs(70)));
+        assert!(!health.stalled(start + Duration::from_secs(71)));
+        assert!(!health.degraded(start + Duration::from_secs(71)));
+    }
+
+    #[test]
+    fn test_hold() {
+        let start = Instant::now();
+        let mut health = StallHealth::default();
+        let mut holds = Vec::new();
+        let mut now = 0;
+        for _ in 0..6 {
+            assert_eq!(
+                simulate(&mut health, start, &[now, now + 1, now + 2]).len(),
+                1
+            );
+            holds.push(health.remaining(start + Duration::from_secs(now + 2)));
+            // the path is still bad as soon as it recovers
+            now += 2 + holds.last().unwrap().as_secs();
+        }
+        assert_eq!(holds[0], MIN_HOLD);
+        assert_eq!(holds[1], MIN_HOLD * 2);
+        assert_eq!(holds[5], MAX_HOLD);
+
+        // a clean period as long as the hold starts over
+        now += MAX_HOLD.as_secs() + 1;
+        assert_eq!(
+            simulate(&mut health, start, &[now, now + 1, now + 2]).len(),
+            1
+        );
+        assert_eq!(
+            health.remaining(start + Duration::from_secs(now + 2)),
+            MIN_HOLD
+        );
+    }
+
+    #[test]
+    fn test_refused() {
+        let start = Instant::now();
+        let mut health = StallHealth::default();
+        assert!(health.refused(start));
+        assert!(!health.refused(start + Duration::from_secs(1)));
+        assert_eq!(health.remaining(start), MIN_HOLD);
+        // still in maintenance right after the hold
+        assert!(health.refused(start + MIN_HOLD));
+        assert_eq!(health.remaining(start + MIN_HOLD), MIN_HOLD * 2);
+    }
+}
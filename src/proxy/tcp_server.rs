@@ -1,8 +1,8 @@
 use std::{
     collections::HashMap,
-    io::ErrorKind,
+    io::{ErrorKind, Read},
     net::{Shutdown, SocketAddr},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use bytes::BytesMut;
@@ -13,119 +13,786 @@ use mio::{
 };
 
 use crate::{
-    config::OPTIONS,
-    idle_pool::IdlePool,
-    proto::{TrojanRequest, CONNECT, MAX_PACKET_SIZE},
-    proxy::{next_index, CHANNEL_CLIENT, CHANNEL_CNT, CHANNEL_TCP, MIN_INDEX},
+    audit,
+    codel::Codel,
+    compress::{Compressor, Decompressor},
+    config::{RemotePrefers, RouteMode, OPTIONS},
+    dump::Dump,
+    family,
+    idle_pool::{Checkout, IdlePool},
+    metrics::{
+        ACCEPT_BACKLOG, ALLOWLIST_DENIED, CLOSE_NOTICES, DIRECT_CONNECTIONS, EARLY_RETRIES,
+        FAMILY_REFUSED, MAINTENANCE_REFUSALS, MISMATCHED_EVENTS, POOL_CLOSED_AT_FIRST_USE,
+        ROUTE_MODE_REJECTED, SELF_LOOPS, SETUP_POOL_FAILURES, SETUP_REGISTER_FAILURES,
+        SETUP_REQUEST_FAILURES, SNIFFED, SNIFF_MISSES, TCP_LISTENER_OVERFLOW, TUNNEL_STALLS,
+    },
+    notice::Notice,
+    padding::Padder,
+    profile::{self, Category},
+    proto::{
+        TrojanRequest, COMPRESSED, CONNECT, FAMILIES, MAX_HEADER_LEN, MAX_PACKET_SIZE, NOTICE,
+        PADDED, RESET,
+    },
+    proxy::{
+        allowlist,
+        direct::DirectTcp,
+        latency, next_index,
+        route::Router,
+        route_mode, self_test,
+        sniff::{self, Sniffed, SNIFF_LIMIT, SNIFF_TIMEOUT},
+        traffic::Traffic,
+        CHANNEL_CLIENT, CHANNEL_CNT, CHANNEL_TCP, MIN_INDEX,
+    },
     resolver::DnsResolver,
-    status::{ConnStatus, StatusProvider},
-    sys, tcp_util,
-    tls_conn::TlsConn,
-    types::{Result, TrojanError},
+    stale::StaleEvents,
+    status::{CloseReason, ConnStatus, StatusProvider},
+    summary::Summary,
+    sys,
+    tcp_util::{self, Outcome},
+    trace::{Stage, Trace},
+    types::{Result, SetupPhase, TrojanError},
 };
 
 pub struct TcpServer {
     tcp_listener: TcpListener,
+    listen_addr: Option<SocketAddr>,
     conns: HashMap<usize, Connection>,
     next_id: usize,
     removed: Option<Vec<usize>>,
+    marker: u8,
+    stale: StaleEvents,
+    traffic: Traffic,
+    /// Clients read for their server name before opening the tunnel
+    sniffing: HashMap<usize, Sniffing>,
+    /// Clients relayed without the tunnel by --route-mode direct
+    direct: HashMap<usize, DirectTcp>,
+    /// Connections by the index of the token their server connection took
+    /// on a retry
+    retried: HashMap<usize, usize>,
 }
 
-struct Connection {
+struct Sniffing {
+    client: TcpStream,
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+    buffer: Vec<u8>,
+    accepted: Instant,
+    deadline: Instant,
+    eof: bool,
+    trace: Trace,
+}
+
+impl Sniffing {
+    /// Reads up to the limit, returns true once there is enough to decide.
+    fn read(&mut self) -> std::io::Result<bool> {
+        let mut chunk = [0u8; SNIFF_LIMIT];
+        loop {
+            let room = SNIFF_LIMIT - self.buffer.len();
+            if room == 0 {
+                return Ok(true);
+            }
+            match (&self.client).read(&mut chunk[..room]) {
+                Ok(0) => {
+                    self.eof = true;
+                    return Ok(true);
+                }
+                Ok(size) => {
+                    self.buffer.extend_from_slice(&chunk[..size]);
+                    if sniff::sniff(self.dst_addr.port(), &self.buffer) != Sniffed::More {
+                        return Ok(true);
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn name(&self) -> Option<String> {
+        match sniff::sniff(self.dst_addr.port(), &self.buffer) {
+            Sniffed::Name(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// A client tunneled over a server session, the client stream is a type
+/// parameter so tests can script it
+struct Connection<C = TcpStream> {
     index: usize,
+    src_addr: SocketAddr,
     dst_addr: SocketAddr,
-    client: TcpStream,
+    /// Server name sniffed from the first bytes
+    name: Option<String>,
+    client: C,
+    client_registered: bool,
     recv_buffer: Vec<u8>,
     send_buffer: BytesMut,
+    server_buffer: BytesMut,
+    /// Bytes read from and sent to the client
+    client_read: usize,
+    client_sent: usize,
+    summary: Summary,
     status: ConnStatus,
-    server_conn: TlsConn,
+    /// Returned to the pool until the request is written
+    server_conn: Checkout,
+    client_time: Instant,
     last_active_time: Instant,
+    close_reason: Option<CloseReason>,
+    drain_time: Option<Instant>,
     read_client: bool,
     read_server: bool,
+    request_len: usize,
+    retried: bool,
+    /// The first byte of the server is a close notice
+    notice: bool,
+    /// The request header is held back for the first payload
+    corked: bool,
+    /// Resets pass through the tunnel, see [`RESET`](crate::proto::RESET)
+    reset: bool,
+    /// The client is closed by an RST instead of a FIN
+    aborted: bool,
+    /// Index and name of the server endpoint
+    endpoint: (usize, &'static str),
+    /// Bytes received from the server and since when, sampled by the
+    /// sweep while something is pending
+    progress: Option<(usize, Instant)>,
+    /// Counted as stalled until the server sends again
+    stalled: bool,
+    /// The server rejected the tunnel for maintenance
+    refused: bool,
+    trace: Trace,
+    /// The server connection came from the idle pool
+    pool_hit: bool,
+    /// The request has not left for the server yet
+    request_pending: bool,
 }
 
 impl TcpServer {
-    pub fn new(tcp_listener: TcpListener) -> TcpServer {
+    pub fn new(tcp_listener: TcpListener, marker: u8) -> TcpServer {
         TcpServer {
+            listen_addr: tcp_listener.local_addr().ok(),
             tcp_listener,
+            marker,
             conns: HashMap::new(),
             removed: Some(Vec::new()),
             next_id: MIN_INDEX,
+            stale: StaleEvents::new(),
+            traffic: Traffic::new(OPTIONS.proxy_args().top_destinations),
+            sniffing: HashMap::new(),
+            direct: HashMap::new(),
+            retried: HashMap::new(),
         }
     }
 
-    pub fn accept(&mut self, poll: &Poll, pool: &mut IdlePool, resolver: &DnsResolver) {
-        loop {
-            if let Err(err) = self.accept_once(poll, pool, resolver) {
+    /// Routes the events of the server connection of `conn` to it, if a
+    /// retry gave it a token of its own.
+    fn track_retry(retried: &mut HashMap<usize, usize>, conn: &Connection) {
+        let server = Connection::token2index(conn.server_conn.token());
+        if server != conn.index {
+            retried.insert(server, conn.index);
+        }
+    }
+
+    pub fn traffic(&mut self) -> &mut Traffic {
+        &mut self.traffic
+    }
+
+    fn forget(&mut self, index: usize) {
+        if let Some(conn) = self.conns.remove(&index) {
+            self.retried
+                .remove(&Connection::token2index(conn.server_conn.token()));
+            if let Some((up, down)) = conn.server_conn.compression_ratio() {
+                log::info!(
+                    "connection:{} to:{} closed, compression ratio up:{:.2} down:{:.2}",
+                    index,
+                    conn.dst_addr,
+                    up,
+                    down
+                );
+            }
+            let sent = conn.server_conn.sent().saturating_sub(conn.request_len) as u64;
+            let received = conn.server_conn.received() as u64;
+            self.traffic.add(conn.dst_addr.ip(), sent, received);
+            audit::record(
+                conn.src_addr,
+                conn.name.as_deref(),
+                conn.dst_addr,
+                sent,
+                received,
+                conn.client_time.elapsed(),
+            );
+            if conn.trace.enabled() {
+                log::info!(
+                    "connection:{} from:{} trace {}",
+                    index,
+                    conn.src_addr,
+                    conn.trace
+                );
+            }
+        }
+        self.stale.freed(index);
+    }
+
+    fn forget_direct(&mut self, index: usize) {
+        if let Some(direct) = self.direct.remove(&index) {
+            let (sent, received) = direct.bytes();
+            audit::record(
+                direct.src_addr,
+                None,
+                direct.dst_addr,
+                sent as u64,
+                received as u64,
+                direct.created.elapsed(),
+            );
+        }
+        self.stale.freed(index);
+    }
+
+    /// Accepts at most `--accept-burst` connections, returns true if the
+    /// backlog may not be drained yet.
+    pub fn accept(&mut self, poll: &Poll, router: &mut Router, resolver: &DnsResolver) -> bool {
+        let _scope = profile::scope(Category::Accept);
+        for _ in 0..OPTIONS.accept_burst.max(1) {
+            if let Err(err) = self.accept_once(poll, router, resolver) {
                 if let TrojanError::StdIo(err) = &err {
                     if err.kind() == ErrorKind::WouldBlock {
-                        break;
+                        return false;
                     }
                 }
                 log::error!("tcp server accept failed:{:?}", err);
             }
         }
+        ACCEPT_BACKLOG.inc();
+        true
     }
 
     fn accept_once(
         &mut self,
         poll: &Poll,
-        pool: &mut IdlePool,
+        router: &mut Router,
         resolver: &DnsResolver,
     ) -> Result<()> {
         let (client, src_addr) = self.tcp_listener.accept()?;
-        //sys::set_mark(&client, OPTIONS.marker)?;
+        let max_connections = OPTIONS.proxy_args().max_connections;
+        if max_connections != 0 && self.connections() >= max_connections {
+            // dropping the socket closes it, other listeners go on
+            TCP_LISTENER_OVERFLOW.inc();
+            log::info!(
+                "connection from:{} closed, {} connections reached --max-connections",
+                src_addr,
+                max_connections
+            );
+            return Ok(());
+        }
+        if self.marker != 0 {
+            sys::set_mark(&client, self.marker)?;
+        }
         client.set_nodelay(true)?;
         let dst_addr = sys::get_oridst_addr(&client)?;
-        log::info!("got new connection from:{} to:{}", src_addr, dst_addr);
-        if let Some(mut conn) = pool.get(poll, resolver) {
-            let index = next_index(&mut self.next_id);
-            if !conn.reset_index(index, Token(index * CHANNEL_CNT + CHANNEL_TCP), poll) {
-                conn.check_status(poll);
-            } else {
-                let mut conn = Connection::new(index, conn, dst_addr, client);
-                if conn.setup(poll) {
-                    self.conns.insert(conn.index(), conn);
-                } else {
-                    conn.destroy(poll);
-                }
+        let mut trace = Trace::new(src_addr.ip());
+        trace.add(Stage::Destination, "redirected", || dst_addr.to_string());
+        if let Some(listen_addr) = self.listen_addr {
+            if self_test::is_self_loop(dst_addr, listen_addr) {
+                SELF_LOOPS.inc();
+                log::warn!(
+                    "connection from:{} to our own address:{} refused, check the redirect rules",
+                    src_addr,
+                    dst_addr
+                );
+                return Ok(());
+            }
+        }
+        match route_mode::get() {
+            RouteMode::Tunnel => {}
+            RouteMode::Direct => {
+                self.open_direct(poll, client, src_addr, dst_addr);
+                return Ok(());
             }
+            RouteMode::Reject => {
+                ROUTE_MODE_REJECTED.inc();
+                log::info!(
+                    "connection from:{} to:{} refused by route mode reject",
+                    src_addr,
+                    dst_addr
+                );
+                return Ok(());
+            }
+        }
+        if OPTIONS.proxy_args().sniff && sniff::sniffed_port(dst_addr.port()) {
+            self.start_sniff(poll, client, src_addr, dst_addr, trace);
+            return Ok(());
+        }
+        let endpoint = router.route(&dst_addr);
+        trace.add(Stage::Route, router.name(endpoint), || {
+            router.explain(&dst_addr, None)
+        });
+        log::info!(
+            "got new connection from:{} to:{} via:{}",
+            src_addr,
+            dst_addr,
+            router.name(endpoint)
+        );
+        if Self::denied(src_addr, dst_addr, None, &mut trace)
+            || Self::family_refused(
+                src_addr,
+                dst_addr,
+                None,
+                router.name(endpoint),
+                endpoint,
+                &mut trace,
+            )
+        {
+            Self::log_refused(src_addr, &trace);
+            return Ok(());
+        }
+        if router.broken(endpoint, Instant::now()) {
+            Self::fail_fast(&client, src_addr, dst_addr, router.name(endpoint));
+            return Ok(());
+        }
+        if let Err(err) = self.open(
+            poll, router, resolver, client, src_addr, dst_addr, endpoint, trace,
+        ) {
+            Self::setup_failed(err, src_addr, dst_addr, router.name(endpoint));
+        }
+        Ok(())
+    }
+
+    /// Relays the client to its destination without the tunnel.
+    fn open_direct(
+        &mut self,
+        poll: &Poll,
+        client: TcpStream,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+    ) {
+        let index = next_index(&mut self.next_id);
+        match DirectTcp::open(index, client, src_addr, dst_addr, self.marker, poll) {
+            Ok(direct) => {
+                DIRECT_CONNECTIONS.inc();
+                log::info!(
+                    "got new connection from:{} to:{} via:direct",
+                    src_addr,
+                    dst_addr
+                );
+                self.direct.insert(index, direct);
+            }
+            Err(err) => log::warn!(
+                "connection from:{} to:{} via:direct setup failed:{:?}",
+                src_addr,
+                dst_addr,
+                err
+            ),
+        }
+    }
+
+    /// Resets a client whose endpoint has its breaker open, the RST goes
+    /// out once the socket is dropped.
+    fn fail_fast(client: &TcpStream, src_addr: SocketAddr, dst_addr: SocketAddr, via: &str) {
+        if let Err(err) = tcp_util::TcpIo::set_reset(client) {
+            log::warn!("connection from:{} set linger failed:{}", src_addr, err);
+        }
+        log::debug!(
+            "connection from:{} to:{} via:{} reset, the breaker is open",
+            src_addr,
+            dst_addr,
+            via
+        );
+    }
+
+    /// Logs the trace of a connection refused before its tunnel opened.
+    fn log_refused(src_addr: SocketAddr, trace: &Trace) {
+        if trace.enabled() {
+            log::info!("connection from:{} refused, trace {}", src_addr, trace);
+        }
+    }
+
+    /// Refuses a connection to a destination not in the allowlist.
+    fn denied(
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        name: Option<&str>,
+        trace: &mut Trace,
+    ) -> bool {
+        if !OPTIONS.proxy_args().strict_allowlist {
+            return false;
+        }
+        if allowlist::allowed(&dst_addr, name) {
+            trace.add(Stage::Allowlist, "allowed", String::new);
+            return false;
+        }
+        trace.add(Stage::Allowlist, "denied", String::new);
+        ALLOWLIST_DENIED.inc();
+        log::warn!(
+            "connection from:{} to:{} name:{} denied by the allowlist",
+            src_addr,
+            dst_addr,
+            name.unwrap_or("-")
+        );
+        true
+    }
+
+    /// Refuses a connection to a family the server of `endpoint` lacks,
+    /// unless it can go by name.
+    fn family_refused(
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        name: Option<&str>,
+        via: &str,
+        endpoint: usize,
+        trace: &mut Trace,
+    ) -> bool {
+        let families = family::remote(endpoint);
+        if name.is_some() || families.supports(dst_addr.ip()) {
+            return false;
+        }
+        trace.add(Stage::Family, "refused", || {
+            format!("{} has only {}", via, families)
+        });
+        FAMILY_REFUSED.inc();
+        log::warn!(
+            "connection from:{} to:{} refused, the server of {} has no {} egress, only {}",
+            src_addr,
+            dst_addr,
+            via,
+            family::name(dst_addr.ip()),
+            families
+        );
+        true
+    }
+
+    fn trace_pool(trace: &mut Trace, pool: &IdlePool) {
+        if pool.fresh() {
+            trace.add(Stage::Pool, "fresh", || "no idle connection".to_owned());
         } else {
-            log::error!("alloc new connection failed")
+            trace.add(Stage::Pool, "hit", String::new);
         }
+    }
+
+    fn setup_failed(err: TrojanError, src_addr: SocketAddr, dst_addr: SocketAddr, via: &str) {
+        if let TrojanError::Setup(phase, _) = &err {
+            match phase {
+                SetupPhase::PoolGet => SETUP_POOL_FAILURES.inc(),
+                SetupPhase::WriteRequest => SETUP_REQUEST_FAILURES.inc(),
+                SetupPhase::RegisterClient => SETUP_REGISTER_FAILURES.inc(),
+            }
+        }
+        log::warn!(
+            "connection from:{} to:{} via:{} setup failed:{:?}",
+            src_addr,
+            dst_addr,
+            via,
+            err
+        );
+    }
+
+    /// Registers the client for reading its server name, the tunnel is
+    /// opened once it is known or the client sent too little in time.
+    fn start_sniff(
+        &mut self,
+        poll: &Poll,
+        mut client: TcpStream,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        trace: Trace,
+    ) {
+        let index = next_index(&mut self.next_id);
+        if let Err(err) = poll.registry().register(
+            &mut client,
+            Token(index * CHANNEL_CNT + CHANNEL_CLIENT),
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            let err = TrojanError::Setup(SetupPhase::RegisterClient, Some(err));
+            Self::setup_failed(err, src_addr, dst_addr, "sniffing");
+            return;
+        }
+        log::debug!(
+            "connection:{} from:{} to:{} sniffing",
+            index,
+            src_addr,
+            dst_addr
+        );
+        self.sniffing.insert(
+            index,
+            Sniffing {
+                client,
+                src_addr,
+                dst_addr,
+                buffer: Vec::new(),
+                accepted: Instant::now(),
+                deadline: Instant::now() + SNIFF_TIMEOUT,
+                eof: false,
+                trace,
+            },
+        );
+    }
+
+    fn sniff_ready(
+        &mut self,
+        index: usize,
+        event: &Event,
+        poll: &Poll,
+        router: &mut Router,
+        resolver: &DnsResolver,
+    ) {
+        if !event.is_readable() {
+            return;
+        }
+        let sniffing = self.sniffing.get_mut(&index).unwrap();
+        match sniffing.read() {
+            Ok(false) => {}
+            Ok(true) => self.finish_sniff(index, poll, router, resolver),
+            Err(err) => {
+                log::warn!("connection:{} read from client failed:{}", index, err);
+                let mut sniffing = self.sniffing.remove(&index).unwrap();
+                let _ = poll.registry().deregister(&mut sniffing.client);
+            }
+        }
+    }
+
+    /// Opens the tunnel of a sniffed client, with the name if one was found.
+    fn finish_sniff(
+        &mut self,
+        index: usize,
+        poll: &Poll,
+        router: &mut Router,
+        resolver: &DnsResolver,
+    ) {
+        let mut sniffing = self.sniffing.remove(&index).unwrap();
+        let (src_addr, dst_addr) = (sniffing.src_addr, sniffing.dst_addr);
+        if sniffing.eof && sniffing.buffer.is_empty() {
+            log::debug!("connection:{} closed by client while sniffing", index);
+            let _ = poll.registry().deregister(&mut sniffing.client);
+            return;
+        }
+        let name = sniffing.name();
+        let mut trace = std::mem::take(&mut sniffing.trace);
+        if let Some(name) = &name {
+            SNIFFED.inc();
+            trace.add(Stage::Sniff, "name", || name.clone());
+        } else {
+            SNIFF_MISSES.inc();
+            trace.add(Stage::Sniff, "missed", || {
+                format!("{} bytes", sniffing.buffer.len())
+            });
+        }
+        let endpoint = router.route_name(&dst_addr, name.as_deref());
+        trace.add(Stage::Route, router.name(endpoint), || {
+            router.explain(&dst_addr, name.as_deref())
+        });
+        log::info!(
+            "got new connection from:{} to:{} name:{} via:{}",
+            src_addr,
+            dst_addr,
+            name.as_deref().unwrap_or("-"),
+            router.name(endpoint)
+        );
+        let via = router.name(endpoint);
+        if Self::denied(src_addr, dst_addr, name.as_deref(), &mut trace)
+            || Self::family_refused(
+                src_addr,
+                dst_addr,
+                name.as_deref(),
+                via,
+                endpoint,
+                &mut trace,
+            )
+        {
+            Self::log_refused(src_addr, &trace);
+            let _ = poll.registry().deregister(&mut sniffing.client);
+            return;
+        }
+        if router.broken(endpoint, Instant::now()) {
+            let _ = poll.registry().deregister(&mut sniffing.client);
+            Self::fail_fast(&sniffing.client, src_addr, dst_addr, via);
+            return;
+        }
+        let conn = if let Some(conn) = router.pool(endpoint).get(poll, resolver) {
+            Self::trace_pool(&mut trace, router.pool(endpoint));
+            conn
+        } else {
+            let _ = poll.registry().deregister(&mut sniffing.client);
+            let err = TrojanError::Setup(SetupPhase::PoolGet, None);
+            Self::setup_failed(err, src_addr, dst_addr, router.name(endpoint));
+            return;
+        };
+        let mut conn = Connection::new(index, conn, src_addr, dst_addr, sniffing.client);
+        conn.client_registered = true;
+        conn.client_time = sniffing.accepted;
+        conn.pool_hit = !router.pool(endpoint).fresh();
+        conn.endpoint = (endpoint, router.name(endpoint));
+        conn.name = name;
+        conn.trace = trace;
+        if let Err(err) = conn.setup(poll, router, resolver, &mut self.next_id) {
+            conn.destroy(poll);
+            Self::setup_failed(err, src_addr, dst_addr, router.name(endpoint));
+            return;
+        }
+        Self::track_retry(&mut self.retried, &conn);
+        conn.forward_sniffed(sniffing.buffer.as_slice(), sniffing.eof, poll);
+        if conn.destroyed() {
+            return;
+        }
+        self.conns.insert(index, conn);
+    }
+
+    /// Opens the tunnels of clients which sent too little in time.
+    pub fn check_sniffing(&mut self, poll: &Poll, router: &mut Router, resolver: &DnsResolver) {
+        if self.sniffing.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .sniffing
+            .iter()
+            .filter(|(_, sniffing)| sniffing.deadline <= now)
+            .map(|(index, _)| *index)
+            .collect();
+        for index in expired {
+            self.finish_sniff(index, poll, router, resolver);
+        }
+    }
+
+    pub fn sniff_deadline(&self) -> Option<Instant> {
+        self.sniffing
+            .values()
+            .map(|sniffing| sniffing.deadline)
+            .min()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn open(
+        &mut self,
+        poll: &Poll,
+        router: &mut Router,
+        resolver: &DnsResolver,
+        mut client: TcpStream,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        endpoint: usize,
+        mut trace: Trace,
+    ) -> Result<()> {
+        let conn = router
+            .pool(endpoint)
+            .get(poll, resolver)
+            .ok_or(TrojanError::Setup(SetupPhase::PoolGet, None))?;
+        Self::trace_pool(&mut trace, router.pool(endpoint));
+        let index = next_index(&mut self.next_id);
+        // the server session is untouched until the client is registered,
+        // dropping the checkout gives it back to the pool
+        poll.registry()
+            .register(
+                &mut client,
+                Token(index * CHANNEL_CNT + CHANNEL_CLIENT),
+                Interest::READABLE | Interest::WRITABLE,
+            )
+            .map_err(|err| TrojanError::Setup(SetupPhase::RegisterClient, Some(err)))?;
+        let mut conn = Connection::new(index, conn, src_addr, dst_addr, client);
+        conn.client_registered = true;
+        conn.pool_hit = !router.pool(endpoint).fresh();
+        conn.endpoint = (endpoint, router.name(endpoint));
+        conn.trace = trace;
+        if let Err(err) = conn.setup(poll, router, resolver, &mut self.next_id) {
+            conn.destroy(poll);
+            return Err(err);
+        }
+        Self::track_retry(&mut self.retried, &conn);
+        self.conns.insert(conn.index(), conn);
         Ok(())
     }
 
-    pub fn ready(&mut self, event: &Event, poll: &Poll) {
+    pub fn ready(
+        &mut self,
+        event: &Event,
+        poll: &Poll,
+        router: &mut Router,
+        resolver: &DnsResolver,
+    ) {
         let index = Connection::token2index(event.token());
-        if let Some(conn) = self.conns.get_mut(&index) {
-            conn.ready(event, poll);
-            if conn.destroyed() {
-                self.removed.as_mut().unwrap().push(index);
+        let index = self.retried.get(&index).copied().unwrap_or(index);
+        if self.sniffing.contains_key(&index) {
+            self.sniff_ready(index, event, poll, router, resolver);
+            return;
+        }
+        if let Some(direct) = self.direct.get_mut(&index) {
+            direct.ready(event, poll);
+            if direct.closed() {
+                self.forget_direct(index);
             }
-        } else {
-            log::error!("tcp connection:{} not found, check deregister", index)
+            return;
+        }
+        match self.conns.get_mut(&index) {
+            // destroyed by an earlier event of this batch, removed later
+            Some(conn) if conn.destroyed() => {}
+            Some(conn) => {
+                conn.ready(event, poll, router, resolver, &mut self.next_id);
+                Self::track_retry(&mut self.retried, conn);
+                if conn.destroyed() {
+                    self.removed.as_mut().unwrap().push(index);
+                }
+            }
+            None => self.stale.missing("tcp", index),
         }
     }
 
     pub fn remove_closed(&mut self) {
+        self.stale.next_generation();
         if self.removed.as_ref().unwrap().is_empty() {
             return;
         }
         let removed = self.removed.replace(Vec::new()).unwrap();
         for index in removed {
             log::debug!("connection:{} removed from list", index);
-            self.conns.remove(&index);
+            self.forget(index);
         }
     }
 
-    pub fn check_timeout(&mut self, poll: &Poll, now: Instant) {
+    /// The earliest deadline of a connection, the sweep handles it.
+    pub fn next_deadline(&self, now: Instant) -> Option<Instant> {
+        let threshold = Duration::from_secs(OPTIONS.proxy_args().stall_threshold);
+        self.conns
+            .values()
+            .filter(|conn| !conn.destroyed())
+            .filter_map(|conn| conn.deadline(now, threshold))
+            .chain(self.direct.values().filter_map(|direct| direct.deadline()))
+            .min()
+    }
+
+    pub fn check_timeout(&mut self, poll: &Poll, now: Instant, router: &mut Router) {
+        let threshold = Duration::from_secs(OPTIONS.proxy_args().stall_threshold);
         let list: Vec<_> = self
             .conns
             .iter_mut()
             .filter_map(|(index, conn)| {
-                if !conn.destroyed() && conn.timeout(now) {
-                    conn.destroy(poll);
+                if !conn.destroyed() {
+                    conn.summary.log(*index);
+                    if !threshold.is_zero() && conn.check_stall(now, threshold) {
+                        TUNNEL_STALLS.inc();
+                        log::warn!(
+                            "connection:{} via:{} stalled for {}s",
+                            index,
+                            conn.endpoint.1,
+                            threshold.as_secs()
+                        );
+                        router.stalled(conn.endpoint.0, now);
+                    }
+                    if let Some(reason) = conn.timeout(now) {
+                        log::info!(
+                            "connection:{} via:{} closed by {:?}",
+                            index,
+                            conn.endpoint.1,
+                            reason
+                        );
+                        if let CloseReason::LifetimeExceeded = reason {
+                            conn.close_reason.replace(reason);
+                            conn.expire(poll);
+                        } else {
+                            // a drain after the lifetime keeps its reason
+                            conn.close_reason.get_or_insert(reason);
+                            conn.destroy(poll);
+                        }
+                    }
                 }
                 if conn.destroyed() {
                     Some(*index)
@@ -135,80 +802,532 @@ impl TcpServer {
             })
             .collect();
         for index in list {
-            let _ = self.conns.remove(&index);
+            self.forget(index);
+        }
+        let closed: Vec<_> = self
+            .direct
+            .iter_mut()
+            .filter_map(|(index, direct)| {
+                direct.check_timeout(now, poll);
+                direct.closed().then_some(*index)
+            })
+            .collect();
+        for index in closed {
+            self.forget_direct(index);
         }
     }
+
+    /// Tcp connections, sniffing and direct ones included.
+    pub fn connections(&self) -> usize {
+        self.conns.len() + self.sniffing.len() + self.direct.len()
+    }
+
+    /// Registers every client and tls session again, which raises their
+    /// readiness once more after a wakeup got lost.
+    pub fn reregister(&mut self, poll: &Poll) {
+        for (index, sniffing) in self.sniffing.iter_mut() {
+            if let Err(err) = poll.registry().reregister(
+                &mut sniffing.client,
+                Token(index * CHANNEL_CNT + CHANNEL_CLIENT),
+                Interest::READABLE | Interest::WRITABLE,
+            ) {
+                log::warn!("connection:{} reregister client failed:{}", index, err);
+            }
+        }
+        for conn in self.conns.values_mut() {
+            conn.reregister(poll);
+        }
+        for direct in self.direct.values_mut() {
+            direct.reregister(poll);
+        }
+        log::warn!("{} tcp connections registered again", self.connections());
+    }
+
+    pub fn dump(&self, dump: &mut Dump) {
+        dump.line(format_args!(
+            "tcp connections:{} sniffing:{} direct:{} max:{} overflow:{}",
+            self.conns.len(),
+            self.sniffing.len(),
+            self.direct.len(),
+            OPTIONS.proxy_args().max_connections,
+            TCP_LISTENER_OVERFLOW.get()
+        ));
+        for conn in self.conns.values() {
+            conn.dump(dump);
+        }
+        for direct in self.direct.values() {
+            direct.dump(dump);
+        }
+        self.traffic.dump(dump);
+    }
+}
+
+impl<C> Drop for Connection<C> {
+    fn drop(&mut self) {
+        debug_assert!(
+            !self.client_registered || std::thread::panicking(),
+            "connection:{} dropped without deregister",
+            self.index
+        );
+    }
 }
 
 impl Connection {
+    fn token2index(token: Token) -> usize {
+        token.0 / CHANNEL_CNT
+    }
+
+    /// The channel of `token` if it is the client or the server token of
+    /// the connection. An event of a recycled token shares the index but
+    /// not the token, those are not ours to handle.
+    fn channel(token: Token, client: Token, server: Token) -> Option<usize> {
+        if token == client {
+            Some(CHANNEL_CLIENT)
+        } else if token == server {
+            Some(CHANNEL_TCP)
+        } else {
+            None
+        }
+    }
+}
+
+impl<C: tcp_util::TcpIo> Connection<C> {
     fn new(
         index: usize,
-        server_conn: TlsConn,
+        server_conn: Checkout,
+        src_addr: SocketAddr,
         dst_addr: SocketAddr,
-        client: TcpStream,
-    ) -> Connection {
+        client: C,
+    ) -> Connection<C> {
         Connection {
             index,
+            src_addr,
             dst_addr,
+            name: None,
             client,
+            client_registered: false,
             server_conn,
             status: ConnStatus::Connecting,
             send_buffer: BytesMut::new(),
+            server_buffer: BytesMut::new(),
             recv_buffer: vec![0u8; MAX_PACKET_SIZE],
+            client_read: 0,
+            client_sent: 0,
+            summary: Summary::new("client", "chunks"),
+            client_time: Instant::now(),
             last_active_time: Instant::now(),
+            close_reason: None,
+            drain_time: None,
             read_client: false,
             read_server: false,
+            request_len: 0,
+            retried: false,
+            notice: false,
+            corked: false,
+            reset: false,
+            aborted: false,
+            endpoint: (0, ""),
+            progress: None,
+            stalled: false,
+            refused: false,
+            trace: Trace::default(),
+            pool_hit: false,
+            request_pending: false,
         }
     }
 
-    fn timeout(&self, now: Instant) -> bool {
-        now - self.last_active_time > OPTIONS.tcp_idle_duration
+    fn timeout(&self, now: Instant) -> Option<CloseReason> {
+        if let Some(drain_time) = self.drain_time {
+            if now - drain_time > OPTIONS.drain_duration {
+                return Some(CloseReason::DrainTimeout);
+            }
+        } else if let Some(lifetime) = OPTIONS.tcp_lifetime {
+            if now - self.client_time > lifetime {
+                return Some(CloseReason::LifetimeExceeded);
+            }
+        }
+        // a fast open socket is writable before the server accepted it, the
+        // connect is only known to be done once the tls handshake is
+        let fast_open = OPTIONS.proxy_args().tcp_fast_open && self.server_conn.handshaking();
+        let (limit, reason) = if self.server_conn.is_connecting() || fast_open {
+            (OPTIONS.connect_duration, CloseReason::ConnectTimeout)
+        } else if self.server_conn.received() == 0 {
+            (OPTIONS.first_byte_duration, CloseReason::FirstByteTimeout)
+        } else {
+            (None, CloseReason::IdleTimeout)
+        };
+        match limit {
+            Some(limit) if now - self.client_time > limit => Some(reason),
+            _ if now - self.last_active_time > OPTIONS.tcp_idle_duration => {
+                Some(CloseReason::IdleTimeout)
+            }
+            _ => None,
+        }
+    }
+
+    /// When [`timeout`](Connection::timeout) closes it if nothing happens
+    /// first, or a stall or the summary is due.
+    fn deadline(&self, now: Instant, threshold: Duration) -> Option<Instant> {
+        let mut deadlines = Vec::with_capacity(4);
+        if let Some(drain_time) = self.drain_time {
+            deadlines.push(drain_time + OPTIONS.drain_duration);
+        } else if let Some(lifetime) = OPTIONS.tcp_lifetime {
+            deadlines.push(self.client_time + lifetime);
+        }
+        let fast_open = OPTIONS.proxy_args().tcp_fast_open && self.server_conn.handshaking();
+        let limit = if self.server_conn.is_connecting() || fast_open {
+            OPTIONS.connect_duration
+        } else if self.server_conn.received() == 0 {
+            OPTIONS.first_byte_duration
+        } else {
+            None
+        };
+        if let Some(limit) = limit {
+            deadlines.push(self.client_time + limit);
+        }
+        deadlines.push(self.last_active_time + OPTIONS.tcp_idle_duration);
+        if !threshold.is_zero() && self.server_conn.pending() {
+            match self.progress {
+                Some((received, since)) if received == self.server_conn.received() => {
+                    if !self.stalled {
+                        deadlines.push(since + threshold);
+                    }
+                }
+                // the next sweep takes a sample
+                _ => deadlines.push(now),
+            }
+        }
+        deadlines.extend(self.summary.deadline());
+        deadlines.into_iter().min()
+    }
+
+    fn dump(&self, dump: &mut Dump) {
+        dump.line(format_args!(
+            "tcp:{} {}->{} via:{} client:{:?}/{} read:{} sent:{} buf:{} server:{} idle:{}s close:{:?}",
+            self.index,
+            self.src_addr,
+            self.dst_addr,
+            self.endpoint.1,
+            self.status,
+            self.interests(),
+            self.client_read,
+            self.client_sent,
+            self.send_buffer.len(),
+            self.server_conn.dump_state(),
+            self.last_active_time.elapsed().as_secs(),
+            self.close_reason
+        ));
     }
 
     fn destroyed(&self) -> bool {
         self.deregistered() && self.server_conn.deregistered()
     }
 
+    fn reregister(&mut self, poll: &Poll) {
+        if self.client_registered {
+            if let Err(err) = poll.registry().reregister(
+                &mut self.client,
+                Token(self.index * CHANNEL_CNT + CHANNEL_CLIENT),
+                Interest::READABLE | Interest::WRITABLE,
+            ) {
+                log::warn!("connection:{} reregister client failed:{}", self.index, err);
+                self.shutdown();
+            }
+        }
+        if !self.server_conn.deregistered() {
+            self.server_conn.reregister(poll);
+        }
+    }
+
+    /// Returns true once the tunnel has had data pending toward a server
+    /// which answered before, without a byte back for `threshold`.
+    fn check_stall(&mut self, now: Instant, threshold: Duration) -> bool {
+        let received = self.server_conn.received();
+        let since = match self.progress {
+            Some((last, since)) if last == received && self.server_conn.pending() => since,
+            _ => {
+                self.progress = self.server_conn.pending().then_some((received, now));
+                self.stalled = false;
+                return false;
+            }
+        };
+        if received == 0 || self.stalled || self.drain_time.is_some() {
+            return false;
+        }
+        self.stalled = now - since > threshold;
+        self.stalled
+    }
+
     fn destroy(&mut self, poll: &Poll) {
         self.shutdown();
-        self.server_conn.shutdown();
+        self.check_status(poll);
+        // an unused server connection goes back to the pool when dropped
+        if self.server_conn.committed() {
+            self.server_conn.shutdown();
+            self.server_conn.check_status(poll);
+        }
+    }
+
+    /// Ends a connection over its lifetime like one closed by both peers,
+    /// pending data is flushed within the drain timeout before closing.
+    fn expire(&mut self, poll: &Poll) {
+        self.peer_closed();
+        self.server_conn.peer_closed();
+        self.drain();
         self.check_status(poll);
         self.server_conn.check_status(poll);
     }
 
-    fn setup(&mut self, poll: &Poll) -> bool {
-        let mut request = BytesMut::new();
-        TrojanRequest::generate(&mut request, CONNECT, &self.dst_addr);
-        let token = self.client_token();
-        if !self.server_conn.write_session(request.as_ref()) {
-            false
-        } else if let Err(err) = poll.registry().register(
-            &mut self.client,
-            token,
-            Interest::READABLE | Interest::WRITABLE,
-        ) {
-            log::warn!("connection:{} register client failed:{}", self.index(), err);
-            false
+    fn write_request(&mut self) -> bool {
+        let args = OPTIONS.proxy_args();
+        let compress = args.compress && !args.compress_skip_ports.contains(&self.dst_addr.port());
+        let command = if compress {
+            CONNECT | COMPRESSED
+        } else {
+            CONNECT
+        };
+        self.server_conn.set_codel(Codel::from_options());
+        let padder = Padder::from_options();
+        let command = if padder.is_some() {
+            command | PADDED
         } else {
-            true
+            command
+        };
+        let auto = args.remote_prefers == RemotePrefers::Auto;
+        let command = if args.close_notice || auto {
+            command | NOTICE
+        } else {
+            command
+        };
+        let command = if auto { command | FAMILIES } else { command };
+        let command = if args.propagate_reset {
+            command | RESET
+        } else {
+            command
+        };
+        let mut request = [0u8; MAX_HEADER_LEN];
+        let domain = args.sniff_request_domain
+            || !family::remote(self.endpoint.0).supports(self.dst_addr.ip());
+        self.request_len = match &self.name {
+            Some(name) if domain => {
+                TrojanRequest::write_domain(&mut request, command, name, self.dst_addr.port())
+            }
+            _ => TrojanRequest::write(&mut request, command, &self.dst_addr),
+        };
+        self.request_len = TrojanRequest::write_metadata(
+            &mut request,
+            self.request_len,
+            &OPTIONS.request_metadata,
+        );
+        match &self.name {
+            Some(_) if domain && args.sniff_request_domain => {
+                self.trace.add(Stage::Family, "name", || {
+                    "--sniff-request-domain".to_owned()
+                })
+            }
+            Some(_) if domain => {
+                let ip = self.dst_addr.ip();
+                self.trace.add(Stage::Family, "name", || {
+                    format!("no {} egress", family::name(ip))
+                })
+            }
+            _ => self.trace.add(Stage::Family, "address", String::new),
         }
+        let request = &request[..self.request_len];
+        let written = match padder {
+            Some(padder) => self.server_conn.write_padded(request, padder),
+            None => self.server_conn.write_session(request),
+        }
+        .accepted();
+        if written {
+            // the session is used, the pool can't have it back
+            self.server_conn.keep();
+        }
+        if written && compress {
+            self.server_conn.set_compressor(Compressor::default());
+            self.server_conn.set_decompressor(Decompressor::await_ack());
+        }
+        self.notice = args.close_notice || auto;
+        self.reset = args.propagate_reset;
+        if written && args.initial_cork {
+            self.server_conn.cork();
+            self.corked = true;
+        }
+        written
     }
 
-    fn index(&self) -> usize {
-        self.index
+    /// Sends the held back request with the payload so far, called once
+    /// the client sent something, closed, or the server spoke first.
+    fn uncork(&mut self) {
+        if self.corked {
+            self.corked = false;
+            self.server_conn.uncork();
+        }
     }
 
-    fn token2index(token: Token) -> usize {
-        token.0 / CHANNEL_CNT
+    /// Strips the close notice off the first bytes of the server, returns
+    /// false if the server rejected the connection.
+    fn take_notice(&mut self, buffer: &mut BytesMut) -> bool {
+        self.notice = false;
+        let code = buffer.split_to(1)[0];
+        let notice = match Notice::from_code(code) {
+            Some(Notice::Ok) => return true,
+            Some(Notice::Families(families)) => {
+                family::learn(self.endpoint.0, families);
+                return true;
+            }
+            Some(Notice::Maintenance) => {
+                MAINTENANCE_REFUSALS.inc();
+                self.refused = true;
+                Notice::Maintenance.to_string()
+            }
+            Some(notice) => notice.to_string(),
+            None => format!("unknown notice {}", code),
+        };
+        CLOSE_NOTICES.inc();
+        log::warn!(
+            "connection:{} to:{} closed by server, {}",
+            self.index,
+            self.dst_addr,
+            notice
+        );
+        false
+    }
+
+    /// Moves the server connection to our token and sends the request, on
+    /// a fresh one if the server closed this one meanwhile.
+    fn setup(
+        &mut self,
+        poll: &Poll,
+        router: &mut Router,
+        resolver: &DnsResolver,
+        next_id: &mut usize,
+    ) -> Result<()> {
+        let token = Token(self.index * CHANNEL_CNT + CHANNEL_TCP);
+        if !self.server_conn.reset_index(self.index, token, poll) {
+            Err(TrojanError::Setup(SetupPhase::PoolGet, None))
+        } else if self.write_request()
+            || (self.server_conn.remote_closed() && self.retry(poll, router, resolver, next_id))
+        {
+            self.request_pending = true;
+            self.check_request_sent();
+            Ok(())
+        } else {
+            Err(TrojanError::Setup(SetupPhase::WriteRequest, None))
+        }
+    }
+
+    /// Forwards the bytes read while sniffing, and goes on reading like a
+    /// readable client.
+    fn forward_sniffed(&mut self, data: &[u8], eof: bool, poll: &Poll) {
+        self.client_read += data.len();
+        self.summary.received(1, data.len());
+        if !data.is_empty() && !self.server_conn.write_session(data).accepted() {
+            log::warn!("connection:{} forward sniffed data failed", self.index);
+            self.shutdown();
+        } else if eof {
+            self.server_conn.peer_closed();
+        } else if self.server_conn.writable() {
+            self.try_read_client();
+        } else {
+            self.read_client = true;
+        }
+        self.try_send_server();
+        self.uncork();
+        if self.is_shutdown() {
+            self.server_conn.peer_closed();
+        }
+        self.drain();
+        self.check_request_sent();
+        self.check_status(poll);
+        self.server_conn.check_status(poll);
+    }
+
+    fn index(&self) -> usize {
+        self.index
     }
 
     fn writable(&self) -> bool {
         self.send_buffer.is_empty() && self.alive()
     }
 
-    fn ready(&mut self, event: &Event, poll: &Poll) {
+    /// Server connection failed before any client payload was forwarded
+    /// and nothing came back, so the request can be replayed safely.
+    fn early_failed(&self) -> bool {
+        !self.retried
+            && !self.is_shutdown()
+            && self.server_conn.is_shutdown()
+            && self.server_conn.sent() == self.request_len
+            && self.server_conn.received() == 0
+    }
+
+    /// Replays the request on a fresh pooled connection, returns whether it
+    /// was written. The new connection takes a token of the next index, the
+    /// events of the failed one still queued don't reach it.
+    fn retry(
+        &mut self,
+        poll: &Poll,
+        router: &mut Router,
+        resolver: &DnsResolver,
+        next_id: &mut usize,
+    ) -> bool {
+        self.retried = true;
+        // the failed connection is closed rather than returned
+        self.server_conn.keep();
+        if self.server_conn.remote_closed() {
+            POOL_CLOSED_AT_FIRST_USE.inc();
+            log::info!(
+                "connection:{} pooled connection closed by server at first use",
+                self.index
+            );
+        }
+        self.server_conn.check_status(poll);
+        if let Some(mut conn) = router.pool(self.endpoint.0).get(poll, resolver) {
+            let token = Token(next_index(next_id) * CHANNEL_CNT + CHANNEL_TCP);
+            if !conn.reset_index(self.index, token, poll) {
+                return false;
+            }
+            let failed = std::mem::replace(&mut self.server_conn, conn);
+            if self.write_request() {
+                EARLY_RETRIES.inc();
+                log::warn!(
+                    "connection:{} server connection failed early, retrying to {}",
+                    self.index,
+                    self.dst_addr
+                );
+                return true;
+            }
+            // the new one goes back to the pool, the failed one is closed
+            self.server_conn = failed;
+        } else {
+            log::error!("connection:{} alloc retry connection failed", self.index);
+        }
+        false
+    }
+
+    fn ready(
+        &mut self,
+        event: &Event,
+        poll: &Poll,
+        router: &mut Router,
+        resolver: &DnsResolver,
+        next_id: &mut usize,
+    ) {
+        let client = Token(self.index * CHANNEL_CNT + CHANNEL_CLIENT);
+        let channel = match Connection::channel(event.token(), client, self.server_conn.token()) {
+            Some(channel) => channel,
+            None => {
+                MISMATCHED_EVENTS.inc();
+                log::debug!(
+                    "connection:{} drop event of token:{}, not a current one",
+                    self.index,
+                    event.token().0
+                );
+                return;
+            }
+        };
         self.last_active_time = Instant::now();
-        match event.token().0 % CHANNEL_CNT {
+        match channel {
             CHANNEL_CLIENT => {
                 if event.is_readable() {
                     if self.server_conn.writable() {
@@ -245,37 +1364,103 @@ impl Connection {
                     self.try_send_server();
                 }
             }
-            _ => {
-                log::error!("invalid token found in tcp listener");
-                self.shutdown();
-            }
+            _ => unreachable!(),
+        }
+        if self.early_failed() {
+            self.retry(poll, router, resolver, next_id);
+        }
+        if self.refused {
+            self.refused = false;
+            router.refused(self.endpoint.0, Instant::now());
         }
         if self.is_shutdown() {
             self.server_conn.peer_closed();
         }
         if self.server_conn.is_shutdown() {
-            self.peer_closed();
+            if self.reset && self.server_conn.aborted() && !self.is_shutdown() {
+                self.abort();
+            } else {
+                self.peer_closed();
+            }
         }
+        self.drain();
+        self.check_request_sent();
         self.check_status(poll);
         self.server_conn.check_status(poll);
     }
 
-    fn client_token(&self) -> Token {
-        Token(self.index * CHANNEL_CNT + CHANNEL_CLIENT)
+    /// Resets the client after the target did, the RST goes out once the
+    /// client socket is dropped.
+    fn abort(&mut self) {
+        log::info!("connection:{} reset by target", self.index);
+        match tcp_util::TcpIo::set_reset(&self.client) {
+            Ok(()) => self.aborted = true,
+            Err(err) => log::warn!("connection:{} set linger failed:{}", self.index, err),
+        }
+        self.shutdown();
+    }
+
+    /// Records the tunnel latency once the request left the session.
+    fn check_request_sent(&mut self) {
+        if self.request_pending && !self.corked && self.server_conn.flushed() {
+            self.request_pending = false;
+            latency::record(self.pool_hit, self.client_time.elapsed());
+        }
+    }
+
+    /// Flushes pending data of a closing side right away, a writable event
+    /// only comes after a blocked write.
+    fn drain(&mut self) {
+        let client = matches!(self.status, ConnStatus::PeerClosed);
+        let server = matches!(self.server_conn.get_status(), ConnStatus::PeerClosed);
+        if !client && !server {
+            return;
+        }
+        if self.drain_time.is_none() {
+            self.drain_time.replace(Instant::now());
+        }
+        if client && !self.send_buffer.is_empty() {
+            self.try_send_client(&[]);
+        }
+        if server {
+            self.server_conn.do_send();
+        }
     }
 
     fn try_read_client(&mut self) {
-        if !tcp_util::tcp_read(
+        let transfer = tcp_util::tcp_read(
             self.index,
             &self.client,
             &mut self.recv_buffer,
             &mut self.server_conn,
-        ) {
-            self.shutdown();
-        }
+        );
+        self.client_read += transfer.bytes;
+        self.summary.received(transfer.chunks, transfer.bytes);
+        self.summary.log(self.index);
+        let uncork =
+            transfer.bytes > 0 || matches!(transfer.outcome, Outcome::Eof | Outcome::Error(_));
         self.read_client = false;
+        match transfer.outcome {
+            Outcome::Ok | Outcome::WouldBlock => {}
+            // resumed by try_send_server once the session drained
+            Outcome::Paused => self.read_client = true,
+            // close the server once it has flushed, which closes us in turn
+            Outcome::Eof => self.server_conn.peer_closed(),
+            Outcome::Error(_) if self.reset && transfer.outcome.reset() => {
+                log::info!("connection:{} reset by client", self.index);
+                self.server_conn.abort();
+                self.shutdown();
+            }
+            Outcome::Error(err) => {
+                log::warn!("connection:{} read from client failed:{}", self.index, err);
+                self.shutdown();
+            }
+        }
 
         self.try_send_server();
+        if uncork {
+            self.uncork();
+        }
     }
 
     fn try_send_client(&mut self, buffer: &[u8]) {
@@ -299,15 +1484,34 @@ impl Connection {
     }
 
     fn do_send_client(&mut self, data: &[u8]) {
-        if !tcp_util::tcp_send(self.index, &self.client, &mut self.send_buffer, data) {
+        let transfer = tcp_util::tcp_send(self.index, &self.client, &mut self.send_buffer, data);
+        self.client_sent += transfer.bytes;
+        self.summary.sent(transfer.chunks, transfer.bytes, None);
+        self.summary.log(self.index);
+        if !transfer.open() {
             self.shutdown();
         }
     }
 
     fn try_read_server(&mut self) {
-        if let Some(buffer) = self.server_conn.do_read() {
-            self.try_send_client(buffer.as_slice());
+        if !self.send_buffer.is_empty() {
+            // data is queued already, append behind it and flush them together
+            if self.server_conn.do_read_into(&mut self.send_buffer) > 0 {
+                self.try_send_client(&[]);
+            }
+            return;
+        }
+        let mut buffer = std::mem::take(&mut self.server_buffer);
+        if self.server_conn.do_read_into(&mut buffer) > 0 {
+            self.uncork();
+            if self.notice && !self.take_notice(&mut buffer) {
+                self.shutdown();
+            } else if !buffer.is_empty() {
+                self.try_send_client(buffer.as_ref());
+            }
         }
+        buffer.clear();
+        self.server_buffer = buffer;
     }
 
     fn try_send_server(&mut self) {
@@ -322,7 +1526,7 @@ impl Connection {
     }
 }
 
-impl StatusProvider for Connection {
+impl<C: tcp_util::TcpIo> StatusProvider for Connection<C> {
     fn set_status(&mut self, status: ConnStatus) {
         self.status = status;
     }
@@ -331,12 +1535,18 @@ impl StatusProvider for Connection {
     }
 
     fn close_conn(&mut self) -> bool {
-        let _ = self.client.shutdown(Shutdown::Both);
+        if !self.aborted {
+            let _ = tcp_util::TcpIo::shutdown(&self.client, Shutdown::Both);
+        }
         true
     }
 
     fn deregister(&mut self, poll: &Poll) -> bool {
-        let _ = poll.registry().deregister(&mut self.client);
+        if self.client_registered {
+            self.client_registered = false;
+            let _ = poll.registry().deregister(&mut self.client);
+        }
+        self.summary.flush(self.index);
         true
     }
 
@@ -344,3 +1554,110 @@ impl StatusProvider for Connection {
         self.send_buffer.is_empty()
     }
 }
+
+mod test {
+    #![allow(unused_imports, dead_code)]
+
+    use std::{convert::TryInto, sync::Arc, time::Duration};
+
+    use mio::{
+        event::Event,
+        net::{TcpListener, TcpStream, UdpSocket},
+        Events, Interest, Poll, Token, Waker,
+    };
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    use crate::{
+        idle_pool::Checkout,
+        metrics::MISMATCHED_EVENTS,
+        proxy::{
+            route::Router, tcp_server::Connection, CHANNEL_CLIENT, CHANNEL_CNT, CHANNEL_IDLE,
+            CHANNEL_TCP, CHANNEL_UDP,
+        },
+        resolver::DnsResolver,
+        sim::{Op, ScriptedStream},
+        status::{ConnStatus, StatusProvider},
+        tls_conn::TlsConn,
+    };
+
+    /// A readable and writable event of `token`, from a udp socket
+    /// registered with it for the moment.
+    fn event(poll: &mut Poll, token: Token) -> Event {
+        let mut socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        socket.send_to(b"x", socket.local_addr().unwrap()).unwrap();
+        poll.registry()
+            .register(&mut socket, token, Interest::READABLE | Interest::WRITABLE)
+            .unwrap();
+        let mut events = Events::with_capacity(4);
+        poll.poll(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+        let event = events.iter().find(|event| event.token() == token).cloned();
+        poll.registry().deregister(&mut socket).unwrap();
+        event.unwrap()
+    }
+
+    /// A connection of `index` with a scripted client and a server session
+    /// over loopback, whose handshake never completes.
+    fn connection(index: usize, listener: &TcpListener) -> Connection<ScriptedStream> {
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let session =
+            ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let token = Token(index * CHANNEL_CNT + CHANNEL_TCP);
+        let server_conn = Checkout::detached(TlsConn::new(index, token, session.into(), stream));
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let conn = Connection::new(index, server_conn, addr, addr, ScriptedStream::default());
+        conn.client.allow(usize::MAX / 2);
+        conn
+    }
+
+    #[test]
+    fn test_recycled_token() {
+        let index = 5;
+        let client = Token(index * CHANNEL_CNT + CHANNEL_CLIENT);
+        let server = Token(index * CHANNEL_CNT + CHANNEL_TCP);
+        // one batch: the connection's own events mixed with events of the
+        // same index from a pooled connection and a udp association freed
+        // earlier in the batch, and a server token of another index
+        let batch = [
+            client,
+            Token(index * CHANNEL_CNT + CHANNEL_IDLE),
+            server,
+            Token(index * CHANNEL_CNT + CHANNEL_UDP),
+            Token((index + 1) * CHANNEL_CNT + CHANNEL_TCP),
+            client,
+        ];
+        let mut poll = Poll::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut conn = connection(index, &listener);
+        let waker = Arc::new(Waker::new(poll.registry(), Token(1)).unwrap());
+        let resolver = DnsResolver::new(waker, Token(1), Vec::new());
+        let mut router = Router::empty();
+        let mut next_id = index + 1;
+        let mismatched = MISMATCHED_EVENTS.get();
+        for token in batch.iter() {
+            let event = event(&mut poll, *token);
+            conn.ready(&event, &poll, &mut router, &resolver, &mut next_id);
+        }
+        // the events of other tokens are dropped, the connection's own
+        // ones are handled and it stays open
+        assert_eq!(MISMATCHED_EVENTS.get() - mismatched, 3);
+        assert_eq!(conn.get_status(), ConnStatus::Established);
+        assert!(!conn.server_conn.is_shutdown());
+        assert!(!conn.client.take_ops().contains(&Op::Shutdown));
+        assert_eq!(
+            [client, server, client]
+                .iter()
+                .map(|token| Connection::channel(*token, client, server))
+                .collect::<Vec<_>>(),
+            [
+                Some(CHANNEL_CLIENT),
+                Some(CHANNEL_TCP),
+                Some(CHANNEL_CLIENT)
+            ]
+        );
+    }
+}
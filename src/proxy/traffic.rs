@@ -0,0 +1,137 @@
+//! Traffic of tcp connections aggregated by original destination.
+//!
+//! The table keeps at most `--top-destinations` entries with the space
+//! saving algorithm: a new destination replaces the one with the least
+//! traffic and inherits its count as error, so heavy destinations stay
+//! while the tail comes and goes. It is written to the state dump, logged
+//! with the metrics report if `--destination-metrics` is set, and reset
+//! with SIGHUP.
+use std::{collections::HashMap, net::IpAddr};
+
+use crate::dump::Dump;
+
+#[derive(Default, Clone, Copy)]
+struct Entry {
+    up: u64,
+    down: u64,
+    conns: u64,
+    /// Traffic of the evicted entry this one replaced, an upper bound of
+    /// what was missed
+    error: u64,
+}
+
+impl Entry {
+    fn weight(&self) -> u64 {
+        self.up + self.down + self.error
+    }
+}
+
+pub struct Traffic {
+    entries: HashMap<IpAddr, Entry>,
+    capacity: usize,
+}
+
+impl Traffic {
+    pub fn new(capacity: usize) -> Traffic {
+        Traffic {
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Accounts a closed connection to `ip`.
+    pub fn add(&mut self, ip: IpAddr, up: u64, down: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.entries.contains_key(&ip) && self.entries.len() >= self.capacity {
+            let (min_ip, min) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.weight())
+                .map(|(ip, entry)| (*ip, *entry))
+                .unwrap();
+            self.entries.remove(&min_ip);
+            self.entries.insert(
+                ip,
+                Entry {
+                    error: min.weight(),
+                    ..Default::default()
+                },
+            );
+        }
+        let entry = self.entries.entry(ip).or_default();
+        entry.up += up;
+        entry.down += down;
+        entry.conns += 1;
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Returns the `n` destinations with the most traffic.
+    fn top(&self, n: usize) -> Vec<(IpAddr, Entry)> {
+        let mut top: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(ip, entry)| (*ip, *entry))
+            .collect();
+        top.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.weight()));
+        top.truncate(n);
+        top
+    }
+
+    /// Logs the top `n` destinations, for the metrics report.
+    pub fn report(&self, n: usize) {
+        let line = self
+            .top(n)
+            .iter()
+            .map(|(ip, entry)| format!("{}={}/{}/{}", ip, entry.up, entry.down, entry.conns))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if !line.is_empty() {
+            log::info!("destinations up/down/conns: {}", line);
+        }
+    }
+
+    pub fn dump(&self, dump: &mut Dump) {
+        dump.line(format_args!("destinations:{}", self.entries.len()));
+        for (ip, entry) in self.top(self.capacity) {
+            dump.line(format_args!(
+                "destination:{} up:{} down:{} conns:{} error:{}",
+                ip, entry.up, entry.down, entry.conns, entry.error
+            ));
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::net::IpAddr;
+
+    use crate::proxy::traffic::Traffic;
+
+    #[test]
+    fn test_traffic() {
+        let ip = |n: u8| IpAddr::from([10, 0, 0, n]);
+        let mut traffic = Traffic::new(2);
+        traffic.add(ip(1), 100, 1000);
+        traffic.add(ip(1), 10, 10);
+        traffic.add(ip(2), 1, 1);
+        // replaces the smallest one
+        traffic.add(ip(3), 5, 5);
+        let top = traffic.top(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].0, ip(1));
+        assert_eq!((top[0].1.up, top[0].1.down, top[0].1.conns), (110, 1010, 2));
+        assert_eq!(top[1].0, ip(3));
+        assert_eq!((top[1].1.up, top[1].1.error), (5, 2));
+        traffic.reset();
+        assert!(traffic.top(2).is_empty());
+        let mut traffic = Traffic::new(0);
+        traffic.add(ip(1), 1, 1);
+        assert!(traffic.top(1).is_empty());
+    }
+}
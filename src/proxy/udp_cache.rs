@@ -1,4 +1,4 @@
-use std::{collections::HashMap, net::SocketAddr, rc::Rc};
+use std::{collections::HashMap, net::SocketAddr, rc::Rc, time::Instant};
 
 use mio::net::UdpSocket;
 
@@ -35,6 +35,15 @@ impl UdpSvrCache {
         }
     }
 
+    /// Sockets are swept once a second while there are any.
+    pub fn next_deadline(&self, now: Instant) -> Option<Instant> {
+        if self.conns.is_empty() {
+            None
+        } else {
+            Some(now)
+        }
+    }
+
     pub fn check_timeout(&mut self) {
         let mut list = Vec::new();
         for (addr, socket) in &self.conns {
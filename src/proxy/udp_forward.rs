@@ -0,0 +1,438 @@
+//! Forwards local udp ports to a fixed remote through the tunnel, for
+//! clients like WireGuard which don't speak SOCKS.
+//!
+//! Each `--udp-forward` entry listens on its local address. The server
+//! sends all datagrams of an association from one socket, so replies of
+//! peers sharing an association can't be told apart. Each local peer gets
+//! its own UDP_ASSOCIATE tunnel instead, found by the source address of its
+//! datagrams. A tunnel closed under a live peer is opened again on the next
+//! datagram or timeout tick, waiting a backoff doubling up to
+//! [`MAX_BACKOFF`] while the tunnel keeps failing. Datagrams are dropped
+//! while there is no tunnel, WireGuard resends them. Peers quiet for the
+//! udp idle timeout are forgotten.
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use mio::{event::Event, net::UdpSocket, Interest, Poll, Token};
+
+use crate::{
+    config::OPTIONS,
+    dump::Dump,
+    endpoint,
+    proto::{
+        TrojanRequest, UdpAssociate, UdpParseResult, MAX_PACKET_SIZE, MAX_REQUEST_LEN,
+        MAX_UDP_HEAD_LEN, UDP_ASSOCIATE,
+    },
+    proxy::{route::Router, UDP_FORWARD_BASE, UDP_FORWARD_TOKENS},
+    resolver::DnsResolver,
+    startup,
+    status::StatusProvider,
+    tls_conn::TlsConn,
+    types::Result,
+};
+
+/// Index of the tunnel connections in logs, below the indexes of the pool
+const TUNNEL_INDEX: usize = 1;
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Parses an entry like `127.0.0.1:51820=203.0.113.5:51820`.
+/// Returns local and remote address of an entry, the remote port defaults
+/// to the local one.
+fn parse(text: &str) -> Option<(SocketAddr, SocketAddr)> {
+    let (local, remote) = text.split_once('=')?;
+    let local = endpoint::parse_endpoint(local, None).ok()?.socket_addr()?;
+    let remote = endpoint::parse_endpoint(remote, Some(local.port()))
+        .ok()?
+        .socket_addr()?;
+    Some((local, remote))
+}
+
+struct Forward {
+    socket: UdpSocket,
+    local: SocketAddr,
+    remote: SocketAddr,
+    endpoint: usize,
+    /// Tokens of the peers by their address
+    peers: HashMap<SocketAddr, usize>,
+}
+
+struct Peer {
+    forward: usize,
+    addr: SocketAddr,
+    tunnel: Option<TlsConn>,
+    buffer: BytesMut,
+    last_active: Instant,
+    backoff: Duration,
+    retry_time: Instant,
+    /// Whether the tunnel got a reply since it was opened
+    replied: bool,
+}
+
+impl Peer {
+    fn tunnel_ok(&self) -> bool {
+        self.tunnel
+            .as_ref()
+            .is_some_and(|tunnel| tunnel.alive() || tunnel.is_connecting())
+    }
+
+    fn close_tunnel(&mut self, poll: &Poll) {
+        if let Some(mut tunnel) = self.tunnel.take() {
+            tunnel.shutdown();
+            tunnel.check_status(poll);
+        }
+        self.buffer.clear();
+    }
+
+    /// Waits longer before the next tunnel, the last one failed.
+    fn failed(&mut self) {
+        self.retry_time = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+
+    /// Opens a new tunnel if the backoff allows, returns whether one is open.
+    fn open(
+        &mut self,
+        token: usize,
+        forward: &Forward,
+        poll: &Poll,
+        router: &mut Router,
+        resolver: &DnsResolver,
+    ) -> bool {
+        if self.tunnel_ok() {
+            return true;
+        }
+        if self.tunnel.is_some() {
+            // a tunnel closed without any reply counts as failed
+            if !self.replied {
+                self.failed();
+            }
+            self.close_tunnel(poll);
+        }
+        if Instant::now() < self.retry_time {
+            return false;
+        }
+        self.replied = false;
+        let mut conn = if let Some(conn) = router.pool(forward.endpoint).get(poll, resolver) {
+            conn.commit()
+        } else {
+            log::error!("alloc udp forward tunnel to {} failed", forward.remote);
+            self.failed();
+            return false;
+        };
+        if !conn.reset_index(TUNNEL_INDEX, Token(token), poll) {
+            conn.check_status(poll);
+            self.failed();
+            return false;
+        }
+        let mut request = [0u8; MAX_REQUEST_LEN];
+        let len = TrojanRequest::write(
+            &mut request,
+            UDP_ASSOCIATE,
+            OPTIONS.empty_addr.as_ref().unwrap(),
+        );
+        if !conn.write_session(&request[..len]).accepted() {
+            conn.check_status(poll);
+            self.failed();
+            return false;
+        }
+        log::debug!(
+            "udp forward tunnel of {} to {} is ready",
+            self.addr,
+            forward.remote
+        );
+        self.tunnel.replace(conn);
+        true
+    }
+}
+
+pub struct UdpForwarder {
+    forwards: Vec<Forward>,
+    peers: HashMap<usize, Peer>,
+    next_token: usize,
+    recv_buffer: Vec<u8>,
+}
+
+impl UdpForwarder {
+    /// Binds the `--udp-forward` entries, panics for invalid ones like other
+    /// startup options.
+    pub fn new(entries: &[String], poll: &Poll, router: &Router) -> Result<UdpForwarder> {
+        let mut forwards = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            let (local, remote) = parse(entry).unwrap_or_else(|| {
+                panic!(
+                    "invalid --udp-forward value:{}, format like 127.0.0.1:51820=203.0.113.5:51820 \
+                     or [::1]:51820=[2001:db8::5], ip addresses only",
+                    entry
+                )
+            });
+            let mut socket = UdpSocket::bind(local)
+                .map_err(|err| startup::bind_error("udp-forward", local, true, err))?;
+            poll.registry().register(
+                &mut socket,
+                Token(UDP_FORWARD_BASE + i),
+                Interest::READABLE,
+            )?;
+            log::info!("udp forward {} to {}", local, remote);
+            forwards.push(Forward {
+                socket,
+                local,
+                remote,
+                endpoint: router.route(&remote),
+                peers: HashMap::new(),
+            });
+        }
+        Ok(UdpForwarder {
+            next_token: forwards.len(),
+            forwards,
+            peers: HashMap::new(),
+            recv_buffer: vec![0u8; MAX_PACKET_SIZE],
+        })
+    }
+
+    fn alloc_token(&mut self) -> Option<usize> {
+        for _ in self.forwards.len()..UDP_FORWARD_TOKENS {
+            let offset = self.next_token;
+            self.next_token += 1;
+            if self.next_token >= UDP_FORWARD_TOKENS {
+                self.next_token = self.forwards.len();
+            }
+            if !self.peers.contains_key(&(UDP_FORWARD_BASE + offset)) {
+                return Some(UDP_FORWARD_BASE + offset);
+            }
+        }
+        None
+    }
+
+    pub fn ready(
+        &mut self,
+        event: &Event,
+        poll: &Poll,
+        router: &mut Router,
+        resolver: &DnsResolver,
+    ) {
+        let token = event.token().0;
+        if token - UDP_FORWARD_BASE < self.forwards.len() {
+            self.accept(token - UDP_FORWARD_BASE, poll, router, resolver);
+        } else {
+            self.tunnel_ready(token, event, poll);
+        }
+    }
+
+    fn accept(&mut self, index: usize, poll: &Poll, router: &mut Router, resolver: &DnsResolver) {
+        loop {
+            let forward = &self.forwards[index];
+            let (size, addr) = match forward.socket.recv_from(self.recv_buffer.as_mut_slice()) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("udp forward {} receive failed:{}", forward.local, err);
+                    break;
+                }
+            };
+            let token = if let Some(token) = forward.peers.get(&addr) {
+                *token
+            } else if let Some(token) = self.alloc_token() {
+                log::debug!(
+                    "udp forward {} got new peer {}",
+                    self.forwards[index].local,
+                    addr
+                );
+                self.forwards[index].peers.insert(addr, token);
+                self.peers.insert(
+                    token,
+                    Peer {
+                        forward: index,
+                        addr,
+                        tunnel: None,
+                        buffer: BytesMut::new(),
+                        last_active: Instant::now(),
+                        backoff: MIN_BACKOFF,
+                        retry_time: Instant::now(),
+                        replied: false,
+                    },
+                );
+                token
+            } else {
+                log::warn!(
+                    "udp forward has too many peers, drop datagram from {}",
+                    addr
+                );
+                continue;
+            };
+            let forward = &self.forwards[index];
+            let peer = self.peers.get_mut(&token).unwrap();
+            peer.last_active = Instant::now();
+            if !peer.open(token, forward, poll, router, resolver) {
+                log::debug!("udp forward tunnel of {} is down, drop datagram", addr);
+                continue;
+            }
+            let tunnel = peer.tunnel.as_mut().unwrap();
+            if !tunnel.is_connecting() && !tunnel.writable() {
+                log::debug!("udp forward tunnel of {} is blocked, drop datagram", addr);
+                continue;
+            }
+            let mut head = [0u8; MAX_UDP_HEAD_LEN];
+            let len = UdpAssociate::write(&mut head, &forward.remote, size as u16);
+            if tunnel
+                .write_frame(&head[..len], &self.recv_buffer[..size])
+                .accepted()
+            {
+                tunnel.do_send();
+            }
+            tunnel.check_status(poll);
+        }
+    }
+
+    fn tunnel_ready(&mut self, token: usize, event: &Event, poll: &Poll) {
+        let peer = if let Some(peer) = self.peers.get_mut(&token) {
+            peer
+        } else {
+            log::error!("udp forward peer:{} not found, check deregister", token);
+            return;
+        };
+        let tunnel = if let Some(tunnel) = peer.tunnel.as_mut() {
+            tunnel
+        } else {
+            log::error!(
+                "udp forward tunnel of {} not found, check deregister",
+                peer.addr
+            );
+            return;
+        };
+        if event.is_readable() && tunnel.do_read_into(&mut peer.buffer) > 0 {
+            let socket = &self.forwards[peer.forward].socket;
+            Self::reply(peer, socket);
+        }
+        let tunnel = if let Some(tunnel) = peer.tunnel.as_mut() {
+            tunnel
+        } else {
+            return;
+        };
+        if event.is_writable() {
+            tunnel.established();
+            tunnel.do_send();
+        }
+        if tunnel.is_shutdown() {
+            tunnel.peer_closed();
+        }
+        tunnel.check_status(poll);
+        if tunnel.deregistered() {
+            log::debug!("udp forward tunnel of {} closed", peer.addr);
+            peer.tunnel.take();
+            peer.buffer.clear();
+            if !peer.replied {
+                peer.failed();
+            }
+        }
+    }
+
+    fn reply(peer: &mut Peer, socket: &UdpSocket) {
+        let data = peer.buffer.split();
+        let mut buffer = data.as_ref();
+        loop {
+            match UdpAssociate::parse(buffer) {
+                UdpParseResult::Packet(packet) => {
+                    peer.last_active = Instant::now();
+                    peer.replied = true;
+                    peer.backoff = MIN_BACKOFF;
+                    if let Err(err) = socket.send_to(&packet.payload[..packet.length], peer.addr) {
+                        log::debug!("udp forward send to {} failed:{}", peer.addr, err);
+                    }
+                    buffer = &packet.payload[packet.length..];
+                }
+                UdpParseResult::InvalidProtocol => {
+                    log::error!(
+                        "udp forward tunnel of {} got invalid udp protocol",
+                        peer.addr
+                    );
+                    if let Some(tunnel) = peer.tunnel.as_mut() {
+                        tunnel.shutdown();
+                    }
+                    return;
+                }
+                UdpParseResult::Continued => break,
+            }
+        }
+        peer.buffer.extend_from_slice(buffer);
+    }
+
+    /// Returns when the next peer expires or its tunnel is due to reopen.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.peers
+            .values()
+            .map(|peer| {
+                let expire = peer.last_active + OPTIONS.udp_idle_duration;
+                if peer.tunnel_ok() {
+                    expire
+                } else {
+                    expire.min(peer.retry_time)
+                }
+            })
+            .min()
+    }
+
+    pub fn check_timeout(&mut self, poll: &Poll, router: &mut Router, resolver: &DnsResolver) {
+        let forwards = &mut self.forwards;
+        self.peers.retain(|token, peer| {
+            let forward = &mut forwards[peer.forward];
+            if peer.last_active.elapsed() > OPTIONS.udp_idle_duration {
+                log::debug!("udp forward peer {} idle, close now", peer.addr);
+                peer.close_tunnel(poll);
+                forward.peers.remove(&peer.addr);
+                return false;
+            }
+            if !peer.tunnel_ok() {
+                peer.open(*token, forward, poll, router, resolver);
+            }
+            true
+        });
+    }
+
+    pub fn dump(&self, dump: &mut Dump) {
+        for forward in &self.forwards {
+            dump.line(format_args!(
+                "udp forward {} to {} peers:{}",
+                forward.local,
+                forward.remote,
+                forward.peers.len()
+            ));
+            for token in forward.peers.values() {
+                let peer = &self.peers[token];
+                dump.line(format_args!(
+                    "  peer {} idle:{}s backoff:{}ms tunnel:{}",
+                    peer.addr,
+                    peer.last_active.elapsed().as_secs(),
+                    peer.backoff.as_millis(),
+                    peer.tunnel
+                        .as_ref()
+                        .map_or_else(|| "none".to_owned(), |tunnel| tunnel.dump_state())
+                ));
+            }
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use crate::proxy::udp_forward::parse;
+
+    #[test]
+    fn test_parse() {
+        let (local, remote) = parse("127.0.0.1:51820 = 203.0.113.5:51821").unwrap();
+        assert_eq!(local.to_string(), "127.0.0.1:51820");
+        assert_eq!(remote.to_string(), "203.0.113.5:51821");
+        let (local, remote) = parse("[::1]:51820=2001:db8::5").unwrap();
+        assert_eq!(local.to_string(), "[::1]:51820");
+        assert_eq!(remote.to_string(), "[2001:db8::5]:51820");
+        assert!(parse("127.0.0.1=203.0.113.5:51821").is_none());
+        assert!(parse("127.0.0.1:51820").is_none());
+        assert!(parse("127.0.0.1:51820=vpn.example.com:51820").is_none());
+    }
+}
@@ -1,34 +1,73 @@
-use std::{collections::HashMap, io::ErrorKind, net::SocketAddr, rc::Rc, sync::Arc, time::Instant};
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    net::SocketAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 use bytes::BytesMut;
 use mio::{event::Event, net::UdpSocket, Poll, Token};
 
 use crate::{
-    config::OPTIONS,
-    idle_pool::IdlePool,
-    proto::{TrojanRequest, UdpAssociate, UdpParseResult, MAX_PACKET_SIZE, UDP_ASSOCIATE},
-    proxy::{next_index, udp_cache::UdpSvrCache, CHANNEL_CNT, CHANNEL_UDP, MIN_INDEX},
+    codel::Codel,
+    config::{RouteMode, OPTIONS},
+    dump::Dump,
+    metrics::{
+        ALLOWLIST_DENIED, DIRECT_UDP, ROUTE_MODE_REJECTED, UDP_KEEPALIVES, UDP_LISTENER_OVERFLOW,
+        UDP_RESUMED, UDP_RESUME_FAILED,
+    },
+    padding::Padder,
+    profile::{self, Category},
+    proto::{
+        TrojanRequest, UdpAssociate, UdpParseResult, MAX_HEADER_LEN, MAX_PACKET_SIZE,
+        MAX_UDP_HEAD_LEN, PADDED, UDP_ASSOCIATE,
+    },
+    proxy::{
+        allowlist,
+        direct::DirectUdp,
+        next_index,
+        pacer::{Admit, Pacer},
+        route::Router,
+        route_mode,
+        udp_cache::UdpSvrCache,
+        CHANNEL_CNT, CHANNEL_UDP, MIN_INDEX,
+    },
     resolver::DnsResolver,
-    status::{ConnStatus, StatusProvider},
+    stale::StaleEvents,
+    status::{CloseReason, ConnStatus, StatusProvider},
     sys,
     tls_conn::TlsConn,
     types::{Result, TrojanError},
+    udp_loss::{Loss, UdpStats},
 };
 
+/// Time an association with a broken tunnel waits for a new one
+const RESUME_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct UdpServer {
     udp_listener: UdpSocket,
-    conns: HashMap<usize, Arc<Connection>>,
-    src_map: HashMap<SocketAddr, Arc<Connection>>,
+    conns: HashMap<usize, Rc<Connection>>,
+    src_map: HashMap<SocketAddr, Rc<Connection>>,
     removed: Option<Vec<usize>>,
     next_id: usize,
     recv_buffer: Vec<u8>,
+    stale: StaleEvents,
+    /// Connections with paced datagrams waiting
+    paced: HashSet<usize>,
+    /// Clients relayed without the tunnel by --route-mode direct, by index
+    /// and by source
+    direct: HashMap<usize, DirectUdp>,
+    direct_src: HashMap<SocketAddr, usize>,
+    marker: u8,
 }
 
 struct Connection {
     index: usize,
     src_addr: SocketAddr,
     send_buffer: BytesMut,
-    recv_buffer: BytesMut,
+    server_buffer: BytesMut,
+    recv_head: [u8; MAX_UDP_HEAD_LEN],
     server_conn: TlsConn,
     status: ConnStatus,
     socket: Rc<UdpSocket>,
@@ -36,10 +75,21 @@ struct Connection {
     bytes_read: usize,
     bytes_sent: usize,
     last_active: Instant,
+    created: Instant,
+    pacer: Option<Pacer>,
+    stats: UdpStats,
+    /// Endpoint the tunnels are taken from
+    endpoint: usize,
+    /// Since when the tunnel is broken, with --udp-session-resume
+    broken: Option<Instant>,
+    /// Last destination of the client and when it got a datagram
+    last_dst: Option<(SocketAddr, Instant)>,
+    /// Last frame written into the tunnel, keepalives included
+    last_frame: Instant,
 }
 
 impl UdpServer {
-    pub fn new(udp_listener: UdpSocket) -> UdpServer {
+    pub fn new(udp_listener: UdpSocket, marker: u8) -> UdpServer {
         UdpServer {
             udp_listener,
             conns: HashMap::new(),
@@ -47,18 +97,23 @@ impl UdpServer {
             removed: Some(Vec::new()),
             next_id: MIN_INDEX,
             recv_buffer: vec![0u8; MAX_PACKET_SIZE],
+            stale: StaleEvents::new(),
+            paced: HashSet::new(),
+            direct: HashMap::new(),
+            direct_src: HashMap::new(),
+            marker,
         }
     }
 
     pub fn accept(
         &mut self,
         poll: &Poll,
-        pool: &mut IdlePool,
+        router: &mut Router,
         udp_cache: &mut UdpSvrCache,
         resolver: &DnsResolver,
     ) {
         loop {
-            if let Err(err) = self.accept_once(poll, pool, udp_cache, resolver) {
+            if let Err(err) = self.accept_once(poll, router, udp_cache, resolver) {
                 if let TrojanError::StdIo(err) = &err {
                     if err.kind() == ErrorKind::WouldBlock {
                         break;
@@ -72,18 +127,34 @@ impl UdpServer {
     fn accept_once(
         &mut self,
         poll: &Poll,
-        pool: &mut IdlePool,
+        router: &mut Router,
         udp_cache: &mut UdpSvrCache,
         resolver: &DnsResolver,
     ) -> Result<()> {
-        let (size, src_addr, dst_addr) =
-            sys::recv_from_with_destination(&self.udp_listener, self.recv_buffer.as_mut_slice())?;
+        let (size, src_addr, dst_addr) = {
+            let _scope = profile::scope(Category::UdpRead);
+            sys::recv_from_with_destination(&self.udp_listener, self.recv_buffer.as_mut_slice())?
+        };
         log::debug!(
             "udp received {} byte from {} to {}",
             size,
             src_addr,
             dst_addr
         );
+        if !allowlist::allowed(&dst_addr, None) {
+            ALLOWLIST_DENIED.inc();
+            log::info!(
+                "udp from:{} to:{} denied by the allowlist",
+                src_addr,
+                dst_addr
+            );
+            return Ok(());
+        }
+        if let Some(index) = self.direct_src.get(&src_addr) {
+            let payload = &self.recv_buffer.as_slice()[..size];
+            self.direct.get_mut(index).unwrap().send(payload, dst_addr);
+            return Ok(());
+        }
         let mut conn = if let Some(conn) = self.src_map.get(&src_addr) {
             log::debug!(
                 "connection:{} already exists for address{}",
@@ -92,21 +163,60 @@ impl UdpServer {
             );
             conn.clone()
         } else {
+            let max_connections = OPTIONS.proxy_args().max_udp_connections;
+            if max_connections != 0 && self.connections() >= max_connections {
+                UDP_LISTENER_OVERFLOW.inc();
+                log::info!(
+                    "udp from:{} dropped, {} associations reached --max-udp-connections",
+                    src_addr,
+                    max_connections
+                );
+                return Ok(());
+            }
+            match route_mode::get() {
+                RouteMode::Tunnel => {}
+                RouteMode::Direct => {
+                    self.open_direct(poll, src_addr, dst_addr, size);
+                    return Ok(());
+                }
+                RouteMode::Reject => {
+                    ROUTE_MODE_REJECTED.inc();
+                    log::info!(
+                        "udp from:{} to:{} dropped by route mode reject",
+                        src_addr,
+                        dst_addr
+                    );
+                    return Ok(());
+                }
+            }
+            let endpoint = router.route(&dst_addr);
+            if router.broken(endpoint, Instant::now()) {
+                log::debug!(
+                    "udp from:{} to:{} via:{} dropped, the breaker is open",
+                    src_addr,
+                    dst_addr,
+                    router.name(endpoint)
+                );
+                return Ok(());
+            }
             log::debug!(
-                "address:{} not found, connecting to {}",
+                "address:{} not found, connecting via {}",
                 src_addr,
-                OPTIONS.back_addr.as_ref().unwrap()
+                router.name(endpoint)
             );
-            if let Some(mut conn) = pool.get(poll, resolver) {
+            if let Some(conn) = router.pool(endpoint).get(poll, resolver) {
+                // without a socket the checkout goes back to the pool
                 if let Some(socket) = udp_cache.get_socket(dst_addr) {
+                    let mut conn = conn.commit();
                     let index = next_index(&mut self.next_id);
                     if !conn.reset_index(index, Token(index * CHANNEL_CNT + CHANNEL_UDP), poll) {
                         conn.check_status(poll);
                         return Ok(());
                     }
                     let mut conn = Connection::new(index, conn, src_addr, socket);
+                    conn.endpoint = endpoint;
                     if conn.setup() {
-                        let conn = Arc::new(conn);
+                        let conn = Rc::new(conn);
                         let _ = self.conns.insert(index, conn.clone());
                         self.src_map.insert(src_addr, conn.clone());
                         log::debug!("connection:{} is ready", index);
@@ -116,8 +226,6 @@ impl UdpServer {
                         return Ok(());
                     }
                 } else {
-                    conn.shutdown();
-                    conn.check_status(poll);
                     return Ok(());
                 }
             } else {
@@ -126,26 +234,98 @@ impl UdpServer {
             }
         };
         let payload = &self.recv_buffer.as_slice()[..size];
-        unsafe { Arc::get_mut_unchecked(&mut conn) }.send_request(payload, &dst_addr, poll);
+        let conn_mut = unsafe { Rc::get_mut_unchecked(&mut conn) };
+        if conn_mut.broken.is_some() {
+            conn_mut.resume(poll, router, resolver);
+        }
+        conn_mut.send_request(payload, &dst_addr, poll);
         if conn.destroyed() {
             self.removed.as_mut().unwrap().push(conn.index);
+        } else if conn.is_paced() {
+            self.paced.insert(conn.index);
         }
         Ok(())
     }
 
-    pub fn ready(&mut self, event: &Event, poll: &Poll, udp_cache: &mut UdpSvrCache) {
+    /// Starts relaying client `src_addr` without the tunnel, with the
+    /// first `size` bytes of the receive buffer.
+    fn open_direct(
+        &mut self,
+        poll: &Poll,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        size: usize,
+    ) {
+        let index = next_index(&mut self.next_id);
+        match DirectUdp::open(index, src_addr, dst_addr, self.marker, poll) {
+            Ok(mut direct) => {
+                DIRECT_UDP.inc();
+                log::info!("udp from:{} to:{} via:direct", src_addr, dst_addr);
+                direct.send(&self.recv_buffer.as_slice()[..size], dst_addr);
+                self.direct.insert(index, direct);
+                self.direct_src.insert(src_addr, index);
+            }
+            Err(err) => log::warn!(
+                "udp from:{} to:{} via:direct setup failed:{:?}",
+                src_addr,
+                dst_addr,
+                err
+            ),
+        }
+    }
+
+    /// Whether paced datagrams are waiting for [`release`](Self::release).
+    pub fn pacing(&self) -> bool {
+        !self.paced.is_empty()
+    }
+
+    /// Sends the paced datagrams the budget allows, called on every tick
+    /// while [`pacing`](Self::pacing).
+    pub fn release(&mut self, poll: &Poll) {
+        let now = Instant::now();
+        let conns = &mut self.conns;
+        let removed = self.removed.as_mut().unwrap();
+        self.paced.retain(|index| match conns.get_mut(index) {
+            Some(conn) if !conn.destroyed() => {
+                let conn = unsafe { Rc::get_mut_unchecked(conn) };
+                conn.release(now, poll);
+                if conn.destroyed() {
+                    removed.push(*index);
+                }
+                conn.is_paced()
+            }
+            _ => false,
+        });
+    }
+
+    pub fn ready(
+        &mut self,
+        event: &Event,
+        poll: &Poll,
+        udp_cache: &mut UdpSvrCache,
+        router: &mut Router,
+        resolver: &DnsResolver,
+    ) {
         let index = Connection::token2index(event.token());
-        if let Some(conn) = self.conns.get_mut(&index) {
-            unsafe { Arc::get_mut_unchecked(conn) }.ready(event, poll, udp_cache);
-            if conn.destroyed() {
-                self.removed.as_mut().unwrap().push(index);
+        if let Some(direct) = self.direct.get_mut(&index) {
+            direct.ready(udp_cache, self.recv_buffer.as_mut_slice());
+            return;
+        }
+        match self.conns.get_mut(&index) {
+            Some(conn) if conn.destroyed() => {}
+            Some(conn) => {
+                let conn_mut = unsafe { Rc::get_mut_unchecked(conn) };
+                conn_mut.ready(event, poll, udp_cache, router, resolver);
+                if conn.destroyed() {
+                    self.removed.as_mut().unwrap().push(index);
+                }
             }
-        } else {
-            log::error!("udp connection:{} not found, check deregister", index);
+            None => self.stale.missing("udp", index),
         }
     }
 
     pub fn remove_closed(&mut self) {
+        self.stale.next_generation();
         if self.removed.as_ref().unwrap().is_empty() {
             return;
         }
@@ -158,13 +338,171 @@ impl UdpServer {
             if let Some(src_addr) = src_addr {
                 self.conns.remove(&index);
                 self.src_map.remove(&src_addr);
+                self.stale.freed(index);
                 log::debug!("connection:{} removed from list", index);
             }
         }
     }
+
+    /// Time the oldest association reaches `--udp-max-lifetime` or the
+    /// first one is due a keepalive, direct clients are swept once a second
+    /// while there are any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if !self.direct.is_empty() {
+            return Some(Instant::now());
+        }
+        let lifetime = OPTIONS.udp_lifetime.and_then(|lifetime| {
+            self.conns
+                .values()
+                .map(|conn| conn.created + lifetime)
+                .min()
+        });
+        let keepalive = self
+            .conns
+            .values()
+            .filter_map(|conn| conn.keepalive_deadline())
+            .min();
+        lifetime.into_iter().chain(keepalive).min()
+    }
+
+    /// Sends the keepalives which came due, see `--udp-keepalive`.
+    fn keepalive(&mut self, poll: &Poll, now: Instant) {
+        let removed = self.removed.as_mut().unwrap();
+        for (index, conn) in self.conns.iter_mut() {
+            if conn
+                .keepalive_deadline()
+                .is_none_or(|deadline| deadline > now)
+            {
+                continue;
+            }
+            let conn = unsafe { Rc::get_mut_unchecked(conn) };
+            conn.send_keepalive(now);
+            conn.do_status(poll);
+            if conn.destroyed() {
+                removed.push(*index);
+            }
+        }
+    }
+
+    /// Closes associations over `--udp-max-lifetime`, the client starts a
+    /// new one with its next datagram, and idle direct clients, then sends
+    /// the keepalives due.
+    pub fn check_timeout(&mut self, poll: &Poll, now: Instant) {
+        let direct_src = &mut self.direct_src;
+        let stale = &mut self.stale;
+        self.direct.retain(|index, direct| {
+            if !direct.idle(now) {
+                return true;
+            }
+            log::debug!(
+                "direct udp:{} closed by {:?}",
+                index,
+                CloseReason::IdleTimeout
+            );
+            direct.close(poll);
+            direct_src.remove(&direct.src_addr);
+            stale.freed(*index);
+            false
+        });
+        if OPTIONS.proxy_args().udp_keepalive > 0 {
+            self.keepalive(poll, now);
+        }
+        let lifetime = match OPTIONS.udp_lifetime {
+            Some(lifetime) => lifetime,
+            None => return,
+        };
+        let removed = self.removed.as_mut().unwrap();
+        for (index, conn) in self.conns.iter_mut() {
+            if conn.destroyed() || now - conn.created <= lifetime {
+                continue;
+            }
+            log::info!(
+                "udp connection:{} closed by {:?}",
+                index,
+                CloseReason::LifetimeExceeded
+            );
+            let conn = unsafe { Rc::get_mut_unchecked(conn) };
+            conn.shutdown();
+            conn.do_status(poll);
+            if conn.destroyed() {
+                removed.push(*index);
+            }
+        }
+    }
+
+    /// Moves associations with a broken tunnel to a new one, those waiting
+    /// over [`RESUME_TIMEOUT`] are closed.
+    pub fn resume_broken(
+        &mut self,
+        poll: &Poll,
+        now: Instant,
+        router: &mut Router,
+        resolver: &DnsResolver,
+    ) {
+        let removed = self.removed.as_mut().unwrap();
+        for (index, conn) in self.conns.iter_mut() {
+            let broken = match conn.broken {
+                Some(broken) if !conn.destroyed() => broken,
+                _ => continue,
+            };
+            let conn = unsafe { Rc::get_mut_unchecked(conn) };
+            if now - broken > RESUME_TIMEOUT {
+                UDP_RESUME_FAILED.inc();
+                log::warn!(
+                    "udp connection:{} closed, no new tunnel in {}s",
+                    index,
+                    RESUME_TIMEOUT.as_secs()
+                );
+                conn.broken = None;
+                conn.shutdown();
+            } else {
+                conn.resume(poll, router, resolver);
+            }
+            conn.do_status(poll);
+            if conn.destroyed() {
+                removed.push(*index);
+            }
+        }
+    }
+
+    pub fn connections(&self) -> usize {
+        self.conns.len() + self.direct.len()
+    }
+
+    pub fn dump(&self, dump: &mut Dump) {
+        dump.line(format_args!(
+            "udp connections:{} direct:{} max:{} overflow:{}",
+            self.conns.len(),
+            self.direct.len(),
+            OPTIONS.proxy_args().max_udp_connections,
+            UDP_LISTENER_OVERFLOW.get()
+        ));
+        for conn in self.conns.values() {
+            conn.dump(dump);
+        }
+        for direct in self.direct.values() {
+            direct.dump(dump);
+        }
+    }
 }
 
 impl Connection {
+    fn dump(&self, dump: &mut Dump) {
+        dump.line(format_args!(
+            "udp:{} {}->{} client:{:?} buf:{} server:{} sent:{} recv:{} {} idle:{}s",
+            self.index,
+            self.src_addr,
+            self.dst_addr,
+            self.status,
+            self.send_buffer.len(),
+            self.server_conn.dump_state(),
+            self.bytes_sent,
+            self.bytes_read,
+            self.stats,
+            self.last_active.elapsed().as_secs()
+        ));
+    }
+
     fn new(
         index: usize,
         server_conn: TlsConn,
@@ -179,11 +517,19 @@ impl Connection {
             socket,
             dst_addr,
             send_buffer: BytesMut::new(),
-            recv_buffer: BytesMut::new(),
+            server_buffer: BytesMut::new(),
+            recv_head: [0u8; MAX_UDP_HEAD_LEN],
             status: ConnStatus::Established,
             bytes_read: 0,
             bytes_sent: 0,
             last_active: Instant::now(),
+            created: Instant::now(),
+            pacer: Pacer::from_options(),
+            stats: UdpStats::default(),
+            endpoint: 0,
+            broken: None,
+            last_dst: None,
+            last_frame: Instant::now(),
         }
     }
 
@@ -192,13 +538,21 @@ impl Connection {
     }
 
     fn setup(&mut self) -> bool {
-        self.recv_buffer.clear();
-        TrojanRequest::generate(
-            &mut self.recv_buffer,
-            UDP_ASSOCIATE,
-            OPTIONS.empty_addr.as_ref().unwrap(),
-        );
-        self.server_conn.write_session(self.recv_buffer.as_ref())
+        self.server_conn.set_codel(Codel::from_options());
+        let mut request = [0u8; MAX_HEADER_LEN];
+        let padder = Padder::from_options();
+        let command = if padder.is_some() {
+            UDP_ASSOCIATE | PADDED
+        } else {
+            UDP_ASSOCIATE
+        };
+        let len = TrojanRequest::write(&mut request, command, OPTIONS.empty_addr.as_ref().unwrap());
+        let len = TrojanRequest::write_metadata(&mut request, len, &OPTIONS.request_metadata);
+        match padder {
+            Some(padder) => self.server_conn.write_padded(&request[..len], padder),
+            None => self.server_conn.write_session(&request[..len]),
+        }
+        .accepted()
     }
 
     fn destroyed(&self) -> bool {
@@ -215,25 +569,116 @@ impl Connection {
             self.do_status(poll);
             return;
         }
-        if !self.server_conn.is_connecting() && !self.server_conn.writable() {
+        if let Some(pacer) = &mut self.pacer {
+            match pacer.admit(dst_addr, payload, Instant::now()) {
+                Admit::Now => {}
+                Admit::Queued => return,
+                Admit::Evicted => {
+                    self.stats.lose(Loss::RateLimit);
+                    return;
+                }
+            }
+        }
+        self.forward(payload, dst_addr);
+        self.try_send_server();
+        self.do_status(poll);
+    }
+
+    fn forward(&mut self, payload: &[u8], dst_addr: &SocketAddr) {
+        if self.broken.is_some() {
+            self.stats.lose(Loss::BufferFull);
+            return;
+        }
+        if !self.server_conn.is_connecting() && !self.server_conn.has_room() {
             log::warn!("udp packet is too fast, ignore now");
+            self.stats.lose(Loss::BufferFull);
+            return;
+        }
+        if self.server_conn.aqm_drop(Instant::now()) {
+            log::debug!("udp connection:{} queue standing, drop", self.index);
+            self.stats.lose(Loss::QueueDelay);
             return;
         }
         self.bytes_read += payload.len();
-        self.recv_buffer.clear();
-        UdpAssociate::generate(&mut self.recv_buffer, dst_addr, payload.len() as u16);
-        if self.server_conn.write_session(self.recv_buffer.as_ref()) {
-            self.server_conn.write_session(payload);
+        let len = UdpAssociate::write(&mut self.recv_head, dst_addr, payload.len() as u16);
+        if self
+            .server_conn
+            .write_frame(&self.recv_head[..len], payload)
+            .accepted()
+        {
+            let now = Instant::now();
+            self.last_dst = Some((*dst_addr, now));
+            self.last_frame = now;
+            self.stats.forward();
+        } else {
+            self.stats.lose(Loss::BufferFull);
+        }
+    }
+
+    /// When the association is due an empty datagram to the destination
+    /// of the client, while that was active within `--udp-idle-timeout`.
+    fn keepalive_deadline(&self) -> Option<Instant> {
+        let interval = OPTIONS.proxy_args().udp_keepalive;
+        if interval == 0 || self.broken.is_some() || self.destroyed() {
+            return None;
+        }
+        let (_, active) = self.last_dst?;
+        let deadline = self.last_frame + Duration::from_secs(interval);
+        if deadline > active + OPTIONS.udp_idle_duration {
+            return None;
+        }
+        Some(deadline)
+    }
+
+    /// Writes an empty datagram for the last destination into the tunnel,
+    /// the server discards it, the frames refresh the nat on the way.
+    fn send_keepalive(&mut self, now: Instant) {
+        let dst_addr = match self.last_dst {
+            Some((dst_addr, _)) => dst_addr,
+            None => return,
+        };
+        // a frame is due again after the interval, written or not
+        self.last_frame = now;
+        if !self.server_conn.has_room() {
+            return;
+        }
+        let len = UdpAssociate::write(&mut self.recv_head, &dst_addr, 0);
+        if self
+            .server_conn
+            .write_frame(&self.recv_head[..len], &[])
+            .accepted()
+        {
+            UDP_KEEPALIVES.inc();
+            log::debug!("udp connection:{} keepalive to {}", self.index, dst_addr);
+            self.try_send_server();
+        }
+    }
+
+    fn is_paced(&self) -> bool {
+        self.pacer.as_ref().is_some_and(|pacer| pacer.queued() > 0)
+    }
+
+    fn release(&mut self, now: Instant, poll: &Poll) {
+        let mut released = false;
+        while let Some((dst_addr, payload)) =
+            self.pacer.as_mut().and_then(|pacer| pacer.release(now))
+        {
+            self.forward(payload.as_slice(), &dst_addr);
+            released = true;
+        }
+        if released {
+            self.try_send_server();
+            self.do_status(poll);
         }
-        self.try_send_server();
-        self.do_status(poll);
     }
 
     fn do_status(&mut self, poll: &Poll) {
         if self.is_shutdown() {
             self.server_conn.peer_closed();
         }
-        if self.server_conn.is_shutdown() {
+        if self.tunnel_broken() {
+            self.broken.get_or_insert_with(Instant::now);
+        } else if self.server_conn.is_shutdown() {
             self.peer_closed();
         }
 
@@ -241,7 +686,14 @@ impl Connection {
         self.server_conn.check_status(poll);
     }
 
-    fn ready(&mut self, event: &Event, poll: &Poll, udp_cache: &mut UdpSvrCache) {
+    fn ready(
+        &mut self,
+        event: &Event,
+        poll: &Poll,
+        udp_cache: &mut UdpSvrCache,
+        router: &mut Router,
+        resolver: &DnsResolver,
+    ) {
         self.last_active = Instant::now();
         if event.is_readable() {
             self.try_read_server(udp_cache);
@@ -250,17 +702,69 @@ impl Connection {
             self.server_conn.established();
             self.try_send_server();
         }
+        if self.tunnel_broken() {
+            self.resume(poll, router, resolver);
+        }
         self.do_status(poll);
     }
 
+    /// Whether the tunnel ended under an open association which may move
+    /// to a new one.
+    fn tunnel_broken(&self) -> bool {
+        OPTIONS.proxy_args().udp_session_resume
+            && !self.is_shutdown()
+            && (self.server_conn.is_shutdown() || self.server_conn.remote_closed())
+    }
+
+    /// Sends the association request on a new tunnel of the endpoint, if
+    /// there is one yet. Datagrams on the way are lost.
+    fn resume(&mut self, poll: &Poll, router: &mut Router, resolver: &DnsResolver) {
+        let broken = *self.broken.get_or_insert_with(Instant::now);
+        if !self.server_conn.deregistered() {
+            // the new tunnel takes over the token
+            self.server_conn.shutdown();
+            self.server_conn.check_status(poll);
+        }
+        let mut conn = match router.pool(self.endpoint).get(poll, resolver) {
+            Some(conn) => conn.commit(),
+            None => {
+                log::debug!("udp connection:{} has no tunnel to resume on", self.index);
+                return;
+            }
+        };
+        let token = Token(self.index * CHANNEL_CNT + CHANNEL_UDP);
+        if !conn.reset_index(self.index, token, poll) {
+            conn.check_status(poll);
+            return;
+        }
+        self.server_conn = conn;
+        // a datagram cut short by the old tunnel never completes
+        self.send_buffer.clear();
+        if !self.setup() {
+            return;
+        }
+        self.try_send_server();
+        self.broken = None;
+        UDP_RESUMED.inc();
+        log::warn!(
+            "udp connection:{} from:{} resumed on a new tunnel after {}ms",
+            self.index,
+            self.src_addr,
+            broken.elapsed().as_millis()
+        );
+    }
+
     fn try_send_server(&mut self) {
         self.server_conn.do_send();
     }
 
     fn try_read_server(&mut self, udp_cache: &mut UdpSvrCache) {
-        if let Some(buffer) = self.server_conn.do_read() {
-            self.try_send_client(buffer.as_slice(), udp_cache);
+        let mut buffer = std::mem::take(&mut self.server_buffer);
+        if self.server_conn.do_read_into(&mut buffer) > 0 {
+            self.try_send_client(buffer.as_ref(), udp_cache);
         }
+        buffer.clear();
+        self.server_buffer = buffer;
     }
 
     pub fn try_send_client(&mut self, buffer: &[u8], udp_cache: &mut UdpSvrCache) {
@@ -284,9 +788,11 @@ impl Connection {
                 self.socket = socket;
                 self.dst_addr = dst_addr;
             } else {
+                self.stats.lose(Loss::SendError);
                 return;
             }
         }
+        let _scope = profile::scope(Category::UdpSend);
         match self.socket.send_to(data, self.src_addr) {
             Ok(size) => {
                 self.bytes_sent += size;
@@ -297,10 +803,14 @@ impl Connection {
                     self.src_addr
                 );
                 if size != data.len() {
-                    log::error!("send {} byte to client fragmented to {}", data.len(), size)
+                    log::error!("send {} byte to client fragmented to {}", data.len(), size);
+                    self.stats.lose(Loss::Oversize);
+                } else {
+                    self.stats.forward();
                 }
             }
             Err(err) => {
+                self.stats.lose(Loss::SendError);
                 log::error!(
                     "send udp data from {} to {} failed {}",
                     dst_addr,
@@ -325,6 +835,9 @@ impl Connection {
                 }
                 UdpParseResult::InvalidProtocol => {
                     log::error!("connection:{} got invalid protocol", self.index());
+                    self.stats.lose(Loss::Parse);
+                    // a broken server is not resumed
+                    self.shutdown();
                     self.server_conn.shutdown();
                     break;
                 }
@@ -347,6 +860,7 @@ impl StatusProvider for Connection {
     }
 
     fn deregister(&mut self, _: &Poll) -> bool {
+        self.stats.log_closed(self.index);
         true
     }
 
@@ -0,0 +1,153 @@
+//! Watchdog of the poll loop, for `--watchdog-timeout`.
+//!
+//! The loop tells when it wakes up and when it goes back to poll, so a loop
+//! waiting for events is never stuck. One busy for longer than the timeout
+//! while connections are open is, like in a handler retrying a read that
+//! always blocks. The watchdog thread then writes the time stuck and the
+//! token of the last event to the state dump, which is all it can see of
+//! the loop. `--watchdog-action abort` aborts the process right after for
+//! the supervisor to restart it. With `recover` the loop writes the full
+//! state dump once it returns and registers the tcp connections again,
+//! which brings back readiness a lost edge never raised. Each stuck period
+//! is reported once.
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{WatchdogAction, OPTIONS},
+    dump::{self, Dump},
+    metrics::WATCHDOG_STUCK,
+};
+
+/// Milliseconds since [`EPOCH`] plus one the loop woke up at, 0 while it waits
+static BUSY_SINCE: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static LAST_TOKEN: AtomicUsize = AtomicUsize::new(usize::MAX);
+static RECOVER: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref EPOCH: Instant = Instant::now();
+}
+
+fn now() -> u64 {
+    EPOCH.elapsed().as_millis() as u64 + 1
+}
+
+/// Starts the watchdog thread unless `--watchdog-timeout` is 0.
+pub fn init() {
+    let timeout = Duration::from_secs(OPTIONS.proxy_args().watchdog_timeout);
+    if timeout.is_zero() {
+        return;
+    }
+    lazy_static::initialize(&EPOCH);
+    if let Err(err) = std::thread::Builder::new()
+        .name("watchdog".to_owned())
+        .spawn(move || watch(timeout))
+    {
+        log::error!("start watchdog failed:{}", err);
+    }
+}
+
+/// The loop woke up with `connections` open.
+pub fn busy(connections: usize) {
+    CONNECTIONS.store(connections, Ordering::Relaxed);
+    BUSY_SINCE.store(now(), Ordering::Relaxed);
+}
+
+/// The loop goes back to poll.
+pub fn idle() {
+    BUSY_SINCE.store(0, Ordering::Relaxed);
+}
+
+/// The loop handles an event of `token`.
+pub fn dispatch(token: usize) {
+    LAST_TOKEN.store(token, Ordering::Relaxed);
+}
+
+/// Whether the connections have to be registered again, once after a
+/// stuck loop with `--watchdog-action recover`.
+pub fn take_recover() -> bool {
+    RECOVER.swap(false, Ordering::Relaxed)
+}
+
+/// How long a loop busy since `busy_since` is stuck at `now`, None if it
+/// waits, has no connections or is within the timeout.
+fn stuck_for(busy_since: u64, now: u64, connections: usize, timeout: Duration) -> Option<Duration> {
+    if busy_since == 0 || connections == 0 {
+        return None;
+    }
+    let busy = Duration::from_millis(now.saturating_sub(busy_since));
+    if busy >= timeout {
+        Some(busy)
+    } else {
+        None
+    }
+}
+
+fn watch(timeout: Duration) {
+    let check = (timeout / 4).max(Duration::from_millis(100));
+    let mut reported = 0;
+    loop {
+        std::thread::sleep(check);
+        let busy_since = BUSY_SINCE.load(Ordering::Relaxed);
+        if busy_since == reported {
+            continue;
+        }
+        let connections = CONNECTIONS.load(Ordering::Relaxed);
+        if let Some(stuck) = stuck_for(busy_since, now(), connections, timeout) {
+            reported = busy_since;
+            report(stuck, connections);
+        }
+    }
+}
+
+fn report(stuck: Duration, connections: usize) {
+    WATCHDOG_STUCK.inc();
+    let token = LAST_TOKEN.load(Ordering::Relaxed);
+    log::error!(
+        "poll loop stuck for {}s with {} connections, last event token:{}",
+        stuck.as_secs(),
+        connections,
+        token
+    );
+    let mut dump = Dump::new();
+    dump.line(format_args!(
+        "watchdog: poll loop stuck for {:?} with {} connections, last event token:{}",
+        stuck, connections, token
+    ));
+    drop(dump);
+    match OPTIONS.proxy_args().watchdog_action {
+        WatchdogAction::Abort => {
+            log::error!("watchdog aborts the process");
+            log::logger().flush();
+            std::process::abort();
+        }
+        WatchdogAction::Recover => {
+            log::error!("watchdog requested a state dump and a recovery pass");
+            dump::request();
+            RECOVER.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::time::Duration;
+
+    use crate::proxy::watchdog::stuck_for;
+
+    #[test]
+    fn test_stuck_for() {
+        let timeout = Duration::from_secs(30);
+        assert_eq!(stuck_for(0, 60_000, 10, timeout), None);
+        assert_eq!(stuck_for(1, 60_000, 0, timeout), None);
+        assert_eq!(stuck_for(40_000, 60_000, 10, timeout), None);
+        assert_eq!(
+            stuck_for(20_000, 60_000, 10, timeout),
+            Some(Duration::from_secs(40))
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! Pacing of the connections a pool opens by itself, so a restarted server
+//! isn't met by every pool of every client at once.
+//!
+//! After a failed connect or handshake the pool waits a random time up to
+//! [`BACKOFF_BASE`] doubled per failure in a row, at most [`BACKOFF_CAP`],
+//! before opening more. At most `--pool-handshakes` connections of a pool
+//! connect or handshake at a time. The first success after failures
+//! starts a ramp, the pool may hold a share of its size growing to all of
+//! it over [`RAMP`]. Pools refill as they are used, an idle one never wakes
+//! up for it. Connections handed out right away don't wait, they are made
+//! for a client which waits already.
+use std::time::{Duration, Instant};
+
+/// Longest wait of the first failure
+pub const BACKOFF_BASE: Duration = Duration::from_millis(500);
+pub const BACKOFF_CAP: Duration = Duration::from_secs(30);
+/// Time a pool takes to fill up again once its server is back
+pub const RAMP: Duration = Duration::from_secs(10);
+
+pub struct Refill {
+    /// Connects at the same time, 0 for any number
+    max_handshakes: usize,
+    /// Failures since the last success
+    failures: u32,
+    /// No connection is opened before then
+    next_attempt: Option<Instant>,
+    /// Since when the pool fills up again after failures
+    ramp_start: Option<Instant>,
+}
+
+impl Refill {
+    pub fn new(max_handshakes: usize) -> Refill {
+        Refill {
+            max_handshakes,
+            failures: 0,
+            next_attempt: None,
+            ramp_start: None,
+        }
+    }
+
+    /// A connect or handshake failed, `random` picks the wait.
+    pub fn failed(&mut self, now: Instant, random: u32) {
+        self.failures = self.failures.saturating_add(1);
+        self.ramp_start = None;
+        let limit = BACKOFF_BASE
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(BACKOFF_CAP);
+        let wait = limit.mul_f64(random as f64 / u32::MAX as f64);
+        self.next_attempt.replace(now + wait);
+    }
+
+    /// A handshake finished, the first one after failures starts the ramp.
+    pub fn succeeded(&mut self, now: Instant) {
+        if self.failures > 0 {
+            self.ramp_start.replace(now);
+        }
+        self.failures = 0;
+        self.next_attempt = None;
+    }
+
+    /// Connections the pool may open at `now`, with `open` connections
+    /// idle or handshaking, `in_flight` of them handshaking.
+    pub fn allowance(&mut self, now: Instant, size: usize, open: usize, in_flight: usize) -> usize {
+        if self.next_attempt.is_some_and(|next| now < next) {
+            return 0;
+        }
+        let target = match self.ramp_start {
+            Some(start) if now - start < RAMP => {
+                let grown = size as u128 * (now - start).as_nanos() / RAMP.as_nanos();
+                (1 + grown as usize).min(size)
+            }
+            Some(_) => {
+                self.ramp_start = None;
+                size
+            }
+            None => size,
+        };
+        let missing = target.saturating_sub(open);
+        if self.max_handshakes == 0 {
+            missing
+        } else {
+            missing.min(self.max_handshakes.saturating_sub(in_flight))
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::time::Duration;
+
+    use crate::{
+        refill::{Refill, BACKOFF_BASE, BACKOFF_CAP, RAMP},
+        sim::MockClock,
+    };
+
+    #[test]
+    fn test_backoff() {
+        let clock = MockClock::new();
+        let mut refill = Refill::new(4);
+        assert_eq!(refill.allowance(clock.now(), 10, 0, 0), 4);
+        // the longest waits double up to the cap
+        for limit in [1, 2, 4, 8, 16, 32, 60, 60] {
+            refill.failed(clock.now(), u32::MAX);
+            let wait = BACKOFF_BASE * limit;
+            clock.advance(wait - Duration::from_millis(1));
+            assert_eq!(refill.allowance(clock.now(), 10, 0, 0), 0);
+            clock.advance(Duration::from_millis(1));
+            assert_eq!(refill.allowance(clock.now(), 10, 0, 0), 4);
+            assert!(wait <= BACKOFF_CAP);
+        }
+        // full jitter, a low draw retries soon
+        refill.failed(clock.now(), 0);
+        assert_eq!(refill.allowance(clock.now(), 10, 0, 0), 4);
+    }
+
+    #[test]
+    fn test_handshakes() {
+        let clock = MockClock::new();
+        let mut refill = Refill::new(4);
+        assert_eq!(refill.allowance(clock.now(), 10, 3, 3), 1);
+        assert_eq!(refill.allowance(clock.now(), 10, 8, 1), 2);
+        assert_eq!(refill.allowance(clock.now(), 10, 6, 0), 4);
+        assert_eq!(Refill::new(0).allowance(clock.now(), 10, 3, 3), 7);
+    }
+
+    #[test]
+    fn test_ramp() {
+        let clock = MockClock::new();
+        let mut refill = Refill::new(0);
+        refill.failed(clock.now(), 0);
+        refill.succeeded(clock.now());
+        assert_eq!(refill.allowance(clock.now(), 10, 0, 0), 1);
+        let mut open = 1;
+        let mut shape = Vec::new();
+        for _ in 0..10 {
+            clock.advance(RAMP / 20);
+            open += refill.allowance(clock.now(), 10, open, 0);
+            shape.push(open);
+        }
+        // one more every second, restored within the ramp
+        assert_eq!(shape, vec![1, 2, 2, 3, 3, 4, 4, 5, 5, 6]);
+        clock.advance(RAMP / 2);
+        assert_eq!(refill.allowance(clock.now(), 10, open, 0), 4);
+    }
+}
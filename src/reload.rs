@@ -0,0 +1,79 @@
+//! Reloading of configuration files, requested with SIGHUP, and stopping
+//! with SIGTERM or SIGINT for saving state first.
+//!
+//! Like the state dump, the handlers only set a flag which the poll loop
+//! checks, so reloading never races with connection handling.
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use mio::Waker;
+
+lazy_static::lazy_static! {
+    static ref REQUESTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    static ref TERMINATED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    static ref STOPPING: AtomicBool = AtomicBool::new(false);
+}
+
+/// Installs the SIGHUP handler.
+pub fn init() {
+    #[cfg(unix)]
+    if let Err(err) = signal_hook::flag::register(libc::SIGHUP, REQUESTED.clone()) {
+        log::error!("register SIGHUP for reloading failed:{}", err);
+    }
+}
+
+/// Requests a reload like SIGHUP does.
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Returns true if a reload was requested since the last call.
+pub fn take() -> bool {
+    REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Installs the SIGTERM and SIGINT handlers, which stop the poll loop so
+/// the state can be saved. Only used with state to save, otherwise the
+/// signals kill the process as usual.
+pub fn init_stop() {
+    STOPPING.store(true, Ordering::SeqCst);
+    #[cfg(unix)]
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        if let Err(err) = signal_hook::flag::register(signal, TERMINATED.clone()) {
+            log::error!("register signal {} for stopping failed:{}", signal, err);
+        }
+    }
+}
+
+/// Returns true once the process was asked to stop.
+pub fn stopped() -> bool {
+    TERMINATED.load(Ordering::SeqCst)
+}
+
+/// Wakes a poll loop without timeout on the signals the flags of this
+/// module and the state dump are set by, so they are acted on right away.
+pub fn wake_on_signals(waker: &Arc<Waker>) {
+    #[cfg(unix)]
+    {
+        let mut signals = vec![libc::SIGHUP, libc::SIGUSR2];
+        if STOPPING.load(Ordering::SeqCst) {
+            signals.extend_from_slice(&[libc::SIGTERM, libc::SIGINT]);
+        }
+        for signal in signals {
+            let waker = waker.clone();
+            // waking only writes to an eventfd or a pipe, which is async signal safe
+            let result = unsafe {
+                signal_hook::register(signal, move || {
+                    let _ = waker.wake();
+                })
+            };
+            if let Err(err) = result {
+                log::error!("register signal {} for waking failed:{}", signal, err);
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = waker;
+}
@@ -1,34 +1,213 @@
 use std::{
+    cell::RefCell,
     collections::HashMap,
-    net::IpAddr,
+    fs,
+    net::{IpAddr, SocketAddr, SocketAddrV6},
     sync::{
-        Arc,
         mpsc::{channel, Receiver, Sender},
+        Arc, RwLock,
     },
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use mio::{Token, Waker};
 
-use crate::types::TrojanError;
+use crate::{config::OPTIONS, metrics::DNS_COALESCED};
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+/// Interval between two modification checks of resolv.conf
+const RESOLV_CONF_CHECK: Duration = Duration::from_secs(30);
 
 pub struct DnsEntry {
     pub address: IpAddr,
     pub expired_time: Instant,
 }
 
+/// Upstream dns servers shared with the lookup threads, empty for the
+/// system resolver.
+#[derive(Default)]
+struct Upstreams {
+    servers: Vec<SocketAddr>,
+    current: usize,
+    /// Bumped on every swap, so lookups failed against a replaced
+    /// server retry against the new ones.
+    generation: usize,
+}
+
+impl Upstreams {
+    fn pick(&self) -> (Option<SocketAddr>, usize) {
+        let server = if self.servers.is_empty() {
+            None
+        } else {
+            Some(self.servers[self.current % self.servers.len()])
+        };
+        (server, self.generation)
+    }
+
+    fn failed(&mut self, server: SocketAddr) {
+        if !OPTIONS.dns_stick && self.servers[self.current % self.servers.len()] == server {
+            self.current += 1;
+        }
+    }
+
+    fn swap(&mut self, servers: Vec<SocketAddr>) {
+        log::warn!("dns upstreams changed to {:?}", servers);
+        self.servers = servers;
+        self.current = 0;
+        self.generation += 1;
+    }
+}
+
+/// Lookup running in a thread, keyed by domain and whether it is a server
+/// lookup, which filters answers differently.
+struct Inflight {
+    started: Instant,
+    waiters: Vec<Token>,
+}
+
+/// Attaches `token` to the lookup of `key` in flight, returns false if
+/// there is none younger than `limit` and a new one has to be started.
+fn join(
+    inflight: &mut HashMap<(String, bool), Inflight>,
+    key: &(String, bool),
+    token: Token,
+    now: Instant,
+    limit: Duration,
+) -> bool {
+    match inflight.get_mut(key) {
+        Some(query) if now - query.started < limit => {
+            if !query.waiters.contains(&token) {
+                query.waiters.push(token);
+            }
+            true
+        }
+        _ => {
+            inflight.insert(
+                key.clone(),
+                Inflight {
+                    started: now,
+                    waiters: vec![token],
+                },
+            );
+            false
+        }
+    }
+}
+
+struct ResolvConf {
+    modified: Option<SystemTime>,
+    check_time: Instant,
+}
+
 pub struct DnsResolver {
     waker: Arc<Waker>,
-    receiver: Option<Receiver<(Token, String, Option<IpAddr>)>>,
-    sender: Sender<(Token, String, Option<IpAddr>)>,
+    receiver: Option<Receiver<(String, bool, Option<IpAddr>)>>,
+    sender: Sender<(String, bool, Option<IpAddr>)>,
     dns_cache: HashMap<String, DnsEntry>,
+    /// Callers asking for a domain already being looked up wait for the
+    /// same answer, the entry goes with it, answer or not
+    inflight: RefCell<HashMap<(String, bool), Inflight>>,
     dns_cache_duration: Duration,
     token: Token,
-    dns_server: Option<String>,
+    upstreams: Arc<RwLock<Upstreams>>,
+    resolv_conf: Option<ResolvConf>,
+}
+
+/// Returns the nameservers of a resolv.conf.
+fn parse_resolv_conf(content: &str) -> Vec<SocketAddr> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            if fields.next() != Some("nameserver") {
+                return None;
+            }
+            let mut ip = fields.next()?.splitn(2, '%');
+            let addr = SocketAddr::new(ip.next()?.parse::<IpAddr>().ok()?, 53);
+            match (addr, ip.next()) {
+                // link local addresses are only reachable through their scope
+                (SocketAddr::V6(addr), Some(scope)) => Some(SocketAddr::V6(SocketAddrV6::new(
+                    *addr.ip(),
+                    53,
+                    0,
+                    scope_id(scope)?,
+                ))),
+                (addr, _) => Some(addr),
+            }
+        })
+        .collect()
+}
+
+/// Returns the index of the interface `scope` names, or its number.
+fn scope_id(scope: &str) -> Option<u32> {
+    if let Ok(id) = scope.parse() {
+        return Some(id);
+    }
+    #[cfg(unix)]
+    {
+        let name = std::ffi::CString::new(scope).ok()?;
+        let id = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if id != 0 {
+            return Some(id);
+        }
+    }
+    log::warn!("unknown scope {} of nameserver", scope);
+    None
+}
+
+/// Drops the answers outside of `--server-ip-allowlist` for server lookups,
+/// None if nothing is left.
+fn check_answer(domain: &str, ips: Vec<IpAddr>, server: bool) -> Option<Vec<IpAddr>> {
+    if !server {
+        return Some(ips);
+    }
+    let ips: Vec<_> = ips
+        .into_iter()
+        .filter(|ip| OPTIONS.server_ip_allowed(domain, *ip))
+        .collect();
+    if ips.is_empty() {
+        None
+    } else {
+        Some(ips)
+    }
+}
+
+fn lookup(upstreams: &RwLock<Upstreams>, domain: &str, is_server: bool) -> Option<Vec<IpAddr>> {
+    let timeout = Duration::from_millis(OPTIONS.dns_timeout);
+    let mut tries = 0;
+    loop {
+        let (server, generation) = upstreams.read().unwrap().pick();
+        let server = if let Some(server) = server {
+            server
+        } else {
+            return check_answer(domain, dns_lookup::lookup_host(domain).ok()?, is_server);
+        };
+        let answer = crate::utils::resolve_timeout(domain, server, timeout)
+            .map(|ips| check_answer(domain, ips, is_server));
+        match answer {
+            Ok(Some(ips)) => return Some(ips),
+            answer => {
+                log::info!(
+                    "resolve {} with {} failed:{:?}",
+                    domain,
+                    server,
+                    answer.err()
+                );
+                let mut upstreams = upstreams.write().unwrap();
+                if upstreams.generation == generation {
+                    upstreams.failed(server);
+                    tries += 1;
+                    if tries >= OPTIONS.dns_tries {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl DnsResolver {
-    pub fn new(waker: Arc<Waker>, token: Token, dns_server: Option<String>) -> Self {
+    pub fn new(waker: Arc<Waker>, token: Token, servers: Vec<SocketAddr>) -> Self {
         let (sender, receiver) = channel();
         Self {
             sender,
@@ -36,11 +215,60 @@ impl DnsResolver {
             token,
             receiver: Some(receiver),
             dns_cache: HashMap::new(),
+            inflight: RefCell::new(HashMap::new()),
             dns_cache_duration: Duration::new(10, 0),
-            dns_server,
+            upstreams: Arc::new(RwLock::new(Upstreams {
+                servers,
+                ..Default::default()
+            })),
+            resolv_conf: None,
+        }
+    }
+
+    /// Follows the nameservers of /etc/resolv.conf with `--dns-resolv-conf`
+    /// unless upstreams were given explicitly.
+    pub fn watch_resolv_conf(&mut self) {
+        if OPTIONS.dns_resolv_conf && self.upstreams.read().unwrap().servers.is_empty() {
+            self.resolv_conf.replace(ResolvConf {
+                modified: None,
+                check_time: Instant::now(),
+            });
+            self.check_resolv_conf(true);
         }
     }
 
+    /// Returns when resolv.conf is to be checked again, if it is watched.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.resolv_conf
+            .as_ref()
+            .map(|watch| watch.check_time + RESOLV_CONF_CHECK)
+    }
+
+    /// Swaps the upstreams if resolv.conf changed, checks at most once in
+    /// [`RESOLV_CONF_CHECK`] unless `force`.
+    pub fn check_resolv_conf(&mut self, force: bool) {
+        let watch = if let Some(watch) = self.resolv_conf.as_mut() {
+            watch
+        } else {
+            return;
+        };
+        if !force && watch.check_time.elapsed() < RESOLV_CONF_CHECK {
+            return;
+        }
+        watch.check_time = Instant::now();
+        let modified = fs::metadata(RESOLV_CONF)
+            .and_then(|meta| meta.modified())
+            .ok();
+        if modified == watch.modified {
+            return;
+        }
+        watch.modified = modified;
+        let servers = fs::read_to_string(RESOLV_CONF)
+            .map(|content| parse_resolv_conf(content.as_str()))
+            .unwrap_or_default();
+        self.upstreams.write().unwrap().swap(servers);
+    }
+
     pub fn set_cache_timeout(&mut self, timeout: u64) {
         self.dns_cache_duration = Duration::new(timeout, 0);
     }
@@ -72,19 +300,45 @@ impl DnsResolver {
     }
 
     pub fn resolve(&self, domain: String, token: Option<Token>) {
+        self.spawn(domain, token, false);
+    }
+
+    /// Resolves a trojan server host, rejecting answers outside of
+    /// `--server-ip-allowlist` and trying the next upstream for them.
+    pub fn resolve_server(&self, domain: String, token: Option<Token>) {
+        self.spawn(domain, token, true);
+    }
+
+    fn spawn(&self, domain: String, token: Option<Token>, server: bool) {
         let token = token.unwrap_or(self.token);
+        // a lookup always answers within its tries, an older entry is from
+        // a lost thread and must not swallow new callers
+        let limit = Duration::from_millis(OPTIONS.dns_timeout) * (OPTIONS.dns_tries as u32 + 1);
+        let key = (domain, server);
+        if join(
+            &mut self.inflight.borrow_mut(),
+            &key,
+            token,
+            Instant::now(),
+            limit,
+        ) {
+            DNS_COALESCED.inc();
+            log::debug!(
+                "resolve domain:{} with token:{} joins the lookup in flight",
+                key.0,
+                token.0
+            );
+            return;
+        }
+        let domain = key.0;
         log::info!("resolve domain:{} with token:{}", domain, token.0);
         let sender = self.sender.clone();
         let waker = self.waker.clone();
-        let dns_server = self.dns_server.clone();
+        let upstreams = self.upstreams.clone();
         rayon::spawn(move || {
             log::info!("thread resolve domain:{} with token:{}", domain, token.0);
             let mut address = None;
-            if let Ok(ips) = if let Some(dns_server) = dns_server {
-                crate::utils::resolve(domain.as_str(), dns_server.as_str())
-            } else {
-                dns_lookup::lookup_host(domain.as_str()).map_err(|_| TrojanError::Dummy(()))
-            } {
+            if let Some(ips) = lookup(upstreams.as_ref(), domain.as_str(), server) {
                 for addr in ips {
                     if address.is_none() || addr.is_ipv4() {
                         address.replace(addr);
@@ -94,7 +348,7 @@ impl DnsResolver {
                     }
                 }
             }
-            if let Err(err) = sender.send((token, domain.clone(), address)) {
+            if let Err(err) = sender.send((domain.clone(), server, address)) {
                 log::error!("send resolver result failed:{:?}", err);
             } else if let Err(err) = waker.wake() {
                 log::error!("wake failed {}", err);
@@ -106,12 +360,66 @@ impl DnsResolver {
 
     pub fn consume<F: FnMut(Token, Option<IpAddr>)>(&mut self, mut f: F) {
         let receiver = self.receiver.take().unwrap();
-        receiver.try_iter().for_each(|(token, domain, ip)| {
+        receiver.try_iter().for_each(|(domain, server, ip)| {
+            let waiters = self
+                .inflight
+                .borrow_mut()
+                .remove(&(domain.clone(), server))
+                .map(|query| query.waiters)
+                .unwrap_or_default();
             if let Some(ip) = ip {
                 self.update_dns(domain, ip);
             }
-            f(token, ip);
+            for token in waiters {
+                f(token, ip);
+            }
         });
         self.receiver.replace(receiver);
     }
 }
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use mio::Token;
+
+    use crate::resolver::join;
+
+    #[test]
+    fn test_join() {
+        let mut inflight = HashMap::new();
+        let key = ("example.com".to_owned(), false);
+        let limit = Duration::from_secs(9);
+        let now = Instant::now();
+        assert!(!join(&mut inflight, &key, Token(1), now, limit));
+        assert!(join(&mut inflight, &key, Token(2), now, limit));
+        assert!(join(&mut inflight, &key, Token(2), now, limit));
+        // server lookups filter answers, they don't share with plain ones
+        let server = ("example.com".to_owned(), true);
+        assert!(!join(&mut inflight, &server, Token(3), now, limit));
+        assert_eq!(inflight[&key].waiters, [Token(1), Token(2)]);
+        // a lookup older than its tries is taken as lost
+        assert!(!join(&mut inflight, &key, Token(4), now + limit, limit));
+        assert_eq!(inflight[&key].waiters, [Token(4)]);
+    }
+
+    #[test]
+    fn test_parse_resolv_conf() {
+        let servers = crate::resolver::parse_resolv_conf(
+            "# generated\nsearch lan\nnameserver 192.168.1.1\nnameserver fe80::1%2\nnameserver fe80::2%1\nnameserver fe80::3%nonexistent0\noptions edns0\n",
+        );
+        assert_eq!(
+            servers,
+            vec![
+                "192.168.1.1:53".parse().unwrap(),
+                "[fe80::1%2]:53".parse().unwrap(),
+                "[fe80::2%1]:53".parse().unwrap(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! Egress policy for targets requested by clients.
+//!
+//! Rules are read from a file, one per line, the first matching rule wins:
+//!
+//! ```text
+//! # comments and empty lines are ignored
+//! default allow
+//! deny 10.0.0.0/8
+//! deny * 25
+//! allow 192.168.1.10/32 443
+//! ```
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    net::{IpAddr, SocketAddr},
+    sync::RwLock,
+};
+
+use crate::{
+    cidr::{to_u128, Cidr},
+    config::OPTIONS,
+    types::{Result, TrojanError},
+};
+
+lazy_static::lazy_static! {
+    static ref ACL: RwLock<Acl> = RwLock::new(Acl::default());
+}
+
+struct Rule {
+    allow: bool,
+    // None for any address
+    cidr: Option<Cidr>,
+    // None for any port
+    port: Option<u16>,
+    /// Line in the rule file, for traces
+    line: usize,
+}
+
+impl Rule {
+    fn parse(line: &str, no: usize) -> Option<Rule> {
+        let mut items = line.split_whitespace();
+        let allow = match items.next()? {
+            "allow" => true,
+            "deny" => false,
+            _ => return None,
+        };
+        let cidr = match items.next()? {
+            "*" => None,
+            cidr => Some(Cidr::parse(cidr)?),
+        };
+        let port = match items.next() {
+            Some(port) => Some(port.parse().ok()?),
+            None => None,
+        };
+        if items.next().is_some() {
+            return None;
+        }
+        Some(Rule {
+            allow,
+            cidr,
+            port,
+            line: no + 1,
+        })
+    }
+
+    fn matches(&self, ip: u128, port: u16) -> bool {
+        self.cidr.as_ref().is_none_or(|cidr| cidr.contains(ip))
+            && self.port.is_none_or(|p| p == port)
+    }
+}
+
+pub struct Acl {
+    rules: Vec<Rule>,
+    default_allow: bool,
+    block_private: bool,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Acl {
+            rules: Vec::new(),
+            default_allow: true,
+            block_private: false,
+        }
+    }
+}
+
+/// Loopback, RFC1918, link local and unspecified addresses, also in their
+/// ipv4 mapped form.
+fn is_private(ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4() {
+            Some(v4) if v6.segments()[5] == 0xffff => IpAddr::V4(v4),
+            _ => IpAddr::V6(v6),
+        },
+        ip => ip,
+    };
+    match ip {
+        IpAddr::V4(v4) => {
+            v4.is_unspecified() || v4.is_loopback() || v4.is_private() || v4.is_link_local()
+        }
+        IpAddr::V6(v6) => {
+            let head = v6.segments()[0];
+            v6.is_unspecified()
+                || v6.is_loopback()
+                || head & 0xfe00 == 0xfc00 // fc00::/7
+                || head & 0xffc0 == 0xfe80 // fe80::/10
+        }
+    }
+}
+
+impl Acl {
+    fn parse(reader: impl BufRead, block_private: bool) -> Result<Acl> {
+        let mut acl = Acl {
+            block_private,
+            ..Default::default()
+        };
+        for (no, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line {
+                "default allow" => acl.default_allow = true,
+                "default deny" => acl.default_allow = false,
+                line => {
+                    if let Some(rule) = Rule::parse(line, no) {
+                        acl.rules.push(rule);
+                    } else {
+                        log::error!("invalid egress rule at line {}:{}", no + 1, line);
+                        return Err(TrojanError::Dummy(()));
+                    }
+                }
+            }
+        }
+        Ok(acl)
+    }
+
+    fn allowed(&self, addr: &SocketAddr) -> bool {
+        if self.block_private && is_private(addr.ip()) {
+            return false;
+        }
+        self.rule(addr)
+            .map_or(self.default_allow, |rule| rule.allow)
+    }
+
+    fn rule(&self, addr: &SocketAddr) -> Option<&Rule> {
+        let ip = to_u128(addr.ip());
+        self.rules.iter().find(|rule| rule.matches(ip, addr.port()))
+    }
+
+    fn explain(&self, addr: &SocketAddr) -> String {
+        if self.block_private && is_private(addr.ip()) {
+            return "private target".to_owned();
+        }
+        match self.rule(addr) {
+            Some(rule) => format!("rule at line {}", rule.line),
+            None => "default".to_owned(),
+        }
+    }
+}
+
+fn load() -> Result<Acl> {
+    let args = OPTIONS.server_args();
+    if args.egress_acl_file.is_empty() {
+        Ok(Acl {
+            block_private: args.block_private_targets,
+            ..Default::default()
+        })
+    } else {
+        let file = File::open(args.egress_acl_file.as_str())?;
+        Acl::parse(BufReader::new(file), args.block_private_targets)
+    }
+}
+
+/// Loads the rules at startup, panics if the rule file is invalid.
+pub fn init() {
+    *ACL.write().unwrap() = load().unwrap_or_else(|err| {
+        panic!(
+            "load egress acl {} failed:{:?}",
+            OPTIONS.server_args().egress_acl_file,
+            err
+        )
+    });
+}
+
+/// Reloads the rules, keeps the current ones if the rule file is invalid.
+pub fn reload() {
+    match load() {
+        Ok(acl) => {
+            *ACL.write().unwrap() = acl;
+            log::warn!("egress acl reloaded");
+        }
+        Err(err) => log::error!("reload egress acl failed:{:?}", err),
+    }
+}
+
+pub fn allowed(addr: &SocketAddr) -> bool {
+    ACL.read().unwrap().allowed(addr)
+}
+
+/// What decided about `addr`, for traces.
+pub fn explain(addr: &SocketAddr) -> String {
+    ACL.read().unwrap().explain(addr)
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::net::SocketAddr;
+
+    use crate::server::acl::Acl;
+
+    #[test]
+    fn test_rules() {
+        let rules = "# test\ndefault deny\ndeny * 25\nallow 1.2.3.0/24\nallow 2001:db8::/32 443\n";
+        let acl = Acl::parse(rules.as_bytes(), true).unwrap();
+        let allowed = |addr: &str| acl.allowed(&addr.parse::<SocketAddr>().unwrap());
+        assert!(allowed("1.2.3.4:80"));
+        assert!(!allowed("1.2.3.4:25"));
+        assert!(!allowed("1.2.4.4:80"));
+        assert!(allowed("[2001:db8::1]:443"));
+        assert!(!allowed("[2001:db8::1]:80"));
+        assert!(!allowed("192.168.1.1:80"));
+        assert!(!allowed("[::ffff:10.0.0.1]:80"));
+        assert!(!allowed("[fe80::1]:443"));
+        assert_eq!(
+            acl.explain(&"1.2.3.4:25".parse().unwrap()),
+            "rule at line 3"
+        );
+        assert_eq!(acl.explain(&"1.2.4.4:80".parse().unwrap()), "default");
+    }
+
+    #[test]
+    fn test_invalid_rule() {
+        assert!(Acl::parse("deny 1.2.3.0/40".as_bytes(), false).is_err());
+        assert!(Acl::parse("block *".as_bytes(), false).is_err());
+    }
+}
@@ -1,28 +1,46 @@
 use std::{
     io::Write,
     net::{IpAddr, SocketAddr},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-use mio::{
-    net::{TcpStream, UdpSocket},
-    Poll, Token,
-};
+use bytes::BytesMut;
+use mio::{event::Event, Poll, Token};
 
 use crate::{
+    compress::{Compressor, Decompressor, ACK_FRAMED, ACK_RAW},
     config::OPTIONS,
-    proto::{CONNECT, Sock5Address, TrojanRequest},
+    dump::Dump,
+    family,
+    metrics::{
+        EGRESS_DENIED, FULL_HANDSHAKES, MAINTENANCE_REJECTED, QUOTA_REJECTED, RESUMED_HANDSHAKES,
+        TEST_DISCARDED, TEST_ECHOED,
+    },
+    notice::Notice,
+    padding::Unpadder,
+    proto::{
+        Metadata, RequestParseResult, Sock5Address, TrojanRequest, CONNECT, CONTROL, DISCARD, ECHO,
+        MAX_HEADER_LEN,
+    },
     resolver::DnsResolver,
     server::{
-        CHANNEL_BACKEND,
-        CHANNEL_CNT,
-        CHANNEL_PROXY,
-        tcp_backend::TcpBackend, tls_server::{Backend, PollEvent}, udp_backend::UdpBackend,
+        acl, control, egress, history, maintenance, quota,
+        targets::{self, Target},
+        tcp_backend::TcpBackend,
+        tls_server::{Backend, PollEvent},
+        udp_backend::UdpBackend,
+        udp_ports, CHANNEL_BACKEND, CHANNEL_CNT, CHANNEL_PROXY,
     },
-    status::StatusProvider,
+    status::{CloseReason, ConnStatus, StatusProvider},
     tls_conn::TlsConn,
+    trace::{Stage, Trace},
 };
 
+/// Longest time between two sweeps of a udp association, for the quota
+/// and the traffic summary
+const UDP_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
 enum Status {
     HandShake,
     DnsWait,
@@ -33,6 +51,8 @@ enum Status {
 pub struct Connection {
     index: usize,
     proxy: TlsConn,
+    src_addr: SocketAddr,
+    created: Instant,
     status: Status,
     sock5_addr: Sock5Address,
     command: u8,
@@ -40,15 +60,43 @@ pub struct Connection {
     backend: Option<Box<dyn Backend>>,
     target_addr: Option<SocketAddr>,
     data: Vec<u8>,
+    proxy_buffer: BytesMut,
+    /// Front of a request split across reads
+    partial: BytesMut,
+    /// Strips padding frames until the proxy ends them
+    unpadder: Option<Unpadder>,
+    unpadded: BytesMut,
+    /// Restores compressed payload of the proxy, behind the unpadder
+    decompressor: Option<Decompressor>,
+    decompressed: BytesMut,
     read_backend: bool,
     read_proxy: bool,
+    close_reason: Option<CloseReason>,
+    /// The proxy waits for a close notice, cleared once it is sent
+    notice: bool,
+    /// The ok notice carries the egress families
+    families: bool,
+    /// Resets pass through the tunnel, see [`RESET`](crate::proto::RESET)
+    reset: bool,
+    /// Metadata of the request, handed to the backend
+    metadata: Metadata,
+    drain_time: Option<Instant>,
+    /// Bytes of the proxy connection counted by the quota so far
+    accounted: usize,
+    trace: Trace,
+    /// When the target connect started, until its first event
+    connecting: Option<Instant>,
+    /// Since when the request waits for the port exhaustion cooldown
+    port_wait: Option<Instant>,
 }
 
 impl Connection {
-    pub fn new(index: usize, proxy: TlsConn) -> Connection {
+    pub fn new(index: usize, proxy: TlsConn, src_addr: SocketAddr) -> Connection {
         Connection {
             index,
             proxy,
+            src_addr,
+            created: Instant::now(),
             status: Status::HandShake,
             command: 0,
             sock5_addr: Sock5Address::None,
@@ -56,12 +104,46 @@ impl Connection {
             backend: None,
             target_addr: None,
             data: Vec::new(),
+            proxy_buffer: BytesMut::new(),
+            partial: BytesMut::new(),
+            unpadder: None,
+            unpadded: BytesMut::new(),
+            decompressor: None,
+            decompressed: BytesMut::new(),
             read_proxy: false,
             read_backend: false,
+            close_reason: None,
+            notice: false,
+            families: false,
+            reset: false,
+            metadata: Vec::new(),
+            drain_time: None,
+            accounted: 0,
+            trace: Trace::new(src_addr.ip()),
+            connecting: None,
+            port_wait: None,
         }
     }
 
+    pub fn dump(&self, dump: &mut Dump) {
+        let backend = self.backend.as_ref().map(|backend| backend.dump_state());
+        dump.line(format_args!(
+            "conn:{} {:?} target:{:?} proxy:{} backend:{} idle:{}s close:{:?}",
+            self.index,
+            self.status,
+            self.target_addr,
+            self.proxy.dump_state(),
+            backend.as_deref().unwrap_or("none"),
+            self.last_active_time.elapsed().as_secs(),
+            self.close_reason
+        ));
+    }
+
     pub fn destroy(&mut self, poll: &Poll) {
+        // still waiting means the target never connected
+        if self.notify(Notice::Timeout) {
+            self.proxy.do_send();
+        }
         self.proxy.shutdown();
         self.proxy.check_status(poll);
         if let Some(backend) = &mut self.backend {
@@ -70,11 +152,176 @@ impl Connection {
         }
     }
 
-    pub fn timeout(&self, recent_active_time: Instant) -> bool {
-        if let Some(backend) = &self.backend {
-            backend.timeout(self.last_active_time, recent_active_time)
+    /// Closes both sides at once, whatever they still have to send.
+    pub fn close_now(&mut self, poll: &Poll) {
+        self.proxy.close_now(poll);
+        if let Some(backend) = &mut self.backend {
+            backend.close_now(poll);
+        }
+    }
+
+    pub fn timeout(&self, recent_active_time: Instant) -> Option<CloseReason> {
+        if let Some(drain_time) = self.drain_time {
+            if recent_active_time - drain_time > OPTIONS.drain_duration {
+                return Some(CloseReason::DrainTimeout);
+            }
+        }
+        // with defer accept a client without a byte at accept already kept
+        // silent for the deferral, it gets as long again for its first one
+        let defer = OPTIONS.server_args().defer_accept_secs;
+        if defer > 0
+            && self.proxy.wire_received() == 0
+            && recent_active_time - self.created > Duration::from_secs(defer as u64)
+        {
+            return Some(CloseReason::FirstByteTimeout);
+        }
+        let idle = if let Some(backend) = &self.backend {
+            let idle = backend.timeout(self.last_active_time, recent_active_time);
+            if idle {
+                backend.expired();
+            }
+            idle
         } else {
             self.last_active_time.elapsed().as_secs() > OPTIONS.tcp_idle_timeout
+        };
+        if idle {
+            Some(CloseReason::IdleTimeout)
+        } else {
+            None
+        }
+    }
+
+    /// When the sweep should look at the connection again. Udp associations
+    /// wait for their idle or drain deadline, checked again from the real
+    /// times then, so datagrams meanwhile cost nothing. Other connections
+    /// are looked at on every sweep.
+    pub fn next_check(&self, now: Instant) -> Instant {
+        match &self.backend {
+            Some(backend) if matches!(self.status, Status::UDPForward) => {
+                let mut deadline = self.last_active_time + backend.get_timeout();
+                if let Some(drain_time) = self.drain_time {
+                    deadline = deadline.min(drain_time + OPTIONS.drain_duration);
+                }
+                deadline.min(now + UDP_CHECK_INTERVAL)
+            }
+            _ => now,
+        }
+    }
+
+    pub fn tick(&mut self) {
+        if let Some(backend) = self.backend.as_mut() {
+            backend.tick();
+        }
+    }
+
+    pub fn sample_kernel(&mut self) {
+        if let Some(backend) = self.backend.as_mut() {
+            backend.sample_kernel();
+        }
+    }
+
+    /// Returns the bytes to and from the proxy since the last call.
+    pub fn account(&mut self) -> u64 {
+        let total = self.proxy.sent() + self.proxy.received();
+        let bytes = total - self.accounted;
+        self.accounted = total;
+        bytes as u64
+    }
+
+    /// Whether the tls handshake is done and no request came yet, like on
+    /// an idle pooled connection of a proxy.
+    pub fn awaiting_request(&self) -> bool {
+        matches!(self.status, Status::HandShake)
+            && !self.proxy.handshaking()
+            && self.partial.is_empty()
+    }
+
+    pub fn set_close_reason(&mut self, reason: CloseReason) {
+        self.close_reason.replace(reason);
+    }
+
+    /// Sends the notice if the proxy waits for one, returns whether it
+    /// was written.
+    fn notify(&mut self, notice: Notice) -> bool {
+        if !self.notice {
+            return false;
+        }
+        self.notice = false;
+        let notice = match notice {
+            Notice::Ok if self.families => Notice::Families(family::egress()),
+            notice => notice,
+        };
+        log::debug!("connection:{} notify proxy:{}", self.index, notice);
+        self.proxy.write_session(&[notice.code()]).accepted()
+    }
+
+    /// Closes the proxy, after the notice of the rejection if it takes one.
+    fn reject(&mut self, notice: Notice) {
+        if self.notify(notice) {
+            self.proxy.do_send();
+            self.proxy.peer_closed();
+        } else {
+            self.proxy.shutdown();
+        }
+    }
+
+    /// Tells the proxy on the first target event whether it connected.
+    fn notify_connect(&mut self, event: &Event) {
+        if event.is_error() || event.is_write_closed() {
+            log::info!("connection:{} connect to target failed", self.index);
+            self.trace.add(Stage::Backend, "unreachable", String::new);
+            self.reject(Notice::Unreachable);
+        } else if event.is_writable() {
+            self.trace.add(Stage::Backend, "connected", String::new);
+            self.notify(Notice::Ok);
+        }
+    }
+
+    /// Counts the target connect in the target statistics on its first
+    /// event.
+    fn record_connect(&mut self, event: &Event) {
+        let started = match self.connecting.take() {
+            Some(started) => started,
+            None => return,
+        };
+        let target = match self.stats_target() {
+            Some(target) => target,
+            None => return,
+        };
+        if event.is_error() || event.is_write_closed() {
+            let err = self
+                .backend
+                .as_ref()
+                .and_then(|backend| backend.take_error())
+                .unwrap_or_else(|| std::io::ErrorKind::Other.into());
+            targets::failed(target, &err);
+        } else if event.is_writable() {
+            targets::connected(target, started.elapsed());
+        } else {
+            self.connecting.replace(started);
+        }
+    }
+
+    /// Key of the target statistics, None for the default backend.
+    fn stats_target(&self) -> Option<Target<'_>> {
+        match &self.sock5_addr {
+            Sock5Address::Domain(domain, _) => Some(Target::Domain(domain.as_str())),
+            Sock5Address::Socket(addr) => Some(Target::Addr(addr.ip())),
+            _ => None,
+        }
+    }
+
+    /// Counts the bytes relayed by a closed tcp target.
+    pub fn record_target(&self) {
+        if !matches!(self.status, Status::TCPForward) || self.connecting.is_some() {
+            return;
+        }
+        if let Some(target) = self.stats_target() {
+            targets::relayed(
+                target,
+                self.proxy.received() as u64,
+                self.proxy.sent() as u64,
+            );
         }
     }
 
@@ -82,7 +329,7 @@ impl Connection {
         token.0 % CHANNEL_CNT == CHANNEL_PROXY
     }
 
-    pub fn ready(&mut self, poll: &Poll, event: PollEvent, resolver: Option<&mut DnsResolver>) {
+    pub fn ready(&mut self, poll: &Poll, event: PollEvent, mut resolver: Option<&mut DnsResolver>) {
         self.last_active_time = Instant::now();
 
         match event {
@@ -91,11 +338,14 @@ impl Connection {
                     if event.is_readable() {
                         let writable = if let Some(backend) = self.backend.as_ref() {
                             backend.writable()
+                        } else if self.command == ECHO {
+                            // the echo waits for the proxy to take it like a target
+                            self.proxy.writable()
                         } else {
                             true
                         };
                         if writable {
-                            self.try_read_proxy(poll, resolver);
+                            self.try_read_proxy(poll, resolver.as_deref_mut());
                         } else {
                             log::trace!(
                                 "backend connection:{} is not writable, stop reading from proxy",
@@ -107,31 +357,47 @@ impl Connection {
                     if event.is_writable() {
                         self.proxy.established();
                         self.try_send_proxy();
+                        if self.command == ECHO && self.read_proxy && self.proxy.writable() {
+                            log::trace!(
+                                "proxy connection:{} is writable, restore the echo",
+                                self.index
+                            );
+                            self.read_proxy = false;
+                            self.try_read_proxy(poll, resolver.as_deref_mut());
+                        }
                         if self.proxy.writable() && self.read_backend {
                             if let Some(backend) = self.backend.as_mut() {
-                                backend.do_read(&mut self.proxy);
+                                backend.do_read(&mut self.proxy, poll);
                             }
                             log::trace!(
                                 "proxy connection:{} is writable, restore reading from backend",
                                 self.index
                             );
-                            self.read_backend = false;
+                            self.read_backend = !self.proxy.writable();
                         }
                     }
                 } else {
+                    if self.connecting.is_some() {
+                        self.record_connect(event);
+                    }
+                    if self.notice && matches!(self.status, Status::TCPForward) {
+                        self.notify_connect(event);
+                    }
                     match self.status {
                         Status::UDPForward | Status::TCPForward => {
                             if let Some(backend) = self.backend.as_mut() {
                                 if event.is_readable() {
                                     if self.proxy.writable() {
-                                        backend.do_read(&mut self.proxy);
+                                        backend.do_read(&mut self.proxy, poll);
+                                        // a full session paused the read
+                                        self.read_backend = !self.proxy.writable();
                                     } else {
                                         log::trace!("proxy connection:{} is not writable, stop reading from backend", self.index);
                                         self.read_backend = true;
                                     }
                                 }
                                 if event.is_writable() {
-                                    backend.dispatch(&[]);
+                                    backend.dispatch(&[], poll);
                                     if backend.writable() && self.read_proxy {
                                         log::trace!("backend connection:{} is writable, restore reading from proxy", self.index);
                                         self.try_read_proxy(poll, resolver);
@@ -150,12 +416,29 @@ impl Connection {
         }
 
         if let Some(backend) = &mut self.backend {
-            if self.proxy.is_shutdown() {
-                backend.peer_closed();
+            if self.proxy.is_shutdown() && !backend.is_shutdown() {
+                if self.reset && self.proxy.aborted() {
+                    backend.abort();
+                } else {
+                    backend.peer_closed();
+                }
             }
             if backend.is_shutdown() {
                 self.proxy.peer_closed();
             }
+            if self.drain_time.is_none()
+                && (matches!(self.proxy.get_status(), ConnStatus::PeerClosed)
+                    || matches!(backend.get_status(), ConnStatus::PeerClosed))
+            {
+                self.drain_time.replace(Instant::now());
+            }
+            // a writable event only comes after a blocked write
+            if matches!(backend.get_status(), ConnStatus::PeerClosed) {
+                backend.dispatch(&[], poll);
+            }
+        }
+        if matches!(self.proxy.get_status(), ConnStatus::PeerClosed) {
+            self.proxy.do_send();
         }
         self.proxy.check_status(poll);
         if let Some(backend) = &mut self.backend {
@@ -165,7 +448,7 @@ impl Connection {
 
     pub fn try_resolve(&mut self, poll: &Poll, ip: Option<IpAddr>) {
         if let Status::DnsWait = self.status {
-            if let Sock5Address::Domain(domain, port) = &self.sock5_addr {
+            if let Some((domain, port)) = self.target_host() {
                 if let Some(address) = ip {
                     log::debug!(
                         "connection:{} got resolve result {} = {}",
@@ -173,12 +456,23 @@ impl Connection {
                         domain,
                         address
                     );
-                    let addr = SocketAddr::new(address, *port);
+                    let addr = SocketAddr::new(address, port);
+                    self.trace
+                        .add(Stage::Resolve, "resolved", || address.to_string());
                     self.target_addr.replace(addr);
                     self.dispatch(&[], poll, None);
+                } else if let Sock5Address::None = self.sock5_addr {
+                    log::error!(
+                        "connection:{} resolve remote address host:{} failed, connection closed",
+                        self.index,
+                        domain
+                    );
+                    self.trace.add(Stage::Resolve, "failed", String::new);
+                    self.proxy.shutdown();
                 } else {
                     log::error!("connection:{} resolve host:{} failed", self.index, domain);
-                    self.proxy.shutdown();
+                    self.trace.add(Stage::Resolve, "failed", String::new);
+                    self.reject(Notice::Unreachable);
                 }
             } else {
                 log::error!("connection:{} got bug, not a resolver status", self.index);
@@ -196,15 +490,90 @@ impl Connection {
     }
 
     fn try_read_proxy(&mut self, poll: &Poll, resolver: Option<&mut DnsResolver>) {
-        if let Some(buffer) = self.proxy.do_read() {
-            self.dispatch(buffer.as_slice(), poll, resolver);
+        let mut buffer = std::mem::take(&mut self.proxy_buffer);
+        if self.proxy.do_read_into(&mut buffer) > 0 {
+            self.dispatch(buffer.as_ref(), poll, resolver);
         }
+        buffer.clear();
+        self.proxy_buffer = buffer;
+    }
+
+    fn record_handshake(&self) {
+        if self.proxy.resumed() {
+            RESUMED_HANDSHAKES.inc();
+        } else {
+            FULL_HANDSHAKES.inc();
+        }
+        let info = self.proxy.tls_info();
+        log::debug!("connection:{} negotiated {}", self.index, info);
+        control::record(&info);
     }
 
     fn try_handshake(&mut self, buffer: &mut &[u8], resolver: &mut &mut DnsResolver) -> bool {
-        if let Some(request) = TrojanRequest::parse(buffer) {
+        let request = match TrojanRequest::parse(buffer, OPTIONS.server_args().protocol_compat) {
+            RequestParseResult::Request(request) => Some(request),
+            RequestParseResult::Continued if buffer.len() < MAX_HEADER_LEN => {
+                log::debug!(
+                    "connection:{} got {} bytes of a request, waiting for more",
+                    self.index,
+                    buffer.len()
+                );
+                self.partial.extend_from_slice(buffer);
+                return false;
+            }
+            _ => None,
+        };
+        let trojan = request.is_some();
+        if let Some(request) = request {
+            if let Some(deviation) = request.deviation {
+                log::info!(
+                    "connection:{} accepted request with deviation {}",
+                    self.index,
+                    deviation.name()
+                );
+            }
+            // the notice of the quota follows the answer to compression
+            if request.compressed && request.command == CONNECT {
+                self.accept_compression();
+            }
+            self.notice = request.notice && request.command == CONNECT;
+            self.families = request.families;
+            self.reset = request.reset;
+            if !request.metadata.is_empty() {
+                log::debug!(
+                    "connection:{} got request metadata {:?}",
+                    self.index,
+                    request.metadata
+                );
+                let metadata = &request.metadata;
+                self.trace
+                    .add(Stage::Request, "metadata", || format!("{:?}", metadata));
+            }
+            self.metadata = request.metadata;
+            if request.command != CONTROL && quota::exceeded() {
+                QUOTA_REJECTED.inc();
+                log::warn!("connection:{} closed, traffic quota is used up", self.index);
+                self.close_reason.replace(CloseReason::QuotaExceeded);
+                self.trace
+                    .add(Stage::Request, "refused", || "quota".to_owned());
+                self.reject(Notice::Quota);
+                return false;
+            }
+            if request.command != CONTROL && maintenance::enabled() {
+                MAINTENANCE_REJECTED.inc();
+                log::info!("connection:{} rejected, server in maintenance", self.index);
+                self.close_reason.replace(CloseReason::Maintenance);
+                self.trace
+                    .add(Stage::Request, "refused", || "maintenance".to_owned());
+                self.reject(Notice::Maintenance);
+                return false;
+            }
             self.command = request.command;
             self.sock5_addr = request.address;
+            if request.padded {
+                log::debug!("connection:{} got padded request", self.index);
+                self.unpadder.replace(Unpadder::default());
+            }
             *buffer = request.payload;
         } else {
             log::debug!(
@@ -214,19 +583,30 @@ impl Connection {
             self.command = CONNECT;
             self.sock5_addr = Sock5Address::None;
         }
+        if self.trace.enabled() {
+            let decision = match self.command {
+                _ if !trojan => "passthrough",
+                CONNECT => "connect",
+                CONTROL => "control",
+                ECHO => "echo",
+                DISCARD => "discard",
+                _ => "associate",
+            };
+            let target = self.target_text();
+            self.trace.add(Stage::Request, decision, || {
+                target.unwrap_or_else(|| "default".to_owned())
+            });
+        }
+        if matches!(self.command, ECHO | DISCARD) {
+            // answered without a target, the address is ignored
+            return true;
+        }
         match &self.sock5_addr {
-            Sock5Address::Domain(domain, port) => {
-                if self.command != CONNECT {
-                    //udp associate bind at 0.0.0.0:0, ignore all domain
-                    return true;
-                }
-                log::debug!("connection:{} has to resolve {}", self.index, domain);
-                if let Some(ip) = (*resolver).query_dns(domain.as_str()) {
-                    self.target_addr.replace(SocketAddr::new(ip, *port));
-                } else {
-                    resolver.resolve(domain.clone(), Some(self.target_token()));
-                }
+            Sock5Address::Domain(_, _) if self.command != CONNECT => {
+                //udp associate bind at 0.0.0.0:0, ignore all domain
+                return true;
             }
+            Sock5Address::Domain(..) => self.resolve_target(resolver),
             Sock5Address::Socket(address) => {
                 log::debug!(
                     "connection:{} got resolved target address:{}",
@@ -235,6 +615,7 @@ impl Connection {
                 );
                 self.target_addr.replace(*address);
             }
+            Sock5Address::None if OPTIONS.back_host.is_some() => self.resolve_target(resolver),
             Sock5Address::None => {
                 log::debug!(
                     "connection:{} got default target address:{}",
@@ -250,20 +631,164 @@ impl Connection {
         true
     }
 
-    fn dispatch(&mut self, mut buffer: &[u8], poll: &Poll, mut resolver: Option<&mut DnsResolver>) {
+    /// Answers a compressed request before anything else is sent, the
+    /// payload of the proxy is framed either way.
+    fn accept_compression(&mut self) {
+        log::debug!("connection:{} got compressed request", self.index);
+        self.decompressor.replace(Decompressor::default());
+        if OPTIONS.server_args().compression {
+            let _ = self.proxy.write_session(&[ACK_FRAMED]);
+            self.proxy.set_compressor(Compressor::default());
+        } else {
+            let _ = self.proxy.write_session(&[ACK_RAW]);
+        }
+    }
+
+    /// Keeps the summary of the closed connection for the history
+    /// command, management connections and ones without a request aside.
+    pub fn record_history(&self) {
+        if self.command == CONTROL {
+            return;
+        }
+        let target = match self.target_text() {
+            Some(target) => target,
+            None if self.backend.is_some() => "default".to_owned(),
+            None => return,
+        };
+        history::record(
+            self.src_addr,
+            target.as_str(),
+            self.proxy.sent() as u64,
+            self.proxy.received() as u64,
+            self.created.elapsed(),
+            self.close_reason,
+        );
+    }
+
+    /// The requested target, None without a trojan request.
+    fn target_text(&self) -> Option<String> {
+        match &self.sock5_addr {
+            Sock5Address::Domain(domain, port) => Some(format!("{}:{}", domain, port)),
+            Sock5Address::Socket(addr) => Some(addr.to_string()),
+            Sock5Address::Endpoint(endpoint) => Some(endpoint.to_string()),
+            Sock5Address::None => None,
+        }
+    }
+
+    /// Logs the decisions of a traced connection once it is closed.
+    pub fn log_trace(&self) {
+        if self.trace.enabled() {
+            log::info!(
+                "connection:{} from:{} trace {}",
+                self.index,
+                self.src_addr,
+                self.trace
+            );
+        }
+    }
+
+    /// Bytes on the wire per payload byte sent and received, if compressed.
+    pub fn compression_ratio(&self) -> Option<(f64, f64)> {
+        let received = self.decompressor.as_ref()?.ratio();
+        let sent = self.proxy.compression_ratio().map_or(1.0, |(sent, _)| sent);
+        Some((sent, received))
+    }
+
+    /// Host name and port of the target if it has to be resolved, the
+    /// default backend given by name included.
+    fn target_host(&self) -> Option<(&str, u16)> {
+        match &self.sock5_addr {
+            Sock5Address::Domain(domain, port) => Some((domain.as_str(), *port)),
+            Sock5Address::None => OPTIONS
+                .back_host
+                .as_ref()
+                .map(|(host, port)| (host.as_str(), *port)),
+            _ => None,
+        }
+    }
+
+    /// Takes the target address from the dns cache, or waits for it while
+    /// the request data is cached.
+    fn resolve_target(&mut self, resolver: &mut DnsResolver) {
+        let (host, port) = self.target_host().unwrap();
+        log::debug!("connection:{} has to resolve {}", self.index, host);
+        if let Some(ip) = resolver.query_dns(host) {
+            self.trace.add(Stage::Resolve, "cached", || ip.to_string());
+            self.target_addr.replace(SocketAddr::new(ip, port));
+        } else {
+            resolver.resolve(host.to_owned(), Some(self.target_token()));
+            self.trace.add(Stage::Resolve, "queried", String::new);
+        }
+    }
+
+    fn dispatch(&mut self, buffer: &[u8], poll: &Poll, resolver: Option<&mut DnsResolver>) {
+        let unpadder = match self.unpadder.as_mut() {
+            Some(unpadder) => unpadder,
+            None => return self.inflate(buffer, poll, resolver),
+        };
+        let mut unpadded = std::mem::take(&mut self.unpadded);
+        if !unpadder.strip(buffer, &mut unpadded) {
+            log::warn!("connection:{} got invalid padding", self.index);
+            self.proxy.shutdown();
+            return;
+        }
+        if unpadder.finished() {
+            self.unpadder = None;
+        }
+        self.inflate(unpadded.as_ref(), poll, resolver);
+        unpadded.clear();
+        self.unpadded = unpadded;
+    }
+
+    fn inflate(&mut self, buffer: &[u8], poll: &Poll, resolver: Option<&mut DnsResolver>) {
+        let decompressor = match self.decompressor.as_mut() {
+            Some(decompressor) => decompressor,
+            None => return self.forward(buffer, poll, resolver),
+        };
+        let mut decompressed = std::mem::take(&mut self.decompressed);
+        if !decompressor.strip(buffer, &mut decompressed) {
+            log::warn!("connection:{} got invalid compression frame", self.index);
+            self.proxy.shutdown();
+            return;
+        }
+        self.forward(decompressed.as_ref(), poll, resolver);
+        decompressed.clear();
+        self.decompressed = decompressed;
+    }
+
+    fn forward(&mut self, buffer: &[u8], poll: &Poll, mut resolver: Option<&mut DnsResolver>) {
         log::debug!(
             "connection:{} dispatch {} bytes request data",
             self.index,
             buffer.len()
         );
+        // a split request is parsed again from its first byte
+        let partial;
+        let split = !self.partial.is_empty();
+        let mut buffer = if split {
+            self.partial.extend_from_slice(buffer);
+            partial = std::mem::take(&mut self.partial);
+            partial.as_ref()
+        } else {
+            buffer
+        };
         loop {
             match self.status {
                 Status::HandShake => {
+                    if !split {
+                        self.record_handshake();
+                    }
                     if self.try_handshake(&mut buffer, resolver.as_mut().unwrap()) {
                         self.status = Status::DnsWait;
+                        if self.unpadder.is_some() || self.decompressor.is_some() {
+                            // the rest of this record is framed already
+                            return self.dispatch(buffer, poll, resolver);
+                        }
                         continue;
                     }
                 }
+                Status::DnsWait if self.command == CONTROL => self.control(buffer),
+                Status::DnsWait if matches!(self.command, ECHO | DISCARD) => self.test(buffer),
                 Status::DnsWait => {
                     if self.command == CONNECT {
                         //if dns query is not done, cache data now
@@ -272,6 +797,11 @@ impl Connection {
                             self.proxy.shutdown();
                         } else if self.target_addr.is_none() {
                             log::warn!("connection:{} dns query not done yet", self.index);
+                        } else if !self.egress_allowed() {
+                            self.close_reason.replace(CloseReason::EgressDenied);
+                            self.reject(Notice::Denied);
+                        } else if egress::cooling(Instant::now()) {
+                            self.wait_port();
                         } else if self.try_setup_tcp_target(poll) {
                             buffer = &[];
                             self.status = Status::TCPForward;
@@ -284,7 +814,7 @@ impl Connection {
                 }
                 _ => {
                     if let Some(backend) = self.backend.as_mut() {
-                        backend.dispatch(buffer);
+                        backend.dispatch(buffer, poll);
                     } else {
                         log::error!("connection:{} has no backend yet", self.index);
                     }
@@ -294,22 +824,99 @@ impl Connection {
         }
     }
 
+    /// Answers the management command line in `buffer`, then closes.
+    fn control(&mut self, buffer: &[u8]) {
+        if !OPTIONS.server_args().control {
+            log::warn!("connection:{} sent a command, control is off", self.index);
+            self.proxy.shutdown();
+            return;
+        }
+        self.data.extend_from_slice(buffer);
+        let line = match self.data.iter().position(|byte| *byte == b'\n') {
+            Some(position) => String::from_utf8_lossy(&self.data[..position]).into_owned(),
+            None => {
+                if self.data.len() > control::MAX_COMMAND_LEN {
+                    log::warn!("connection:{} sent a too long command", self.index);
+                    self.proxy.shutdown();
+                }
+                return;
+            }
+        };
+        log::info!("connection:{} runs command {}", self.index, line.trim());
+        let response = control::handle(line.trim());
+        self.data.clear();
+        if self.proxy.write_session(response.as_bytes()).accepted() {
+            self.proxy.do_send();
+            self.proxy.peer_closed();
+        }
+    }
+
+    /// Answers the echo and discard test commands without a target, the
+    /// echo pauses reading while the proxy doesn't take it.
+    fn test(&mut self, buffer: &[u8]) {
+        if !OPTIONS.server_args().test_backends {
+            log::warn!(
+                "connection:{} sent a test command, test backends are off",
+                self.index
+            );
+            self.proxy.shutdown();
+            return;
+        }
+        if self.command == DISCARD {
+            TEST_DISCARDED.add(buffer.len());
+        } else if !buffer.is_empty() && self.proxy.write_session(buffer).accepted() {
+            TEST_ECHOED.add(buffer.len());
+        }
+    }
+
+    /// Checks trojan targets against the egress policy, the default
+    /// backend for non trojan requests is always allowed.
+    fn egress_allowed(&mut self) -> bool {
+        if let Sock5Address::None = self.sock5_addr {
+            return true;
+        }
+        let addr = self.target_addr.unwrap();
+        if acl::allowed(&addr) {
+            self.trace
+                .add(Stage::Acl, "allowed", || acl::explain(&addr));
+            true
+        } else {
+            self.trace.add(Stage::Acl, "denied", || acl::explain(&addr));
+            EGRESS_DENIED.inc();
+            log::info!(
+                "connection:{} target {} denied by egress policy",
+                self.index,
+                addr
+            );
+            false
+        }
+    }
+
     fn try_setup_tcp_target(&mut self, poll: &Poll) -> bool {
         log::debug!(
             "connection:{} make a target connection to {}",
             self.index,
             self.target_addr.unwrap()
         );
-        match TcpStream::connect(self.target_addr.unwrap()) {
+        let addr = self.target_addr.unwrap();
+        match OPTIONS.backend_tuning.connect_from(
+            "backend",
+            addr,
+            egress::source(addr),
+            OPTIONS.server_args().bind_address_no_port,
+        ) {
             Ok(tcp_target) => {
                 match TcpBackend::new(tcp_target, self.index, self.target_token(), poll) {
                     Ok(mut backend) => {
+                        backend.set_reset(self.reset);
+                        backend.set_metadata(&self.metadata);
                         if !self.data.is_empty() {
-                            backend.dispatch(self.data.as_slice());
+                            backend.dispatch(self.data.as_slice(), poll);
                             self.data.clear();
                             self.data.shrink_to_fit();
                         }
                         self.backend.replace(Box::new(backend));
+                        self.connecting.replace(Instant::now());
                     }
                     Err(err) => {
                         log::error!("connection:{} setup backend failed:{:?}", self.index, err);
@@ -317,18 +924,64 @@ impl Connection {
                     }
                 }
             }
+            Err(err) if egress::exhausted(&err) => {
+                log::debug!("connection:{} connect to target failed:{}", self.index, err);
+                egress::cool_down(Instant::now());
+                self.wait_port();
+                return false;
+            }
             Err(err) => {
                 log::warn!("connection:{} connect to target failed:{}", self.index, err);
-                self.proxy.shutdown();
+                if let Some(target) = self.stats_target() {
+                    targets::failed(target, &err);
+                }
+                self.trace
+                    .add(Stage::Backend, "unreachable", || err.to_string());
+                self.reject(Notice::Unreachable);
                 return false;
             }
         }
         true
     }
 
+    fn wait_port(&mut self) {
+        if self.port_wait.is_none() {
+            log::debug!("connection:{} waits for free local ports", self.index);
+            self.trace.add(Stage::Backend, "port wait", String::new);
+            self.port_wait.replace(Instant::now());
+        }
+    }
+
+    /// Connects a request which waited for the port exhaustion cooldown,
+    /// or refuses it once it waited too long.
+    pub fn retry_target(&mut self, poll: &Poll) {
+        let since = match self.port_wait {
+            Some(since) => since,
+            None => return,
+        };
+        let now = Instant::now();
+        if now - since > egress::MAX_WAIT {
+            log::warn!(
+                "connection:{} gave up waiting for free local ports",
+                self.index
+            );
+            self.port_wait = None;
+            self.trace
+                .add(Stage::Backend, "unreachable", || "no free port".to_owned());
+            self.reject(Notice::Unreachable);
+        } else if !egress::cooling(now) {
+            self.port_wait = None;
+            self.dispatch(&[], poll, None);
+        }
+    }
+
+    pub fn has_backend(&self) -> bool {
+        self.backend.is_some()
+    }
+
     fn try_setup_udp_target(&mut self, poll: &Poll) -> bool {
         log::debug!("connection:{} got udp connection", self.index);
-        match UdpSocket::bind(OPTIONS.empty_addr.unwrap()) {
+        match udp_ports::bind(udp_ports::source(self.proxy.local_addr())) {
             Err(err) => {
                 log::error!("connection:{} bind udp socket failed:{}", self.index, err);
                 self.proxy.shutdown();
@@ -336,7 +989,8 @@ impl Connection {
             }
             Ok(udp_target) => {
                 match UdpBackend::new(udp_target, self.index, self.target_token(), poll) {
-                    Ok(backend) => {
+                    Ok(mut backend) => {
+                        backend.set_metadata(&self.metadata);
                         self.backend.replace(Box::new(backend));
                     }
                     Err(err) => {
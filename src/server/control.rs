@@ -0,0 +1,109 @@
+//! Management commands sent by `trojan ctl` as trojan requests.
+//!
+//! Commands are read only unless the server runs with --control-mutating,
+//! which also allows the history and targets commands, since they show
+//! client and target addresses.
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{
+    cert::CertInfo,
+    config::OPTIONS,
+    metrics::COUNTERS,
+    reload,
+    server::{history, kernel_stats, maintenance, quota, targets},
+    tls_conn::TlsInfo,
+    trace, udp_loss, worker,
+};
+
+/// Longest command line accepted
+pub const MAX_COMMAND_LEN: usize = 256;
+
+lazy_static::lazy_static! {
+    /// Accepted connections by negotiated parameters
+    static ref NEGOTIATED: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+    static ref CERTIFICATE: Mutex<String> = Mutex::new(String::new());
+}
+
+/// Counts an accepted connection for the `tls` command.
+pub fn record(info: &TlsInfo) {
+    *NEGOTIATED
+        .lock()
+        .unwrap()
+        .entry(info.to_string())
+        .or_default() += 1;
+}
+
+pub fn set_certificate(cert: &CertInfo) {
+    *CERTIFICATE.lock().unwrap() = cert.to_string();
+}
+
+fn tls() -> String {
+    let negotiated = NEGOTIATED
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(info, count)| format!("{:?}:{}", info, count))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"certificate\":{:?},\"negotiated\":{{{}}}}}",
+        CERTIFICATE.lock().unwrap().as_str(),
+        negotiated
+    )
+}
+
+fn stats() -> String {
+    let counters = COUNTERS
+        .iter()
+        .map(|counter| format!("\"{}\":{}", counter.name(), counter.get()))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"counters\":{{{}}},\"workers\":{},\"kernel\":{},\"maintenance\":{}}}",
+        counters,
+        worker::stats(),
+        kernel_stats::stats(),
+        maintenance::enabled()
+    )
+}
+
+/// Returns the json response of `command`.
+pub fn handle(command: &str) -> String {
+    let mutating = OPTIONS.server_args().control_mutating;
+    match command {
+        "stats" => stats(),
+        "tls" => tls(),
+        "config" => format!("{{\"config\":{:?}}}", OPTIONS.describe()),
+        "list" => format!("{{\"udp\":{}}}", udp_loss::list()),
+        "usage" => quota::usage(),
+        "trace" => trace::command(""),
+        "maintenance" => maintenance::command(""),
+        "history" if mutating => history::query(""),
+        "targets" if mutating => targets::query(""),
+        command if command.starts_with("history ") && mutating => history::query(&command[8..]),
+        command if command.starts_with("targets ") && mutating => targets::query(&command[8..]),
+        "usage reset" if mutating => {
+            quota::reset();
+            quota::usage()
+        }
+        "reload" if mutating => {
+            reload::request();
+            "{\"ok\":true}".to_owned()
+        }
+        command if command.starts_with("trace ") && mutating => trace::command(&command[6..]),
+        command if command.starts_with("maintenance ") && mutating => {
+            maintenance::command(&command[12..])
+        }
+        "reload" | "usage reset" => "{\"error\":\"mutating commands are disabled\"}".to_owned(),
+        "history" | "targets" => {
+            "{\"error\":\"history and targets need --control-mutating\"}".to_owned()
+        }
+        command if command.starts_with("history ") || command.starts_with("targets ") => {
+            "{\"error\":\"history and targets need --control-mutating\"}".to_owned()
+        }
+        command if command.starts_with("trace ") || command.starts_with("maintenance ") => {
+            "{\"error\":\"mutating commands are disabled\"}".to_owned()
+        }
+        _ => "{\"error\":\"unknown command\"}".to_owned(),
+    }
+}
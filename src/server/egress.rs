@@ -0,0 +1,142 @@
+//! Source addresses of target connections, and a cooldown when the
+//! ephemeral ports run out.
+//!
+//! `--egress-source-ip` may be given several times, target connects take
+//! the next address of their family in turn, so each address adds its own
+//! port range. `--bind-address-no-port` sets IP_BIND_ADDRESS_NO_PORT on
+//! them, the port is then picked at connect for the whole 4-tuple instead
+//! of at bind, linux only. A connect failing with EADDRNOTAVAIL or
+//! EADDRINUSE means the ports are used up, retrying right away only burns
+//! cpu: no target connection is made for [`COOLDOWN`] while accepts go on.
+//! Requests arriving meanwhile wait, they are retried on the timeout check
+//! and refused after [`MAX_WAIT`]. Each exhaustion counts
+//! `port_exhaustion`, a warning with the established target connections is
+//! logged at most every [`WARN_INTERVAL`].
+use std::{
+    io::{Error, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{config::OPTIONS, metrics::PORT_EXHAUSTION};
+
+pub const COOLDOWN: Duration = Duration::from_secs(1);
+/// Longest wait of a request for the cooldown to end
+pub const MAX_WAIT: Duration = Duration::from_secs(5);
+pub const WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+static NEXT_V4: AtomicUsize = AtomicUsize::new(0);
+static NEXT_V6: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Default)]
+struct Exhaustion {
+    until: Option<Instant>,
+    /// Failures since the last warning
+    failures: u64,
+    warned: Option<Instant>,
+}
+
+lazy_static::lazy_static! {
+    static ref EXHAUSTION: Mutex<Exhaustion> = Mutex::new(Exhaustion::default());
+}
+
+/// Picks the `n`th address of the family of `target` among `sources`,
+/// wrapping around.
+fn pick(sources: &[IpAddr], target: SocketAddr, n: usize) -> Option<IpAddr> {
+    let same = |ip: &&IpAddr| ip.is_ipv4() == target.is_ipv4();
+    let count = sources.iter().filter(same).count();
+    if count == 0 {
+        return None;
+    }
+    sources.iter().filter(same).nth(n % count).copied()
+}
+
+/// The source address of the next connect to `target`, None to leave it
+/// to the routing table.
+pub fn source(target: SocketAddr) -> Option<IpAddr> {
+    let sources = &OPTIONS.server_args().egress_source_ip;
+    if sources.is_empty() {
+        return None;
+    }
+    let next = if target.is_ipv4() { &NEXT_V4 } else { &NEXT_V6 };
+    pick(sources, target, next.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Whether a connect error means the local ports are used up.
+pub fn exhausted(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::AddrNotAvailable | ErrorKind::AddrInUse
+    )
+}
+
+/// Starts the cooldown after a connect ran out of ports.
+pub fn cool_down(now: Instant) {
+    PORT_EXHAUSTION.inc();
+    let mut exhaustion = EXHAUSTION.lock().unwrap();
+    exhaustion.until.replace(now + COOLDOWN);
+    exhaustion.failures += 1;
+}
+
+/// Whether new target connections wait.
+pub fn cooling(now: Instant) -> bool {
+    EXHAUSTION
+        .lock()
+        .unwrap()
+        .until
+        .is_some_and(|until| now < until)
+}
+
+/// Logs the failures since the last warning, if it is time for one.
+pub fn warn(now: Instant, established: impl FnOnce() -> usize) {
+    let mut exhaustion = EXHAUSTION.lock().unwrap();
+    if exhaustion.failures == 0
+        || exhaustion
+            .warned
+            .is_some_and(|warned| now - warned < WARN_INTERVAL)
+    {
+        return;
+    }
+    log::warn!(
+        "{} target connects ran out of local ports with {} established, pausing new ones for {:?}, more --egress-source-ip or --bind-address-no-port may help",
+        exhaustion.failures,
+        established(),
+        COOLDOWN
+    );
+    exhaustion.failures = 0;
+    exhaustion.warned.replace(now);
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::{
+        io::{Error, ErrorKind},
+        net::IpAddr,
+    };
+
+    use crate::server::egress::{exhausted, pick};
+
+    #[test]
+    fn test_pick() {
+        let sources: Vec<IpAddr> = ["10.0.0.1", "2001:db8::1", "10.0.0.2"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        let v4 = "93.184.216.34:443".parse().unwrap();
+        let picked: Vec<_> = (0..3)
+            .map(|n| pick(&sources, v4, n).unwrap().to_string())
+            .collect();
+        assert_eq!(picked, vec!["10.0.0.1", "10.0.0.2", "10.0.0.1"]);
+        let v6 = "[2001:db8::2]:443".parse().unwrap();
+        assert_eq!(pick(&sources, v6, 5).unwrap().to_string(), "2001:db8::1");
+        assert_eq!(pick(&sources[..1], v6, 0), None);
+
+        assert!(exhausted(&Error::from(ErrorKind::AddrNotAvailable)));
+        assert!(!exhausted(&Error::from(ErrorKind::ConnectionRefused)));
+    }
+}
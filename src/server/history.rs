@@ -0,0 +1,213 @@
+//! Summaries of recently closed connections for the `history` command.
+//!
+//! The last `--history-size` connections are kept in memory, the oldest
+//! one goes once the ring is full. `history [n] [filter]` returns the most
+//! recent `n` of them, newest first, optionally only the ones whose target
+//! contains `filter` or which closed for the reason named `filter`. Targets
+//! are interned, entries to one target share its name. A size of 0 keeps
+//! nothing, for deployments which must not remember targets.
+use std::{
+    collections::{HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config::OPTIONS, status::CloseReason};
+
+/// Entries returned by a query when no count is given
+pub const DEFAULT_QUERY: usize = 20;
+/// Entries returned at most by a query, to fit in one response
+pub const MAX_QUERY: usize = 200;
+
+pub struct Entry {
+    /// Seconds since the epoch when the connection closed
+    pub closed: u64,
+    pub source: SocketAddr,
+    pub target: Arc<str>,
+    /// Bytes sent to and received from the proxy
+    pub sent: u64,
+    pub received: u64,
+    pub duration: Duration,
+    pub reason: Option<CloseReason>,
+}
+
+impl Entry {
+    fn reason(&self) -> String {
+        self.reason
+            .map_or_else(|| "closed".to_owned(), |reason| format!("{:?}", reason))
+    }
+
+    fn matches(&self, filter: &str) -> bool {
+        filter.is_empty()
+            || self.target.contains(filter)
+            || self.reason().eq_ignore_ascii_case(filter)
+    }
+
+    fn json(&self) -> String {
+        format!(
+            "{{\"closed\":{},\"source\":\"{}\",\"target\":{:?},\"sent\":{},\"received\":{},\"duration_ms\":{},\"reason\":\"{}\"}}",
+            self.closed,
+            self.source,
+            self.target,
+            self.sent,
+            self.received,
+            self.duration.as_millis(),
+            self.reason()
+        )
+    }
+}
+
+#[derive(Default)]
+struct History {
+    entries: VecDeque<Entry>,
+    targets: HashSet<Arc<str>>,
+}
+
+impl History {
+    fn intern(&mut self, target: &str) -> Arc<str> {
+        if let Some(target) = self.targets.get(target) {
+            return target.clone();
+        }
+        let target: Arc<str> = Arc::from(target);
+        self.targets.insert(target.clone());
+        target
+    }
+
+    fn push(&mut self, entry: Entry, size: usize) {
+        while self.entries.len() >= size {
+            let oldest = match self.entries.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            // the set and the entry are the last owners
+            if Arc::strong_count(&oldest.target) == 2 {
+                self.targets.remove(&oldest.target);
+            }
+        }
+        self.entries.push_back(entry);
+    }
+
+    fn query(&self, count: usize, filter: &str) -> String {
+        let entries = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.matches(filter))
+            .take(count.min(MAX_QUERY))
+            .map(Entry::json)
+            .collect::<Vec<_>>()
+            .join(",");
+        format!("[{}]", entries)
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref HISTORY: Mutex<History> = Mutex::new(History::default());
+}
+
+/// Keeps the summary of a closed connection, if the history is enabled.
+pub fn record(
+    source: SocketAddr,
+    target: &str,
+    sent: u64,
+    received: u64,
+    duration: Duration,
+    reason: Option<CloseReason>,
+) {
+    let size = OPTIONS.server_args().history_size;
+    if size == 0 {
+        return;
+    }
+    let closed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let mut history = HISTORY.lock().unwrap();
+    let target = history.intern(target);
+    let entry = Entry {
+        closed,
+        source,
+        target,
+        sent,
+        received,
+        duration,
+        reason,
+    };
+    history.push(entry, size);
+}
+
+/// Answers `history [n] [filter]`, `args` is what follows the command.
+pub fn query(args: &str) -> String {
+    if OPTIONS.server_args().history_size == 0 {
+        return "{\"error\":\"history is disabled\"}".to_owned();
+    }
+    let mut args = args.split_whitespace();
+    let mut first = args.next().unwrap_or_default();
+    let count = match first.parse() {
+        Ok(count) => {
+            first = args.next().unwrap_or_default();
+            count
+        }
+        Err(_) => DEFAULT_QUERY,
+    };
+    HISTORY.lock().unwrap().query(count, first)
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        server::history::{Entry, History},
+        status::CloseReason,
+    };
+
+    #[allow(dead_code)]
+    fn entry(history: &mut History, target: &str, reason: Option<CloseReason>) -> Entry {
+        Entry {
+            closed: 1700000000,
+            source: "10.0.0.1:40000".parse().unwrap(),
+            target: history.intern(target),
+            sent: 100,
+            received: 2000,
+            duration: Duration::from_millis(1500),
+            reason,
+        }
+    }
+
+    #[test]
+    fn test_ring() {
+        let mut history = History::default();
+        for target in [
+            "a.example.com:443",
+            "b.example.com:443",
+            "a.example.com:443",
+        ] {
+            let entry = entry(&mut history, target, None);
+            history.push(entry, 2);
+        }
+        assert_eq!(history.entries.len(), 2);
+        // the evicted a shares its name with the newest entry
+        assert_eq!(history.targets.len(), 2);
+        let entry = entry(
+            &mut history,
+            "c.example.com:443",
+            Some(CloseReason::IdleTimeout),
+        );
+        history.push(entry, 2);
+        assert_eq!(history.targets.len(), 2);
+        assert!(!history.targets.contains("b.example.com:443"));
+
+        assert_eq!(
+            history.query(10, "idletimeout"),
+            "[{\"closed\":1700000000,\"source\":\"10.0.0.1:40000\",\"target\":\"c.example.com:443\",\"sent\":100,\"received\":2000,\"duration_ms\":1500,\"reason\":\"IdleTimeout\"}]"
+        );
+        assert_eq!(
+            history.query(10, "a.example").matches("\"target\"").count(),
+            1
+        );
+        assert_eq!(history.query(1, "").matches("c.example.com").count(), 1);
+        assert_eq!(history.query(10, "missing"), "[]");
+    }
+}
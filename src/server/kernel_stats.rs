@@ -0,0 +1,123 @@
+//! Accept queues and drops seen by the kernel, with --kernel-stats.
+//!
+//! Connections refused by a full listen backlog never reach the server, so
+//! its own counters can't tell them from failures of the proxy side. Every
+//! [`SAMPLE_DURATION`] the poll loop samples the accept queue of each
+//! listener and the listen overflows and drops of the host from
+//! /proc/net/netstat, which are reported with the metrics and the `stats`
+//! command. Drops of udp sockets are added to
+//! [`UDP_SOCKET_DROPS`](crate::metrics::UDP_SOCKET_DROPS) as they are
+//! sampled. Nothing is sampled between, so the hot path pays nothing.
+use std::{sync::Mutex, time::Duration};
+
+/// Interval between two samples
+pub const SAMPLE_DURATION: Duration = Duration::from_secs(10);
+
+pub struct ListenQueue {
+    pub name: String,
+    pub queued: u32,
+    pub backlog: u32,
+}
+
+struct Sample {
+    listeners: Vec<ListenQueue>,
+    /// Host wide counters of the TcpExt line, None if not readable
+    overflows: Option<(u64, u64)>,
+}
+
+lazy_static::lazy_static! {
+    static ref SAMPLE: Mutex<Option<Sample>> = Mutex::new(None);
+}
+
+/// Reads ListenOverflows and ListenDrops of the TcpExt lines.
+fn parse_netstat(text: &str) -> Option<(u64, u64)> {
+    let mut lines = text.lines().filter(|line| line.starts_with("TcpExt:"));
+    let names = lines.next()?.split_whitespace();
+    let values = lines.next()?.split_whitespace();
+    let mut overflows = None;
+    let mut drops = None;
+    for (name, value) in names.zip(values).skip(1) {
+        match name {
+            "ListenOverflows" => overflows = value.parse().ok(),
+            "ListenDrops" => drops = value.parse().ok(),
+            _ => {}
+        }
+    }
+    Some((overflows?, drops?))
+}
+
+/// Stores the queues of the listeners sampled by the poll loop along with
+/// the host counters.
+pub fn record(listeners: Vec<ListenQueue>) {
+    let overflows = std::fs::read_to_string("/proc/net/netstat")
+        .ok()
+        .and_then(|text| parse_netstat(text.as_str()));
+    *SAMPLE.lock().unwrap() = Some(Sample {
+        listeners,
+        overflows,
+    });
+}
+
+/// Logs the last sample, with the metrics.
+pub fn report() {
+    let sample = SAMPLE.lock().unwrap();
+    let sample = match sample.as_ref() {
+        Some(sample) => sample,
+        None => return,
+    };
+    let mut line = sample
+        .listeners
+        .iter()
+        .map(|queue| format!("{}={}/{}", queue.name, queue.queued, queue.backlog))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if let Some((overflows, drops)) = sample.overflows {
+        line.push_str(format!(" listen_overflows={} listen_drops={}", overflows, drops).as_str());
+    }
+    log::info!("accept queues: {}", line);
+}
+
+/// The last sample as json, null without one.
+pub fn stats() -> String {
+    let sample = SAMPLE.lock().unwrap();
+    let sample = match sample.as_ref() {
+        Some(sample) => sample,
+        None => return "null".to_owned(),
+    };
+    let queues = sample
+        .listeners
+        .iter()
+        .map(|queue| {
+            format!(
+                "{:?}:{{\"queued\":{},\"backlog\":{}}}",
+                queue.name, queue.queued, queue.backlog
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let overflows = match sample.overflows {
+        Some((overflows, drops)) => format!(
+            ",\"listen_overflows\":{},\"listen_drops\":{}",
+            overflows, drops
+        ),
+        None => String::new(),
+    };
+    format!("{{\"listeners\":{{{}}}{}}}", queues, overflows)
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use crate::server::kernel_stats::parse_netstat;
+
+    #[test]
+    fn test_parse_netstat() {
+        let text = "TcpExt: SyncookiesSent ListenOverflows ListenDrops TCPLostRetransmit\n\
+                    TcpExt: 0 12 15 3\n\
+                    IpExt: InNoRoutes\n\
+                    IpExt: 0\n";
+        assert_eq!(parse_netstat(text), Some((12, 15)));
+        assert_eq!(parse_netstat("IpExt: InNoRoutes\nIpExt: 0\n"), None);
+        assert_eq!(parse_netstat("TcpExt: ListenOverflows\nTcpExt: 1\n"), None);
+    }
+}
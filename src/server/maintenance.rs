@@ -0,0 +1,47 @@
+//! Maintenance mode, set with the `maintenance on` control command.
+//!
+//! While on, trojan requests other than control commands are rejected with
+//! [`Notice::Maintenance`](crate::notice::Notice::Maintenance) and the
+//! connection is closed, open sessions go on until they end. Connections
+//! without a request yet, like the idle pool of a proxy, are closed when
+//! it turns on, so proxies find out before they use them. Non trojan
+//! traffic still reaches the fallback backend. The mode is kept in memory,
+//! a reload leaves it as it is and a restart turns it off.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static MAINTENANCE: AtomicBool = AtomicBool::new(false);
+/// Set when maintenance turns on, taken by the next timeout check
+static CLOSE_IDLE: AtomicBool = AtomicBool::new(false);
+
+pub fn enabled() -> bool {
+    MAINTENANCE.load(Ordering::Relaxed)
+}
+
+/// Whether connections without a request have to be closed, once after
+/// maintenance turned on.
+pub fn take_close_idle() -> bool {
+    CLOSE_IDLE.swap(false, Ordering::Relaxed)
+}
+
+fn set(on: bool) {
+    if MAINTENANCE.swap(on, Ordering::Relaxed) != on {
+        CLOSE_IDLE.store(on, Ordering::Relaxed);
+        if on {
+            log::warn!("maintenance on, new trojan sessions are rejected");
+        } else {
+            log::warn!("maintenance off");
+        }
+    }
+}
+
+/// Answers `maintenance`, `maintenance on` and `maintenance off`, `args`
+/// is what follows the command.
+pub fn command(args: &str) -> String {
+    match args.trim() {
+        "" => {}
+        "on" => set(true),
+        "off" => set(false),
+        _ => return "{\"error\":\"use maintenance on or off\"}".to_owned(),
+    }
+    format!("{{\"maintenance\":{}}}", enabled())
+}
@@ -1,33 +1,91 @@
 use std::{
     fs::File,
-    io::BufReader,
+    io::{BufReader, ErrorKind},
+    net::SocketAddr,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use mio::{net::TcpListener, Events, Interest, Poll, Token, Waker};
 use rustls::{
     server::{AllowAnyAnonymousOrAuthenticatedClient, NoClientAuth},
-    KeyLogFile, RootCertStore, ServerConfig,
+    KeyLogFile, RootCertStore, ServerConfig, Ticketer,
 };
 use rustls_pemfile::{certs, read_one, Item};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
 pub use tls_server::TlsServer;
 
-use crate::{config::OPTIONS, resolver::DnsResolver, server::tls_server::PollEvent, types::Result};
+use crate::{
+    cert::{CertInfo, EXPIRY_CHECK_DURATION},
+    config::{ServerArgs, OPTIONS},
+    dump, endpoint, family, metrics,
+    profile::{self, Category},
+    reload,
+    resolver::DnsResolver,
+    server::{ticket::FileTicketer, tls_server::PollEvent},
+    startup, sys, trace,
+    types::Result,
+    worker,
+};
 
+mod acl;
 mod connection;
+mod control;
+mod egress;
+mod history;
+mod kernel_stats;
+mod maintenance;
+mod quota;
+mod targets;
 mod tcp_backend;
+mod ticket;
 mod tls_server;
 mod udp_backend;
+mod udp_ports;
+mod udp_timeout;
+mod upgrade;
 
-const MIN_INDEX: usize = 2;
+const MIN_INDEX: usize = (LISTENER + MAX_LISTENERS) / CHANNEL_CNT;
 const MAX_INDEX: usize = usize::MAX / CHANNEL_CNT;
 const CHANNEL_CNT: usize = 2;
 const CHANNEL_PROXY: usize = 0;
 const CHANNEL_BACKEND: usize = 1;
-const RESOLVER: usize = 2;
-const LISTENER: usize = 1;
+const RESOLVER: usize = 1;
+/// Token of the first listener, the others follow
+const LISTENER: usize = 2;
+/// Listeners at most, as many as an upgrade can pass
+const MAX_LISTENERS: usize = 8;
+
+/// Binds local-addr and the extra addresses.
+fn bind_listeners() -> Result<Vec<TcpListener>> {
+    let args = OPTIONS.server_args();
+    let mut listeners = Vec::new();
+    let extra = args
+        .extra_local_addr
+        .iter()
+        .map(|addr| ("extra-local-addr", addr));
+    for (name, addr) in std::iter::once(("local-addr", &OPTIONS.local_addr)).chain(extra) {
+        if listeners.len() == MAX_LISTENERS {
+            panic!("at most {} listen addresses are supported", MAX_LISTENERS);
+        }
+        let addr = endpoint::parse_addr(name, addr, Some(443));
+        let listener = listen(addr).map_err(|err| startup::bind_error(name, addr, false, err))?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Binds a listener like `TcpListener::bind` with `--listen-backlog`.
+fn listen(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(OPTIONS.listen_backlog as i32)?;
+    Ok(TcpListener::from_std(socket.into()))
+}
 
 fn load_certs(filename: &str) -> Vec<rustls::Certificate> {
     let cert_file = File::open(filename).unwrap();
@@ -56,7 +114,7 @@ fn load_private_key(filename: &str) -> rustls::PrivateKey {
     )
 }
 
-fn init_config() -> Result<Arc<ServerConfig>> {
+fn init_config(ticketer: Option<Arc<FileTicketer>>) -> Result<Arc<ServerConfig>> {
     let client_auth = if OPTIONS.server_args().check_auth {
         let roots = load_certs(OPTIONS.server_args().cert.as_str());
         let mut client_auth_roots = RootCertStore::empty();
@@ -79,6 +137,11 @@ fn init_config() -> Result<Arc<ServerConfig>> {
         .with_client_cert_verifier(client_auth)
         .with_single_cert_with_ocsp_and_sct(certs, private_key, vec![], vec![])?;
     config.key_log = Arc::new(KeyLogFile::new());
+    if let Some(ticketer) = ticketer {
+        config.ticketer = ticketer;
+    } else if OPTIONS.server_args().session_ticket {
+        config.ticketer = Ticketer::new()?;
+    }
 
     let mut protocols: Vec<Vec<u8>> = Vec::new();
     for protocol in &OPTIONS.server_args().alpn {
@@ -90,26 +153,141 @@ fn init_config() -> Result<Arc<ServerConfig>> {
     Ok(Arc::new(config))
 }
 
+/// Directory of `path` where its temporary files and sockets go.
+fn parent_dir(path: &str) -> String {
+    match std::path::Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_string_lossy().into_owned(),
+        _ => ".".to_owned(),
+    }
+}
+
+/// Drops root once everything privileged is done, checking the files opened
+/// again later, on reload, log rotation, usage saves and upgrades.
+fn drop_privileges(args: &ServerArgs) -> Result<()> {
+    let usage_dir = if args.usage_file.is_empty() {
+        String::new()
+    } else {
+        parent_dir(args.usage_file.as_str())
+    };
+    // the new process of an upgrade is started without root and loads
+    // the certificate again
+    let (upgrade_dir, cert, key) = if args.upgrade_socket.is_empty() {
+        (String::new(), "", "")
+    } else {
+        (
+            parent_dir(args.upgrade_socket.as_str()),
+            args.cert.as_str(),
+            args.key.as_str(),
+        )
+    };
+    startup::drop_privileges(
+        args.user.as_str(),
+        args.group.as_str(),
+        &[
+            ("ticket-key-file", args.ticket_key_file.as_str(), false),
+            ("egress-acl-file", args.egress_acl_file.as_str(), false),
+            ("log-file", OPTIONS.log_file.as_str(), true),
+            ("usage-file", usage_dir.as_str(), true),
+            ("upgrade-socket", upgrade_dir.as_str(), true),
+            ("cert", cert, false),
+            ("key", key, false),
+        ],
+    )
+}
+
 pub fn run() -> Result<()> {
-    let config = init_config()?;
+    worker::start("poll");
+    let args = OPTIONS.server_args();
+    let ticketer = if args.session_ticket && !args.ticket_key_file.is_empty() {
+        Some(Arc::new(FileTicketer::new(args.ticket_key_file.as_str())?))
+    } else {
+        None
+    };
+    let config = init_config(ticketer.clone())?;
+    let cert = load_certs(args.cert.as_str())
+        .first()
+        .and_then(|cert| CertInfo::parse(cert.0.as_slice()));
+    if let Some(cert) = &cert {
+        log::info!("server certificate {}", cert);
+        control::set_certificate(cert);
+    }
+    let mut last_expiry_check: Option<Instant> = None;
     let mut poll = Poll::new()?;
     let waker = Arc::new(Waker::new(poll.registry(), Token(RESOLVER))?);
-    let mut resolver = DnsResolver::new(waker, Token(RESOLVER), None);
+    let dns_servers = endpoint::parse_addrs("dns-server", &OPTIONS.dns_server, Some(53));
+    let mut resolver = DnsResolver::new(waker, Token(RESOLVER), dns_servers);
     resolver.set_cache_timeout(OPTIONS.server_args().dns_cache_time);
-    let addr = OPTIONS.local_addr.parse()?;
-    let mut listener = TcpListener::bind(addr)?;
-    poll.registry()
-        .register(&mut listener, Token(LISTENER), Interest::READABLE)?;
-    let mut server = TlsServer::new(listener, config);
+    let (mut listeners, handoff) = match upgrade::inherit()? {
+        Some((listeners, handoff)) => (listeners, Some(handoff)),
+        None => (bind_listeners()?, None),
+    };
+    for (i, listener) in listeners.iter_mut().enumerate() {
+        // accepted sockets inherit the options
+        OPTIONS.tunnel_tuning.apply("tunnel", &*listener);
+        if args.tcp_fast_open_backlog > 0 {
+            if let Err(err) = sys::set_fast_open(&*listener, args.tcp_fast_open_backlog) {
+                log::warn!("enable tcp fast open failed, continuing without it:{}", err);
+            }
+        }
+        // inherited listeners are set again, the new process may differ
+        if let Err(err) = sys::set_defer_accept(&*listener, args.defer_accept_secs) {
+            if args.defer_accept_secs > 0 {
+                log::warn!("enable defer accept failed, continuing without it:{}", err);
+            }
+        }
+        poll.registry()
+            .register(listener, Token(LISTENER + i), Interest::READABLE)?;
+    }
+    let listener_cnt = listeners.len();
+    let mut upgrader = upgrade::Upgrader::new(args.upgrade_socket.as_str(), &listeners);
+    if !args.user.is_empty() {
+        drop_privileges(args)?;
+    }
+    let mut server = TlsServer::new(listeners, config);
     let mut events = Events::with_capacity(1024);
     let mut last_check_time = Instant::now();
     let check_duration = Duration::new(1, 0);
+    let mut last_report_time = Instant::now();
+    let mut last_save_time = Instant::now();
+    let mut last_sample_time: Option<Instant> = None;
+    // listeners are edge triggered, a capped accept is resumed by the loop
+    let mut accept_pending = vec![false; listener_cnt];
+    acl::init();
+    trace::init();
+    dump::init();
+    reload::init();
+    quota::init();
+    udp_timeout::init();
+    udp_ports::init();
+    // found now to log them at startup
+    family::egress();
+    upgrade::init();
+    if !args.usage_file.is_empty() {
+        reload::init_stop();
+    }
+    if let Some(handoff) = handoff {
+        handoff.confirm();
+    }
+    let mut draining = false;
     loop {
-        poll.poll(&mut events, Some(check_duration))?;
+        let timeout = if accept_pending.contains(&true) {
+            Duration::ZERO
+        } else {
+            check_duration
+        };
+        worker::idle();
+        let result = poll.poll(&mut events, Some(timeout));
+        worker::busy();
+        match result {
+            Ok(()) => {}
+            // a signal, its flag is checked below
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
         for event in &events {
             match event.token() {
-                Token(LISTENER) => {
-                    server.accept(&poll);
+                Token(i) if (LISTENER..LISTENER + listener_cnt).contains(&i) => {
+                    accept_pending[i - LISTENER] = true;
                 }
                 Token(RESOLVER) => {
                     resolver.consume(|token, ip| {
@@ -121,11 +299,80 @@ pub fn run() -> Result<()> {
                 }
             }
         }
+        if !draining {
+            for (i, pending) in accept_pending.iter_mut().enumerate() {
+                if *pending {
+                    *pending = server.accept(&poll, i);
+                }
+            }
+        }
         server.remove_closed();
+        if upgrader.check() {
+            if !draining {
+                draining = true;
+                server.stop_accepting(&poll);
+            }
+            if server.is_empty() {
+                log::warn!("all connections closed after the upgrade, exiting");
+                return Ok(());
+            }
+        }
+        if let Some(mut dump) = dump::take() {
+            server.dump(&mut dump);
+            profile::dump(&mut dump);
+            worker::dump(&mut dump);
+        }
         let now = Instant::now();
         if now - last_check_time > check_duration {
+            let _scope = profile::scope(Category::TimeoutSweep);
             server.check_timeout(now, &poll);
+            quota::check_period();
             last_check_time = now;
         }
+        if let Some(cert) = &cert {
+            if last_expiry_check.is_none_or(|time| now - time > EXPIRY_CHECK_DURATION) {
+                cert.check_expiry(SystemTime::now());
+                last_expiry_check.replace(now);
+            }
+        }
+        if args.kernel_stats
+            && last_sample_time.is_none_or(|time| now - time > kernel_stats::SAMPLE_DURATION)
+        {
+            server.sample_kernel();
+            last_sample_time.replace(now);
+        }
+        if now - last_report_time > metrics::REPORT_DURATION {
+            metrics::report();
+            server.report();
+            if args.kernel_stats {
+                kernel_stats::report();
+            }
+            udp_timeout::report();
+            profile::report();
+            last_report_time = now;
+        }
+        if !args.usage_file.is_empty() {
+            if reload::stopped() {
+                server.close_all(&poll);
+                // a draining process leaves the usage to the new one
+                if upgrader.saving() {
+                    quota::save();
+                    log::warn!("server stopped, usage saved to {}", args.usage_file);
+                } else {
+                    log::warn!("draining server stopped, usage left to the new process");
+                }
+                return Ok(());
+            }
+            if upgrader.saving() && now - last_save_time > quota::SAVE_DURATION {
+                quota::save();
+                last_save_time = now;
+            }
+        }
+        if reload::take() {
+            acl::reload();
+            if let Some(ticketer) = &ticketer {
+                ticketer.reload();
+            }
+        }
     }
 }
@@ -0,0 +1,160 @@
+//! Monthly traffic quota of the server password.
+//!
+//! Bytes between proxies and the server are counted once a second and
+//! kept in `--usage-file`, rewritten every minute and when the server is
+//! stopped with SIGTERM or SIGINT. The file holds one line with the
+//! period and the bytes used in it:
+//!
+//! ```text
+//! 2026-10 1234567890
+//! ```
+//!
+//! A period starts on `--quota-reset-day` of each month. Once the usage is
+//! over `--quota-gb`, new requests are closed after authentication, the
+//! connections already running go on until they close.
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::{Datelike, Local, NaiveDate};
+
+use crate::config::OPTIONS;
+
+/// Interval between two saves in the poll loop
+pub const SAVE_DURATION: Duration = Duration::from_secs(60);
+const GB: u64 = 1 << 30;
+
+lazy_static::lazy_static! {
+    static ref USED: AtomicU64 = AtomicU64::new(0);
+    /// Year and month in which the current period started
+    static ref PERIOD: Mutex<(i32, u32)> = Mutex::new((0, 0));
+}
+
+/// Returns the year and month in which the period holding `date` started.
+fn period(date: NaiveDate, reset_day: u32) -> (i32, u32) {
+    if date.day() >= reset_day {
+        (date.year(), date.month())
+    } else if date.month() == 1 {
+        (date.year() - 1, 12)
+    } else {
+        (date.year(), date.month() - 1)
+    }
+}
+
+fn reset_day() -> u32 {
+    OPTIONS.server_args().quota_reset_day.clamp(1, 28) as u32
+}
+
+fn format((year, month): (i32, u32), used: u64) -> String {
+    format!("{}-{:02} {}\n", year, month, used)
+}
+
+fn parse(text: &str) -> Option<((i32, u32), u64)> {
+    let (period, used) = text.trim().split_once(' ')?;
+    let (year, month) = period.split_once('-')?;
+    Some((
+        (year.parse().ok()?, month.parse().ok()?),
+        used.parse().ok()?,
+    ))
+}
+
+/// Reads the usage saved by an earlier run, which only counts if it is of
+/// the current period.
+pub fn init() {
+    let current = period(Local::now().naive_local().date(), reset_day());
+    *PERIOD.lock().unwrap() = current;
+    let path = OPTIONS.server_args().usage_file.as_str();
+    if path.is_empty() {
+        return;
+    }
+    match fs::read_to_string(path)
+        .ok()
+        .and_then(|text| parse(text.as_str()))
+    {
+        Some((saved, used)) if saved == current => {
+            log::info!("usage of this period is {} bytes", used);
+            USED.store(used, Ordering::Relaxed);
+        }
+        Some(_) => log::info!("saved usage is of an earlier period, starting from zero"),
+        None => log::warn!("no usable usage in {}, starting from zero", path),
+    }
+}
+
+pub fn save() {
+    let path = OPTIONS.server_args().usage_file.as_str();
+    if path.is_empty() {
+        return;
+    }
+    let temp = format!("{}.tmp", path);
+    let text = format(*PERIOD.lock().unwrap(), USED.load(Ordering::Relaxed));
+    if let Err(err) = fs::write(&temp, text).and_then(|_| fs::rename(&temp, path)) {
+        log::warn!("save usage file {} failed:{}", path, err);
+    }
+}
+
+pub fn add(bytes: u64) {
+    USED.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Starts a new period once the reset day is reached.
+pub fn check_period() {
+    let current = period(Local::now().naive_local().date(), reset_day());
+    let mut period = PERIOD.lock().unwrap();
+    if *period != current {
+        log::warn!(
+            "new quota period, {} bytes were used in the last one",
+            USED.swap(0, Ordering::Relaxed)
+        );
+        *period = current;
+    }
+}
+
+/// Whether the quota of this period is used up.
+pub fn exceeded() -> bool {
+    let quota = OPTIONS.server_args().quota_gb;
+    quota > 0 && USED.load(Ordering::Relaxed) >= quota.saturating_mul(GB)
+}
+
+pub fn reset() {
+    log::warn!("usage reset by control command");
+    USED.store(0, Ordering::Relaxed);
+}
+
+/// Usage for the control commands.
+pub fn usage() -> String {
+    let (year, month) = *PERIOD.lock().unwrap();
+    format!(
+        "{{\"period\":\"{}-{:02}\",\"used\":{},\"quota\":{},\"exceeded\":{}}}",
+        year,
+        month,
+        USED.load(Ordering::Relaxed),
+        OPTIONS.server_args().quota_gb.saturating_mul(GB),
+        exceeded()
+    )
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use chrono::NaiveDate;
+
+    use crate::server::quota::{format, parse, period};
+
+    #[test]
+    fn test_period() {
+        let date = |year, month, day| NaiveDate::from_ymd_opt(year, month, day).unwrap();
+        assert_eq!(period(date(2026, 10, 14), 1), (2026, 10));
+        assert_eq!(period(date(2026, 10, 14), 15), (2026, 9));
+        assert_eq!(period(date(2026, 10, 15), 15), (2026, 10));
+        assert_eq!(period(date(2026, 1, 3), 5), (2025, 12));
+        let text = format((2026, 9), 1234);
+        assert_eq!(text, "2026-09 1234\n");
+        assert_eq!(parse(text.as_str()), Some(((2026, 9), 1234)));
+        assert!(parse("2026-09").is_none());
+    }
+}
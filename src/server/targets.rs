@@ -0,0 +1,341 @@
+//! Connect latency, failures and traffic of tcp targets for the `targets`
+//! command.
+//!
+//! Targets are keyed by the requested domain, or by the /24 of their
+//! address, /48 for ipv6, when the request had none. At most
+//! `--target-stats` keys are kept, a new one replaces the one with the
+//! fewest connects like the destination table of the proxy, inheriting its
+//! count as error. Domain keys are interned in the table, a connection to a
+//! known domain allocates nothing. `targets [failures|latency] [n]` returns
+//! the top `n` sorted by failure rate or by the 90th percentile of the
+//! connect latency.
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{codel::Delays, config::OPTIONS};
+
+/// Targets returned by a query when no count is given
+const DEFAULT_QUERY: usize = 20;
+
+/// Classes of connect errors, by the errno
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Failure {
+    Refused,
+    Timeout,
+    Unreachable,
+    Reset,
+    Other,
+}
+
+const FAILURES: [Failure; 5] = [
+    Failure::Refused,
+    Failure::Timeout,
+    Failure::Unreachable,
+    Failure::Reset,
+    Failure::Other,
+];
+
+impl Failure {
+    fn of(err: &Error) -> Failure {
+        match err.kind() {
+            ErrorKind::ConnectionRefused => Failure::Refused,
+            ErrorKind::TimedOut => Failure::Timeout,
+            ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => Failure::Unreachable,
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => Failure::Reset,
+            _ => Failure::Other,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Failure::Refused => "refused",
+            Failure::Timeout => "timeout",
+            Failure::Unreachable => "unreachable",
+            Failure::Reset => "reset",
+            Failure::Other => "other",
+        }
+    }
+}
+
+/// Key of a target as requested, borrowed from the connection.
+#[derive(Debug, Copy, Clone)]
+pub enum Target<'a> {
+    Domain(&'a str),
+    Addr(IpAddr),
+}
+
+#[derive(Default)]
+struct Entry {
+    connects: u64,
+    failures: [u64; FAILURES.len()],
+    latency: Delays,
+    sent: u64,
+    received: u64,
+    /// Connects of the evicted entry this one replaced
+    error: u64,
+}
+
+impl Entry {
+    fn failed(&self) -> u64 {
+        self.failures.iter().sum()
+    }
+
+    fn weight(&self) -> u64 {
+        self.connects + self.failed() + self.error
+    }
+
+    fn failure_rate(&self) -> f64 {
+        let attempts = self.connects + self.failed();
+        if attempts == 0 {
+            0.0
+        } else {
+            self.failed() as f64 / attempts as f64
+        }
+    }
+
+    fn json(&self, target: &str) -> String {
+        let failures = FAILURES
+            .iter()
+            .map(|failure| {
+                format!(
+                    "\"{}\":{}",
+                    failure.name(),
+                    self.failures[*failure as usize]
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let ms = |percent| self.latency.percentile(percent).as_secs_f64() * 1000.0;
+        format!(
+            "{{\"target\":{:?},\"connects\":{},\"failures\":{{{}}},\"failure_rate\":{:.3},\"latency_ms\":{{\"p50\":{:.3},\"p90\":{:.3},\"p99\":{:.3}}},\"sent\":{},\"received\":{},\"error\":{}}}",
+            target,
+            self.connects,
+            failures,
+            self.failure_rate(),
+            ms(50),
+            ms(90),
+            ms(99),
+            self.sent,
+            self.received,
+            self.error
+        )
+    }
+}
+
+/// The /24 or /48 an address is counted under.
+fn prefix(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            IpAddr::from([segments[0], segments[1], segments[2], 0, 0, 0, 0, 0])
+        }
+    }
+}
+
+fn prefix_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(_) => format!("{}/24", ip),
+        IpAddr::V6(_) => format!("{}/48", ip),
+    }
+}
+
+#[derive(Default)]
+struct Targets {
+    domains: HashMap<Arc<str>, Entry>,
+    prefixes: HashMap<IpAddr, Entry>,
+}
+
+impl Targets {
+    fn len(&self) -> usize {
+        self.domains.len() + self.prefixes.len()
+    }
+
+    /// Returns the entry of `target`, replacing the lightest one if the
+    /// table is full.
+    fn entry(&mut self, target: Target, capacity: usize) -> &mut Entry {
+        let known = match target {
+            Target::Domain(domain) => self.domains.contains_key(domain),
+            Target::Addr(ip) => self.prefixes.contains_key(&prefix(ip)),
+        };
+        let mut error = 0;
+        if !known && self.len() >= capacity {
+            error = self.evict();
+        }
+        let entry = match target {
+            Target::Domain(domain) if known => self.domains.get_mut(domain).unwrap(),
+            Target::Domain(domain) => self.domains.entry(Arc::from(domain)).or_default(),
+            Target::Addr(ip) => self.prefixes.entry(prefix(ip)).or_default(),
+        };
+        entry.error += error;
+        entry
+    }
+
+    /// Removes the entry with the least weight, returns its weight.
+    fn evict(&mut self) -> u64 {
+        let domain = self
+            .domains
+            .iter()
+            .min_by_key(|(_, entry)| entry.weight())
+            .map(|(domain, entry)| (domain.clone(), entry.weight()));
+        let prefix = self
+            .prefixes
+            .iter()
+            .min_by_key(|(_, entry)| entry.weight())
+            .map(|(ip, entry)| (*ip, entry.weight()));
+        match (domain, prefix) {
+            (Some((_, weight)), Some((ip, min))) if min < weight => {
+                self.prefixes.remove(&ip);
+                min
+            }
+            (Some((domain, weight)), _) => {
+                self.domains.remove(&domain);
+                weight
+            }
+            (None, Some((ip, weight))) => {
+                self.prefixes.remove(&ip);
+                weight
+            }
+            (None, None) => 0,
+        }
+    }
+
+    fn query(&self, by_latency: bool, count: usize) -> String {
+        let mut entries: Vec<_> = self
+            .domains
+            .iter()
+            .map(|(domain, entry)| (domain.to_string(), entry))
+            .chain(
+                self.prefixes
+                    .iter()
+                    .map(|(ip, entry)| (prefix_name(*ip), entry)),
+            )
+            .collect();
+        if by_latency {
+            entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.latency.percentile(90)));
+        } else {
+            entries.sort_by(|(_, a), (_, b)| {
+                b.failure_rate()
+                    .total_cmp(&a.failure_rate())
+                    .then(b.weight().cmp(&a.weight()))
+            });
+        }
+        let targets = entries
+            .iter()
+            .take(count)
+            .map(|(target, entry)| entry.json(target))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!("{{\"targets\":[{}]}}", targets)
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref TARGETS: Mutex<Targets> = Mutex::new(Targets::default());
+}
+
+fn update(target: Target, update: impl FnOnce(&mut Entry)) {
+    let capacity = OPTIONS.server_args().target_stats;
+    if capacity > 0 {
+        update(TARGETS.lock().unwrap().entry(target, capacity));
+    }
+}
+
+/// Counts a connect to `target` which took `latency`.
+pub fn connected(target: Target, latency: Duration) {
+    update(target, |entry| {
+        entry.connects += 1;
+        entry.latency.add(latency);
+    });
+}
+
+pub fn failed(target: Target, err: &Error) {
+    update(target, |entry| {
+        entry.failures[Failure::of(err) as usize] += 1
+    });
+}
+
+/// Counts the bytes relayed by a closed connection, `sent` to the target.
+pub fn relayed(target: Target, sent: u64, received: u64) {
+    update(target, |entry| {
+        entry.sent += sent;
+        entry.received += received;
+    });
+}
+
+/// Answers `targets [failures|latency] [n]`, `args` is what follows the
+/// command.
+pub fn query(args: &str) -> String {
+    let mut by_latency = false;
+    let mut count = DEFAULT_QUERY;
+    for arg in args.split_whitespace() {
+        match arg {
+            "failures" => by_latency = false,
+            "latency" => by_latency = true,
+            arg => match arg.parse() {
+                Ok(n) => count = n,
+                Err(_) => return format!("{{\"error\":\"invalid argument {}\"}}", arg),
+            },
+        }
+    }
+    TARGETS.lock().unwrap().query(by_latency, count)
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::{
+        io::{Error, ErrorKind},
+        net::IpAddr,
+        time::Duration,
+    };
+
+    use crate::server::targets::{Failure, Target, Targets};
+
+    #[test]
+    fn test_targets() {
+        let mut targets = Targets::default();
+        let refused = Error::from(ErrorKind::ConnectionRefused);
+        for _ in 0..3 {
+            let entry = targets.entry(Target::Domain("example.com"), 2);
+            entry.connects += 1;
+            entry.latency.add(Duration::from_millis(3));
+        }
+        targets
+            .entry(Target::Addr(IpAddr::from([10, 0, 0, 1])), 2)
+            .failures[Failure::of(&refused) as usize] += 1;
+        // the same /24
+        targets
+            .entry(Target::Addr(IpAddr::from([10, 0, 0, 2])), 2)
+            .connects += 1;
+        assert_eq!(targets.len(), 2);
+
+        let failures = targets.query(false, 1);
+        assert!(
+            failures.starts_with("{\"targets\":[{\"target\":\"10.0.0.0/24\",\"connects\":1,\"failures\":{\"refused\":1,"),
+            "{}",
+            failures
+        );
+        assert!(failures.contains("\"failure_rate\":0.500"));
+        let latency = targets.query(true, 1);
+        assert!(
+            latency.contains("\"target\":\"example.com\""),
+            "{}",
+            latency
+        );
+
+        // replaces the prefix, which has fewer connects
+        targets.entry(Target::Domain("example.org"), 2).connects += 1;
+        assert_eq!(targets.len(), 2);
+        assert!(targets.prefixes.is_empty());
+        assert_eq!(targets.domains["example.org"].error, 2);
+    }
+}
@@ -1,25 +1,36 @@
 use std::{net::Shutdown, time::Duration};
 
 use bytes::BytesMut;
-use mio::{Interest, net::TcpStream, Poll, Token};
+use mio::{net::TcpStream, Interest, Poll, Token};
 
 use crate::{
     config::OPTIONS,
     proto::MAX_PACKET_SIZE,
     server::tls_server::Backend,
     status::{ConnStatus, StatusProvider},
-    tcp_util,
+    summary::Summary,
+    tcp_util::{self, Outcome, TcpIo},
     tls_conn::TlsConn,
     types::Result,
 };
 
-pub struct TcpBackend {
-    conn: TcpStream,
+/// Backend of a CONNECT request, generic over the stream for the
+/// simulation tests
+pub struct TcpBackend<S = TcpStream> {
+    conn: S,
     status: ConnStatus,
     index: usize,
     timeout: Duration,
     send_buffer: BytesMut,
     recv_buffer: Vec<u8>,
+    /// Bytes read from and sent to the target
+    read: usize,
+    sent: usize,
+    summary: Summary,
+    /// Resets pass through the tunnel, see [`RESET`](crate::proto::RESET)
+    reset: bool,
+    /// Closed by an RST instead of a FIN
+    aborted: bool,
 }
 
 impl TcpBackend {
@@ -27,25 +38,47 @@ impl TcpBackend {
         poll.registry()
             .register(&mut conn, token, Interest::READABLE | Interest::WRITABLE)?;
         conn.set_nodelay(true)?;
-        Ok(TcpBackend {
+        Ok(TcpBackend::with_stream(
             conn,
             index,
-            timeout: OPTIONS.tcp_idle_duration,
+            OPTIONS.tcp_idle_duration,
+        ))
+    }
+}
+
+impl<S: TcpIo> TcpBackend<S> {
+    fn with_stream(conn: S, index: usize, timeout: Duration) -> TcpBackend<S> {
+        TcpBackend {
+            conn,
+            index,
+            timeout,
             status: ConnStatus::Established,
             send_buffer: BytesMut::new(),
             recv_buffer: vec![0u8; MAX_PACKET_SIZE],
-        })
+            read: 0,
+            sent: 0,
+            summary: Summary::new("tcp", "chunks"),
+            reset: false,
+            aborted: false,
+        }
+    }
+
+    pub fn set_reset(&mut self, reset: bool) {
+        self.reset = reset;
     }
 
     fn do_send(&mut self, data: &[u8]) {
-        if !tcp_util::tcp_send(self.index, &self.conn, &mut self.send_buffer, data) {
+        let transfer = tcp_util::tcp_send(self.index, &self.conn, &mut self.send_buffer, data);
+        self.sent += transfer.bytes;
+        self.summary.sent(transfer.chunks, transfer.bytes, None);
+        if !transfer.open() {
             self.shutdown();
         }
     }
 }
 
-impl Backend for TcpBackend {
-    fn dispatch(&mut self, buffer: &[u8]) {
+impl<S: TcpIo> Backend for TcpBackend<S> {
+    fn dispatch(&mut self, buffer: &[u8], _: &Poll) {
         // send immediately first
         if self.send_buffer.is_empty() {
             self.do_send(buffer);
@@ -54,6 +87,7 @@ impl Backend for TcpBackend {
             let buffer = self.send_buffer.split();
             self.do_send(buffer.as_ref());
         }
+        self.summary.log(self.index);
     }
     fn get_timeout(&self) -> Duration {
         self.timeout
@@ -63,16 +97,61 @@ impl Backend for TcpBackend {
         self.send_buffer.is_empty() && self.alive()
     }
 
-    fn do_read(&mut self, conn: &mut TlsConn) {
-        if !tcp_util::tcp_read(self.index, &self.conn, &mut self.recv_buffer, conn) {
-            self.shutdown();
+    fn take_error(&self) -> Option<std::io::Error> {
+        self.conn.take_error().ok().flatten()
+    }
+
+    fn do_read(&mut self, conn: &mut TlsConn, _: &Poll) {
+        let transfer = tcp_util::tcp_read(self.index, &self.conn, &mut self.recv_buffer, conn);
+        self.read += transfer.bytes;
+        self.summary.received(transfer.chunks, transfer.bytes);
+        match transfer.outcome {
+            // a paused read is resumed by the connection once the proxy
+            // is writable again
+            Outcome::Ok | Outcome::WouldBlock | Outcome::Paused => {}
+            // close the proxy once it has flushed, which closes us in turn
+            Outcome::Eof => conn.peer_closed(),
+            Outcome::Error(_) if self.reset && transfer.outcome.reset() => {
+                log::info!("connection:{} reset by target", self.index);
+                conn.abort();
+                self.shutdown();
+            }
+            Outcome::Error(err) => {
+                log::warn!("connection:{} read from target failed:{}", self.index, err);
+                self.shutdown();
+            }
         }
 
         conn.do_send();
+        self.summary.log(self.index);
+    }
+
+    fn tick(&mut self) {
+        self.summary.log(self.index);
+    }
+
+    fn abort(&mut self) {
+        if let Err(err) = self.conn.set_reset() {
+            log::warn!("connection:{} set linger failed:{}", self.index, err);
+        } else {
+            self.aborted = true;
+        }
+        self.shutdown();
+    }
+
+    fn dump_state(&self) -> String {
+        format!(
+            "tcp {:?}/{} read:{} sent:{} buf:{}",
+            self.status,
+            self.interests(),
+            self.read,
+            self.sent,
+            self.send_buffer.len()
+        )
     }
 }
 
-impl StatusProvider for TcpBackend {
+impl<S: TcpIo> StatusProvider for TcpBackend<S> {
     fn set_status(&mut self, status: ConnStatus) {
         self.status = status
     }
@@ -82,12 +161,16 @@ impl StatusProvider for TcpBackend {
     }
 
     fn close_conn(&mut self) -> bool {
-        let _ = self.conn.shutdown(Shutdown::Both);
+        // the RST goes out once the socket is dropped, a FIN would go first
+        if !self.aborted {
+            let _ = self.conn.shutdown(Shutdown::Both);
+        }
         true
     }
 
     fn deregister(&mut self, poll: &Poll) -> bool {
         let _ = poll.registry().deregister(&mut self.conn);
+        self.summary.flush(self.index);
         true
     }
 
@@ -95,3 +178,72 @@ impl StatusProvider for TcpBackend {
         self.send_buffer.is_empty()
     }
 }
+
+mod test {
+    #![allow(unused_imports, dead_code)]
+
+    use std::time::Duration;
+
+    use mio::Poll;
+
+    use crate::{
+        server::{tcp_backend::TcpBackend, tls_server::Backend},
+        sim::{MockClock, Op, ScriptedStream},
+        status::{ConnStatus, StatusProvider},
+    };
+
+    fn backend() -> TcpBackend<ScriptedStream> {
+        TcpBackend::with_stream(ScriptedStream::default(), 2, Duration::from_secs(10))
+    }
+
+    #[test]
+    fn test_idle_boundary() {
+        let clock = MockClock::new();
+        let backend = backend();
+        let active = clock.now();
+        clock.advance(Duration::from_secs(10));
+        assert!(!backend.timeout(active, clock.now()));
+        clock.advance(Duration::from_millis(1));
+        assert!(backend.timeout(active, clock.now()));
+    }
+
+    #[test]
+    fn test_requeue_on_would_block() {
+        let poll = Poll::new().unwrap();
+        let mut backend = backend();
+        backend.conn.allow(5);
+        backend.dispatch(b"hello world", &poll);
+        assert_eq!(backend.conn.written(), b"hello");
+        assert_eq!(backend.send_buffer.as_ref(), b" world");
+        assert!(!backend.writable());
+        // later data queues behind the rest
+        backend.dispatch(b"!", &poll);
+        assert_eq!(backend.send_buffer.as_ref(), b" world!");
+        // an interrupted write is retried
+        backend.conn.interrupt(1);
+        backend.conn.allow(100);
+        backend.dispatch(&[], &poll);
+        assert_eq!(backend.conn.written(), b"hello world!");
+        assert!(backend.writable());
+        assert_eq!(backend.sent, 12);
+    }
+
+    #[test]
+    fn test_drain_then_close() {
+        let poll = Poll::new().unwrap();
+        let mut backend = backend();
+        backend.conn.allow(3);
+        backend.dispatch(b"abcdef", &poll);
+        backend.peer_closed();
+        backend.check_status(&poll);
+        // not closed while data is pending
+        assert_eq!(backend.get_status(), ConnStatus::PeerClosed);
+        assert_eq!(backend.conn.take_ops(), [Op::Write(b"abc".to_vec())]);
+        backend.conn.allow(3);
+        backend.dispatch(&[], &poll);
+        backend.check_status(&poll);
+        assert_eq!(backend.get_status(), ConnStatus::Deregistered);
+        let ops = backend.conn.take_ops();
+        assert_eq!(ops, [Op::Write(b"def".to_vec()), Op::Shutdown]);
+    }
+}
@@ -0,0 +1,109 @@
+//! Session ticket encryption with a key shared by several server processes.
+use std::{fs, sync::RwLock};
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use rustls::server::ProducesTickets;
+use sha2::{Digest, Sha256};
+
+use crate::types::{Result, TrojanError};
+
+/// Ticket lifetime hint for clients, the same as rustls uses.
+const TICKET_LIFETIME: u32 = 12 * 60 * 60;
+
+/// Encrypts tickets with a key derived from the content of `path`.
+/// After a reload the previous key still decrypts, so tickets issued
+/// just before a rotation stay valid.
+pub struct FileTicketer {
+    path: String,
+    keys: RwLock<(LessSafeKey, Option<LessSafeKey>)>,
+    random: SystemRandom,
+}
+
+fn load_key(path: &str) -> Result<LessSafeKey> {
+    let secret = fs::read(path)?;
+    if secret.len() < 32 {
+        log::error!("ticket key file {} should hold at least 32 bytes", path);
+        return Err(TrojanError::Dummy(()));
+    }
+    let digest = Sha256::digest(secret.as_slice());
+    let key = UnboundKey::new(&CHACHA20_POLY1305, digest.as_slice())
+        .map_err(|_| TrojanError::Dummy(()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+impl FileTicketer {
+    pub fn new(path: &str) -> Result<FileTicketer> {
+        Ok(FileTicketer {
+            path: path.to_owned(),
+            keys: RwLock::new((load_key(path)?, None)),
+            random: SystemRandom::new(),
+        })
+    }
+
+    /// Re-reads the key file, keeps the current key if it fails.
+    pub fn reload(&self) {
+        match load_key(self.path.as_str()) {
+            Ok(key) => {
+                let mut keys = self.keys.write().unwrap();
+                let previous = std::mem::replace(&mut keys.0, key);
+                keys.1.replace(previous);
+                log::warn!("ticket key reloaded from {}", self.path);
+            }
+            Err(err) => {
+                log::error!("reload ticket key from {} failed:{:?}", self.path, err);
+            }
+        }
+    }
+}
+
+fn decrypt(key: &LessSafeKey, cipher: &[u8]) -> Option<Vec<u8>> {
+    if cipher.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, sealed) = cipher.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+    let mut plain = sealed.to_vec();
+    let len = key
+        .open_in_place(nonce, Aad::empty(), &mut plain)
+        .ok()?
+        .len();
+    plain.truncate(len);
+    Some(plain)
+}
+
+impl ProducesTickets for FileTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        TICKET_LIFETIME
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.random.fill(&mut nonce).ok()?;
+        let mut cipher = Vec::with_capacity(NONCE_LEN + plain.len() + CHACHA20_POLY1305.tag_len());
+        cipher.extend_from_slice(&nonce);
+        cipher.extend_from_slice(plain);
+        let keys = self.keys.read().ok()?;
+        let tag = keys
+            .0
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut cipher[NONCE_LEN..],
+            )
+            .ok()?;
+        cipher.extend_from_slice(tag.as_ref());
+        Some(cipher)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let keys = self.keys.read().ok()?;
+        decrypt(&keys.0, cipher).or_else(|| keys.1.as_ref().and_then(|key| decrypt(key, cipher)))
+    }
+}
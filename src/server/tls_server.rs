@@ -1,20 +1,37 @@
 use std::{
     collections::HashMap,
-    net::IpAddr,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
     time::{Duration, Instant},
 };
 
+use bytes::Bytes;
 use mio::{event::Event, net::TcpListener, Poll, Token};
 use rustls::{ServerConfig, ServerConnection};
 
 use crate::{
+    config::OPTIONS,
+    dump::Dump,
+    metrics::{ACCEPT_BACKLOG, SWEEP_VISITS},
+    profile::{self, Category},
     resolver::DnsResolver,
-    server::{connection::Connection, CHANNEL_CNT, CHANNEL_PROXY, MAX_INDEX, MIN_INDEX},
-    status::StatusProvider,
+    server::{
+        connection::Connection,
+        egress,
+        kernel_stats::{self, ListenQueue},
+        maintenance, quota, CHANNEL_CNT, CHANNEL_PROXY, MAX_INDEX, MIN_INDEX,
+    },
+    stale::StaleEvents,
+    status::{CloseReason, StatusProvider},
+    sys,
     tls_conn::TlsConn,
+    wheel::Wheel,
 };
 
+/// Slots of the sweep wheel, one per sweep, deadlines past them are
+/// checked again after as long
+const WHEEL_SLOTS: usize = 64;
+
 pub enum PollEvent<'a> {
     Network(&'a Event),
     Dns((Token, Option<IpAddr>)),
@@ -29,48 +46,102 @@ impl<'a> PollEvent<'a> {
     }
 }
 
-pub struct TlsServer {
+struct Listener {
     listener: TcpListener,
+    addr: Option<SocketAddr>,
+    accepted: u64,
+}
+
+pub struct TlsServer {
+    listeners: Vec<Listener>,
     config: Arc<ServerConfig>,
     next_id: usize,
     conns: HashMap<usize, Connection>,
     removed: Option<Vec<usize>>,
+    stale: StaleEvents,
+    /// Connections by when the sweep visits them next
+    wheel: Wheel,
+    due: Vec<usize>,
 }
 
 pub trait Backend: StatusProvider {
-    fn dispatch(&mut self, data: &[u8]);
+    fn dispatch(&mut self, data: &[u8], poll: &Poll);
     fn timeout(&self, t1: Instant, t2: Instant) -> bool {
         t2 - t1 > self.get_timeout()
     }
     fn get_timeout(&self) -> Duration;
+    /// Called once the backend is closed by its idle timeout.
+    fn expired(&self) {}
+    /// Called on the timeout tick, like for logging traffic summaries.
+    fn tick(&mut self) {}
+    /// Called every kernel stats sample, like for counting socket drops.
+    fn sample_kernel(&mut self) {}
     fn writable(&self) -> bool;
-    fn do_read(&mut self, conn: &mut TlsConn);
+    /// Pending error of the target socket, like the one of a failed connect.
+    fn take_error(&self) -> Option<std::io::Error> {
+        None
+    }
+    fn do_read(&mut self, conn: &mut TlsConn, poll: &Poll);
+    /// Takes the metadata of the request before the first dispatch, for
+    /// backends routing on it, see [`METADATA`](crate::proto::METADATA).
+    fn set_metadata(&mut self, _metadata: &[(u8, Bytes)]) {}
+    /// Closes after the proxy aborted, with an RST where the target is tcp.
+    fn abort(&mut self) {
+        self.shutdown();
+    }
+    /// Compact state for the state dump.
+    fn dump_state(&self) -> String;
 }
 
 impl TlsServer {
-    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> TlsServer {
+    pub fn new(listeners: Vec<TcpListener>, config: Arc<ServerConfig>) -> TlsServer {
+        let listeners = listeners
+            .into_iter()
+            .map(|listener| Listener {
+                addr: listener.local_addr().ok(),
+                listener,
+                accepted: 0,
+            })
+            .collect();
         TlsServer {
-            listener,
+            listeners,
             config,
             removed: Some(Vec::new()),
             next_id: MIN_INDEX,
             conns: HashMap::new(),
+            stale: StaleEvents::new(),
+            wheel: Wheel::new(WHEEL_SLOTS, Duration::from_secs(1), Instant::now()),
+            due: Vec::new(),
         }
     }
 
-    pub fn accept(&mut self, poll: &Poll) {
-        loop {
-            match self.listener.accept() {
+    /// Accepts at most `--accept-burst` connections on the listener
+    /// `listener`, returns true if the backlog may not be drained yet.
+    pub fn accept(&mut self, poll: &Poll, listener: usize) -> bool {
+        let _scope = profile::scope(Category::Accept);
+        for _ in 0..OPTIONS.accept_burst.max(1) {
+            match self.listeners[listener].listener.accept() {
                 Ok((stream, addr)) => {
+                    self.listeners[listener].accepted += 1;
                     log::debug!(
-                        "get new connection, token:{}, address:{}",
+                        "get new connection, token:{}, address:{}, listener:{}",
                         self.next_id,
-                        addr
+                        addr,
+                        listener
                     );
                     if let Err(err) = stream.set_nodelay(true) {
                         log::error!("set nodelay failed:{}", err);
                         continue;
                     }
+                    let keepalive = OPTIONS.server_args().tcp_keepalive;
+                    if keepalive > 0 {
+                        if let Err(err) =
+                            sys::set_keepalive(&stream, Duration::from_secs(keepalive))
+                        {
+                            log::error!("set keepalive failed:{}", err);
+                            continue;
+                        }
+                    }
                     let session = ServerConnection::new(self.config.clone()).unwrap();
                     let index = self.next_index();
                     let mut tls_conn = TlsConn::new(
@@ -80,8 +151,9 @@ impl TlsServer {
                         stream,
                     );
                     if tls_conn.register(poll) {
-                        let conn = Connection::new(index, tls_conn);
+                        let conn = Connection::new(index, tls_conn, addr);
                         self.conns.insert(index, conn);
+                        self.wheel.schedule(index, Instant::now());
                     } else {
                         tls_conn.shutdown();
                         tls_conn.check_status(poll);
@@ -89,7 +161,7 @@ impl TlsServer {
                 }
                 Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
                     log::debug!("no more connection to be accepted");
-                    break;
+                    return false;
                 }
                 Err(err) => {
                     log::error!("accept failed with error:{}, exit now", err);
@@ -97,6 +169,8 @@ impl TlsServer {
                 }
             }
         }
+        ACCEPT_BACKLOG.inc();
+        true
     }
 
     fn next_index(&mut self) -> usize {
@@ -119,47 +193,176 @@ impl TlsServer {
         resolver: Option<&mut DnsResolver>,
     ) {
         let index = self.token2index(event.token());
-        if self.conns.contains_key(&index) {
-            let conn = self.conns.get_mut(&index).unwrap();
-            conn.ready(poll, event, resolver);
-            if conn.destroyed() {
-                self.removed.as_mut().unwrap().push(index);
+        match self.conns.get_mut(&index) {
+            // destroyed by an earlier event of this batch, removed later
+            Some(conn) if conn.destroyed() => {}
+            Some(conn) => {
+                conn.ready(poll, event, resolver);
+                if conn.destroyed() {
+                    self.removed.as_mut().unwrap().push(index);
+                }
+            }
+            None => self.stale.missing("tls", index),
+        }
+    }
+
+    /// Stops accepting, the connections still open go on.
+    pub fn stop_accepting(&mut self, poll: &Poll) {
+        for listener in &mut self.listeners {
+            if let Err(err) = poll.registry().deregister(&mut listener.listener) {
+                log::error!("deregister listener failed:{}", err);
+            }
+        }
+    }
+
+    /// Samples the accept queues and the drops of the udp backends.
+    pub fn sample_kernel(&mut self) {
+        let listeners = self
+            .listeners
+            .iter()
+            .filter_map(|listener| match sys::listen_queue(&listener.listener) {
+                Ok((queued, backlog)) => Some(ListenQueue {
+                    name: Self::name(listener),
+                    queued,
+                    backlog,
+                }),
+                Err(err) => {
+                    log::debug!("sample listen queue failed:{}", err);
+                    None
+                }
+            })
+            .collect();
+        kernel_stats::record(listeners);
+        for conn in self.conns.values_mut() {
+            conn.sample_kernel();
+        }
+    }
+
+    /// Logs the connections accepted by each listener, if there are several.
+    pub fn report(&self) {
+        if self.listeners.len() < 2 {
+            return;
+        }
+        let line = self
+            .listeners
+            .iter()
+            .map(|listener| format!("{}={}", Self::name(listener), listener.accepted))
+            .collect::<Vec<_>>()
+            .join(" ");
+        log::info!("accepted by listener: {}", line);
+    }
+
+    fn name(listener: &Listener) -> String {
+        listener
+            .addr
+            .map_or_else(|| "unknown".to_owned(), |addr| addr.to_string())
+    }
+
+    /// Closes all connections at once, their traffic accounted, when the
+    /// server stops.
+    pub fn close_all(&mut self, poll: &Poll) {
+        let indexes: Vec<_> = self.conns.keys().copied().collect();
+        for index in indexes {
+            if let Some(conn) = self.conns.get_mut(&index) {
+                conn.close_now(poll);
             }
-        } else {
-            log::error!("connection:{} not found to do event", index);
+            self.forget(index);
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+
     pub fn remove_closed(&mut self) {
+        self.stale.next_generation();
         if self.removed.as_ref().unwrap().is_empty() {
             return;
         }
         let removed = self.removed.replace(Vec::new()).unwrap();
         for index in removed {
-            self.conns.remove(&index);
+            self.forget(index);
             log::debug!("connection:{} closed, remove from pool", index);
         }
     }
 
+    fn forget(&mut self, index: usize) {
+        if let Some(mut conn) = self.conns.remove(&index) {
+            quota::add(conn.account());
+            conn.record_history();
+            conn.record_target();
+            conn.log_trace();
+            if let Some((up, down)) = conn.compression_ratio() {
+                log::info!(
+                    "connection:{} closed, compression ratio up:{:.2} down:{:.2}",
+                    index,
+                    up,
+                    down
+                );
+            }
+        }
+        self.stale.freed(index);
+    }
+
     pub fn check_timeout(&mut self, check_active_time: Instant, poll: &Poll) {
-        let list: Vec<_> = self
-            .conns
-            .iter_mut()
-            .filter_map(|(index, conn)| {
-                if !conn.destroyed() && conn.timeout(check_active_time) {
-                    log::warn!("connection:{} timeout, close now", index);
+        egress::warn(check_active_time, || {
+            self.conns
+                .values()
+                .filter(|conn| conn.has_backend())
+                .count()
+        });
+        let close_idle = maintenance::take_close_idle();
+        let mut due = std::mem::take(&mut self.due);
+        self.wheel.expire(check_active_time, &mut due);
+        for index in due.drain(..) {
+            // closed meanwhile, its entry is only dropped here
+            let conn = match self.conns.get_mut(&index) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            SWEEP_VISITS.inc();
+            quota::add(conn.account());
+            if !conn.destroyed() {
+                conn.tick();
+                conn.retry_target(poll);
+                if close_idle && conn.awaiting_request() {
+                    log::info!(
+                        "connection:{} without request closed for maintenance",
+                        index
+                    );
+                    conn.set_close_reason(CloseReason::Maintenance);
+                    conn.destroy(poll);
+                } else if let Some(reason) = conn.timeout(check_active_time) {
+                    log::warn!("connection:{} closed by {:?}", index, reason);
+                    conn.set_close_reason(reason);
                     conn.destroy(poll);
                 }
-                if conn.destroyed() {
-                    Some(*index)
-                } else {
-                    None
-                }
-            })
-            .collect();
+            }
+            if conn.destroyed() {
+                self.forget(index);
+            } else {
+                let next = conn.next_check(check_active_time);
+                self.wheel.schedule(index, next);
+            }
+        }
+        self.due = due;
+    }
 
-        for index in list {
-            self.conns.remove(&index);
+    pub fn dump(&self, dump: &mut Dump) {
+        for listener in &self.listeners {
+            dump.line(format_args!(
+                "listener {} accepted:{}",
+                Self::name(listener),
+                listener.accepted
+            ));
+        }
+        dump.line(format_args!(
+            "server connections:{} scheduled:{}",
+            self.conns.len(),
+            self.wheel.len()
+        ));
+        for conn in self.conns.values() {
+            conn.dump(dump);
         }
     }
 }
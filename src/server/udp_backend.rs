@@ -1,28 +1,191 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use bytes::BytesMut;
-use mio::{Interest, net::UdpSocket, Poll, Token};
+use bytes::{Buf, BufMut, BytesMut};
+use mio::{
+    net::{TcpStream, UdpSocket},
+    Interest, Poll, Token,
+};
 
 use crate::{
-    config::OPTIONS,
-    proto::{MAX_PACKET_SIZE, UdpAssociate, UdpParseResult},
-    server::tls_server::Backend,
+    config::{UdpTruncate, OPTIONS},
+    metrics::{EGRESS_DENIED, UDP_EMPTY_DISCARDED, UDP_SOCKET_DROPS, UDP_TRUNCATED},
+    profile::{self, Category},
+    proto::{UdpAssociate, UdpParseResult, MAX_PACKET_SIZE, MAX_UDP_HEAD_LEN},
+    server::{acl, egress, tls_server::Backend, udp_timeout},
     status::{ConnStatus, StatusProvider},
-    tls_conn::TlsConn,
+    summary::Summary,
+    sys,
+    tls_conn::{TlsConn, Written},
     types::Result,
+    udp_loss::{self, Loss, UdpStats},
 };
 
 pub struct UdpBackend {
     socket: UdpSocket,
     send_buffer: BytesMut,
     recv_body: Vec<u8>,
-    recv_head: BytesMut,
+    recv_head: [u8; MAX_UDP_HEAD_LEN],
     index: usize,
+    token: Token,
     status: ConnStatus,
     timeout: Duration,
+    /// Destination port whose timeout applies, None for the default one
+    timeout_port: Option<u16>,
+    /// No datagram was sent yet, the first one picks the timeout
+    first_packet: bool,
     bytes_read: usize,
     bytes_sent: usize,
     remote_addr: SocketAddr,
+    /// Source port of the socket, for matching firewall logs
+    local_port: u16,
+    tcp_relays: HashMap<SocketAddr, TcpRelay>,
+    stats: Arc<UdpStats>,
+    summary: Summary,
+    /// Kernel drops of the socket already counted
+    kernel_drops: u32,
+}
+
+/// Bytes a tcp relay buffers each way, datagrams beyond are dropped
+const RELAY_BUFFER_LIMIT: usize = 64 * 1024;
+
+/// Carries datagrams for one target as a length prefixed TCP stream,
+/// the same framing DNS uses over TCP.
+struct TcpRelay {
+    stream: TcpStream,
+    send_buffer: BytesMut,
+    recv_buffer: BytesMut,
+}
+
+impl TcpRelay {
+    /// Connects like the tcp backend, tuned by `--backend-socket` and from
+    /// the next `--egress-source-ip`.
+    fn new(addr: SocketAddr, token: Token, poll: &Poll) -> std::io::Result<TcpRelay> {
+        let mut stream = OPTIONS.backend_tuning.connect_from(
+            "backend",
+            addr,
+            egress::source(addr),
+            OPTIONS.server_args().bind_address_no_port,
+        )?;
+        poll.registry()
+            .register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)?;
+        Ok(TcpRelay {
+            stream,
+            send_buffer: BytesMut::new(),
+            recv_buffer: BytesMut::new(),
+        })
+    }
+
+    /// Queues `payload`, returns false if it is dropped for a full buffer.
+    fn push(&mut self, payload: &[u8]) -> bool {
+        if self.send_buffer.len() + 2 + payload.len() > RELAY_BUFFER_LIMIT {
+            return false;
+        }
+        self.send_buffer.put_u16(payload.len() as u16);
+        self.send_buffer.extend_from_slice(payload);
+        true
+    }
+
+    /// Returns false if the stream is broken.
+    fn flush(&mut self) -> bool {
+        while !self.send_buffer.is_empty() {
+            match self.stream.write(self.send_buffer.as_ref()) {
+                Ok(0) => return false,
+                Ok(size) => self.send_buffer.advance(size),
+                Err(err)
+                    if err.kind() == ErrorKind::WouldBlock
+                        || err.kind() == ErrorKind::NotConnected =>
+                {
+                    break
+                }
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+
+    /// Reads all available data, returns false if the stream is closed.
+    /// Over the buffer limit the oldest datagrams are dropped, counted in
+    /// `dropped`.
+    fn fill(&mut self, buffer: &mut [u8], dropped: &mut usize) -> bool {
+        loop {
+            match self.stream.read(buffer) {
+                Ok(0) => return false,
+                Ok(size) => {
+                    self.recv_buffer.extend_from_slice(&buffer[..size]);
+                    while self.recv_buffer.len() > RELAY_BUFFER_LIMIT {
+                        match self.pop() {
+                            Ok(Some(_)) => *dropped += 1,
+                            // too long, the caller closes the relay
+                            _ => return true,
+                        }
+                    }
+                }
+                Err(err)
+                    if err.kind() == ErrorKind::WouldBlock
+                        || err.kind() == ErrorKind::NotConnected =>
+                {
+                    return true
+                }
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Pops next complete datagram, `Err` for a datagram the tunnel can't carry.
+    fn pop(&mut self) -> std::result::Result<Option<BytesMut>, usize> {
+        if self.recv_buffer.len() < 2 {
+            return Ok(None);
+        }
+        let length = (self.recv_buffer[0] as usize) << 8 | self.recv_buffer[1] as usize;
+        if length > MAX_PACKET_SIZE {
+            return Err(length);
+        }
+        if self.recv_buffer.len() < length + 2 {
+            return Ok(None);
+        }
+        self.recv_buffer.advance(2);
+        Ok(Some(self.recv_buffer.split_to(length)))
+    }
+}
+
+/// Result of sending one datagram to a udp target
+#[derive(Debug)]
+enum Sent {
+    Done(usize),
+    /// Bytes actually sent, and whether the session should be closed
+    Truncated(usize, bool),
+    Blocked,
+    Failed(std::io::Error),
+}
+
+/// Sends `payload` as one datagram, applying `policy` to short sends.
+fn send_datagram(
+    mut send: impl FnMut(&[u8]) -> std::io::Result<usize>,
+    payload: &[u8],
+    policy: UdpTruncate,
+) -> Sent {
+    let mut tries = if policy == UdpTruncate::Retry { 2 } else { 1 };
+    let mut truncated = None;
+    while tries > 0 {
+        tries -= 1;
+        match send(payload) {
+            Ok(size) if size == payload.len() => return Sent::Done(size),
+            Ok(size) => truncated = Some(size),
+            // the retry is best effort, the first try sent something already
+            Err(err) if err.kind() == ErrorKind::WouldBlock && truncated.is_none() => {
+                return Sent::Blocked
+            }
+            Err(_) if truncated.is_some() => break,
+            Err(err) => return Sent::Failed(err),
+        }
+    }
+    Sent::Truncated(truncated.unwrap(), policy == UdpTruncate::Shutdown)
 }
 
 impl UdpBackend {
@@ -35,67 +198,278 @@ impl UdpBackend {
         poll.registry()
             .register(&mut socket, token, Interest::READABLE | Interest::WRITABLE)?;
         let remote_addr = socket.local_addr().unwrap();
+        log::debug!(
+            "connection:{} udp association on local port {}",
+            index,
+            remote_addr.port()
+        );
         Ok(UdpBackend {
             socket,
             index,
+            token,
+            local_port: remote_addr.port(),
             remote_addr,
             send_buffer: Default::default(),
             recv_body: vec![0u8; MAX_PACKET_SIZE],
-            recv_head: Default::default(),
+            recv_head: [0u8; MAX_UDP_HEAD_LEN],
             status: ConnStatus::Established,
             timeout: OPTIONS.udp_idle_duration,
+            timeout_port: None,
+            first_packet: true,
             bytes_read: 0,
             bytes_sent: 0,
+            tcp_relays: HashMap::new(),
+            stats: udp_loss::register(index),
+            summary: Summary::new("udp", "datagrams"),
+            kernel_drops: 0,
         })
     }
 
-    fn do_send(&mut self, mut buffer: &[u8]) {
+    fn count_kernel_drops(&mut self) {
+        if let Ok(drops) = sys::socket_drops(&self.socket) {
+            UDP_SOCKET_DROPS.add(drops.wrapping_sub(self.kernel_drops) as usize);
+            self.kernel_drops = drops;
+        }
+    }
+
+    fn relay_tcp(&mut self, addr: SocketAddr, payload: &[u8], poll: &Poll) {
+        if !self.tcp_relays.contains_key(&addr) {
+            if egress::cooling(Instant::now()) {
+                log::debug!(
+                    "connection:{} tcp relay to {} waits for free local ports, dropped",
+                    self.index,
+                    addr
+                );
+                self.stats.lose(Loss::SendError);
+                return;
+            }
+            match TcpRelay::new(addr, self.token, poll) {
+                Ok(relay) => {
+                    log::debug!("connection:{} relay udp to {} over tcp", self.index, addr);
+                    self.tcp_relays.insert(addr, relay);
+                }
+                Err(err) => {
+                    if egress::exhausted(&err) {
+                        egress::cool_down(Instant::now());
+                    }
+                    log::warn!(
+                        "connection:{} connect tcp relay to {} failed:{:?}",
+                        self.index,
+                        addr,
+                        err
+                    );
+                    self.stats.lose(Loss::SendError);
+                    return;
+                }
+            }
+        }
+        let relay = self.tcp_relays.get_mut(&addr).unwrap();
+        if !relay.push(payload) {
+            log::debug!(
+                "connection:{} tcp relay to {} buffer full, dropped",
+                self.index,
+                addr
+            );
+            self.stats.lose(Loss::BufferFull);
+            return;
+        }
+        self.bytes_sent += payload.len();
+        if relay.flush() {
+            self.stats.forward();
+        } else {
+            log::warn!("connection:{} tcp relay to {} broken", self.index, addr);
+            self.stats.lose(Loss::SendError);
+            self.close_relay(addr, poll);
+        }
+    }
+
+    fn close_relay(&mut self, addr: SocketAddr, poll: &Poll) {
+        if let Some(mut relay) = self.tcp_relays.remove(&addr) {
+            let _ = relay.stream.shutdown(Shutdown::Both);
+            let _ = poll.registry().deregister(&mut relay.stream);
+        }
+    }
+
+    fn flush_relays(&mut self, poll: &Poll) {
+        let broken: Vec<_> = self
+            .tcp_relays
+            .iter_mut()
+            .filter_map(|(addr, relay)| if relay.flush() { None } else { Some(*addr) })
+            .collect();
+        for addr in broken {
+            self.close_relay(addr, poll);
+        }
+    }
+
+    fn read_relays(&mut self, conn: &mut TlsConn, poll: &Poll) {
+        let mut closed = Vec::new();
+        for (addr, relay) in self.tcp_relays.iter_mut() {
+            let mut dropped = 0;
+            let alive = relay.fill(self.recv_body.as_mut_slice(), &mut dropped);
+            for _ in 0..dropped {
+                self.stats.lose(Loss::BufferFull);
+            }
+            loop {
+                match relay.pop() {
+                    Ok(Some(payload)) => {
+                        self.bytes_read += payload.len();
+                        let len =
+                            UdpAssociate::write(&mut self.recv_head, addr, payload.len() as u16);
+                        if !conn
+                            .write_frame(&self.recv_head[..len], payload.as_ref())
+                            .accepted()
+                        {
+                            self.stats.lose(Loss::BufferFull);
+                            return;
+                        }
+                        self.stats.forward();
+                    }
+                    Ok(None) => {
+                        if !alive {
+                            closed.push(*addr);
+                        }
+                        break;
+                    }
+                    Err(length) => {
+                        log::warn!(
+                            "connection:{} tcp relay from {} got {} bytes datagram, too long",
+                            self.index,
+                            addr,
+                            length
+                        );
+                        self.stats.lose(Loss::Oversize);
+                        closed.push(*addr);
+                        break;
+                    }
+                }
+            }
+        }
+        for addr in closed {
+            log::debug!("connection:{} tcp relay to {} closed", self.index, addr);
+            self.close_relay(addr, poll);
+        }
+    }
+
+    /// Takes the timeout of `port` if it is the first destination, or if it
+    /// is longer than the current one.
+    fn update_timeout(&mut self, port: u16) {
+        let first = std::mem::replace(&mut self.first_packet, false);
+        match udp_timeout::lookup(port) {
+            Some(timeout) if first || timeout > self.timeout => {
+                self.timeout = timeout;
+                self.timeout_port.replace(port);
+            }
+            None if !first && self.timeout < OPTIONS.udp_idle_duration => {
+                self.timeout = OPTIONS.udp_idle_duration;
+                self.timeout_port = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn do_send(&mut self, mut buffer: &[u8], poll: &Poll) {
         loop {
             match UdpAssociate::parse(buffer) {
+                // --udp-keepalive of the proxy, only refreshing the path
+                UdpParseResult::Packet(packet) if packet.length == 0 => {
+                    UDP_EMPTY_DISCARDED.inc();
+                    log::trace!(
+                        "connection:{} discard empty udp packet to {}",
+                        self.index,
+                        packet.address
+                    );
+                    buffer = packet.payload;
+                }
+                UdpParseResult::Packet(packet) if !acl::allowed(&packet.address) => {
+                    EGRESS_DENIED.inc();
+                    log::debug!(
+                        "connection:{} udp packet to {} denied by egress policy",
+                        self.index,
+                        packet.address
+                    );
+                    buffer = &packet.payload[packet.length..];
+                }
+                UdpParseResult::Packet(packet)
+                    if OPTIONS
+                        .server_args()
+                        .udp_via_tcp_ports
+                        .contains(&packet.address.port()) =>
+                {
+                    self.update_timeout(packet.address.port());
+                    self.relay_tcp(packet.address, &packet.payload[..packet.length], poll);
+                    buffer = &packet.payload[packet.length..];
+                }
                 UdpParseResult::Packet(packet) => {
-                    match self
-                        .socket
-                        .send_to(&packet.payload[..packet.length], packet.address)
-                    {
-                        Ok(size) => {
+                    self.update_timeout(packet.address.port());
+                    let socket = &self.socket;
+                    let sent = {
+                        let _scope = profile::scope(Category::UdpSend);
+                        send_datagram(
+                            |payload| socket.send_to(payload, packet.address),
+                            &packet.payload[..packet.length],
+                            OPTIONS.server_args().udp_truncate,
+                        )
+                    };
+                    match sent {
+                        Sent::Done(size) => {
                             self.bytes_sent += size;
-                            if size != packet.length {
-                                log::error!(
-                                    "connection:{} udp packet is truncated, {}：{}",
-                                    self.index,
-                                    packet.length,
-                                    size
-                                );
-                                self.shutdown();
-                                return;
-                            }
-                            log::debug!(
+                            self.stats.forward();
+                            self.summary.sent(1, size, Some(packet.address));
+                            log::trace!(
                                 "connection:{} write {} bytes to udp target:{}",
                                 self.index,
                                 size,
                                 packet.address
                             );
-                            buffer = &packet.payload[packet.length..];
                         }
-                        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                        Sent::Truncated(size, false) => {
+                            self.bytes_sent += size;
+                            UDP_TRUNCATED.inc();
+                            self.stats.lose(Loss::Oversize);
+                            log::warn!(
+                                "connection:{} udp packet to {} is truncated, {}:{}, dropped",
+                                self.index,
+                                packet.address,
+                                packet.length,
+                                size
+                            );
+                        }
+                        Sent::Truncated(size, true) => {
+                            self.bytes_sent += size;
+                            UDP_TRUNCATED.inc();
+                            self.stats.lose(Loss::Oversize);
+                            log::error!(
+                                "connection:{} udp packet is truncated, {}:{}",
+                                self.index,
+                                packet.length,
+                                size
+                            );
+                            self.shutdown();
+                            return;
+                        }
+                        Sent::Blocked => {
                             log::debug!("connection:{} write to udp target blocked", self.index);
                             self.send_buffer.extend_from_slice(buffer);
                             break;
                         }
-                        Err(err) => {
+                        Sent::Failed(err) => {
                             log::warn!(
                                 "connection:{} send_to {} failed:{}",
                                 self.index,
                                 packet.address,
                                 err
                             );
+                            self.stats.lose(Loss::SendError);
                             self.shutdown();
                             return;
                         }
                     }
+                    // the whole frame is consumed even if the datagram was cut
+                    buffer = &packet.payload[packet.length..];
                 }
                 UdpParseResult::InvalidProtocol => {
                     log::error!("connection:{} got invalid udp protocol", self.index);
+                    self.stats.lose(Loss::Parse);
                     self.shutdown();
                     return;
                 }
@@ -110,47 +484,77 @@ impl UdpBackend {
 }
 
 impl Backend for UdpBackend {
-    fn dispatch(&mut self, buffer: &[u8]) {
+    fn dispatch(&mut self, buffer: &[u8], poll: &Poll) {
+        self.flush_relays(poll);
         if self.send_buffer.is_empty() {
-            self.do_send(buffer);
+            self.do_send(buffer, poll);
         } else {
             self.send_buffer.extend_from_slice(buffer);
             let buffer = self.send_buffer.split();
-            self.do_send(buffer.as_ref());
+            self.do_send(buffer.as_ref(), poll);
         }
+        self.summary.log(self.index);
     }
 
     fn get_timeout(&self) -> Duration {
         self.timeout
     }
 
+    fn expired(&self) {
+        udp_timeout::expired(self.timeout_port);
+    }
+
     fn writable(&self) -> bool {
         self.alive()
     }
 
-    fn do_read(&mut self, conn: &mut TlsConn) {
+    fn do_read(&mut self, conn: &mut TlsConn, poll: &Poll) {
         loop {
-            match self.socket.recv_from(self.recv_body.as_mut_slice()) {
+            let received = {
+                let _scope = profile::scope(Category::UdpRead);
+                self.socket.recv_from(self.recv_body.as_mut_slice())
+            };
+            match received {
                 Ok((size, addr)) => {
                     self.remote_addr = addr;
                     self.bytes_read += size;
-                    log::debug!(
+                    self.summary.received(1, size);
+                    log::trace!(
                         "connection:{} got {} bytes udp data from:{}",
                         self.index,
                         size,
                         addr
                     );
-                    self.recv_head.clear();
-                    UdpAssociate::generate(&mut self.recv_head, &addr, size as u16);
-                    if conn.write_session(self.recv_head.as_ref())
-                        && conn.write_session(&self.recv_body.as_slice()[..size])
-                    {
-                        continue;
+                    let len = UdpAssociate::write(&mut self.recv_head, &addr, size as u16);
+                    let written = conn
+                        .write_frame(&self.recv_head[..len], &self.recv_body.as_slice()[..size]);
+                    match written {
+                        Written::Accepted => {
+                            self.stats.forward();
+                            continue;
+                        }
+                        Written::Full => {
+                            self.stats.forward();
+                            // the rest waits in the socket until the session drained
+                            conn.do_send();
+                            if conn.writable() {
+                                continue;
+                            }
+                            log::debug!("connection:{} session full, pause reading", self.index);
+                        }
+                        Written::Failed => self.stats.lose(Loss::BufferFull),
                     }
                 }
                 Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
                     log::debug!("connection:{} write to session blocked", self.index);
                 }
+                // Winsock reports an ICMP port unreachable of an earlier
+                // send_to as WSAECONNRESET on the next receive.
+                #[cfg(windows)]
+                Err(err) if err.kind() == std::io::ErrorKind::ConnectionReset => {
+                    log::debug!("connection:{} udp target unreachable", self.index);
+                    continue;
+                }
                 Err(err) => {
                     log::warn!("connection:{} got udp read err:{}", self.index, err);
                     self.shutdown();
@@ -158,7 +562,40 @@ impl Backend for UdpBackend {
             }
             break;
         }
+        if !self.tcp_relays.is_empty() {
+            self.read_relays(conn, poll);
+        }
         conn.do_send();
+        self.summary.log(self.index);
+    }
+
+    fn tick(&mut self) {
+        self.summary.log(self.index);
+    }
+
+    fn sample_kernel(&mut self) {
+        self.count_kernel_drops();
+    }
+
+    fn dump_state(&self) -> String {
+        format!(
+            "udp {:?}/{} port:{} buf:{} relays:{} timeout:{}s sent:{} recv:{} {}",
+            self.status,
+            self.interests(),
+            self.local_port,
+            self.send_buffer.len(),
+            self.tcp_relays.len(),
+            self.timeout.as_secs(),
+            self.bytes_sent,
+            self.bytes_read,
+            self.stats
+        )
+    }
+}
+
+impl Drop for UdpBackend {
+    fn drop(&mut self) {
+        udp_loss::unregister(self.index);
     }
 }
 
@@ -176,7 +613,16 @@ impl StatusProvider for UdpBackend {
     }
 
     fn deregister(&mut self, poll: &Poll) -> bool {
+        if OPTIONS.server_args().kernel_stats {
+            self.count_kernel_drops();
+        }
         let _ = poll.registry().deregister(&mut self.socket);
+        let relays: Vec<_> = self.tcp_relays.keys().copied().collect();
+        for addr in relays {
+            self.close_relay(addr, poll);
+        }
+        self.stats.log_closed(self.index);
+        self.summary.flush(self.index);
         true
     }
 
@@ -187,3 +633,123 @@ impl StatusProvider for UdpBackend {
         )
     }
 }
+
+mod test {
+    #![allow(unused_imports, dead_code)]
+
+    use std::{
+        collections::VecDeque,
+        io::{ErrorKind, Write},
+        net::SocketAddr,
+    };
+
+    use bytes::BytesMut;
+    use mio::net::TcpStream;
+
+    use crate::{
+        config::UdpTruncate,
+        proto::{UdpAssociate, UdpParseResult, MAX_PACKET_SIZE},
+        server::udp_backend::{send_datagram, Sent, TcpRelay, RELAY_BUFFER_LIMIT},
+    };
+
+    /// Parses two frames like do_send, the socket cuts the first send short.
+    fn replay(policy: UdpTruncate, results: &[usize]) -> (Vec<String>, Vec<Vec<u8>>) {
+        let addr: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let mut buffer = vec![0u8; 64];
+        let mut len = UdpAssociate::write(&mut buffer, &addr, 5);
+        buffer.truncate(len);
+        buffer.extend_from_slice(b"first");
+        len = buffer.len();
+        buffer.resize(len + 64, 0);
+        len += UdpAssociate::write(&mut buffer[len..], &addr, 6);
+        buffer.truncate(len);
+        buffer.extend_from_slice(b"second");
+
+        let mut results: VecDeque<_> = results.iter().copied().collect();
+        let mut sent = Vec::new();
+        let mut outcomes = Vec::new();
+        let mut buffer = buffer.as_slice();
+        while let UdpParseResult::Packet(packet) = UdpAssociate::parse(buffer) {
+            let payload = &packet.payload[..packet.length];
+            let result = send_datagram(
+                |data| {
+                    let size = results.pop_front().unwrap_or(data.len()).min(data.len());
+                    sent.push(data[..size].to_vec());
+                    Ok(size)
+                },
+                payload,
+                policy,
+            );
+            let done = matches!(result, Sent::Truncated(_, true));
+            outcomes.push(format!("{:?}", result));
+            if done {
+                break;
+            }
+            buffer = &packet.payload[packet.length..];
+        }
+        (outcomes, sent)
+    }
+
+    #[test]
+    fn test_truncate_policies() {
+        let (outcomes, sent) = replay(UdpTruncate::Drop, &[2]);
+        assert_eq!(outcomes, ["Truncated(2, false)", "Done(6)"]);
+        assert_eq!(sent, [b"fi".to_vec(), b"second".to_vec()]);
+
+        let (outcomes, sent) = replay(UdpTruncate::Retry, &[2]);
+        assert_eq!(outcomes, ["Done(5)", "Done(6)"]);
+        assert_eq!(sent.last().unwrap(), b"second");
+        let (outcomes, _) = replay(UdpTruncate::Retry, &[2, 3]);
+        assert_eq!(outcomes, ["Truncated(3, false)", "Done(6)"]);
+
+        let (outcomes, sent) = replay(UdpTruncate::Shutdown, &[2]);
+        assert_eq!(outcomes, ["Truncated(2, true)"]);
+        assert_eq!(sent.len(), 1);
+
+        let result = send_datagram(
+            |_| Err(ErrorKind::WouldBlock.into()),
+            b"data",
+            UdpTruncate::Drop,
+        );
+        assert!(matches!(result, Sent::Blocked));
+    }
+
+    #[test]
+    fn test_relay_limits() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut relay = TcpRelay {
+            stream: TcpStream::from_std(stream),
+            send_buffer: BytesMut::new(),
+            recv_buffer: BytesMut::new(),
+        };
+        let payload = [0x5au8; 1000];
+        let pushed = (0..100).filter(|_| relay.push(&payload)).count();
+        assert_eq!(pushed, RELAY_BUFFER_LIMIT / (payload.len() + 2));
+        assert!(relay.send_buffer.len() <= RELAY_BUFFER_LIMIT);
+
+        // the peer sends more than the buffer holds before any is popped
+        let count = 100;
+        for n in 0..count {
+            peer.write_all(&[0x03, 0xe8]).unwrap();
+            peer.write_all(&[n as u8; 1000]).unwrap();
+        }
+        let total = count * (payload.len() + 2);
+        let mut buffer = vec![0u8; MAX_PACKET_SIZE];
+        let mut dropped = 0;
+        while dropped * (payload.len() + 2) + relay.recv_buffer.len() < total {
+            assert!(relay.fill(buffer.as_mut_slice(), &mut dropped));
+            assert!(relay.recv_buffer.len() <= RELAY_BUFFER_LIMIT);
+        }
+        assert!(dropped > 0);
+        // the newest datagrams are kept
+        let mut next = dropped;
+        while let Ok(Some(datagram)) = relay.pop() {
+            assert_eq!(datagram[0] as usize, next);
+            next += 1;
+        }
+        assert_eq!(next, count);
+    }
+}
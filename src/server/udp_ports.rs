@@ -0,0 +1,153 @@
+//! Source ports and addresses of the udp backend sockets.
+//!
+//! Some hosting networks drop long lived udp flows of one source port. With
+//! `--udp-port-range 20000-30000` each new association binds the next port
+//! of the range in turn, skipping ports in use. After [`MAX_ATTEMPTS`] ports
+//! in use in a row the range counts as exhausted and the socket gets an
+//! ephemeral port like without the option.
+//!
+//! On a server with several addresses replies leave from the address of
+//! the default route, which strict clients drop. `--udp-bind-ingress-ip`
+//! binds the socket to the local address the tls connection of the
+//! association came in on. An ingress of the other family than the udp
+//! sockets, or a loopback one like behind a local load balancer, keeps the
+//! wildcard address.
+use std::{
+    io::{ErrorKind, Result},
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+};
+
+use mio::net::UdpSocket;
+
+use crate::{config::OPTIONS, metrics::UDP_PORT_RANGE_EXHAUSTED};
+
+/// Ports tried at most for one socket
+pub const MAX_ATTEMPTS: usize = 64;
+
+struct PortRange {
+    first: u16,
+    last: u16,
+    next: u16,
+}
+
+impl PortRange {
+    fn len(&self) -> usize {
+        (self.last - self.first) as usize + 1
+    }
+
+    fn advance(&mut self) -> u16 {
+        let port = self.next;
+        self.next = if port == self.last {
+            self.first
+        } else {
+            port + 1
+        };
+        port
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref RANGE: Mutex<Option<PortRange>> = Mutex::new(None);
+}
+
+fn parse(text: &str) -> Option<PortRange> {
+    let (first, last) = text.split_once('-')?;
+    let first: u16 = first.trim().parse().ok()?;
+    let last: u16 = last.trim().parse().ok()?;
+    if first == 0 || first > last {
+        return None;
+    }
+    Some(PortRange {
+        first,
+        last,
+        next: first,
+    })
+}
+
+/// Reads the range, panics for an invalid one like other startup options.
+pub fn init() {
+    let text = OPTIONS.server_args().udp_port_range.as_str();
+    if text.is_empty() {
+        return;
+    }
+    let range = parse(text).unwrap_or_else(|| panic!("invalid --udp-port-range:{}", text));
+    *RANGE.lock().unwrap() = Some(range);
+}
+
+/// Address of the udp socket of an association whose tls connection came
+/// in on `ingress`.
+pub fn source(ingress: Option<SocketAddr>) -> SocketAddr {
+    let empty = OPTIONS.empty_addr.unwrap();
+    if OPTIONS.server_args().udp_bind_ingress_ip {
+        ingress_source(empty, ingress)
+    } else {
+        empty
+    }
+}
+
+fn ingress_source(empty: SocketAddr, ingress: Option<SocketAddr>) -> SocketAddr {
+    let ip = match ingress.map(|ingress| ingress.ip()) {
+        // a dual stack listener sees ipv4 clients mapped
+        Some(IpAddr::V6(ip)) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        Some(ip) => ip,
+        None => return empty,
+    };
+    if ip.is_loopback() || ip.is_ipv4() != empty.is_ipv4() {
+        empty
+    } else {
+        SocketAddr::new(ip, 0)
+    }
+}
+
+/// Binds a socket on `addr` with the next free port of the range, or an
+/// ephemeral one.
+pub fn bind(addr: SocketAddr) -> Result<UdpSocket> {
+    if let Some(range) = RANGE.lock().unwrap().as_mut() {
+        for _ in 0..range.len().min(MAX_ATTEMPTS) {
+            let port = range.advance();
+            match UdpSocket::bind(SocketAddr::new(addr.ip(), port)) {
+                Ok(socket) => return Ok(socket),
+                Err(err) if err.kind() == ErrorKind::AddrInUse => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        UDP_PORT_RANGE_EXHAUSTED.inc();
+        log::debug!("udp port range exhausted, binding an ephemeral port");
+    }
+    UdpSocket::bind(addr)
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::net::SocketAddr;
+
+    use crate::server::udp_ports::{ingress_source, parse};
+
+    #[test]
+    fn test_range() {
+        let mut range = parse("20000-20002").unwrap();
+        assert_eq!(range.len(), 3);
+        let ports: Vec<_> = (0..4).map(|_| range.advance()).collect();
+        assert_eq!(ports, vec![20000, 20001, 20002, 20000]);
+        assert_eq!(parse("65535-65535").unwrap().advance(), 65535);
+        assert!(parse("30000-20000").is_none());
+        assert!(parse("0-100").is_none());
+        assert!(parse("20000").is_none());
+    }
+
+    #[test]
+    fn test_ingress_source() {
+        let empty: SocketAddr = "0.0.0.0:0".parse().unwrap();
+        let source = |ingress: &str| ingress_source(empty, Some(ingress.parse().unwrap()));
+        assert_eq!(source("203.0.113.7:443"), "203.0.113.7:0".parse().unwrap());
+        assert_eq!(
+            source("[::ffff:203.0.113.7]:443"),
+            "203.0.113.7:0".parse().unwrap()
+        );
+        assert_eq!(source("[2001:db8::1]:443"), empty);
+        assert_eq!(source("127.0.0.1:443"), empty);
+        assert_eq!(ingress_source(empty, None), empty);
+    }
+}
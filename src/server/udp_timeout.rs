@@ -0,0 +1,92 @@
+//! Idle timeouts of udp associations by destination port.
+//!
+//! `--udp-port-timeout 53=3 --udp-port-timeout 443=120` gives associations
+//! whose first datagram goes to port 53 a 3 seconds timeout, and QUIC on 443
+//! two minutes. Other ports use `--udp-idle-timeout`. A later datagram to a
+//! port with a longer timeout raises it, one association never expires
+//! earlier than its slowest destination would. Expired associations are
+//! counted by the port whose timeout they had, reported with the metrics.
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+use crate::config::OPTIONS;
+
+lazy_static::lazy_static! {
+    static ref TIMEOUTS: Mutex<Vec<(u16, Duration)>> = Mutex::new(Vec::new());
+    /// Expired associations, None for the ones with the default timeout
+    static ref EXPIRED: Mutex<BTreeMap<Option<u16>, usize>> = Mutex::new(BTreeMap::new());
+}
+
+fn parse(text: &str) -> Option<(u16, Duration)> {
+    let (port, secs) = text.trim().split_once('=')?;
+    let secs: u64 = secs.trim().parse().ok()?;
+    if secs == 0 {
+        return None;
+    }
+    Some((port.trim().parse().ok()?, Duration::from_secs(secs)))
+}
+
+/// Reads the overrides, panics for invalid ones like other startup options.
+pub fn init() {
+    let timeouts = OPTIONS
+        .server_args()
+        .udp_port_timeout
+        .iter()
+        .map(|text| {
+            parse(text.as_str()).unwrap_or_else(|| panic!("invalid --udp-port-timeout:{}", text))
+        })
+        .collect();
+    *TIMEOUTS.lock().unwrap() = timeouts;
+}
+
+/// Returns the timeout override of `port`, the last option given wins.
+pub fn lookup(port: u16) -> Option<Duration> {
+    TIMEOUTS
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|(key, _)| *key == port)
+        .map(|(_, timeout)| *timeout)
+}
+
+pub fn expired(class: Option<u16>) {
+    *EXPIRED.lock().unwrap().entry(class).or_default() += 1;
+}
+
+fn format(expired: &BTreeMap<Option<u16>, usize>) -> String {
+    expired
+        .iter()
+        .map(|(class, count)| match class {
+            Some(port) => format!("{}={}", port, count),
+            None => format!("default={}", count),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Logs the expired associations by port class.
+pub fn report() {
+    let expired = EXPIRED.lock().unwrap();
+    if !expired.is_empty() {
+        log::info!("udp expired: {}", format(&expired));
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::{collections::BTreeMap, time::Duration};
+
+    use crate::server::udp_timeout::{format, parse};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("53=3"), Some((53, Duration::from_secs(3))));
+        assert_eq!(parse(" 443 = 120"), Some((443, Duration::from_secs(120))));
+        assert!(parse("53=0").is_none());
+        assert!(parse("53=3s").is_none());
+        assert!(parse("70000=3").is_none());
+        let expired: BTreeMap<_, _> = [(Some(443), 2), (None, 5), (Some(53), 9)].into();
+        assert_eq!(format(&expired), "default=5 53=9 443=2");
+    }
+}
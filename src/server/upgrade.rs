@@ -0,0 +1,257 @@
+//! Upgrades of the server without dropping connections, requested with
+//! SIGUSR1 when `--upgrade-socket` is set.
+//!
+//! The old process binds a unix socket at that path, only its user may
+//! connect to, and starts the binary it was started as, with the same
+//! arguments and the path in `TROJAN_UPGRADE_SOCKET`. Instead of binding,
+//! the new process connects to the socket and, once its pid is checked,
+//! gets the listeners passed with SCM_RIGHTS along with [`HELLO`], in the
+//! order of local-addr and extra-local-addr, and answers [`ACK`] once it
+//! accepts on them. The old process then stops accepting and drains,
+//! exiting once its last connection is closed. Established connections
+//! are not moved, a long download stays on the old process till it ends.
+//!
+//! If the new process exits or doesn't answer within [`HANDOFF_TIMEOUT`],
+//! it is killed and the old process goes on accepting. The quota usage is
+//! saved right before the handoff for the new process to start from, the
+//! draining process doesn't save it any more.
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{Receiver, TryRecvError},
+    Arc,
+};
+#[cfg(unix)]
+use std::time::Duration;
+
+use mio::net::TcpListener;
+
+use crate::types::Result;
+
+#[cfg(unix)]
+const SOCKET_ENV: &str = "TROJAN_UPGRADE_SOCKET";
+/// Message carrying the listener
+#[cfg(unix)]
+const HELLO: &[u8] = b"TRJUPGRADE1";
+/// Answer of the new process once it accepts
+#[cfg(unix)]
+const ACK: &[u8] = b"OK";
+#[cfg(unix)]
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+    static ref REQUESTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+}
+
+/// Installs the SIGUSR1 handler.
+pub fn init() {
+    #[cfg(unix)]
+    if let Err(err) = signal_hook::flag::register(libc::SIGUSR1, REQUESTED.clone()) {
+        log::error!("register SIGUSR1 for upgrading failed:{}", err);
+    }
+}
+
+/// Connection to the old process of an upgrade, answered once the
+/// inherited listeners are registered.
+pub struct Handoff {
+    #[cfg(unix)]
+    stream: std::os::unix::net::UnixStream,
+}
+
+impl Handoff {
+    pub fn confirm(self) {
+        #[cfg(unix)]
+        if let Err(err) = std::io::Write::write_all(&mut &self.stream, ACK) {
+            log::error!("confirm upgrade failed:{}", err);
+        }
+    }
+}
+
+/// Takes the listeners from the process being upgraded, if this process
+/// was started by one.
+#[cfg(unix)]
+pub fn inherit() -> Result<Option<(Vec<TcpListener>, Handoff)>> {
+    use std::{
+        io::{Error, ErrorKind},
+        os::unix::{io::FromRawFd, net::UnixStream},
+    };
+
+    let path = match std::env::var(SOCKET_ENV) {
+        Ok(path) => path,
+        Err(_) => return Ok(None),
+    };
+    std::env::remove_var(SOCKET_ENV);
+    let stream = UnixStream::connect(path.as_str())?;
+    stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+    let mut buffer = [0u8; 32];
+    let (len, fds) = crate::sys::recv_fds(&stream, &mut buffer)?;
+    if fds.is_empty() || &buffer[..len] != HELLO {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid upgrade message").into());
+    }
+    let mut listeners = Vec::new();
+    for fd in fds {
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        log::warn!(
+            "listener {} inherited from the old process",
+            listener.local_addr()?
+        );
+        listeners.push(TcpListener::from_std(listener));
+    }
+    Ok(Some((listeners, Handoff { stream })))
+}
+
+#[cfg(not(unix))]
+pub fn inherit() -> Result<Option<(Vec<TcpListener>, Handoff)>> {
+    Ok(None)
+}
+
+/// Passes `fds` to a new process, returns its pid once it accepts.
+#[cfg(unix)]
+fn hand_off(path: &str, fds: &[std::os::unix::io::RawFd]) -> std::io::Result<u32> {
+    use std::{
+        io::{Error, ErrorKind, Read},
+        process::Command,
+        thread,
+        time::Instant,
+    };
+
+    let _ = std::fs::remove_file(path);
+    let socket = crate::sys::bind_private(path)?;
+    socket.set_nonblocking(true)?;
+    let mut args = std::env::args_os();
+    let program = args.next().unwrap_or_default();
+    let mut child = Command::new(program)
+        .args(args)
+        .env(SOCKET_ENV, path)
+        .spawn()?;
+    let deadline = Instant::now() + HANDOFF_TIMEOUT;
+    let result = (|| {
+        let mut stream = loop {
+            match socket.accept() {
+                Ok((stream, _)) => match crate::sys::peer_cred(&stream) {
+                    Ok((pid, _)) if pid == child.id() => break stream,
+                    Ok((pid, uid)) => {
+                        log::error!(
+                            "upgrade socket connected by pid:{} uid:{}, closed",
+                            pid,
+                            uid
+                        )
+                    }
+                    Err(err) => log::error!("upgrade socket peer unknown:{}", err),
+                },
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if let Some(status) = child.try_wait()? {
+                        return Err(Error::other(format!("new process exited with {}", status)));
+                    }
+                    if Instant::now() > deadline {
+                        return Err(Error::new(ErrorKind::TimedOut, "new process not connected"));
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(deadline.saturating_duration_since(Instant::now())))?;
+        crate::sys::send_fds(&stream, HELLO, fds)?;
+        let mut ack = [0u8; 2];
+        stream.read_exact(&mut ack)?;
+        if ack != ACK {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid upgrade answer"));
+        }
+        Ok(child.id())
+    })();
+    let _ = std::fs::remove_file(path);
+    if result.is_err() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    result
+}
+
+// only Active where upgrades aren't supported
+#[cfg_attr(not(unix), allow(dead_code))]
+enum State {
+    Active,
+    Handing(Receiver<std::io::Result<u32>>),
+    Draining,
+}
+
+pub struct Upgrader {
+    #[cfg(unix)]
+    path: String,
+    #[cfg(unix)]
+    fds: Vec<std::os::unix::io::RawFd>,
+    state: State,
+}
+
+impl Upgrader {
+    pub fn new(path: &str, listeners: &[TcpListener]) -> Upgrader {
+        #[cfg(not(unix))]
+        let _ = (path, listeners);
+        Upgrader {
+            #[cfg(unix)]
+            path: path.to_owned(),
+            #[cfg(unix)]
+            fds: listeners
+                .iter()
+                .map(std::os::unix::io::AsRawFd::as_raw_fd)
+                .collect(),
+            state: State::Active,
+        }
+    }
+
+    /// Starts a requested upgrade and follows the one running, returns
+    /// true once the new process accepts and this one has to drain.
+    pub fn check(&mut self) -> bool {
+        match &self.state {
+            State::Active if REQUESTED.swap(false, Ordering::SeqCst) => self.start(),
+            State::Handing(receiver) => match receiver.try_recv() {
+                Ok(Ok(pid)) => {
+                    log::warn!("new process {} accepts, draining connections", pid);
+                    self.state = State::Draining;
+                }
+                Ok(Err(err)) => {
+                    log::error!("upgrade failed, going on accepting:{}", err);
+                    self.state = State::Active;
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => {
+                    log::error!("upgrade thread gone, going on accepting");
+                    self.state = State::Active;
+                }
+            },
+            _ => {}
+        }
+        matches!(self.state, State::Draining)
+    }
+
+    /// Whether the quota usage may still be saved.
+    pub fn saving(&self) -> bool {
+        !matches!(self.state, State::Draining)
+    }
+
+    #[cfg(unix)]
+    fn start(&mut self) {
+        if self.path.is_empty() {
+            log::warn!("upgrade requested without --upgrade-socket, ignored");
+            return;
+        }
+        log::warn!("upgrade requested, starting the new process");
+        super::quota::save();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let path = self.path.clone();
+        let fds = self.fds.clone();
+        // the listeners stay open while handing, the old process only
+        // deregisters them once the new one accepts
+        std::thread::spawn(move || {
+            let _ = sender.send(hand_off(path.as_str(), fds.as_slice()));
+        });
+        self.state = State::Handing(receiver);
+    }
+
+    #[cfg(not(unix))]
+    fn start(&mut self) {
+        log::warn!("upgrade not supported on this platform, ignored");
+    }
+}
@@ -0,0 +1,223 @@
+//! Stand-ins for the clock and sockets, and sessions over loopback, so
+//! tests can drive connection logic through an exact scenario. The code
+//! under test takes the time as an argument already, [`MockClock`] only
+//! makes the scenario read well.
+#![allow(dead_code)]
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::{Cell, RefCell},
+    convert::TryInto,
+    fs::File,
+    io::{BufReader, Error, ErrorKind, Result},
+    net::Shutdown,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use mio::{
+    event::Source,
+    net::{TcpListener, TcpStream},
+    Interest, Registry, Token,
+};
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, Connection, PrivateKey, RootCertStore,
+    ServerConfig, ServerConnection,
+};
+
+use crate::{status::StatusProvider, tcp_util::TcpIo, tls_conn::TlsConn};
+
+/// The allocator of the test binary, counting the allocations of each
+/// thread so tests can assert a hot path doesn't allocate.
+pub struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Allocations and reallocations of this thread so far.
+pub fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+pub struct MockClock(Cell<Instant>);
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock(Cell::new(Instant::now()))
+    }
+
+    pub fn now(&self) -> Instant {
+        self.0.get()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.0.set(self.0.get() + duration);
+    }
+}
+
+/// What a [`ScriptedStream`] was asked to do, in order
+#[derive(Debug, PartialEq, Eq)]
+pub enum Op {
+    Write(Vec<u8>),
+    Shutdown,
+}
+
+/// A stream whose writes take at most the allowed bytes before blocking,
+/// reads always block.
+#[derive(Default)]
+pub struct ScriptedStream {
+    allowed: Cell<usize>,
+    interrupts: Cell<usize>,
+    ops: RefCell<Vec<Op>>,
+}
+
+impl ScriptedStream {
+    /// Lets writes take `bytes` more bytes.
+    pub fn allow(&self, bytes: usize) {
+        self.allowed.set(self.allowed.get() + bytes);
+    }
+
+    /// Fails the next `count` writes with EINTR.
+    pub fn interrupt(&self, count: usize) {
+        self.interrupts.set(count);
+    }
+
+    pub fn take_ops(&self) -> Vec<Op> {
+        std::mem::take(&mut *self.ops.borrow_mut())
+    }
+
+    /// All bytes written so far.
+    pub fn written(&self) -> Vec<u8> {
+        self.ops
+            .borrow()
+            .iter()
+            .filter_map(|op| match op {
+                Op::Write(data) => Some(data.as_slice()),
+                Op::Shutdown => None,
+            })
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
+impl TcpIo for ScriptedStream {
+    fn read(&self, _: &mut [u8]) -> Result<usize> {
+        Err(Error::from(ErrorKind::WouldBlock))
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize> {
+        if self.interrupts.get() > 0 {
+            self.interrupts.set(self.interrupts.get() - 1);
+            return Err(Error::from(ErrorKind::Interrupted));
+        }
+        let size = data.len().min(self.allowed.get());
+        if size == 0 && !data.is_empty() {
+            return Err(Error::from(ErrorKind::WouldBlock));
+        }
+        self.allowed.set(self.allowed.get() - size);
+        self.ops.borrow_mut().push(Op::Write(data[..size].to_vec()));
+        Ok(size)
+    }
+
+    fn shutdown(&self, _: Shutdown) -> Result<()> {
+        self.ops.borrow_mut().push(Op::Shutdown);
+        Ok(())
+    }
+}
+
+impl Source for ScriptedStream {
+    fn register(&mut self, _: &Registry, _: Token, _: Interest) -> Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&mut self, _: &Registry, _: Token, _: Interest) -> Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&mut self, _: &Registry) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub fn load_certs(path: &str) -> Vec<Certificate> {
+    let mut reader = BufReader::new(File::open(path).unwrap());
+    rustls_pemfile::certs(&mut reader)
+        .unwrap()
+        .into_iter()
+        .map(Certificate)
+        .collect()
+}
+
+pub fn load_key(path: &str) -> PrivateKey {
+    let mut reader = BufReader::new(File::open(path).unwrap());
+    PrivateKey(
+        rustls_pemfile::pkcs8_private_keys(&mut reader)
+            .unwrap()
+            .remove(0),
+    )
+}
+
+/// Returns a connected client and server over loopback.
+pub fn loopback_pair() -> (TlsConn, TlsConn) {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs("tests/certs/ca.pem") {
+        roots.add(&cert).unwrap();
+    }
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            load_certs("tests/certs/server.pem"),
+            load_key("tests/certs/server.key"),
+        )
+        .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let accepted = loop {
+        if let Ok((accepted, _)) = listener.accept() {
+            break accepted;
+        }
+    };
+    let session =
+        ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap()).unwrap();
+    let mut client = TlsConn::new(0, Token(0), Connection::Client(session), stream);
+    let session = ServerConnection::new(Arc::new(server_config)).unwrap();
+    let mut server = TlsConn::new(1, Token(1), Connection::Server(session), accepted);
+    client.established();
+    server.established();
+    while client.handshaking() || server.handshaking() {
+        client.do_send();
+        server.do_send();
+        client.do_read_into(&mut BytesMut::new());
+        server.do_read_into(&mut BytesMut::new());
+    }
+    (client, server)
+}
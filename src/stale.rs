@@ -0,0 +1,59 @@
+//! Events for connections which are gone already.
+//!
+//! A poll batch may still carry events for a connection destroyed by an
+//! earlier event of the same batch, and dns results may arrive after the
+//! connection is closed. Neither is actionable, so they are dropped
+//! quietly instead of flooding the log.
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+/// Interval between two logs of unexpected events
+const REPORT_DURATION: Duration = Duration::from_secs(1);
+
+pub struct StaleEvents {
+    /// Indexes removed since the last poll
+    freed: HashSet<usize>,
+    missing: usize,
+    report_time: Instant,
+}
+
+impl StaleEvents {
+    pub fn new() -> StaleEvents {
+        StaleEvents {
+            freed: HashSet::new(),
+            missing: 0,
+            report_time: Instant::now(),
+        }
+    }
+
+    /// Forgets the indexes freed before the last poll, called once per
+    /// poll loop iteration before removing connections.
+    pub fn next_generation(&mut self) {
+        self.freed.clear();
+    }
+
+    pub fn freed(&mut self, index: usize) {
+        self.freed.insert(index);
+    }
+
+    /// Records an event for `index` which has no connection.
+    pub fn missing(&mut self, kind: &str, index: usize) {
+        if self.freed.contains(&index) {
+            log::trace!("drop event of freed {} connection:{}", kind, index);
+            return;
+        }
+        self.missing += 1;
+        if self.report_time.elapsed() > REPORT_DURATION {
+            log::debug!(
+                "{} events of unknown {} connections, last index:{}",
+                self.missing,
+                kind,
+                index
+            );
+            self.missing = 0;
+            self.report_time = Instant::now();
+        }
+    }
+}
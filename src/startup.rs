@@ -0,0 +1,167 @@
+//! Hints for the startup failures new setups hit most: a port in use, a
+//! port below 1024 without the privilege, and socket options which need
+//! CAP_NET_ADMIN. Each exits with its own code, see [`StartupFailure`].
+use std::{io::ErrorKind, net::SocketAddr};
+
+use crate::{
+    sys,
+    types::{Result, StartupFailure, TrojanError},
+};
+
+fn exe() -> String {
+    std::env::current_exe()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| "trojan".to_owned())
+}
+
+/// Classifies a failed bind of `addr` given with `--option`.
+pub fn bind_error(
+    option: &str,
+    addr: SocketAddr,
+    is_udp: bool,
+    err: std::io::Error,
+) -> TrojanError {
+    let protocol = if is_udp { "udp" } else { "tcp" };
+    match err.kind() {
+        ErrorKind::AddrInUse => {
+            let owner = match sys::port_owner(is_udp, addr.port()) {
+                Some((pid, name)) => format!("{} (pid {})", name, pid),
+                None => format!(
+                    "another process, find it with `ss -lnp{} 'sport = :{}'`",
+                    if is_udp { "u" } else { "t" },
+                    addr.port()
+                ),
+            };
+            TrojanError::Startup(
+                StartupFailure::AddrInUse,
+                format!(
+                    "{} address {} of --{} is already used by {}, stop it or change --{}",
+                    protocol, addr, option, owner, option
+                ),
+            )
+        }
+        ErrorKind::PermissionDenied if addr.port() < 1024 => TrojanError::Startup(
+            StartupFailure::BindDenied,
+            format!(
+                "binding {} port {} of --{} needs root or CAP_NET_BIND_SERVICE, run \
+                 `setcap cap_net_bind_service=+ep {}` or use a port from 1024",
+                protocol,
+                addr.port(),
+                option,
+                exe()
+            ),
+        ),
+        _ => err.into(),
+    }
+}
+
+fn privilege_error(hint: String) -> TrojanError {
+    TrojanError::Startup(StartupFailure::PrivilegeDrop, hint)
+}
+
+/// Switches to `user` and `group`, failing unless root is gone for good.
+/// `files` are the options and paths opened again later, each checked to
+/// be readable or writable by the user now rather than on first use.
+pub fn drop_privileges(user: &str, group: &str, files: &[(&str, &str, bool)]) -> Result<()> {
+    let (uid, gid) = sys::switch_user(user, group).map_err(|err| {
+        privilege_error(format!(
+            "switching to user {} group {} failed:{}, start as root to use --user",
+            user,
+            if group.is_empty() {
+                "of the user"
+            } else {
+                group
+            },
+            err
+        ))
+    })?;
+    if sys::is_root() {
+        return Err(privilege_error(format!(
+            "still root after switching to user {}, choose an unprivileged --user",
+            user
+        )));
+    }
+    log::warn!("switched to user {} uid:{} gid:{}", user, uid, gid);
+    for (option, path, write) in files {
+        if path.is_empty() {
+            continue;
+        }
+        if !sys::accessible(path, *write) {
+            return Err(privilege_error(format!(
+                "{} of --{} is not {} by user {}",
+                path,
+                option,
+                if *write { "writable" } else { "readable" },
+                user
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Classifies a failed setsockopt of `what`, which needs CAP_NET_ADMIN.
+pub fn capability_error(what: &str, err: std::io::Error) -> TrojanError {
+    if err.kind() == ErrorKind::PermissionDenied {
+        TrojanError::Startup(
+            StartupFailure::NoCapability,
+            format!(
+                "{} needs CAP_NET_ADMIN, run as root, `setcap cap_net_admin,cap_net_bind_service=+ep {}` \
+                 or set AmbientCapabilities=CAP_NET_ADMIN in the systemd unit",
+                what,
+                exe()
+            ),
+        )
+    } else {
+        err.into()
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::{
+        io::{Error, ErrorKind},
+        net::TcpListener,
+    };
+
+    use crate::{
+        startup::{bind_error, capability_error},
+        types::{StartupFailure, TrojanError},
+    };
+
+    #[test]
+    fn test_classify() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let err = TcpListener::bind(addr).unwrap_err();
+        match bind_error("local-addr", addr, false, err) {
+            TrojanError::Startup(StartupFailure::AddrInUse, hint) => {
+                if cfg!(target_os = "linux") {
+                    assert!(
+                        hint.contains(&format!("pid {}", std::process::id())),
+                        "{}",
+                        hint
+                    );
+                }
+            }
+            err => panic!("not classified:{:?}", err),
+        }
+        let err = Error::from(ErrorKind::PermissionDenied);
+        let addr = "0.0.0.0:443".parse().unwrap();
+        match bind_error("local-addr", addr, false, err) {
+            TrojanError::Startup(StartupFailure::BindDenied, hint) => assert!(hint.contains("443")),
+            err => panic!("not classified:{:?}", err),
+        }
+        let err = Error::from(ErrorKind::PermissionDenied);
+        let addr = "0.0.0.0:8443".parse().unwrap();
+        assert!(matches!(
+            bind_error("local-addr", addr, false, err),
+            TrojanError::StdIo(_)
+        ));
+        let err = Error::from(ErrorKind::PermissionDenied);
+        assert!(matches!(
+            capability_error("--marker", err),
+            TrojanError::Startup(StartupFailure::NoCapability, _)
+        ));
+    }
+}
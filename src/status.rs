@@ -1,19 +1,95 @@
 use mio::Poll;
 
+/// Why a connection was closed by ourselves
 #[derive(Copy, Clone, Debug)]
+pub enum CloseReason {
+    // server side never connected within connect timeout
+    ConnectTimeout,
+    // no response byte within first byte timeout
+    FirstByteTimeout,
+    // no activity within idle timeout
+    IdleTimeout,
+    // target forbidden by the egress policy
+    EgressDenied,
+    // traffic quota used up
+    QuotaExceeded,
+    // pending data not flushed within drain timeout
+    DrainTimeout,
+    // open longer than the max lifetime, however active
+    LifetimeExceeded,
+    // rejected or closed while the server is in maintenance
+    Maintenance,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ConnStatus {
     Connecting,
     //
     Established,
     // connection is ok
     PeerClosed,
-    // peer is closed, sending remaining data
+    // peer is closed, draining remaining data with writable interest kept,
+    // closed once finish_send returns true or the drain timeout expires
     Shutdown,
     // self shutdown now
     Deregistered, // self deregistered
 }
 
+/// Something happening to a connection which may change its status
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StatusEvent {
+    // first writable event
+    Established,
+    // peer finished sending
+    PeerClosed,
+    // closing ourselves
+    Shutdown,
+    // checked by the poll loop
+    Check,
+}
+
+/// What a connection must do before it may take the next status
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StatusAction {
+    Nothing,
+    // close the socket
+    Close,
+    // flush the pending data, then close the socket
+    Drain,
+    // remove the socket from poll
+    Deregister,
+}
+
+impl ConnStatus {
+    /// Returns the action to take on `event` and the status to move to
+    /// once it succeeded, `None` if the event doesn't apply.
+    pub fn next(self, event: StatusEvent) -> Option<(StatusAction, ConnStatus)> {
+        use ConnStatus::*;
+        match (self, event) {
+            (Connecting, StatusEvent::Established) => Some((StatusAction::Nothing, Established)),
+            (Connecting | Established, StatusEvent::PeerClosed) => {
+                Some((StatusAction::Nothing, PeerClosed))
+            }
+            (Connecting | Established | PeerClosed, StatusEvent::Shutdown) => {
+                Some((StatusAction::Close, Shutdown))
+            }
+            (PeerClosed, StatusEvent::Check) => Some((StatusAction::Drain, Shutdown)),
+            (Shutdown, StatusEvent::Check) => Some((StatusAction::Deregister, Deregistered)),
+            _ => None,
+        }
+    }
+}
+
 pub trait StatusProvider {
+    /// Interests registered in poll, shown in the state dump. Sockets are
+    /// always registered readable and writable until deregistered.
+    fn interests(&self) -> &'static str {
+        if self.deregistered() {
+            "-"
+        } else {
+            "rw"
+        }
+    }
     fn deregistered(&self) -> bool {
         matches!(self.get_status(), ConnStatus::Deregistered)
     }
@@ -34,52 +110,198 @@ pub trait StatusProvider {
     }
     fn set_status(&mut self, status: ConnStatus);
     fn get_status(&self) -> ConnStatus;
-    fn peer_closed(&mut self) {
-        match self.get_status() {
-            ConnStatus::Established | ConnStatus::Connecting => {
-                self.set_status(ConnStatus::PeerClosed);
-            }
-            _ => {}
+    /// Applies `event`, returns false if its action failed and the status
+    /// is kept. Events which don't apply succeed without any action.
+    fn apply(&mut self, event: StatusEvent, poll: Option<&Poll>) -> bool {
+        let (action, next) = match self.get_status().next(event) {
+            Some(step) => step,
+            None => return true,
+        };
+        let done = match action {
+            StatusAction::Nothing => true,
+            StatusAction::Close => self.close_conn(),
+            StatusAction::Drain => self.finish_send() && self.close_conn(),
+            StatusAction::Deregister => self.deregister(poll.unwrap()),
+        };
+        if done {
+            self.set_status(next);
         }
+        done
+    }
+    fn peer_closed(&mut self) {
+        self.apply(StatusEvent::PeerClosed, None);
     }
     fn established(&mut self) {
-        if matches!(self.get_status(), ConnStatus::Connecting) {
-            self.set_status(ConnStatus::Established);
-        }
+        self.apply(StatusEvent::Established, None);
     }
     fn close_conn(&mut self) -> bool;
     fn shutdown(&mut self) -> bool {
-        match self.get_status() {
-            ConnStatus::Established | ConnStatus::PeerClosed | ConnStatus::Connecting => {
-                if self.close_conn() {
-                    self.set_status(ConnStatus::Shutdown);
-                } else {
-                    return false;
-                }
-            }
-            ConnStatus::Shutdown | ConnStatus::Deregistered => {}
-        }
-        true
+        self.apply(StatusEvent::Shutdown, None)
     }
     fn deregister(&mut self, poll: &Poll) -> bool;
     fn finish_send(&mut self) -> bool;
     fn check_status(&mut self, poll: &Poll) {
-        loop {
-            match self.get_status() {
-                ConnStatus::Established | ConnStatus::Connecting => {}
-                ConnStatus::PeerClosed => {
-                    if self.finish_send() && self.shutdown() {
-                        continue;
-                    }
-                }
-                ConnStatus::Shutdown => {
-                    if self.deregister(poll) {
-                        self.set_status(ConnStatus::Deregistered);
-                    }
-                }
-                ConnStatus::Deregistered => {}
+        while self.get_status().next(StatusEvent::Check).is_some()
+            && self.apply(StatusEvent::Check, Some(poll))
+        {}
+    }
+    /// Closes and deregisters at once, whatever is still pending, like
+    /// when the process stops.
+    fn close_now(&mut self, poll: &Poll) {
+        if !self.deregistered() {
+            self.close_conn();
+            self.deregister(poll);
+            self.set_status(ConnStatus::Deregistered);
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports, dead_code)]
+
+    use mio::Poll;
+
+    use crate::status::{ConnStatus, StatusAction, StatusEvent, StatusProvider};
+
+    /// Connection without a socket, recording the actions taken.
+    struct Replay {
+        status: ConnStatus,
+        pending: bool,
+        close_ok: bool,
+        actions: Vec<&'static str>,
+    }
+
+    impl Replay {
+        fn new(status: ConnStatus) -> Replay {
+            Replay {
+                status,
+                pending: false,
+                close_ok: true,
+                actions: Vec::new(),
+            }
+        }
+    }
+
+    impl StatusProvider for Replay {
+        fn set_status(&mut self, status: ConnStatus) {
+            self.status = status;
+        }
+
+        fn get_status(&self) -> ConnStatus {
+            self.status
+        }
+
+        fn close_conn(&mut self) -> bool {
+            self.actions.push("close");
+            self.close_ok
+        }
+
+        fn deregister(&mut self, _: &Poll) -> bool {
+            self.actions.push("deregister");
+            true
+        }
+
+        fn finish_send(&mut self) -> bool {
+            self.actions.push("finish_send");
+            !self.pending
+        }
+    }
+
+    #[test]
+    fn test_transition_table() {
+        use ConnStatus::*;
+        use StatusAction::*;
+        let statuses = [Connecting, Established, PeerClosed, Shutdown, Deregistered];
+        let events = [
+            StatusEvent::Established,
+            StatusEvent::PeerClosed,
+            StatusEvent::Shutdown,
+            StatusEvent::Check,
+        ];
+        let expected = [
+            // Established, PeerClosed, Shutdown, Check
+            [
+                Some((Nothing, Established)),
+                Some((Nothing, PeerClosed)),
+                Some((Close, Shutdown)),
+                None,
+            ],
+            [
+                None,
+                Some((Nothing, PeerClosed)),
+                Some((Close, Shutdown)),
+                None,
+            ],
+            [None, None, Some((Close, Shutdown)), Some((Drain, Shutdown))],
+            [None, None, None, Some((Deregister, Deregistered))],
+            [None, None, None, None],
+        ];
+        for (status, expected) in statuses.iter().zip(expected.iter()) {
+            for (event, expected) in events.iter().zip(expected.iter()) {
+                assert_eq!(status.next(*event), *expected, "{:?} {:?}", status, event);
             }
-            break;
         }
     }
+
+    #[test]
+    fn test_peer_eof_with_pending_data() {
+        let poll = Poll::new().unwrap();
+        let mut conn = Replay::new(ConnStatus::Established);
+        conn.pending = true;
+        conn.peer_closed();
+        conn.check_status(&poll);
+        assert_eq!(conn.status, ConnStatus::PeerClosed);
+        conn.pending = false;
+        conn.check_status(&poll);
+        assert_eq!(conn.status, ConnStatus::Deregistered);
+        assert_eq!(
+            conn.actions,
+            ["finish_send", "finish_send", "close", "deregister"]
+        );
+    }
+
+    #[test]
+    fn test_error_while_draining() {
+        let poll = Poll::new().unwrap();
+        let mut conn = Replay::new(ConnStatus::Established);
+        conn.pending = true;
+        conn.peer_closed();
+        assert!(conn.shutdown());
+        conn.check_status(&poll);
+        assert_eq!(conn.status, ConnStatus::Deregistered);
+        assert_eq!(conn.actions, ["close", "deregister"]);
+    }
+
+    #[test]
+    fn test_double_shutdown() {
+        let poll = Poll::new().unwrap();
+        let mut conn = Replay::new(ConnStatus::Connecting);
+        assert!(conn.shutdown());
+        assert!(conn.shutdown());
+        conn.check_status(&poll);
+        conn.check_status(&poll);
+        assert!(conn.shutdown());
+        conn.peer_closed();
+        assert_eq!(conn.status, ConnStatus::Deregistered);
+        assert_eq!(conn.actions, ["close", "deregister"]);
+    }
+
+    #[test]
+    fn test_deregister_only_after_close() {
+        let poll = Poll::new().unwrap();
+        let mut conn = Replay::new(ConnStatus::Connecting);
+        conn.check_status(&poll);
+        conn.established();
+        conn.check_status(&poll);
+        assert!(conn.actions.is_empty());
+        conn.close_ok = false;
+        assert!(!conn.shutdown());
+        conn.check_status(&poll);
+        assert_eq!(conn.status, ConnStatus::Established);
+        conn.close_ok = true;
+        assert!(conn.shutdown());
+        conn.check_status(&poll);
+        assert_eq!(conn.status, ConnStatus::Deregistered);
+        assert_eq!(conn.actions, ["close", "close", "deregister"]);
+    }
 }
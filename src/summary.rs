@@ -0,0 +1,160 @@
+//! Debug summaries of the traffic of a connection.
+//!
+//! A debug line per datagram or read made the logging itself the
+//! bottleneck of a busy server. Connections count their traffic instead and
+//! log it at most once per [`SUMMARY_INTERVAL`], the single lines are only
+//! logged at trace level. The rest is flushed on the timeout tick and when
+//! the connection closes, so quiet connections still log their last
+//! traffic. Nothing is counted with debug logging off.
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+pub const SUMMARY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Counts {
+    packets: usize,
+    bytes: usize,
+}
+
+pub struct Summary {
+    /// Like "udp", and what is counted, like "datagrams"
+    kind: &'static str,
+    unit: &'static str,
+    sent: Counts,
+    received: Counts,
+    destinations: HashSet<SocketAddr>,
+    last_log: Instant,
+}
+
+impl Summary {
+    pub fn new(kind: &'static str, unit: &'static str) -> Summary {
+        Summary {
+            kind,
+            unit,
+            sent: Counts::default(),
+            received: Counts::default(),
+            destinations: HashSet::new(),
+            last_log: Instant::now(),
+        }
+    }
+
+    fn enabled() -> bool {
+        log::log_enabled!(log::Level::Debug)
+    }
+
+    /// Counts `packets` sent with `bytes` in total, to `destination` if
+    /// there are several.
+    pub fn sent(&mut self, packets: usize, bytes: usize, destination: Option<SocketAddr>) {
+        if !Self::enabled() {
+            return;
+        }
+        self.sent.packets += packets;
+        self.sent.bytes += bytes;
+        if let Some(destination) = destination {
+            self.destinations.insert(destination);
+        }
+    }
+
+    pub fn received(&mut self, packets: usize, bytes: usize) {
+        if !Self::enabled() {
+            return;
+        }
+        self.received.packets += packets;
+        self.received.bytes += bytes;
+    }
+
+    /// Takes the line of what was counted since the last one, if there is
+    /// anything and the interval passed or `force` is set.
+    fn take(&mut self, index: usize, now: Instant, force: bool) -> Option<String> {
+        if self.sent.packets == 0 && self.received.packets == 0 {
+            return None;
+        }
+        if !force && now.saturating_duration_since(self.last_log) < SUMMARY_INTERVAL {
+            return None;
+        }
+        let mut line = format!(
+            "connection:{} {} sent {} {}/{} bytes, received {} {}/{} bytes",
+            index,
+            self.kind,
+            self.sent.packets,
+            self.unit,
+            self.sent.bytes,
+            self.received.packets,
+            self.unit,
+            self.received.bytes
+        );
+        if !self.destinations.is_empty() {
+            line.push_str(format!(", destinations:{}", self.destinations.len()).as_str());
+        }
+        self.sent = Counts::default();
+        self.received = Counts::default();
+        self.destinations.clear();
+        self.last_log = now;
+        Some(line)
+    }
+
+    /// Logs the summary if the last one is older than the interval.
+    pub fn log(&mut self, index: usize) {
+        if let Some(line) = self.take(index, Instant::now(), false) {
+            log::debug!("{}", line);
+        }
+    }
+
+    /// When [`log`](Summary::log) has a line, if anything was counted.
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.sent.packets == 0 && self.received.packets == 0 {
+            None
+        } else {
+            Some(self.last_log + SUMMARY_INTERVAL)
+        }
+    }
+
+    /// Logs what is left, called on the timeout tick and on close.
+    pub fn flush(&mut self, index: usize) {
+        if let Some(line) = self.take(index, Instant::now(), true) {
+            log::debug!("{}", line);
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::time::{Duration, Instant};
+
+    use crate::summary::{Counts, Summary};
+
+    #[test]
+    fn test_take() {
+        let mut summary = Summary::new("udp", "datagrams");
+        let now = summary.last_log;
+        assert!(summary.take(3, now, true).is_none());
+        // counted directly, the logger is not set up in tests
+        summary.sent = Counts {
+            packets: 3,
+            bytes: 300,
+        };
+        summary.received = Counts {
+            packets: 1,
+            bytes: 50,
+        };
+        summary.destinations.insert("10.0.0.1:53".parse().unwrap());
+        summary.destinations.insert("10.0.0.2:53".parse().unwrap());
+        assert!(summary.take(3, now, false).is_none());
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            summary.take(3, later, false).unwrap(),
+            "connection:3 udp sent 3 datagrams/300 bytes, received 1 datagrams/50 bytes, destinations:2"
+        );
+        assert!(summary.take(3, later, true).is_none());
+        summary.received.packets = 1;
+        assert_eq!(
+            summary.take(3, later, true).unwrap(),
+            "connection:3 udp sent 0 datagrams/0 bytes, received 1 datagrams/0 bytes"
+        );
+    }
+}
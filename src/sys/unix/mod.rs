@@ -1,12 +1,15 @@
+pub use std::os::unix::io::AsRawFd as RawSocket;
+
 use mio::net::TcpStream;
+use socket2::{SockRef, TcpKeepalive};
 use std::{
     convert::TryFrom,
     io::{Error, ErrorKind, Result},
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
-    os::unix::io::AsRawFd,
+    os::unix::io::{AsRawFd, RawFd},
+    time::Duration,
 };
 
-#[allow(dead_code)]
 pub fn set_mark<T: AsRawFd>(socket: &T, mark: u8) -> Result<()> {
     let fd = socket.as_raw_fd();
     unsafe {
@@ -26,6 +29,160 @@ pub fn set_mark<T: AsRawFd>(socket: &T, mark: u8) -> Result<()> {
     }
 }
 
+/// Sets TCP_CORK, the kernel holds back partial segments until it is
+/// cleared, at most for 200ms.
+pub fn set_cork<T: AsRawFd>(socket: &T, cork: bool) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    unsafe {
+        let cork = cork as libc::c_int;
+        let ret = libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_CORK,
+            &cork as *const _ as *const _,
+            std::mem::size_of_val(&cork) as libc::socklen_t,
+        );
+        if ret != 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Sets SO_SNDBUF and SO_RCVBUF, 0 keeps the system default.
+pub fn set_buffers<T: AsRawFd>(socket: &T, sndbuf: usize, rcvbuf: usize) -> Result<()> {
+    let socket = SockRef::from(socket);
+    if sndbuf > 0 {
+        socket.set_send_buffer_size(sndbuf)?;
+    }
+    if rcvbuf > 0 {
+        socket.set_recv_buffer_size(rcvbuf)?;
+    }
+    Ok(())
+}
+
+/// Sets the congestion control algorithm, like bbr, with TCP_CONGESTION.
+pub fn set_congestion<T: AsRawFd>(socket: &T, name: &str) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    unsafe {
+        let ret = libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            name.as_ptr() as *const _,
+            name.len() as libc::socklen_t,
+        );
+        if ret != 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn set_tcp_option<T: AsRawFd>(socket: &T, option: libc::c_int, value: libc::c_int) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    unsafe {
+        let ret = libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            option,
+            &value as *const _ as *const _,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        );
+        if ret != 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Sets TCP_FASTOPEN_CONNECT, connect returns at once and the SYN goes out
+/// with the first write.
+pub fn set_fast_open_connect<T: AsRawFd>(socket: &T) -> Result<()> {
+    set_tcp_option(socket, libc::TCP_FASTOPEN_CONNECT, 1)
+}
+
+/// Sets IP_BIND_ADDRESS_NO_PORT, a bind to an address leaves the port to
+/// the connect, which may reuse it toward other destinations.
+pub fn set_bind_no_port<T: AsRawFd>(socket: &T) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    unsafe {
+        let value: libc::c_int = 1;
+        let ret = libc::setsockopt(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_BIND_ADDRESS_NO_PORT,
+            &value as *const _ as *const _,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        );
+        if ret != 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Sets TCP_FASTOPEN on a listener, `backlog` is the queue length of
+/// pending fast open requests.
+pub fn set_fast_open<T: AsRawFd>(socket: &T, backlog: u32) -> Result<()> {
+    set_tcp_option(socket, libc::TCP_FASTOPEN, backlog as libc::c_int)
+}
+
+/// Sets TCP_DEFER_ACCEPT on a listener, connections are only accepted
+/// once data arrived or `secs` passed.
+pub fn set_defer_accept<T: AsRawFd>(socket: &T, secs: u32) -> Result<()> {
+    set_tcp_option(socket, libc::TCP_DEFER_ACCEPT, secs as libc::c_int)
+}
+
+/// Pins the calling thread to `core`.
+pub fn set_affinity(core: usize) -> Result<()> {
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err(Error::new(ErrorKind::InvalidInput, "core id out of range"));
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Sets the nice value of the calling thread.
+pub fn set_nice(nice: i32) -> Result<()> {
+    unsafe {
+        let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+        if libc::setpriority(libc::PRIO_PROCESS, tid, nice) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Moves the calling thread to SCHED_FIFO with `priority`, needs root or
+/// CAP_SYS_NICE.
+pub fn set_fifo(priority: i32) -> Result<()> {
+    unsafe {
+        let param = libc::sched_param {
+            sched_priority: priority,
+        };
+        if libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+pub fn set_keepalive<T: AsRawFd>(socket: &T, idle: Duration) -> Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(idle).with_interval(idle);
+    SockRef::from(socket).set_tcp_keepalive(&keepalive)
+}
+
 pub fn set_socket_opts<T: AsRawFd>(v4: bool, is_udp: bool, socket: &T) -> Result<()> {
     let fd = socket.as_raw_fd();
 
@@ -147,8 +304,11 @@ pub fn recv_from_with_destination<T: AsRawFd>(
 
         msg.msg_control = control_buf.as_mut_ptr() as *mut _;
         // This is f*** s***, some platform define msg_controllen as size_t, some define as u32
-        msg.msg_controllen = TryFrom::try_from(control_buf.len())
-            .expect("failed to convert usize to msg_controllen");
+        #[allow(clippy::useless_conversion)]
+        {
+            msg.msg_controllen = TryFrom::try_from(control_buf.len())
+                .expect("failed to convert usize to msg_controllen");
+        }
 
         let fd = socket.as_raw_fd();
         let ret = libc::recvmsg(fd, &mut msg, 0);
@@ -175,6 +335,134 @@ pub fn recv_from_with_destination<T: AsRawFd>(
     }
 }
 
+/// Most descriptors passed in one message
+const MAX_FDS: usize = 8;
+
+/// Sends `data` with `fds` attached as SCM_RIGHTS over a unix socket.
+pub fn send_fds<T: AsRawFd>(socket: &T, data: &[u8], fds: &[RawFd]) -> Result<()> {
+    if fds.is_empty() || fds.len() > MAX_FDS {
+        return Err(Error::new(ErrorKind::InvalidInput, "invalid fd count"));
+    }
+    unsafe {
+        let mut control_buf = [0u64; 8];
+        let fds_len = std::mem::size_of_val(fds) as u32;
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut _,
+            iov_len: data.len() as libc::size_t,
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control_buf.as_mut_ptr() as *mut _;
+        #[allow(clippy::useless_conversion)]
+        {
+            msg.msg_controllen = TryFrom::try_from(libc::CMSG_SPACE(fds_len) as usize)
+                .expect("failed to convert usize to msg_controllen");
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        #[allow(clippy::useless_conversion)]
+        {
+            (*cmsg).cmsg_len = TryFrom::try_from(libc::CMSG_LEN(fds_len) as usize)
+                .expect("failed to convert usize to cmsg_len");
+        }
+        std::ptr::copy_nonoverlapping(
+            fds.as_ptr() as *const u8,
+            libc::CMSG_DATA(cmsg),
+            fds_len as usize,
+        );
+        let ret = libc::sendmsg(socket.as_raw_fd(), &msg, 0);
+        if ret < 0 {
+            Err(Error::last_os_error())
+        } else if (ret as usize) < data.len() {
+            Err(Error::new(ErrorKind::WriteZero, "short fd message"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Receives a message sent by [`send_fds`], returns its length and the
+/// descriptors, which belong to the caller.
+pub fn recv_fds<T: AsRawFd>(socket: &T, buf: &mut [u8]) -> Result<(usize, Vec<RawFd>)> {
+    unsafe {
+        let mut control_buf = [0u64; 8];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut _,
+            iov_len: buf.len() as libc::size_t,
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control_buf.as_mut_ptr() as *mut _;
+        #[allow(clippy::useless_conversion)]
+        {
+            msg.msg_controllen = TryFrom::try_from(std::mem::size_of_val(&control_buf))
+                .expect("failed to convert usize to msg_controllen");
+        }
+        let ret = libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut fds = Vec::new();
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg);
+                for i in 0..len / std::mem::size_of::<RawFd>() {
+                    let mut fd: RawFd = 0;
+                    std::ptr::copy_nonoverlapping(
+                        data.add(i * std::mem::size_of::<RawFd>()),
+                        &mut fd as *mut _ as *mut u8,
+                        std::mem::size_of::<RawFd>(),
+                    );
+                    fds.push(fd);
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        Ok((ret as usize, fds))
+    }
+}
+
+/// Returns the pid and uid of the process on the other end of a unix
+/// socket, as they were when it connected.
+pub fn peer_cred<T: AsRawFd>(socket: &T) -> Result<(u32, u32)> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok((cred.pid as u32, cred.uid))
+}
+
+/// Binds a unix socket at `path` only our user may connect to.
+pub fn bind_private(path: &str) -> Result<std::os::unix::net::UnixListener> {
+    // the socket file takes its mode from the umask at bind, a chmod
+    // afterwards would leave it open for a moment
+    let mask = unsafe { libc::umask(0o177) };
+    let socket = std::os::unix::net::UnixListener::bind(path);
+    unsafe {
+        libc::umask(mask);
+    }
+    socket
+}
+
 fn sockaddr_to_std(saddr: &libc::sockaddr_storage) -> Result<SocketAddr> {
     match saddr.ss_family as libc::c_int {
         libc::AF_INET => unsafe {
@@ -204,3 +492,210 @@ fn sockaddr_to_std(saddr: &libc::sockaddr_storage) -> Result<SocketAddr> {
         }
     }
 }
+
+/// Leading fields of `struct tcp_info`, the kernel copies as much as asked.
+#[repr(C)]
+#[derive(Default)]
+struct TcpInfo {
+    state: [u8; 8],
+    rto: u32,
+    ato: u32,
+    snd_mss: u32,
+    rcv_mss: u32,
+    unacked: u32,
+    sacked: u32,
+}
+
+fn tcp_info<T: AsRawFd>(socket: &T) -> Result<TcpInfo> {
+    let mut info = TcpInfo::default();
+    let mut len = std::mem::size_of::<TcpInfo>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut TcpInfo as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(info)
+}
+
+/// Returns the connections waiting in the accept queue of a listening
+/// socket and the backlog limit.
+pub fn listen_queue<T: AsRawFd>(socket: &T) -> Result<(u32, u32)> {
+    let info = tcp_info(socket)?;
+    // for listeners the kernel puts the queue length and limit here
+    Ok((info.unacked, info.sacked))
+}
+
+/// Returns the segments sent on a connected socket and not acked yet.
+pub fn unacked<T: AsRawFd>(socket: &T) -> Result<u32> {
+    Ok(tcp_info(socket)?.unacked)
+}
+
+/// Returns the packets dropped by a socket so far, like for a full
+/// receive buffer.
+pub fn socket_drops<T: AsRawFd>(socket: &T) -> Result<u32> {
+    let mut meminfo = [0u32; libc::SK_MEMINFO_DROPS as usize + 1];
+    let mut len = std::mem::size_of_val(&meminfo) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MEMINFO,
+            meminfo.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(meminfo[libc::SK_MEMINFO_DROPS as usize])
+}
+
+/// Returns the uid and primary gid of `user`, a name or a number.
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let name = std::ffi::CString::new(user)?;
+    unsafe {
+        let mut passwd = libc::getpwnam(name.as_ptr());
+        if passwd.is_null() {
+            if let Ok(uid) = user.parse() {
+                passwd = libc::getpwuid(uid);
+            }
+        }
+        if passwd.is_null() {
+            return Err(Error::new(ErrorKind::NotFound, format!("no user {}", user)));
+        }
+        Ok(((*passwd).pw_uid, (*passwd).pw_gid))
+    }
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    let name = std::ffi::CString::new(group)?;
+    unsafe {
+        let entry = libc::getgrnam(name.as_ptr());
+        if !entry.is_null() {
+            return Ok((*entry).gr_gid);
+        }
+    }
+    group
+        .parse()
+        .map_err(|_| Error::new(ErrorKind::NotFound, format!("no group {}", group)))
+}
+
+/// Switches the process to `user` and `group`, or the primary group of the
+/// user if empty, with no supplementary groups. Returns the uid and gid.
+pub fn switch_user(user: &str, group: &str) -> Result<(u32, u32)> {
+    let (uid, primary) = lookup_user(user)?;
+    let gid = if group.is_empty() {
+        primary
+    } else {
+        lookup_group(group)?
+    };
+    unsafe {
+        // already switched, like a process started by an upgrade
+        if libc::getuid() == uid && libc::geteuid() == uid && libc::getgid() == gid {
+            return Ok((uid, gid));
+        }
+        if libc::setgroups(1, &gid) != 0 || libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok((uid, gid))
+}
+
+/// Whether the real user may read or write `path`.
+pub fn accessible(path: &str, write: bool) -> bool {
+    let path = match std::ffi::CString::new(path) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mode = if write { libc::W_OK } else { libc::R_OK };
+    unsafe { libc::access(path.as_ptr(), mode) == 0 }
+}
+
+/// Whether the process could get root back.
+pub fn is_root() -> bool {
+    unsafe {
+        libc::getuid() == 0
+            || libc::geteuid() == 0
+            || libc::getgid() == 0
+            || libc::getegid() == 0
+            || libc::setuid(0) == 0
+    }
+}
+
+/// Returns the effective capability set of the process from /proc.
+pub fn capabilities() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(caps.trim(), 16).ok()
+}
+
+/// Returns the pid and name of the process listening on `port`, looked up
+/// in /proc, so only processes this user may inspect are found.
+pub fn port_owner(is_udp: bool, port: u16) -> Option<(u32, String)> {
+    let (tables, state) = if is_udp {
+        (["/proc/net/udp", "/proc/net/udp6"], "07")
+    } else {
+        (["/proc/net/tcp", "/proc/net/tcp6"], "0A")
+    };
+    let inode = tables.iter().find_map(|table| {
+        let text = std::fs::read_to_string(table).ok()?;
+        text.lines().skip(1).find_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let (_, local_port) = fields.get(1)?.rsplit_once(':')?;
+            if u16::from_str_radix(local_port, 16).ok()? == port && *fields.get(3)? == state {
+                fields.get(9).map(|inode| format!("socket:[{}]", inode))
+            } else {
+                None
+            }
+        })
+    })?;
+    std::fs::read_dir("/proc")
+        .ok()?
+        .flatten()
+        .find_map(|entry| {
+            let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+            let found = std::fs::read_dir(entry.path().join("fd"))
+                .ok()?
+                .flatten()
+                .any(|fd| {
+                    std::fs::read_link(fd.path())
+                        .is_ok_and(|link| link.as_os_str() == inode.as_str())
+                });
+            if !found {
+                return None;
+            }
+            let name = std::fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+            Some((pid, name.trim().to_owned()))
+        })
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::os::unix::{fs::PermissionsExt, net::UnixStream};
+
+    #[test]
+    fn test_bind_private() {
+        let path = std::env::temp_dir().join(format!("trojan-private-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let socket = crate::sys::bind_private(path).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let _client = UnixStream::connect(path).unwrap();
+        let (stream, _) = socket.accept().unwrap();
+        let (pid, uid) = crate::sys::peer_cred(&stream).unwrap();
+        assert_eq!(pid, std::process::id());
+        assert_eq!(uid, unsafe { libc::getuid() });
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,21 +1,162 @@
-use std::{any::Any, io::Result, net::SocketAddr};
+pub use std::os::windows::io::AsRawSocket as RawSocket;
+
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    any::Any,
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    os::windows::io::AsRawSocket,
+    time::Duration,
+};
 
-#[allow(dead_code)]
 pub fn set_mark<T: Any>(_socket: &T, _mark: u8) -> Result<()> {
     Ok(())
 }
 
+/// Sets SO_SNDBUF and SO_RCVBUF, 0 keeps the system default.
+pub fn set_buffers<T: AsRawSocket>(socket: &T, sndbuf: usize, rcvbuf: usize) -> Result<()> {
+    let socket = SockRef::from(socket);
+    if sndbuf > 0 {
+        socket.set_send_buffer_size(sndbuf)?;
+    }
+    if rcvbuf > 0 {
+        socket.set_recv_buffer_size(rcvbuf)?;
+    }
+    Ok(())
+}
+
+pub fn set_congestion<T: AsRawSocket>(_socket: &T, _name: &str) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "congestion control selection not supported in windows",
+    ))
+}
+
+pub fn set_fast_open_connect<T: AsRawSocket>(_socket: &T) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "tcp fast open not supported in windows",
+    ))
+}
+
+pub fn set_bind_no_port<T: AsRawSocket>(_socket: &T) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "bind address no port not supported in windows",
+    ))
+}
+
+pub fn set_cork<T: AsRawSocket>(_socket: &T, _cork: bool) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "tcp cork not supported in windows",
+    ))
+}
+
+pub fn set_fast_open<T: AsRawSocket>(_socket: &T, _backlog: u32) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "tcp fast open not supported in windows",
+    ))
+}
+
+pub fn set_defer_accept<T: AsRawSocket>(_socket: &T, _secs: u32) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "tcp defer accept not supported in windows",
+    ))
+}
+
+/// Winsock has no per option keepalive knobs, socket2 sets both values
+/// with a single SIO_KEEPALIVE_VALS ioctl.
+pub fn set_affinity(_core: usize) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "not supported on windows",
+    ))
+}
+
+pub fn set_nice(_nice: i32) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "not supported on windows",
+    ))
+}
+
+pub fn set_fifo(_priority: i32) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "not supported on windows",
+    ))
+}
+
+pub fn listen_queue<T: AsRawSocket>(_socket: &T) -> Result<(u32, u32)> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "listen queue not supported in windows",
+    ))
+}
+
+pub fn unacked<T: AsRawSocket>(_socket: &T) -> Result<u32> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "tcp info not supported in windows",
+    ))
+}
+
+pub fn socket_drops<T: AsRawSocket>(_socket: &T) -> Result<u32> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "socket drops not supported in windows",
+    ))
+}
+
+pub fn switch_user(_user: &str, _group: &str) -> Result<(u32, u32)> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "switching user not supported in windows",
+    ))
+}
+
+pub fn accessible(_path: &str, _write: bool) -> bool {
+    true
+}
+
+pub fn is_root() -> bool {
+    false
+}
+
+pub fn set_keepalive<T: AsRawSocket>(socket: &T, idle: Duration) -> Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(idle).with_interval(idle);
+    SockRef::from(socket).set_tcp_keepalive(&keepalive)
+}
+
+fn transparent_proxy_unsupported() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "transparent proxy mode not supported in windows",
+    )
+}
+
 pub fn set_socket_opts<T: Any>(_v4: bool, _is_udp: bool, _socket: &T) -> Result<()> {
-    unimplemented!("proxy mode not supported in windows");
+    Err(transparent_proxy_unsupported())
 }
 
 pub fn get_oridst_addr<T: Any>(_s: &T) -> Result<SocketAddr> {
-    unimplemented!("proxy mode not supported in windows");
+    Err(transparent_proxy_unsupported())
 }
 
 pub fn recv_from_with_destination<T: Any>(
     _socket: &T,
     _buf: &mut [u8],
 ) -> Result<(usize, SocketAddr, SocketAddr)> {
-    unimplemented!("proxy mode not supported in windows");
+    Err(transparent_proxy_unsupported())
+}
+
+pub fn capabilities() -> Option<u64> {
+    None
+}
+
+pub fn port_owner(_is_udp: bool, _port: u16) -> Option<(u32, String)> {
+    None
 }
@@ -1,77 +1,190 @@
-use std::io::{Read, Write};
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::Shutdown,
+    time::Duration,
+};
 
 use bytes::BytesMut;
-use mio::net::TcpStream;
+use mio::{event::Source, net::TcpStream};
+use socket2::SockRef;
 
-use crate::tls_conn::TlsConn;
+use crate::{
+    profile::{self, Category},
+    tls_conn::{TlsConn, Written},
+};
 
-pub fn tcp_read(
+/// Socket operations of a tcp peer, a trait so the simulation tests can
+/// script them
+pub trait TcpIo: Source {
+    fn read(&self, buffer: &mut [u8]) -> std::io::Result<usize>;
+    fn write(&self, data: &[u8]) -> std::io::Result<usize>;
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()>;
+    /// Pending socket error, like the one of a failed connect.
+    fn take_error(&self) -> std::io::Result<Option<std::io::Error>> {
+        Ok(None)
+    }
+    /// Sets SO_LINGER to 0, dropping the socket then sends an RST.
+    fn set_reset(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TcpIo for TcpStream {
+    fn read(&self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        Read::read(&mut &*self, buffer)
+    }
+
+    fn write(&self, data: &[u8]) -> std::io::Result<usize> {
+        Write::write(&mut &*self, data)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn take_error(&self) -> std::io::Result<Option<std::io::Error>> {
+        TcpStream::take_error(self)
+    }
+
+    fn set_reset(&self) -> std::io::Result<()> {
+        SockRef::from(self).set_linger(Some(Duration::ZERO))
+    }
+}
+
+/// How a [`tcp_read`] or [`tcp_send`] ended
+#[derive(Debug)]
+pub enum Outcome {
+    // read stopped with the peer still open, or all data was sent
+    Ok,
+    // socket blocked, a send keeps the rest in its buffer
+    WouldBlock,
+    // tls session full, reading goes on once it is writable again
+    Paused,
+    // peer finished sending, data towards it may still be flushed
+    Eof,
+    // peer is broken
+    Error(std::io::Error),
+}
+
+impl Outcome {
+    /// Whether the peer reset the connection.
+    pub fn reset(&self) -> bool {
+        matches!(self, Outcome::Error(err) if err.kind() == ErrorKind::ConnectionReset)
+    }
+}
+
+/// Bytes moved by a [`tcp_read`] or [`tcp_send`] before it ended, in
+/// `chunks` reads or writes
+#[derive(Debug)]
+pub struct Transfer {
+    pub bytes: usize,
+    pub chunks: usize,
+    pub outcome: Outcome,
+}
+
+impl Transfer {
+    fn new(bytes: usize, chunks: usize, outcome: Outcome) -> Transfer {
+        Transfer {
+            bytes,
+            chunks,
+            outcome,
+        }
+    }
+
+    /// Whether the peer may still be used.
+    pub fn open(&self) -> bool {
+        matches!(
+            self.outcome,
+            Outcome::Ok | Outcome::WouldBlock | Outcome::Paused
+        )
+    }
+}
+
+pub fn tcp_read<S: TcpIo>(
     index: usize,
-    mut conn: &TcpStream,
+    conn: &S,
     recv_buf: &mut Vec<u8>,
     server_conn: &mut TlsConn,
-) -> bool {
+) -> Transfer {
+    let _scope = profile::scope(Category::ClientRead);
+    let mut bytes = 0;
+    let mut chunks = 0;
     loop {
         match conn.read(recv_buf.as_mut_slice()) {
             Ok(size) => {
-                log::debug!("connection:{} read {} bytes from backend", index, size);
+                log::trace!("connection:{} read {} bytes from backend", index, size);
+                bytes += size;
                 if size == 0 {
-                    log::warn!("connection:{} meets end of file", index);
-                    return false;
-                } else if !server_conn.write_session(&recv_buf.as_slice()[..size]) {
-                    break;
+                    log::info!("connection:{} meets end of file", index);
+                    return Transfer::new(bytes, chunks, Outcome::Eof);
+                }
+                chunks += 1;
+                match server_conn.write_session(&recv_buf.as_slice()[..size]) {
+                    Written::Accepted => {}
+                    Written::Full => {
+                        // a socket taking all of it drains the session right away
+                        server_conn.do_send();
+                        if !server_conn.writable() {
+                            log::trace!("connection:{} session full, pause reading", index);
+                            return Transfer::new(bytes, chunks, Outcome::Paused);
+                        }
+                    }
+                    Written::Failed => return Transfer::new(bytes, chunks, Outcome::Ok),
                 }
             }
-            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
-                log::debug!("connection:{} read from backend blocked", index);
-                break;
-            }
-            Err(err) => {
-                log::warn!("connection:{} read from backend failed:{}", index, err);
-                return false;
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                log::trace!("connection:{} read from backend blocked", index);
+                return Transfer::new(bytes, chunks, Outcome::WouldBlock);
             }
+            Err(err) => return Transfer::new(bytes, chunks, Outcome::Error(err)),
         }
     }
-    true
 }
 
-pub fn tcp_send(
+pub fn tcp_send<S: TcpIo>(
     index: usize,
-    mut conn: &TcpStream,
+    conn: &S,
     send_buffer: &mut BytesMut,
     mut data: &[u8],
-) -> bool {
+) -> Transfer {
+    let _scope = profile::scope(Category::ClientWrite);
+    let mut bytes = 0;
+    let mut chunks = 0;
     loop {
         if data.is_empty() {
-            return true;
+            return Transfer::new(bytes, chunks, Outcome::Ok);
         }
         match conn.write(data) {
             Ok(size) => {
                 if size == 0 {
                     log::warn!("send failed, tcp stream closed");
-                    return false;
+                    let err = std::io::Error::from(ErrorKind::WriteZero);
+                    return Transfer::new(bytes, chunks, Outcome::Error(err));
                 }
                 data = &data[size..];
-                log::debug!(
+                bytes += size;
+                chunks += 1;
+                log::trace!(
                     "connection:{} session write {} byte to backend",
                     index,
                     size
                 );
             }
-            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
-                log::debug!(
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                log::trace!(
                     "connection:{} session write blocked, remaining:{}",
                     index,
                     data.len()
                 );
                 send_buffer.extend_from_slice(data);
-                break;
+                return Transfer::new(bytes, chunks, Outcome::WouldBlock);
             }
             Err(err) => {
                 log::warn!("connection:{} send failed:{}", index, err);
-                return false;
+                return Transfer::new(bytes, chunks, Outcome::Error(err));
             }
         }
     }
-    true
 }
@@ -1,12 +1,45 @@
 use std::{
+    fmt::{Display, Formatter},
     io::{Error, ErrorKind, Read, Write},
-    net::Shutdown,
+    net::{Shutdown, SocketAddr},
+    time::Instant,
 };
 
+use bytes::BytesMut;
 use mio::{net::TcpStream, Interest, Poll, Token};
-use rustls::Connection;
+use rustls::{CipherSuite, Connection, IoState, ProtocolVersion};
 
-use crate::status::{ConnStatus, StatusProvider};
+use crate::{
+    cert::CertInfo,
+    codel::Codel,
+    compress::{Compressor, Decompressor},
+    padding::Padder,
+    profile::{self, Category},
+    status::{ConnStatus, StatusProvider},
+    sys,
+};
+
+/// Unsent tls bytes a session buffers before writes are [`Written::Full`]
+pub const SESSION_LIMIT: usize = 64 * 1024;
+
+/// How a write to the session went.
+#[must_use]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Written {
+    Accepted,
+    /// Taken, but over [`SESSION_LIMIT`] or queued too long, the writer
+    /// should pause until the connection is writable again
+    Full,
+    /// The connection is broken and shut down
+    Failed,
+}
+
+impl Written {
+    /// Whether the data was taken, a full session sends it once drained.
+    pub fn accepted(self) -> bool {
+        self != Written::Failed
+    }
+}
 
 pub struct TlsConn {
     session: Connection,
@@ -15,12 +48,76 @@ pub struct TlsConn {
     token: Token,
     status: ConnStatus,
     writable: bool,
+    sent: usize,
+    received: usize,
+    /// Bytes read from the socket, tls records included
+    wire_received: usize,
+    /// Whether the stream is registered to a poll, deregistered exactly once
+    registered: bool,
+    padder: Option<Padder>,
+    compressor: Option<Compressor>,
+    decompressor: Option<Decompressor>,
+    /// Whether the negotiated parameters were taken already
+    negotiated: bool,
+    /// The server sent close_notify or closed the stream
+    remote_closed: bool,
+    /// The stream ended or broke without close_notify
+    aborted: bool,
+    /// Close without close_notify, see [`abort`](TlsConn::abort)
+    abort: bool,
+    /// Bytes written while the session was full, moved in as it drains
+    backlog: BytesMut,
+    /// Sojourn times of written data, with --aqm-target
+    codel: Option<Codel>,
+    /// Header and payload of a frame joined for one write
+    frame: BytesMut,
+}
+
+/// Parameters negotiated by a finished handshake.
+pub struct TlsInfo {
+    version: Option<ProtocolVersion>,
+    suite: Option<CipherSuite>,
+    alpn: Option<String>,
+    /// Unknown for client sessions, rustls doesn't tell
+    resumed: Option<bool>,
+}
+
+impl Display for TlsInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.version {
+            Some(version) => write!(f, "version:{:?}", version)?,
+            None => write!(f, "version:none")?,
+        }
+        match self.suite {
+            Some(suite) => write!(f, " suite:{:?}", suite)?,
+            None => write!(f, " suite:none")?,
+        }
+        write!(f, " alpn:{}", self.alpn.as_deref().unwrap_or("none"))?;
+        match self.resumed {
+            Some(resumed) => write!(f, " resumed:{}", resumed),
+            None => write!(f, " resumed:unknown"),
+        }
+    }
 }
 
 impl TlsConn {
+    #[allow(dead_code)]
     pub(crate) fn close(&mut self, poll: &Poll) {
         let _ = self.stream.shutdown(Shutdown::Both);
-        let _ = poll.registry().deregister(&mut self.stream);
+        if self.registered {
+            self.registered = false;
+            let _ = poll.registry().deregister(&mut self.stream);
+        }
+    }
+}
+
+impl Drop for TlsConn {
+    fn drop(&mut self) {
+        debug_assert!(
+            !self.registered || std::thread::panicking(),
+            "connection:{} dropped without deregister",
+            self.index
+        );
     }
 }
 
@@ -95,7 +192,7 @@ impl Write for TlsConn {
 
 impl TlsConn {
     pub fn new(index: usize, token: Token, mut session: Connection, stream: TcpStream) -> TlsConn {
-        session.set_buffer_limit(None);
+        session.set_buffer_limit(Some(SESSION_LIMIT));
         TlsConn {
             index,
             token,
@@ -103,15 +200,36 @@ impl TlsConn {
             stream,
             writable: true,
             status: ConnStatus::Connecting,
+            sent: 0,
+            received: 0,
+            wire_received: 0,
+            registered: false,
+            padder: None,
+            compressor: None,
+            decompressor: None,
+            negotiated: false,
+            remote_closed: false,
+            aborted: false,
+            abort: false,
+            backlog: BytesMut::new(),
+            codel: None,
+            frame: BytesMut::new(),
         }
     }
 
+    /// Marks a stream registered by the caller before the connection was built.
+    pub fn set_registered(&mut self) {
+        debug_assert!(!self.registered);
+        self.registered = true;
+    }
+
     pub fn reset_index(&mut self, index: usize, token: Token, poll: &Poll) -> bool {
         self.index = index;
         self.token = token;
         self.reregister(poll)
     }
 
+    #[allow(dead_code)]
     pub fn set_token(&mut self, token: Token, poll: &Poll) -> bool {
         self.token = token;
         poll.registry()
@@ -146,15 +264,127 @@ impl TlsConn {
         }
     }
 
-    fn index(&self) -> usize {
+    pub fn index(&self) -> usize {
         self.index
     }
 
+    /// Local address the connection came in on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.stream.local_addr().ok()
+    }
+
     pub fn token(&self) -> Token {
         self.token
     }
 
-    pub fn do_read(&mut self) -> Option<Vec<u8>> {
+    /// Total plaintext bytes written into the session.
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Total plaintext bytes read from the session.
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Total bytes read from the socket, before the handshake too.
+    pub fn wire_received(&self) -> usize {
+        self.wire_received
+    }
+
+    /// Compresses the payload written after this.
+    pub fn set_compressor(&mut self, compressor: Compressor) {
+        self.compressor.replace(compressor);
+    }
+
+    /// Decompresses the payload read after this.
+    pub fn set_codel(&mut self, codel: Option<Codel>) {
+        self.codel = codel;
+    }
+
+    pub fn set_decompressor(&mut self, decompressor: Decompressor) {
+        self.decompressor.replace(decompressor);
+    }
+
+    /// Bytes on the wire per payload byte sent and received, if compressed.
+    pub fn compression_ratio(&self) -> Option<(f64, f64)> {
+        let sent = self.compressor.as_ref()?.ratio();
+        let received = self.decompressor.as_ref().map_or(1.0, Decompressor::ratio);
+        Some((sent, received))
+    }
+
+    /// Whether the tls handshake is not finished yet.
+    pub fn handshaking(&self) -> bool {
+        self.session.is_handshaking()
+    }
+
+    /// Whether a server session was resumed from a ticket.
+    pub fn resumed(&self) -> bool {
+        match &self.session {
+            Connection::Server(session) => session.received_resumption_data().is_some(),
+            Connection::Client(_) => false,
+        }
+    }
+
+    pub fn tls_info(&self) -> TlsInfo {
+        TlsInfo {
+            version: self.session.protocol_version(),
+            suite: self
+                .session
+                .negotiated_cipher_suite()
+                .map(|suite| suite.suite()),
+            alpn: self
+                .session
+                .alpn_protocol()
+                .map(|alpn| String::from_utf8_lossy(alpn).into_owned()),
+            resumed: match &self.session {
+                Connection::Server(_) => Some(self.resumed()),
+                Connection::Client(_) => None,
+            },
+        }
+    }
+
+    /// Details of the first certificate the peer sent.
+    pub fn peer_cert(&self) -> Option<CertInfo> {
+        CertInfo::parse(self.session.peer_certificates()?.first()?.0.as_slice())
+    }
+
+    /// Returns true once the handshake finished, only for the first call.
+    pub fn take_negotiated(&mut self) -> bool {
+        if self.negotiated || self.handshaking() {
+            false
+        } else {
+            self.negotiated = true;
+            true
+        }
+    }
+
+    /// Compact state for the state dump.
+    pub fn dump_state(&self) -> String {
+        let tls = if self.handshaking() {
+            "handshaking".to_owned()
+        } else {
+            self.tls_info().to_string()
+        };
+        let mut state = format!(
+            "{:?}/{} tls_pending:{} blocked:{} {}",
+            self.status,
+            self.interests(),
+            self.session.wants_write(),
+            !self.writable,
+            tls
+        );
+        if let Some(codel) = &self.codel {
+            state.push_str(format!(" {}", codel.delays()).as_str());
+        }
+        state
+    }
+
+    /// Appends all decrypted plaintext to `buffer`, returns the appended size.
+    pub fn do_read_into(&mut self, buffer: &mut BytesMut) -> usize {
+        let _scope = profile::scope(Category::TlsRead);
+        let offset = buffer.len();
+        let mut broken = false;
         loop {
             match self.session.read_tls(&mut self.stream) {
                 Ok(size) => {
@@ -163,14 +393,22 @@ impl TlsConn {
                             "connection:{} read from server failed with eof",
                             self.index()
                         );
+                        self.remote_closed = true;
+                        broken = true;
                         self.shutdown();
                         break;
                     }
-                    log::info!(
+                    self.wire_received += size;
+                    log::trace!(
                         "connection:{} read {} bytes from server",
                         self.index(),
                         size
                     );
+                    // the session buffers about one record, later rustls
+                    // versions refuse to read more until it is processed
+                    if self.take_plaintext(buffer, offset).is_none() {
+                        return 0;
+                    }
                 }
                 Err(err)
                     if err.kind() == ErrorKind::WouldBlock
@@ -186,57 +424,107 @@ impl TlsConn {
                         err.kind(),
                         err
                     );
+                    broken = true;
                     self.shutdown();
                     break;
                 }
             }
         }
 
-        if let Err(err) = self.session.process_new_packets() {
-            log::info!(
-                "connection:{} process new packets failed:{}",
-                self.index(),
-                err
-            );
-            self.shutdown();
-            return None;
+        match self.take_plaintext(buffer, offset) {
+            Some(state) => {
+                if state.peer_has_closed() {
+                    self.remote_closed = true;
+                } else if broken {
+                    self.aborted = true;
+                }
+            }
+            None => return 0,
+        }
+        let size = buffer.len() - offset;
+        if size == 0 {
+            return 0;
+        }
+        self.received += size;
+        if let Some(decompressor) = self.decompressor.as_mut() {
+            let data = buffer.split_off(offset);
+            if !decompressor.strip(data.as_ref(), buffer) {
+                log::warn!("connection:{} got invalid compression frame", self.index());
+                buffer.truncate(offset);
+                self.shutdown();
+                return 0;
+            }
+            return buffer.len() - offset;
         }
+        size
+    }
 
-        let mut buffer = Vec::new();
-        if let Err(err) = self.session.reader().read_to_end(&mut buffer) {
-            if err.kind() != ErrorKind::WouldBlock {
+    /// Processes the records read so far and appends their plaintext to
+    /// `buffer`. On failure the connection is shut down and `buffer` cut
+    /// back to `offset`.
+    fn take_plaintext(&mut self, buffer: &mut BytesMut, offset: usize) -> Option<IoState> {
+        let state = match self.session.process_new_packets() {
+            Ok(state) => state,
+            Err(err) => {
                 log::info!(
-                    "connection:{} read from session failed:{}",
+                    "connection:{} process new packets failed:{}",
                     self.index(),
                     err
                 );
+                buffer.truncate(offset);
                 self.shutdown();
+                return None;
             }
+        };
+        let size = state.plaintext_bytes_to_read();
+        if size == 0 {
+            return Some(state);
         }
-        if buffer.is_empty() {
-            None
-        } else {
-            Some(buffer)
+        let start = buffer.len();
+        buffer.resize(start + size, 0);
+        if let Err(err) = self.session.reader().read_exact(&mut buffer[start..]) {
+            log::info!(
+                "connection:{} read from session failed:{}",
+                self.index(),
+                err
+            );
+            buffer.truncate(offset);
+            self.shutdown();
+            return None;
         }
+        Some(state)
     }
 
     pub fn do_send(&mut self) {
+        let _scope = profile::scope(Category::TlsWrite);
         if self.is_connecting() {
-            log::info!("connection is not ready");
+            log::debug!("connection is not ready");
             return;
         }
         self.writable = true;
         loop {
             if !self.session.wants_write() {
-                log::info!("nothing in session");
+                if self.refill() {
+                    continue;
+                }
+                if let Some(codel) = &mut self.codel {
+                    codel.drained(Instant::now());
+                }
+                log::trace!("nothing in session");
                 break;
             }
             match self.session.write_tls(&mut self.stream) {
                 Ok(size) => {
-                    log::info!("connection:{} write {} bytes to server", self.index(), size);
+                    log::trace!("connection:{} write {} bytes to server", self.index(), size);
+                    if let Some(codel) = &mut self.codel {
+                        codel.sent(size, Instant::now());
+                    }
                     continue;
                 }
-                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                Err(err)
+                    if err.kind() == ErrorKind::WouldBlock
+                        || err.kind() == ErrorKind::NotConnected =>
+                {
                     log::debug!("connection:{} write to server blocked", self.index());
                     self.writable = false;
                 }
@@ -249,11 +537,105 @@ impl TlsConn {
         }
     }
 
-    pub fn write_session(&mut self, data: &[u8]) -> bool {
-        match self.session.writer().write_all(data) {
-            Ok(_) => {
-                log::info!("write {} byte to session", data.len());
-                true
+    /// Moves the backlog into the session as far as it takes it, returns
+    /// true if something moved.
+    fn refill(&mut self) -> bool {
+        if self.backlog.is_empty() {
+            return false;
+        }
+        match self.session.writer().write(self.backlog.as_ref()) {
+            Ok(size) => {
+                let _ = self.backlog.split_to(size);
+                size > 0
+            }
+            Err(err) => {
+                log::info!(
+                    "connection:{} write backlog to session failed:{}",
+                    self.index(),
+                    err
+                );
+                self.shutdown();
+                false
+            }
+        }
+    }
+
+    pub fn write_session(&mut self, data: &[u8]) -> Written {
+        if let Some(mut compressor) = self.compressor.take() {
+            let written = self.write_padded_record(compressor.frame(data), data.len());
+            self.compressor.replace(compressor);
+            written
+        } else {
+            self.write_padded_record(data, data.len())
+        }
+    }
+
+    /// Writes `head` and the `body` it announces as one write, the frame is
+    /// taken whole or not at all, never the head alone.
+    pub fn write_frame(&mut self, head: &[u8], body: &[u8]) -> Written {
+        let mut frame = std::mem::take(&mut self.frame);
+        frame.extend_from_slice(head);
+        frame.extend_from_slice(body);
+        let written = self.write_session(frame.as_ref());
+        frame.clear();
+        self.frame = frame;
+        written
+    }
+
+    fn write_padded_record(&mut self, data: &[u8], len: usize) -> Written {
+        if let Some(mut padder) = self.padder.take() {
+            let written = self.write_record(padder.frame(&[], data), len);
+            if written.accepted() && !padder.finished() {
+                self.padder.replace(padder);
+            }
+            written
+        } else {
+            self.write_record(data, len)
+        }
+    }
+
+    /// Writes a padded trojan request, `padder` pads the following records.
+    pub fn write_padded(&mut self, request: &[u8], mut padder: Padder) -> Written {
+        let mut written = self.write_record(padder.frame(request, &[]), request.len());
+        if !written.accepted() {
+            return written;
+        }
+        if let Some(dummy) = padder.dummy() {
+            written = self.write_record(dummy, 0);
+            if !written.accepted() {
+                return written;
+            }
+        }
+        if !padder.finished() {
+            self.padder.replace(padder);
+        }
+        written
+    }
+
+    /// Writes `data` holding `len` payload bytes as one record, what the
+    /// full session doesn't take waits in the backlog.
+    fn write_record(&mut self, data: &[u8], len: usize) -> Written {
+        let taken = if self.backlog.is_empty() {
+            self.session.writer().write(data)
+        } else {
+            Ok(0)
+        };
+        match taken {
+            Ok(size) => {
+                log::trace!("write {} byte to session", size);
+                self.sent += len;
+                if let Some(codel) = &mut self.codel {
+                    codel.written(data.len(), Instant::now());
+                }
+                if size == data.len() {
+                    return if self.congested() {
+                        Written::Full
+                    } else {
+                        Written::Accepted
+                    };
+                }
+                self.backlog.extend_from_slice(&data[size..]);
+                Written::Full
             }
             Err(err) => {
                 self.shutdown();
@@ -262,12 +644,17 @@ impl TlsConn {
                     self.index(),
                     err
                 );
-                false
+                Written::Failed
             }
         }
     }
 
     pub fn register(&mut self, poll: &Poll) -> bool {
+        debug_assert!(
+            !self.registered,
+            "connection:{} registered twice",
+            self.index
+        );
         if let Err(err) = poll.registry().register(
             &mut self.stream,
             self.token,
@@ -282,6 +669,7 @@ impl TlsConn {
                 self.index(),
                 self.token.0
             );
+            self.registered = true;
             true
         }
     }
@@ -290,8 +678,86 @@ impl TlsConn {
         matches!(self.status, ConnStatus::Deregistered)
     }
 
+    /// Whether the socket takes more, the session is not full and its
+    /// queue not standing.
     pub fn writable(&self) -> bool {
-        self.writable && self.alive()
+        self.has_room() && !self.congested()
+    }
+
+    /// Whether the socket takes more and the session is not full, however
+    /// long the queue.
+    pub fn has_room(&self) -> bool {
+        self.writable && self.backlog.is_empty() && self.alive()
+    }
+
+    /// Whether data waited longer than --aqm-target for an interval.
+    pub fn congested(&self) -> bool {
+        self.codel.as_ref().is_some_and(|codel| codel.standing())
+    }
+
+    /// Whether a datagram about to be written is dropped to shorten the
+    /// standing queue.
+    pub fn aqm_drop(&mut self, now: Instant) -> bool {
+        self.codel.as_mut().is_some_and(|codel| codel.drop(now))
+    }
+
+    /// Whether everything written went to the socket, the handshake done.
+    pub fn flushed(&self) -> bool {
+        !self.handshaking() && self.backlog.is_empty() && !self.session.wants_write()
+    }
+
+    /// Whether data written to the session is not yet acked by the server,
+    /// still in the session, blocked or in flight.
+    pub fn pending(&self) -> bool {
+        self.session.wants_write()
+            || !self.writable
+            || sys::unacked(&self.stream).is_ok_and(|unacked| unacked > 0)
+    }
+
+    pub fn remote_closed(&self) -> bool {
+        self.remote_closed
+    }
+
+    /// Whether the peer went away without close_notify, standing for a
+    /// reset when [`RESET`](crate::proto::RESET) was negotiated.
+    pub fn aborted(&self) -> bool {
+        self.aborted
+    }
+
+    /// Closes without close_notify, the peer passes it on as a reset.
+    pub fn abort(&mut self) {
+        self.abort = true;
+        self.shutdown();
+    }
+
+    /// Holds back what is sent until [`uncork`](Self::uncork), with TCP_CORK
+    /// or else by turning Nagle on.
+    pub fn cork(&self) {
+        if sys::set_cork(&self.stream, true).is_err() {
+            if let Err(err) = self.stream.set_nodelay(false) {
+                log::warn!("connection:{} cork failed:{}", self.index, err);
+            }
+        }
+    }
+
+    /// Sends what is held back and goes on without delay.
+    pub fn uncork(&self) {
+        let _ = sys::set_cork(&self.stream, false);
+        if let Err(err) = self.stream.set_nodelay(true) {
+            log::warn!("connection:{} uncork failed:{}", self.index, err);
+        }
+    }
+
+    /// Reads what the server sent an idle connection since its last event,
+    /// returns true if it was closed meanwhile.
+    pub fn check_remote_closed(&mut self) -> bool {
+        if !self.remote_closed && self.do_read_into(&mut BytesMut::new()) > 0 {
+            log::error!(
+                "connection:{} found data of an idle connection",
+                self.index()
+            );
+        }
+        self.remote_closed
     }
 }
 
@@ -305,16 +771,224 @@ impl StatusProvider for TlsConn {
     }
 
     fn close_conn(&mut self) -> bool {
+        if !self.abort {
+            self.session.send_close_notify();
+        }
+        let _ = self.session.write_tls(&mut self.stream);
         let _ = self.stream.shutdown(Shutdown::Both);
         true
     }
 
     fn deregister(&mut self, poll: &Poll) -> bool {
-        let _ = poll.registry().deregister(&mut self.stream);
+        if self.registered {
+            self.registered = false;
+            let _ = poll.registry().deregister(&mut self.stream);
+        }
         true
     }
 
     fn finish_send(&mut self) -> bool {
-        !self.session.wants_write()
+        !self.session.wants_write() && self.backlog.is_empty()
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+    extern crate test;
+
+    use bytes::BytesMut;
+    use mio::net::TcpStream;
+    use test::Bencher;
+
+    use crate::{
+        proto::MAX_PACKET_SIZE,
+        sim::{allocations, loopback_pair},
+        status::StatusProvider,
+        tcp_util::{tcp_read, tcp_send},
+        tls_conn::{TlsConn, Written, SESSION_LIMIT},
+    };
+
+    const CHUNK_SIZE: usize = 64 * 1024;
+    /// Packets relayed once the buffers have grown
+    const RELAY_PACKETS: usize = 10_000;
+    /// Allocations of the relay beyond the ones of rustls for all packets
+    const RELAY_ALLOCATIONS: usize = 16;
+
+    fn tcp_pair() -> (std::net::TcpStream, TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        (stream, TcpStream::from_std(accepted))
+    }
+
+    /// Allocations of rustls alone sending `count` packets from `server`
+    /// to `client`, it keeps every record in a Vec of its own.
+    fn rustls_allocations(client: &mut TlsConn, server: &mut TlsConn, count: usize) -> usize {
+        use std::io::{Read, Write};
+
+        let packet = [0x5au8; 1024];
+        let mut received = [0u8; 1024];
+        let before = allocations();
+        for _ in 0..count {
+            server.session.writer().write_all(&packet).unwrap();
+            while server.session.wants_write() {
+                server.session.write_tls(&mut server.stream).unwrap();
+            }
+            let mut read = 0;
+            while read < packet.len() {
+                match client.session.read_tls(&mut client.stream) {
+                    Ok(_) => {
+                        client.session.process_new_packets().unwrap();
+                        read += client.session.reader().read(&mut received).unwrap_or(0);
+                    }
+                    Err(err) => assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock),
+                }
+            }
+        }
+        allocations() - before
+    }
+
+    /// Relays packets from a plain origin through the tunnel to a plain
+    /// sink like an established connection does. Buffers grow for the
+    /// first packets, after them it may allocate only what rustls does.
+    #[test]
+    fn test_relay_allocations() {
+        use std::io::{Read, Write};
+
+        let (mut client, mut server) = loopback_pair();
+        rustls_allocations(&mut client, &mut server, 100);
+        let floor = rustls_allocations(&mut client, &mut server, RELAY_PACKETS);
+        let (mut origin, relay_in) = tcp_pair();
+        let (mut sink, relay_out) = tcp_pair();
+        let packet = [0x5au8; 1024];
+        let mut recv_buffer = vec![0u8; MAX_PACKET_SIZE];
+        let mut tunnel_buffer = BytesMut::new();
+        let mut send_buffer = BytesMut::new();
+        let mut received = [0u8; 1024];
+        let mut relay = |count: usize| {
+            for _ in 0..count {
+                origin.write_all(&packet).unwrap();
+                let mut read = 0;
+                while read < packet.len() {
+                    read += tcp_read(0, &relay_in, &mut recv_buffer, &mut server).bytes;
+                }
+                server.do_send();
+                while tunnel_buffer.len() < packet.len() {
+                    client.do_read_into(&mut tunnel_buffer);
+                }
+                let sent = tcp_send(1, &relay_out, &mut send_buffer, tunnel_buffer.as_ref());
+                assert_eq!(sent.bytes, packet.len());
+                tunnel_buffer.clear();
+                sink.read_exact(&mut received).unwrap();
+            }
+        };
+        relay(100);
+        let before = allocations();
+        relay(RELAY_PACKETS);
+        let made = allocations() - before;
+        assert!(
+            made <= floor + RELAY_ALLOCATIONS,
+            "{} allocations for {} packets, rustls makes {}",
+            made,
+            RELAY_PACKETS,
+            floor
+        );
+    }
+
+    #[test]
+    fn test_cork() {
+        let (mut client, mut server) = loopback_pair();
+        client.cork();
+        assert!(client.write_session(b"request").accepted());
+        client.do_send();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let mut byte = [0u8; 1];
+        assert_eq!(
+            server.stream.peek(&mut byte).unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+        assert!(client.write_session(b"payload").accepted());
+        client.do_send();
+        client.uncork();
+        let mut buffer = BytesMut::new();
+        while buffer.len() < 14 {
+            server.do_read_into(&mut buffer);
+        }
+        assert_eq!(buffer.as_ref(), b"requestpayload");
+    }
+
+    #[test]
+    fn test_full() {
+        let (mut client, mut server) = loopback_pair();
+        let data = vec![0x5au8; SESSION_LIMIT];
+        // the peer reads nothing until the socket buffers and the session
+        // are full
+        let mut writes = 0;
+        while server.backlog.is_empty() {
+            writes += 1;
+            let written = server.write_session(data.as_slice());
+            assert_ne!(written, Written::Failed);
+            server.do_send();
+            assert!(writes < 1024);
+        }
+        assert!(!server.writable());
+        assert!(server.alive());
+        assert_eq!(server.write_session(b"more"), Written::Full);
+
+        let mut received = 0;
+        let mut buffer = BytesMut::new();
+        let total = writes * SESSION_LIMIT + 4;
+        while received < total {
+            received += client.do_read_into(&mut buffer);
+            buffer.clear();
+            server.do_send();
+        }
+        assert_eq!(received, total);
+        assert!(server.writable());
+    }
+
+    #[test]
+    fn test_write_frame() {
+        let (mut client, mut server) = loopback_pair();
+        let head = [0x01u8, 127, 0, 0, 1, 0, 53, 0, 64, b'\r', b'\n'];
+        let body = [0x5au8; 64];
+        // the session takes more than the head but less than the frame
+        server.session.set_buffer_limit(Some(head.len() + 8));
+        assert_eq!(server.write_frame(&head, &body), Written::Full);
+        assert!(!server.backlog.is_empty());
+        assert_eq!(server.write_frame(&head, b"next"), Written::Full);
+
+        server.session.set_buffer_limit(Some(SESSION_LIMIT));
+        let mut expected = head.to_vec();
+        expected.extend_from_slice(&body);
+        expected.extend_from_slice(&head);
+        expected.extend_from_slice(b"next");
+        let mut buffer = BytesMut::new();
+        while buffer.len() < expected.len() {
+            server.do_send();
+            client.do_read_into(&mut buffer);
+        }
+        assert_eq!(buffer.as_ref(), expected.as_slice());
+        assert!(server.writable());
+    }
+
+    #[bench]
+    fn bench_loopback_read(b: &mut Bencher) {
+        let (mut client, mut server) = loopback_pair();
+        let data = vec![0x5au8; CHUNK_SIZE];
+        let mut buffer = BytesMut::new();
+        b.bytes = CHUNK_SIZE as u64;
+        b.iter(|| {
+            assert!(server.write_session(data.as_slice()).accepted());
+            let mut received = 0;
+            while received < CHUNK_SIZE {
+                server.do_send();
+                received += client.do_read_into(&mut buffer);
+                buffer.clear();
+            }
+            assert!(client.alive());
+            received
+        });
     }
 }
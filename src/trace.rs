@@ -0,0 +1,205 @@
+//! Decisions taken for a connection, logged when it closes.
+//!
+//! With `--trace-decisions` every connection keeps why it went where it
+//! went, like the sniffed name, the route rule and the pool checkout on the
+//! proxy or the egress rule on the server. `--trace-source 10.0.0.0/24`
+//! traces only clients from that range, and the server `trace` control
+//! command changes the ranges at runtime. Untraced connections hold an
+//! empty [`Trace`] and never format a reason.
+use std::{
+    fmt::{Display, Formatter},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+};
+
+use crate::{
+    cidr::{to_u128, Cidr},
+    config::OPTIONS,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stage {
+    /// Original destination of a redirected client
+    Destination,
+    Sniff,
+    Allowlist,
+    Route,
+    Family,
+    Pool,
+    /// Target of the trojan request
+    Request,
+    Resolve,
+    Acl,
+    Backend,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Destination => "destination",
+            Stage::Sniff => "sniff",
+            Stage::Allowlist => "allowlist",
+            Stage::Route => "route",
+            Stage::Family => "family",
+            Stage::Pool => "pool",
+            Stage::Request => "request",
+            Stage::Resolve => "resolve",
+            Stage::Acl => "acl",
+            Stage::Backend => "backend",
+        }
+    }
+}
+
+struct Entry {
+    stage: Stage,
+    decision: &'static str,
+    reason: String,
+}
+
+/// Entries of a traced connection, None for one which isn't
+#[derive(Default)]
+pub struct Trace(Option<Vec<Entry>>);
+
+impl Trace {
+    /// A trace for a client from `ip`, empty unless it is traced.
+    pub fn new(ip: IpAddr) -> Trace {
+        if traced(ip) {
+            Trace(Some(Vec::new()))
+        } else {
+            Trace(None)
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Adds a decision, `reason` is only called for a traced connection.
+    pub fn add(&mut self, stage: Stage, decision: &'static str, reason: impl FnOnce() -> String) {
+        if let Some(entries) = self.0.as_mut() {
+            entries.push(Entry {
+                stage,
+                decision,
+                reason: reason(),
+            });
+        }
+    }
+}
+
+impl Display for Trace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let entries = match &self.0 {
+            Some(entries) => entries,
+            None => return Ok(()),
+        };
+        for (i, entry) in entries.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}:{}", entry.stage.name(), entry.decision)?;
+            if !entry.reason.is_empty() {
+                write!(f, "({})", entry.reason)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Nothing is looked up while no connection is traced
+    static ref ACTIVE: AtomicBool = AtomicBool::new(false);
+    /// Traced ranges along with their text
+    static ref SOURCES: RwLock<Vec<(Cidr, String)>> = RwLock::new(Vec::new());
+}
+
+fn add_source(text: &str) -> bool {
+    match Cidr::parse(text) {
+        Some(cidr) => {
+            SOURCES.write().unwrap().push((cidr, text.to_owned()));
+            ACTIVE.store(true, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Reads the trace options, panics for an invalid range.
+pub fn init() {
+    if OPTIONS.trace_decisions {
+        ACTIVE.store(true, Ordering::Relaxed);
+    }
+    for text in &OPTIONS.trace_source {
+        if !add_source(text) {
+            panic!("invalid trace source:{}", text);
+        }
+    }
+}
+
+fn traced(ip: IpAddr) -> bool {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+    if OPTIONS.trace_decisions {
+        return true;
+    }
+    let ip = to_u128(ip);
+    SOURCES
+        .read()
+        .unwrap()
+        .iter()
+        .any(|(cidr, _)| cidr.contains(ip))
+}
+
+/// Answers `trace`, `trace <cidr>` and `trace off`, `args` is what follows
+/// the command.
+pub fn command(args: &str) -> String {
+    match args.trim() {
+        "" => {}
+        "off" => {
+            SOURCES.write().unwrap().clear();
+            ACTIVE.store(OPTIONS.trace_decisions, Ordering::Relaxed);
+        }
+        text => {
+            if !add_source(text) {
+                return format!("{{\"error\":\"invalid range {}\"}}", text);
+            }
+        }
+    }
+    let sources = SOURCES
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(_, text)| format!("{:?}", text))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"all\":{},\"sources\":[{}]}}",
+        OPTIONS.trace_decisions, sources
+    )
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use crate::trace::{Stage, Trace};
+
+    #[test]
+    fn test_trace() {
+        let mut trace = Trace(Some(Vec::new()));
+        trace.add(Stage::Sniff, "name", || "www.example.com".to_owned());
+        trace.add(Stage::Route, "jp", || "rule at line 3".to_owned());
+        trace.add(Stage::Pool, "hit", String::new);
+        assert_eq!(
+            trace.to_string(),
+            "sniff:name(www.example.com) route:jp(rule at line 3) pool:hit"
+        );
+
+        let mut untraced = Trace(None);
+        untraced.add(Stage::Sniff, "name", || unreachable!());
+        assert!(!untraced.enabled());
+        assert_eq!(untraced.to_string(), "");
+    }
+}
@@ -0,0 +1,135 @@
+//! Socket buffer sizes and congestion control, set separately for the
+//! sockets facing local clients, the tunnel sockets between proxy and
+//! server, and the server sockets to targets.
+//!
+//! Each role takes a value like `sndbuf=4194304,rcvbuf=4194304,congestion=bbr`.
+//! Options the system refuses are skipped with a warning logged once.
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use mio::net::TcpStream;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+use crate::sys::{self, RawSocket};
+
+#[derive(Default)]
+pub struct SocketTuning {
+    sndbuf: usize,
+    rcvbuf: usize,
+    congestion: String,
+    /// TCP_FASTOPEN_CONNECT on connecting sockets, set by `--tcp-fast-open`
+    pub fast_open: bool,
+    warned: AtomicBool,
+}
+
+impl SocketTuning {
+    pub fn parse(text: &str) -> Option<SocketTuning> {
+        let mut tuning = SocketTuning::default();
+        for item in text.split(',').filter(|item| !item.trim().is_empty()) {
+            let (key, value) = item.trim().split_once('=')?;
+            match key {
+                "sndbuf" => tuning.sndbuf = value.parse().ok()?,
+                "rcvbuf" => tuning.rcvbuf = value.parse().ok()?,
+                "congestion" if !value.is_empty() => tuning.congestion = value.to_owned(),
+                _ => return None,
+            }
+        }
+        Some(tuning)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.sndbuf == 0 && self.rcvbuf == 0 && self.congestion.is_empty()
+    }
+
+    fn warn(&self, role: &str, err: std::io::Error) {
+        if !self.warned.swap(true, Ordering::Relaxed) {
+            log::warn!("tune {} socket failed, continuing without it:{}", role, err);
+        } else {
+            log::debug!("tune {} socket failed:{}", role, err);
+        }
+    }
+
+    /// Applies the options to `socket`, failures only log.
+    pub fn apply<T: RawSocket>(&self, role: &str, socket: &T) {
+        if self.is_empty() {
+            return;
+        }
+        let mut result = sys::set_buffers(socket, self.sndbuf, self.rcvbuf);
+        if result.is_ok() && !self.congestion.is_empty() {
+            result = sys::set_congestion(socket, self.congestion.as_str());
+        }
+        if let Err(err) = result {
+            self.warn(role, err);
+        }
+    }
+
+    /// Starts connecting to `addr` with the options set before the
+    /// handshake, so the window scale covers the receive buffer. With fast
+    /// open the SYN waits for the first write, which it carries.
+    pub fn connect(&self, role: &str, addr: SocketAddr) -> std::io::Result<TcpStream> {
+        self.connect_from(role, addr, None, false)
+    }
+
+    /// Connects like [`SocketTuning::connect`] from `source` if given,
+    /// with `no_port` the bind leaves the port to the connect.
+    pub fn connect_from(
+        &self,
+        role: &str,
+        addr: SocketAddr,
+        source: Option<IpAddr>,
+        no_port: bool,
+    ) -> std::io::Result<TcpStream> {
+        if self.is_empty() && !self.fast_open && source.is_none() {
+            return TcpStream::connect(addr);
+        }
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        self.apply(role, &socket);
+        if self.fast_open {
+            if let Err(err) = sys::set_fast_open_connect(&socket) {
+                self.warn(role, err);
+            }
+        }
+        if let Some(source) = source {
+            if no_port {
+                if let Err(err) = sys::set_bind_no_port(&socket) {
+                    self.warn(role, err);
+                }
+            }
+            socket.bind(&SockAddr::from(SocketAddr::new(source, 0)))?;
+        }
+        socket.set_nonblocking(true)?;
+        match socket.connect(&SockAddr::from(addr)) {
+            Ok(()) => {}
+            #[cfg(unix)]
+            Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+        Ok(TcpStream::from_std(socket.into()))
+    }
+}
+
+/// Parses the value of a `--*-socket` option, panics for invalid ones like
+/// other startup options.
+pub fn parse_option(option: &str, text: &str) -> SocketTuning {
+    SocketTuning::parse(text).unwrap_or_else(|| panic!("invalid --{} value:{}", option, text))
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use crate::tuning::SocketTuning;
+
+    #[test]
+    fn test_parse() {
+        let tuning = SocketTuning::parse("sndbuf=4096, rcvbuf=8192,congestion=bbr").unwrap();
+        assert_eq!((tuning.sndbuf, tuning.rcvbuf), (4096, 8192));
+        assert_eq!(tuning.congestion, "bbr");
+        assert!(SocketTuning::parse("").unwrap().is_empty());
+        assert!(SocketTuning::parse("sndbuf=4k").is_none());
+        assert!(SocketTuning::parse("window=1").is_none());
+    }
+}
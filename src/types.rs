@@ -22,9 +22,44 @@ pub enum TrojanError {
     TxBreak(Option<std::io::Error>),
     #[from(ignore)]
     RxBreak(Option<std::io::Error>),
+    #[from(ignore)]
+    Setup(SetupPhase, Option<std::io::Error>),
+    /// Startup failure with a hint how to fix it
+    #[from(ignore)]
+    Startup(StartupFailure, String),
     DnsProto(trust_dns_proto::error::ProtoError),
+    RayonBuild(rayon::ThreadPoolBuildError),
 }
 
+/// Step of setting up a proxied connection which failed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SetupPhase {
+    PoolGet,
+    WriteRequest,
+    RegisterClient,
+}
+
+/// Startup failures with their own exit code
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StartupFailure {
+    AddrInUse,
+    BindDenied,
+    NoCapability,
+    PrivilegeDrop,
+}
+
+impl StartupFailure {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StartupFailure::AddrInUse => 10,
+            StartupFailure::BindDenied => 11,
+            StartupFailure::NoCapability => 12,
+            StartupFailure::PrivilegeDrop => 13,
+        }
+    }
+}
+
+#[allow(dead_code)]
 pub enum CopyResult {
     RxBlock,
     TxBlock,
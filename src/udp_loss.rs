@@ -0,0 +1,202 @@
+//! Datagrams a udp association forwards and drops in the relay, the drops
+//! counted by reason so loss in the relay can be told from loss in the
+//! network.
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::metrics::{
+    Counter, UDP_DROPPED_BUFFER_FULL, UDP_DROPPED_OVERSIZE, UDP_DROPPED_PARSE,
+    UDP_DROPPED_QUEUE_DELAY, UDP_DROPPED_SEND_ERROR, UDP_PACE_DROPPED,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Loss {
+    /// Invalid udp frame, the association is closed
+    Parse,
+    /// Datagram longer than the tunnel carries, or cut short by the socket
+    Oversize,
+    /// No room left in the tunnel
+    BufferFull,
+    /// Sending to the socket failed
+    SendError,
+    /// Dropped from a full pace queue
+    RateLimit,
+    /// Dropped while the tunnel queue stood too long
+    QueueDelay,
+}
+
+const LOSSES: [Loss; 6] = [
+    Loss::Parse,
+    Loss::Oversize,
+    Loss::BufferFull,
+    Loss::SendError,
+    Loss::RateLimit,
+    Loss::QueueDelay,
+];
+
+impl Loss {
+    fn name(self) -> &'static str {
+        match self {
+            Loss::Parse => "parse",
+            Loss::Oversize => "oversize",
+            Loss::BufferFull => "buffer_full",
+            Loss::SendError => "send_error",
+            Loss::RateLimit => "rate_limit",
+            Loss::QueueDelay => "queue_delay",
+        }
+    }
+
+    fn counter(self) -> &'static Counter {
+        match self {
+            Loss::Parse => &UDP_DROPPED_PARSE,
+            Loss::Oversize => &UDP_DROPPED_OVERSIZE,
+            Loss::BufferFull => &UDP_DROPPED_BUFFER_FULL,
+            Loss::SendError => &UDP_DROPPED_SEND_ERROR,
+            Loss::RateLimit => &UDP_PACE_DROPPED,
+            Loss::QueueDelay => &UDP_DROPPED_QUEUE_DELAY,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct UdpStats {
+    forwarded: AtomicUsize,
+    dropped: [AtomicUsize; LOSSES.len()],
+}
+
+impl UdpStats {
+    pub fn forward(&self) {
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a dropped datagram here and in the process wide metrics.
+    pub fn lose(&self, loss: Loss) {
+        self.dropped[loss as usize].fetch_add(1, Ordering::Relaxed);
+        loss.counter().inc();
+    }
+
+    pub fn forwarded(&self) -> usize {
+        self.forwarded.load(Ordering::Relaxed)
+    }
+
+    pub fn lost(&self, loss: Loss) -> usize {
+        self.dropped[loss as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> usize {
+        LOSSES.iter().map(|loss| self.lost(*loss)).sum()
+    }
+
+    /// Logs the counts of a closed association, at info if any datagram
+    /// was dropped.
+    pub fn log_closed(&self, index: usize) {
+        let level = if self.dropped() > 0 {
+            log::Level::Info
+        } else {
+            log::Level::Debug
+        };
+        log::log!(
+            level,
+            "connection:{} udp association closed, {}",
+            index,
+            self
+        );
+    }
+
+    fn json(&self, index: usize) -> String {
+        let dropped = LOSSES
+            .iter()
+            .map(|loss| format!("\"{}\":{}", loss.name(), self.lost(*loss)))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"connection\":{},\"forwarded\":{},\"dropped\":{{{}}}}}",
+            index,
+            self.forwarded(),
+            dropped
+        )
+    }
+}
+
+impl Display for UdpStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "forwarded:{} dropped:{}",
+            self.forwarded(),
+            self.dropped()
+        )?;
+        for loss in LOSSES {
+            write!(f, " {}:{}", loss.name(), self.lost(loss))?;
+        }
+        Ok(())
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Open server associations for the `list` control command
+    static ref ASSOCIATIONS: Mutex<HashMap<usize, Arc<UdpStats>>> = Mutex::new(HashMap::new());
+}
+
+/// Returns the counts of a new association, listed until unregistered.
+pub fn register(index: usize) -> Arc<UdpStats> {
+    let stats = Arc::new(UdpStats::default());
+    ASSOCIATIONS.lock().unwrap().insert(index, stats.clone());
+    stats
+}
+
+pub fn unregister(index: usize) {
+    ASSOCIATIONS.lock().unwrap().remove(&index);
+}
+
+/// Json array of the open associations, ordered by connection.
+pub fn list() -> String {
+    let associations = ASSOCIATIONS.lock().unwrap();
+    let mut indexes: Vec<_> = associations.keys().copied().collect();
+    indexes.sort_unstable();
+    let list = indexes
+        .iter()
+        .map(|index| associations[index].json(*index))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("[{}]", list)
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use crate::{
+        metrics::UDP_DROPPED_OVERSIZE,
+        udp_loss::{list, register, unregister, Loss, UdpStats},
+    };
+
+    #[test]
+    fn test_stats() {
+        let stats = UdpStats::default();
+        let before = UDP_DROPPED_OVERSIZE.get();
+        stats.forward();
+        stats.lose(Loss::Oversize);
+        stats.lose(Loss::Oversize);
+        stats.lose(Loss::Parse);
+        assert_eq!((stats.forwarded(), stats.dropped()), (1, 3));
+        assert_eq!(stats.lost(Loss::Oversize), 2);
+        assert!(UDP_DROPPED_OVERSIZE.get() >= before + 2);
+        assert_eq!(
+            stats.to_string(),
+            "forwarded:1 dropped:3 parse:1 oversize:2 buffer_full:0 send_error:0 rate_limit:0 queue_delay:0"
+        );
+
+        register(7).lose(Loss::SendError);
+        assert!(list().contains(
+            "{\"connection\":7,\"forwarded\":0,\"dropped\":{\"parse\":0,\"oversize\":0,\"buffer_full\":0,\"send_error\":1,\"rate_limit\":0,\"queue_delay\":0}}"
+        ));
+        unregister(7);
+        assert!(!list().contains("\"connection\":7,"));
+    }
+}
@@ -1,14 +1,14 @@
 use std::{
     io::{ErrorKind, Read, Write},
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, SocketAddr, UdpSocket},
     str::FromStr,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use bytes::{Buf, BytesMut};
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use ring::rand::{SecureRandom, SystemRandom};
 use trust_dns_proto::{
-    op::{Message, Query},
+    op::{Message, MessageType, Query, ResponseCode},
     rr::{DNSClass, Name, RecordType},
     serialize::binary::BinDecodable,
 };
@@ -19,6 +19,7 @@ use crate::types::{
     Result, TrojanError,
 };
 
+#[allow(dead_code)]
 pub fn copy_stream(
     from: &mut impl Read,
     to: &mut impl Write,
@@ -34,6 +35,7 @@ pub fn copy_stream(
     }
 }
 
+#[allow(dead_code)]
 pub fn send_all(writer: &mut impl Write, buffer: &mut BytesMut) -> Result<bool> {
     if buffer.is_empty() {
         return Ok(true);
@@ -64,6 +66,7 @@ pub fn send_all(writer: &mut impl Write, buffer: &mut BytesMut) -> Result<bool>
     ret
 }
 
+#[allow(dead_code)]
 pub fn read_once(reader: &mut impl Read, buffer: &mut BytesMut) -> Result<bool> {
     buffer.reserve(1500);
     let mut nb = buffer.split_off(buffer.len());
@@ -90,45 +93,216 @@ pub fn read_once(reader: &mut impl Read, buffer: &mut BytesMut) -> Result<bool>
     ret
 }
 
-pub fn resolve(name: &str, dns_server_addr: &str) -> Result<Vec<IpAddr>> {
-    let dns_server_addr: SocketAddr = dns_server_addr.parse()?;
-    let dns_server_addr: SockAddr = dns_server_addr.into();
-    let mut socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-    let addr: SocketAddr = "0.0.0.0:0".parse()?;
-    let addr: SockAddr = addr.into();
-    socket.bind(&addr)?;
-    let mut message = Message::new();
-    message.set_recursion_desired(true);
-    message.set_id(1);
-    let mut query = Query::new();
+pub fn resolve(name: &str, dns_server_addr: SocketAddr) -> Result<Vec<IpAddr>> {
+    resolve_timeout(name, dns_server_addr, Duration::from_millis(3000))
+}
+
+/// Time to wait for the other record type once one answer arrived
+const RESOLVE_GRACE: Duration = Duration::from_millis(50);
+
+/// Queries the A and AAAA records of `name` at `dns_server_addr`, A records
+/// first. Only replies from the server carrying the random ids sent count,
+/// NXDOMAIN and empty answers are errors so callers move on to another
+/// upstream.
+pub fn resolve_timeout(
+    name: &str,
+    dns_server_addr: SocketAddr,
+    timeout: Duration,
+) -> Result<Vec<IpAddr>> {
+    let addr: SocketAddr = if dns_server_addr.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+    let socket = UdpSocket::bind(addr)?;
     let name = Name::from_str(name)?;
-    query.set_name(name);
-    query.set_query_type(RecordType::A);
-    query.set_query_class(DNSClass::IN);
-    message.add_query(query);
-    let request = message.to_vec()?;
-    if request.len() != socket.send_to(request.as_slice(), &dns_server_addr)? {
-        return Err(TrojanError::Dummy(()));
+    let mut random = [0u8; 4];
+    SystemRandom::new()
+        .fill(&mut random)
+        .map_err(|_| TrojanError::Dummy(()))?;
+    let ids = [
+        u16::from_be_bytes([random[0], random[1]]),
+        u16::from_be_bytes([random[2], random[3]]),
+    ];
+    for (id, query_type) in ids.iter().zip([RecordType::A, RecordType::AAAA]) {
+        let mut message = Message::new();
+        message.set_recursion_desired(true);
+        message.set_id(*id);
+        let mut query = Query::new();
+        query.set_name(name.clone());
+        query.set_query_type(query_type);
+        query.set_query_class(DNSClass::IN);
+        message.add_query(query);
+        let request = message.to_vec()?;
+        if request.len() != socket.send_to(request.as_slice(), dns_server_addr)? {
+            return Err(TrojanError::Dummy(()));
+        }
     }
-    let mut response = vec![0u8; 1024];
-    socket.set_read_timeout(Some(Duration::from_millis(3000)))?;
-    let length = socket.read(response.as_mut_slice())?;
-    let message = Message::from_bytes(&response.as_slice()[..length])?;
-    if message.id() != 1 {
+    let mut answers: [Option<Vec<IpAddr>>; 2] = [None, None];
+    let mut response = vec![0u8; 1500];
+    let mut deadline = Instant::now() + timeout;
+    while answers.iter().any(Option::is_none) {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let (length, from) = match socket.recv_from(response.as_mut_slice()) {
+            Ok(received) => received,
+            Err(err)
+                if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+                    && answers.iter().any(Option::is_some) =>
+            {
+                break;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if from != dns_server_addr {
+            log::warn!(
+                "dropped dns reply from {} instead of {}",
+                from,
+                dns_server_addr
+            );
+            continue;
+        }
+        let message = match Message::from_bytes(&response.as_slice()[..length]) {
+            Ok(message) if message.message_type() == MessageType::Response => message,
+            _ => continue,
+        };
+        let index = match ids.iter().position(|id| *id == message.id()) {
+            Some(index) if answers[index].is_none() => index,
+            _ => continue,
+        };
+        // a name without records of one type may still have the other
+        let ips = if message.response_code() == ResponseCode::NXDomain {
+            Vec::new()
+        } else {
+            message
+                .answers()
+                .iter()
+                .filter_map(|record| record.data().and_then(|data| data.to_ip_addr()))
+                .collect()
+        };
+        answers[index].replace(ips);
+        deadline = deadline.min(Instant::now() + RESOLVE_GRACE);
+    }
+    let ips: Vec<_> = answers
+        .iter_mut()
+        .flat_map(|ips| ips.take())
+        .flatten()
+        .collect();
+    if ips.is_empty() {
         Err(TrojanError::Dummy(()))
     } else {
-        Ok(message
-            .answers()
-            .iter()
-            .filter_map(|record| record.data().and_then(|data| data.to_ip_addr()))
-            .collect())
+        Ok(ips)
     }
 }
 
 mod test {
+    #![allow(unused_imports)]
+
+    use std::{
+        net::{IpAddr, UdpSocket},
+        str::FromStr,
+        thread,
+        time::Duration,
+    };
+
+    use trust_dns_proto::{
+        op::{Message, MessageType, ResponseCode},
+        rr::{Name, RData, Record, RecordType},
+        serialize::binary::BinDecodable,
+    };
+
     #[test]
     fn test_resolve() {
-        let result = crate::utils::resolve("www.baidu.com", "192.168.3.1:53");
+        let result = crate::utils::resolve("www.baidu.com", "192.168.3.1:53".parse().unwrap());
         println!("{:?}", result);
     }
+
+    /// Answers the A and AAAA queries of a lookup with the codes of
+    /// `codes`, after a forged reply from another socket and one with a
+    /// wrong id.
+    #[allow(dead_code)]
+    fn serve(server: UdpSocket, codes: [ResponseCode; 2], ips: Vec<IpAddr>) {
+        let mut buffer = vec![0u8; 1500];
+        let mut queries = Vec::new();
+        let mut client = None;
+        for _ in 0..2 {
+            let (length, from) = server.recv_from(buffer.as_mut_slice()).unwrap();
+            queries.push(Message::from_bytes(&buffer[..length]).unwrap());
+            client.replace(from);
+        }
+        let client = client.unwrap();
+        let reply = |query: &Message, id: u16| {
+            let mut message = Message::new();
+            message.set_id(id);
+            message.set_message_type(MessageType::Response);
+            let record_type = query.queries()[0].query_type();
+            let code = if record_type == RecordType::A {
+                codes[0]
+            } else {
+                codes[1]
+            };
+            message.set_response_code(code);
+            for ip in ips.iter() {
+                let data = match (ip, record_type) {
+                    (IpAddr::V4(ip), RecordType::A) => RData::A(*ip),
+                    (IpAddr::V6(ip), RecordType::AAAA) => RData::AAAA(*ip),
+                    _ => continue,
+                };
+                let name = Name::from_str("example.com").unwrap();
+                message.add_answer(Record::from_rdata(name, 60, data));
+            }
+            message.to_vec().unwrap()
+        };
+        let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let bogus = reply(&queries[0], queries[0].id());
+        stranger.send_to(bogus.as_slice(), client).unwrap();
+        let id = (0..)
+            .find(|id| queries.iter().all(|query| query.id() != *id))
+            .unwrap();
+        let wrong_id = reply(&queries[0], id);
+        server.send_to(wrong_id.as_slice(), client).unwrap();
+        // AAAA first, the A records still come first in the result
+        for query in queries.iter().rev() {
+            server
+                .send_to(reply(query, query.id()).as_slice(), client)
+                .unwrap();
+        }
+    }
+
+    #[allow(dead_code)]
+    fn resolve(codes: [ResponseCode; 2], ips: Vec<IpAddr>) -> crate::types::Result<Vec<IpAddr>> {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || serve(server, codes, ips));
+        let result = crate::utils::resolve_timeout("example.com", addr, Duration::from_secs(3));
+        handle.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn test_resolve_timeout() {
+        let ips = vec!["2001:db8::1".parse().unwrap(), "192.0.2.1".parse().unwrap()];
+        let result = resolve([ResponseCode::NoError; 2], ips).unwrap();
+        assert_eq!(
+            result,
+            vec![
+                "192.0.2.1".parse::<IpAddr>().unwrap(),
+                "2001:db8::1".parse().unwrap()
+            ]
+        );
+        // an ipv6 only host resolves
+        let ips = vec!["2001:db8::1".parse().unwrap()];
+        assert_eq!(
+            resolve([ResponseCode::NoError; 2], ips.clone()).unwrap(),
+            ips
+        );
+        // the AAAA answer is kept, the A query came back as NXDOMAIN
+        let codes = [ResponseCode::NXDomain, ResponseCode::NoError];
+        assert_eq!(resolve(codes, ips.clone()).unwrap(), ips);
+        assert!(resolve([ResponseCode::NoError; 2], vec![]).is_err());
+        assert!(resolve([ResponseCode::NXDomain; 2], vec![]).is_err());
+    }
 }
@@ -0,0 +1,236 @@
+//! Verification of the server certificate by the proxy.
+//!
+//! The webpki roots are trusted, along with the CAs of `--ca-file` if
+//! given. `--verify-hostname false` still checks the chain and validity but
+//! lets a certificate issued for another name pass. Both are warned about
+//! at startup, there is no option to skip verification entirely.
+//!
+//! A failed verification logs its cause in one line, like the certificate
+//! being expired or the system clock being behind its start, instead of
+//! the bare webpki error.
+use std::{
+    fs::File,
+    io::BufReader,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, Error, OwnedTrustAnchor, RootCertStore, ServerName,
+};
+
+use crate::{
+    cert::{format_time, CertInfo},
+    config::OPTIONS,
+    types::Result,
+};
+
+/// Clocks before this time, 2022-01-01, are most likely not set
+const MIN_CLOCK: i64 = 1640995200;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Cause {
+    Expired,
+    NotYetValid,
+    HostnameMismatch,
+    UnknownIssuer,
+    Other,
+}
+
+fn cause(err: &Error) -> Cause {
+    let text = err.to_string();
+    if text.contains("CertExpired") {
+        Cause::Expired
+    } else if text.contains("CertNotValidYet") {
+        Cause::NotYetValid
+    } else if text.contains("CertNotValidForName") {
+        Cause::HostnameMismatch
+    } else if text.contains("UnknownIssuer") {
+        Cause::UnknownIssuer
+    } else {
+        Cause::Other
+    }
+}
+
+/// Explains a verification failure of `cert` sent for `name`, `now` in unix
+/// seconds.
+fn describe(err: &Error, cert: Option<&CertInfo>, name: &str, now: i64) -> String {
+    let cert = if let Some(cert) = cert {
+        cert
+    } else {
+        return format!("server certificate of {} is invalid:{}", name, err);
+    };
+    let clock = format_time(now);
+    match cause(err) {
+        Cause::Expired => format!(
+            "server certificate {} expired at {}, system time is {}",
+            cert.subject,
+            format_time(cert.not_after),
+            clock
+        ),
+        Cause::NotYetValid if now < MIN_CLOCK => format!(
+            "clock skew detected, system time {} is not set, server certificate {} is valid from {}",
+            clock,
+            cert.subject,
+            format_time(cert.not_before)
+        ),
+        Cause::NotYetValid => format!(
+            "clock skew detected, system time {} is before the start {} of server certificate {}",
+            clock,
+            format_time(cert.not_before),
+            cert.subject
+        ),
+        Cause::HostnameMismatch => format!(
+            "hostname mismatch, server certificate {} is issued for {}, not {}",
+            cert.subject,
+            cert.sans.join(","),
+            name
+        ),
+        Cause::UnknownIssuer => format!(
+            "unknown issuer {} of server certificate {}, add its CA with --ca-file",
+            cert.issuer, cert.subject
+        ),
+        Cause::Other => format!(
+            "server certificate {} of {} is invalid:{}",
+            cert.subject, name, err
+        ),
+    }
+}
+
+pub struct Verifier {
+    inner: WebPkiVerifier,
+    verify_hostname: bool,
+    /// Last failure logged, repeats of it only go to debug
+    last_failure: Mutex<String>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> std::result::Result<ServerCertVerified, Error> {
+        let err = match self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        ) {
+            Ok(verified) => return Ok(verified),
+            Err(err) => err,
+        };
+        // webpki checks the name last, the chain is fine
+        if !self.verify_hostname && cause(&err) == Cause::HostnameMismatch {
+            log::debug!("server certificate hostname not verified");
+            return Ok(ServerCertVerified::assertion());
+        }
+        let name = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_owned(),
+            name => format!("{:?}", name),
+        };
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        let cert = CertInfo::parse(end_entity.0.as_slice());
+        let message = describe(&err, cert.as_ref(), name.as_str(), now);
+        let mut last_failure = self.last_failure.lock().unwrap();
+        if *last_failure != message {
+            log::error!("{}", message);
+            *last_failure = message;
+        } else {
+            log::debug!("{}", message);
+        }
+        Err(err)
+    }
+}
+
+/// Builds the verifier of the server certificate, warning about relaxed
+/// options.
+pub fn verifier() -> Result<Arc<dyn ServerCertVerifier>> {
+    let mut root_store = RootCertStore::empty();
+    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    if !OPTIONS.ca_file.is_empty() {
+        let mut reader = BufReader::new(File::open(OPTIONS.ca_file.as_str())?);
+        let certs = rustls_pemfile::certs(&mut reader)?;
+        for cert in &certs {
+            root_store.add(&Certificate(cert.clone()))?;
+        }
+        log::warn!(
+            "TRUST RELAXED: {} extra CA certificates from {} are trusted for the server",
+            certs.len(),
+            OPTIONS.ca_file
+        );
+    }
+    if !OPTIONS.verify_hostname {
+        log::warn!(
+            "TRUST RELAXED: server certificate hostname is not verified, any certificate \
+             of a trusted CA is accepted for the server"
+        );
+    }
+    Ok(Arc::new(Verifier {
+        inner: WebPkiVerifier::new(root_store, None),
+        verify_hostname: OPTIONS.verify_hostname,
+        last_failure: Mutex::new(String::new()),
+    }))
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use std::{fs::File, io::BufReader};
+
+    use rustls::Error;
+
+    use crate::{
+        cert::CertInfo,
+        verify::{cause, describe, Cause},
+    };
+
+    #[test]
+    fn test_describe() {
+        let mut reader = BufReader::new(File::open("tests/certs/server.pem").unwrap());
+        let der = rustls_pemfile::certs(&mut reader).unwrap().remove(0);
+        let cert = CertInfo::parse(der.as_slice()).unwrap();
+        let error = |reason: &str| {
+            Error::InvalidCertificateData(format!("invalid peer certificate: {}", reason))
+        };
+
+        let err = error("CertNotValidForName");
+        assert_eq!(cause(&err), Cause::HostnameMismatch);
+        assert_eq!(
+            describe(&err, Some(&cert), "example.com", cert.not_before),
+            "hostname mismatch, server certificate CN=localhost is issued for localhost, not example.com"
+        );
+        let err = error("CertNotValidYet");
+        assert!(describe(&err, Some(&cert), "localhost", 0)
+            .starts_with("clock skew detected, system time 1970-01-01 00:00:00 UTC is not set"));
+        assert!(
+            describe(&err, Some(&cert), "localhost", cert.not_before - 60)
+                .starts_with("clock skew detected, system time")
+        );
+        let err = error("CertExpired");
+        assert_eq!(
+            describe(&err, Some(&cert), "localhost", cert.not_after + 1),
+            "server certificate CN=localhost expired at 2126-09-20 04:39:05 UTC, system time is 2126-09-20 04:39:06 UTC"
+        );
+        let err = error("UnknownIssuer");
+        assert_eq!(
+            describe(&err, Some(&cert), "localhost", cert.not_before),
+            "unknown issuer CN=trojan test ca of server certificate CN=localhost, add its CA with --ca-file"
+        );
+        assert_eq!(cause(&Error::InvalidCertificateEncoding), Cause::Other);
+    }
+}
@@ -0,0 +1,116 @@
+//! Deadlines bucketed by tick in a ring, a sweep visits only the buckets
+//! which came due instead of every entry.
+//!
+//! Entries are never moved when their deadline changes, the owner checks
+//! the real deadline on the visit and schedules the entry again if it lies
+//! ahead, so activity costs nothing. Deadlines beyond the ring land in its
+//! last slot and are pushed on from there.
+use std::time::{Duration, Instant};
+
+pub struct Wheel {
+    slots: Vec<Vec<usize>>,
+    resolution: Duration,
+    start: Instant,
+    /// First tick not swept yet
+    next: u64,
+    len: usize,
+}
+
+impl Wheel {
+    pub fn new(slots: usize, resolution: Duration, now: Instant) -> Wheel {
+        Wheel {
+            slots: vec![Vec::new(); slots.max(1)],
+            resolution,
+            start: now,
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Whole ticks from the start to `time`, and whether it is past them.
+    fn ticks(&self, time: Instant) -> (u64, bool) {
+        let elapsed = time.saturating_duration_since(self.start).as_nanos();
+        let resolution = self.resolution.as_nanos().max(1);
+        ((elapsed / resolution) as u64, !elapsed.is_multiple_of(resolution))
+    }
+
+    /// Adds `index` to be taken by the first [`expire`](Self::expire) at or
+    /// after `deadline`.
+    pub fn schedule(&mut self, index: usize, deadline: Instant) {
+        let (ticks, past) = self.ticks(deadline);
+        // rounded up, an entry isn't due before its deadline
+        let tick = if past { ticks + 1 } else { ticks };
+        let last = self.next + self.slots.len() as u64 - 1;
+        let tick = tick.max(self.next).min(last);
+        let slot = (tick % self.slots.len() as u64) as usize;
+        self.slots[slot].push(index);
+        self.len += 1;
+    }
+
+    /// Moves the entries due at `now` to `due`.
+    pub fn expire(&mut self, now: Instant, due: &mut Vec<usize>) {
+        let (current, _) = self.ticks(now);
+        let mut swept = 0;
+        while self.next <= current && swept < self.slots.len() {
+            let slot = (self.next % self.slots.len() as u64) as usize;
+            self.len -= self.slots[slot].len();
+            due.append(&mut self.slots[slot]);
+            self.next += 1;
+            swept += 1;
+        }
+        // a late sweep took every slot once, the rest of the ticks are empty
+        self.next = self.next.max(current + 1);
+    }
+
+    /// Scheduled entries, those of closed owners included until due.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+mod test {
+    #![allow(unused_imports, dead_code)]
+
+    use std::time::{Duration, Instant};
+
+    use crate::wheel::Wheel;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn expire(wheel: &mut Wheel, now: Instant) -> Vec<usize> {
+        let mut due = Vec::new();
+        wheel.expire(now, &mut due);
+        due.sort_unstable();
+        due
+    }
+
+    #[test]
+    fn test_wheel() {
+        let start = Instant::now();
+        let mut wheel = Wheel::new(8, SECOND, start);
+        wheel.schedule(1, start + SECOND / 2);
+        wheel.schedule(2, start + SECOND);
+        wheel.schedule(3, start + SECOND * 3);
+        // beyond the ring, taken early from the last slot
+        wheel.schedule(4, start + SECOND * 100);
+        assert_eq!(wheel.len(), 4);
+        assert!(expire(&mut wheel, start + SECOND / 2).is_empty());
+        assert_eq!(expire(&mut wheel, start + SECOND), vec![1, 2]);
+        assert!(expire(&mut wheel, start + SECOND * 2).is_empty());
+        assert_eq!(expire(&mut wheel, start + SECOND * 7), vec![3, 4]);
+        assert_eq!(wheel.len(), 0);
+
+        // a deadline passed already is due on the next sweep
+        wheel.schedule(5, start);
+        assert!(expire(&mut wheel, start + SECOND * 7).is_empty());
+        assert_eq!(expire(&mut wheel, start + SECOND * 8), vec![5]);
+
+        // a sweep after a long pause takes every slot once
+        wheel.schedule(6, start + SECOND * 10);
+        wheel.schedule(7, start + SECOND * 15);
+        assert_eq!(expire(&mut wheel, start + SECOND * 60), vec![6, 7]);
+        wheel.schedule(8, start + SECOND * 61);
+        assert!(expire(&mut wheel, start + SECOND * 60).is_empty());
+        assert_eq!(expire(&mut wheel, start + SECOND * 61), vec![8]);
+    }
+}
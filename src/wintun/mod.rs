@@ -7,7 +7,6 @@ use std::{
 };
 
 use mio::{Events, Poll, Token, Waker};
-use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
 use smoltcp::{
     iface::{Interface, InterfaceBuilder, Routes},
     socket::Socket,
@@ -20,11 +19,13 @@ pub use route::route_add_with_if;
 
 use crate::{
     dns::{get_adapter_ip, get_main_adapter_gwif},
-    proxy::IdlePool,
+    endpoint,
+    idle_pool::IdlePool,
     resolver::DnsResolver,
     types::Result,
+    verify,
     wintun::{ipset::IPSet, tcp::TcpServer, tun::WintunInterface, udp::UdpServer, waker::Wakers},
-    OPTIONS,
+    worker, OPTIONS,
 };
 
 mod ipset;
@@ -63,22 +64,8 @@ fn apply_ipset(file: &str, index: u32, inverse: bool) -> Result<()> {
 
 fn prepare_idle_pool(poll: &Poll, resolver: &DnsResolver) -> Result<IdlePool> {
     let hostname = OPTIONS.wintun_args().hostname.as_str().try_into()?;
-    let mut root_store = RootCertStore::empty();
-    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-        OwnedTrustAnchor::from_subject_spki_name_constraints(
-            ta.subject,
-            ta.spki,
-            ta.name_constraints,
-        )
-    }));
-    let config = ClientConfig::builder()
-        .with_safe_default_cipher_suites()
-        .with_safe_default_kx_groups()
-        .with_safe_default_protocol_versions()
-        .unwrap()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
-    let config = Arc::new(config);
+    log::info!("client hello {}", OPTIONS.hello.fingerprint());
+    let config = OPTIONS.hello.client_config(verify::verifier()?);
     let mut pool = IdlePool::new(
         config,
         hostname,
@@ -108,6 +95,7 @@ fn prepare_device<'a>(session: Arc<Session>) -> Interface<'a, WintunInterface> {
 }
 
 pub fn run() -> Result<()> {
+    worker::start("poll");
     log::info!("dll:{}", OPTIONS.wintun_args().wintun);
     let wintun = unsafe { wintun::load_from_path(&OPTIONS.wintun_args().wintun)? };
     let adapter = Adapter::create(&wintun, "trojan", OPTIONS.wintun_args().name.as_str(), None)?;
@@ -134,7 +122,12 @@ pub fn run() -> Result<()> {
     let mut resolver = DnsResolver::new(
         waker,
         Token(RESOLVER),
-        OPTIONS.wintun_args().dns_server_addr.clone(),
+        OPTIONS
+            .wintun_args()
+            .dns_server_addr
+            .iter()
+            .map(|addr| endpoint::parse_addr("dns-server-addr", addr, Some(53)))
+            .collect(),
     );
     let mut pool = prepare_idle_pool(&poll, &resolver)?;
 
@@ -171,10 +164,13 @@ pub fn run() -> Result<()> {
 
         now = Instant::now();
         let timeout = interface.poll_delay(now).or(timeout);
-        poll.poll(
+        worker::idle();
+        let result = poll.poll(
             &mut events,
             timeout.map(|d| std::time::Duration::from_millis(d.total_millis())),
-        )?;
+        );
+        worker::busy();
+        result?;
         for event in &events {
             match event.token().0 {
                 RESOLVER => {
@@ -224,7 +220,7 @@ pub fn run() -> Result<()> {
                 }
             });
             log::info!("total udp sockets count:{}", sockets_count);
-            pool.check_timeout(&poll);
+            pool.check_timeout(&poll, &resolver);
             last_check_time = now;
         }
     }
@@ -343,7 +343,7 @@ impl TcpServer {
             let conn = self.handle2conns.entry(handle).or_insert_with(|| {
                 log::info!("found new tcp connection");
                 let token = next_token();
-                let mut remote = pool.get(poll, resolver).unwrap();
+                let mut remote = pool.get(poll, resolver).unwrap().commit();
                 remote.set_token(token, poll);
                 let conn = Connection::new(token, handle, remote);
                 Arc::new(conn)
@@ -345,7 +345,7 @@ impl UdpServer {
                         src_endpoint,
                         dst_endpoint
                     );
-                    let mut tls = pool.get(poll, resolver).unwrap();
+                    let mut tls = pool.get(poll, resolver).unwrap().commit();
                     tls.set_token(next_token(), poll);
                     let conn = Connection {
                         token: tls.token(),
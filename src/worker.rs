@@ -0,0 +1,200 @@
+//! Pinning, priority and load of the threads doing the work.
+//!
+//! Workers are numbered in the order they start, the poll loop first and
+//! then the handshake workers, and take the core of `--worker-affinity` at
+//! their number. A single core pins the poll loop alone. `--worker-priority`
+//! applies to all of them. An OS refusing either is warned about and the
+//! thread goes on unpinned or at its priority.
+//!
+//! Each worker counts the time it is busy, so the load of a pinning shows
+//! as busy time per wall time in the state dump and the stats command.
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use crate::{config::OPTIONS, dump::Dump, sys};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Priority {
+    Nice(i32),
+    /// SCHED_FIFO with the priority
+    Fifo(i32),
+}
+
+impl Priority {
+    /// Parses a nice value like -5, or fifo:10.
+    fn parse(text: &str) -> Option<Priority> {
+        match text.split_once(':') {
+            Some(("fifo", priority)) => match priority.parse() {
+                Ok(priority @ 1..=99) => Some(Priority::Fifo(priority)),
+                _ => None,
+            },
+            Some(_) => None,
+            None => match text.parse() {
+                Ok(nice @ -20..=19) => Some(Priority::Nice(nice)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Parses `--worker-affinity`, panics for invalid values like other
+/// startup options.
+pub fn parse_affinity(text: &str) -> Vec<usize> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|core| !core.is_empty())
+        .map(|core| {
+            core.parse()
+                .unwrap_or_else(|_| panic!("invalid --worker-affinity value:{}", text))
+        })
+        .collect()
+}
+
+/// Parses `--worker-priority`, empty for the default.
+pub fn parse_priority(text: &str) -> Option<Priority> {
+    if text.is_empty() {
+        return None;
+    }
+    Some(
+        Priority::parse(text).unwrap_or_else(|| panic!("invalid --worker-priority value:{}", text)),
+    )
+}
+
+struct Load {
+    name: String,
+    started: Instant,
+    busy: Arc<AtomicU64>,
+}
+
+impl Load {
+    fn utilization(&self) -> f64 {
+        let wall = self.started.elapsed().as_nanos() as f64;
+        if wall == 0.0 {
+            return 0.0;
+        }
+        self.busy.load(Ordering::Relaxed) as f64 / wall
+    }
+}
+
+struct Current {
+    busy: Arc<AtomicU64>,
+    since: Option<Instant>,
+}
+
+static NEXT_WORKER: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    static ref LOADS: Mutex<Vec<Load>> = Mutex::new(Vec::new());
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Current>> = const { RefCell::new(None) };
+}
+
+fn apply(name: &str, number: usize) {
+    if let Some(core) = OPTIONS.worker_cores.get(number) {
+        match sys::set_affinity(*core) {
+            Ok(()) => log::info!("worker {} pinned to core {}", name, core),
+            Err(err) => log::warn!("pin worker {} to core {} failed:{}", name, core, err),
+        }
+    }
+    let result = match OPTIONS.worker_sched {
+        Some(Priority::Nice(nice)) => sys::set_nice(nice),
+        Some(Priority::Fifo(priority)) => sys::set_fifo(priority),
+        None => return,
+    };
+    if let Err(err) = result {
+        log::warn!(
+            "set priority {:?} of worker {} failed:{}",
+            OPTIONS.worker_sched.unwrap(),
+            name,
+            err
+        );
+    }
+}
+
+/// Called on the start of a worker thread, which is busy from now on.
+pub fn start(kind: &str) {
+    let number = NEXT_WORKER.fetch_add(1, Ordering::SeqCst);
+    let name = format!("{}-{}", kind, number);
+    apply(name.as_str(), number);
+    let busy = Arc::new(AtomicU64::new(0));
+    LOADS.lock().unwrap().push(Load {
+        name,
+        started: Instant::now(),
+        busy: busy.clone(),
+    });
+    CURRENT.with(|current| {
+        current.replace(Some(Current {
+            busy,
+            since: Some(Instant::now()),
+        }));
+    });
+}
+
+/// The worker waits from now on, like in poll.
+pub fn idle() {
+    CURRENT.with(|current| {
+        if let Some(current) = current.borrow_mut().as_mut() {
+            if let Some(since) = current.since.take() {
+                current
+                    .busy
+                    .fetch_add(since.elapsed().as_nanos() as u64, Ordering::Relaxed);
+            }
+        }
+    });
+}
+
+/// The worker is busy from now on.
+pub fn busy() {
+    CURRENT.with(|current| {
+        if let Some(current) = current.borrow_mut().as_mut() {
+            current.since.get_or_insert_with(Instant::now);
+        }
+    });
+}
+
+/// Busy time per wall time of the workers as json.
+pub fn stats() -> String {
+    let loads = LOADS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|load| format!("\"{}\":{:.4}", load.name, load.utilization()))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{{{}}}", loads)
+}
+
+pub fn dump(dump: &mut Dump) {
+    for load in LOADS.lock().unwrap().iter() {
+        dump.line(format_args!(
+            "worker {} utilization:{:.4}",
+            load.name,
+            load.utilization()
+        ));
+    }
+}
+
+mod test {
+    #![allow(unused_imports)]
+
+    use crate::worker::{parse_affinity, Priority};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_affinity("2, 3"), vec![2, 3]);
+        assert!(parse_affinity("").is_empty());
+        assert_eq!(Priority::parse("-5"), Some(Priority::Nice(-5)));
+        assert_eq!(Priority::parse("fifo:10"), Some(Priority::Fifo(10)));
+        assert_eq!(Priority::parse("fifo:0"), None);
+        assert_eq!(Priority::parse("20"), None);
+        assert_eq!(Priority::parse("rr:10"), None);
+    }
+}
@@ -0,0 +1,59 @@
+//! Churns short lived connections through the server, events of closed
+//! connections must not be reported.
+use std::{
+    fs,
+    io::{Read, Write},
+    net::TcpListener,
+    thread,
+    time::Duration,
+};
+
+mod common;
+
+use common::{connect, trojan_request, Server};
+
+const THREADS: usize = 8;
+const ROUNDS: usize = 250;
+
+#[test]
+fn short_connections_leave_no_stale_events() {
+    let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in origin.incoming() {
+            // closes right away, racing the client which closes as well
+            let _ = stream.map(|mut stream| stream.write_all(b"bye"));
+        }
+    });
+
+    let log_file = std::env::temp_dir().join(format!("trojan-churn-{}.log", std::process::id()));
+    let log_path = log_file.to_str().unwrap();
+    let mut server = Server::start(&["-L", "3", "-l", log_path], &[]);
+    let request = trojan_request(origin_addr.ip(), origin_addr.port());
+    let workers: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let request = request.clone();
+            let port = server.port;
+            thread::spawn(move || {
+                for round in 0..ROUNDS {
+                    let mut tls = connect(port);
+                    if tls.write_all(request.as_slice()).is_err() || tls.flush().is_err() {
+                        continue;
+                    }
+                    if (i + round) % 2 == 0 {
+                        let _ = tls.read(&mut [0u8; 16]);
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    assert!(server.child.try_wait().unwrap().is_none(), "server exited");
+    let log = fs::read_to_string(&log_file).unwrap_or_default();
+    let _ = fs::remove_file(&log_file);
+    assert!(!log.contains("not found"), "{}", log);
+}
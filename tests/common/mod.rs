@@ -0,0 +1,113 @@
+//! Helpers to run the server binary against local origins.
+#![allow(dead_code)]
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::BufReader,
+    net::{IpAddr, TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use rustls::{Certificate, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use sha2::{Digest, Sha224};
+
+pub const PASSWORD: &str = "drain-test";
+
+pub struct Server {
+    pub child: Child,
+    pub port: u16,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Server {
+    /// Starts the server on a free local port with extra global `options`
+    /// and `server_options`.
+    pub fn start(options: &[&str], server_options: &[&str]) -> Server {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let child = Command::new(env!("CARGO_BIN_EXE_trojan"))
+            .args(["-a", &format!("127.0.0.1:{}", port)])
+            .args(["-p", PASSWORD])
+            .args(options)
+            .arg("server")
+            .args([
+                "-c",
+                "tests/certs/server.pem",
+                "-k",
+                "tests/certs/server.key",
+            ])
+            .args(server_options)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server { child, port };
+        (0..50)
+            .find_map(|_| {
+                TcpStream::connect(("127.0.0.1", port)).ok().or_else(|| {
+                    thread::sleep(Duration::from_millis(100));
+                    None
+                })
+            })
+            .expect("server not started");
+        server
+    }
+
+    pub fn connect(&self) -> StreamOwned<ClientConnection, TcpStream> {
+        connect(self.port)
+    }
+}
+
+/// Opens a tls connection to the server listening on `port`.
+pub fn connect(port: u16) -> StreamOwned<ClientConnection, TcpStream> {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let session = ClientConnection::new(client_config(), "localhost".try_into().unwrap()).unwrap();
+    StreamOwned::new(session, stream)
+}
+
+pub fn client_config() -> Arc<ClientConfig> {
+    let mut reader = BufReader::new(File::open("tests/certs/ca.pem").unwrap());
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut reader).unwrap() {
+        roots.add(&Certificate(cert)).unwrap();
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+pub fn trojan_request(ip: IpAddr, port: u16) -> Vec<u8> {
+    trojan_command(0x01, ip, port)
+}
+
+/// A request with `command`, flags included.
+pub fn trojan_command(command: u8, ip: IpAddr, port: u16) -> Vec<u8> {
+    let mut request = hex::encode(Sha224::digest(PASSWORD.as_bytes())).into_bytes();
+    request.extend_from_slice(&[b'\r', b'\n', command]);
+    match ip {
+        IpAddr::V4(v4) => {
+            request.push(0x01);
+            request.extend_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            request.push(0x04);
+            request.extend_from_slice(&v6.octets());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(b"\r\n");
+    request
+}
@@ -0,0 +1,178 @@
+//! Runs management commands through the ctl mode against the server.
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, TcpListener, UdpSocket},
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+mod common;
+
+use common::{trojan_request, Server, PASSWORD};
+
+fn ctl(server: &Server, command: &str) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_trojan"))
+        .args(["-a", "127.0.0.1:0", "-p", PASSWORD, "-L", "5", "ctl"])
+        .args(["-H", "127.0.0.1", "-o", &server.port.to_string()])
+        .args(["--sni", "localhost", "--ca", "tests/certs/ca.pem", command])
+        .output()
+        .unwrap();
+    // the response is the last line, after the password digest
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    stdout.lines().last().unwrap_or_default().to_owned()
+}
+
+#[test]
+fn control_commands() {
+    let server = Server::start(&["-L", "5"], &["--control"]);
+    assert!(ctl(&server, "stats").starts_with("{\"counters\":{\"early_retries\":"));
+    assert!(ctl(&server, "reload").contains("disabled"));
+    assert!(ctl(&server, "kill").contains("unknown command"));
+    assert!(ctl(&server, "history").contains("--control-mutating"));
+    assert!(ctl(&server, "targets 5").contains("--control-mutating"));
+
+    let server = Server::start(&["-L", "5"], &["--control", "--control-mutating"]);
+    assert_eq!(ctl(&server, "reload"), "{\"ok\":true}");
+    assert!(!ctl(&server, "history").contains("error"));
+}
+
+#[test]
+fn udp_loss_counters() {
+    let server = Server::start(&["-L", "5"], &["--control"]);
+    let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+    target
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut request = trojan_request(Ipv4Addr::UNSPECIFIED.into(), 0);
+    // udp associate command after the password and CRLF
+    request[58] = 0x03;
+    request.extend_from_slice(b"\x01\x7f\x00\x00\x01");
+    request.extend_from_slice(&target.local_addr().unwrap().port().to_be_bytes());
+    request.extend_from_slice(b"\x00\x04\r\nping");
+    let mut conn = server.connect();
+    conn.write_all(request.as_slice()).unwrap();
+    let mut buffer = [0u8; 16];
+    assert_eq!(target.recv(&mut buffer).unwrap(), 4);
+    let list = ctl(&server, "list");
+    assert!(
+        list.contains("\"forwarded\":1,\"dropped\":{\"parse\":0"),
+        "{}",
+        list
+    );
+
+    // an unknown address type can't be parsed, closing the association
+    conn.write_all(b"\x09\x00\x00\x00\x00\x00\x00\x00\x04\r\nping")
+        .unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert!(ctl(&server, "stats").contains("\"udp_dropped_parse\":1"));
+    assert_eq!(ctl(&server, "list"), "{\"udp\":[]}");
+}
+
+/// Reads `counter` off the stats command.
+fn counter(server: &Server, counter: &str) -> usize {
+    let stats = ctl(server, "stats");
+    let key = format!("\"{}\":", counter);
+    let value = &stats[stats.find(key.as_str()).unwrap() + key.len()..];
+    value[..value.find(',').unwrap()].parse().unwrap()
+}
+
+#[test]
+fn sweep_visits_due_associations() {
+    const ASSOCIATIONS: usize = 20;
+    let server = Server::start(&["-L", "5"], &["--control"]);
+    let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+    target
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut request = trojan_request(Ipv4Addr::UNSPECIFIED.into(), 0);
+    request[58] = 0x03;
+    request.extend_from_slice(b"\x01\x7f\x00\x00\x01");
+    request.extend_from_slice(&target.local_addr().unwrap().port().to_be_bytes());
+    request.extend_from_slice(b"\x00\x04\r\nping");
+    let mut buffer = [0u8; 16];
+    let _conns: Vec<_> = (0..ASSOCIATIONS)
+        .map(|_| {
+            let mut conn = server.connect();
+            conn.write_all(request.as_slice()).unwrap();
+            assert_eq!(target.recv(&mut buffer).unwrap(), 4);
+            conn
+        })
+        .collect();
+    // the first sweep takes every new connection
+    thread::sleep(Duration::from_secs(2));
+    let visits = counter(&server, "sweep_visits");
+    thread::sleep(Duration::from_secs(5));
+    // the associations are due in 10s, polling each on every sweep visits
+    // all of them 5 times meanwhile
+    let visited = counter(&server, "sweep_visits") - visits;
+    assert!(visited < ASSOCIATIONS, "{} visits", visited);
+}
+
+#[test]
+fn udp_keepalive_discarded() {
+    let server = Server::start(&["-L", "5"], &["--control"]);
+    let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+    target
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let port = target.local_addr().unwrap().port().to_be_bytes();
+    let mut request = trojan_request(Ipv4Addr::UNSPECIFIED.into(), 0);
+    request[58] = 0x03;
+    // an empty keepalive frame, then a datagram
+    request.extend_from_slice(b"\x01\x7f\x00\x00\x01");
+    request.extend_from_slice(&port);
+    request.extend_from_slice(b"\x00\x00\r\n\x01\x7f\x00\x00\x01");
+    request.extend_from_slice(&port);
+    request.extend_from_slice(b"\x00\x04\r\nping");
+    let mut conn = server.connect();
+    conn.write_all(request.as_slice()).unwrap();
+    let mut buffer = [0u8; 16];
+    assert_eq!(target.recv(&mut buffer).unwrap(), 4);
+    assert!(ctl(&server, "stats").contains("\"udp_empty_discarded\":1"));
+}
+
+/// Sends `data` to the echoing origin, returns the size echoed, 0 if the
+/// connection was closed.
+fn echo(conn: &mut (impl Read + Write), data: &[u8]) -> usize {
+    conn.write_all(data).unwrap();
+    let mut buffer = [0u8; 64];
+    conn.read(&mut buffer).unwrap_or(0)
+}
+
+#[test]
+fn maintenance_keeps_open_sessions() {
+    let server = Server::start(&["-L", "5"], &["--control", "--control-mutating"]);
+    let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in origin.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut buffer = [0u8; 64];
+                while let Ok(size) = stream.read(&mut buffer) {
+                    if size == 0 || stream.write_all(&buffer[..size]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    let request = || {
+        let mut request = trojan_request(origin_addr.ip(), origin_addr.port());
+        request.extend_from_slice(b"ping");
+        request
+    };
+
+    let mut open = server.connect();
+    assert_eq!(echo(&mut open, request().as_slice()), 4);
+    assert_eq!(ctl(&server, "maintenance on"), "{\"maintenance\":true}");
+    assert!(ctl(&server, "stats").ends_with("\"maintenance\":true}"));
+    assert_eq!(echo(&mut open, b"pong"), 4);
+    let mut rejected = server.connect();
+    assert_eq!(echo(&mut rejected, request().as_slice()), 0);
+
+    assert_eq!(ctl(&server, "maintenance off"), "{\"maintenance\":false}");
+    let mut again = server.connect();
+    assert_eq!(echo(&mut again, request().as_slice()), 4);
+}
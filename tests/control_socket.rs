@@ -0,0 +1,70 @@
+//! Runs proxy commands through `--control-socket` of a running proxy.
+#![cfg(target_os = "linux")]
+use std::{
+    fs,
+    io::{Read, Write},
+    net::TcpListener,
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+fn command(path: &Path, command: &str) -> String {
+    let mut stream = UnixStream::connect(path).unwrap();
+    stream.write_all(command.as_bytes()).unwrap();
+    stream.write_all(b"\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn control_socket_commands() {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let path = std::env::temp_dir().join(format!("trojan-control-{}.sock", std::process::id()));
+    let mut child = Command::new(env!("CARGO_BIN_EXE_trojan"))
+        .args([
+            "-a",
+            &format!("127.0.0.1:{}", port),
+            "-p",
+            "control",
+            "-L",
+            "5",
+        ])
+        .args(["proxy", "-H", "127.0.0.1", "-o", "1"])
+        .args(["--control-socket", path.to_str().unwrap()])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    for _ in 0..50 {
+        if path.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    assert_eq!(
+        command(&path, "route-mode direct"),
+        "{\"route_mode\":\"direct\",\"previous\":\"tunnel\"}\n"
+    );
+    assert_eq!(
+        command(&path, "route-mode"),
+        "{\"route_mode\":\"direct\"}\n"
+    );
+    assert!(command(&path, "stats").starts_with("{\"route_mode\":\"direct\",\"breakers\":"));
+    assert_eq!(
+        command(&path, "reload"),
+        "{\"error\":\"unknown command\"}\n"
+    );
+    let _ = child.kill();
+    let _ = child.wait();
+    let _ = fs::remove_file(&path);
+}
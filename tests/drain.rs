@@ -0,0 +1,45 @@
+//! Runs the server against an origin which closes right after a large
+//! write, every byte must still reach the client.
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    thread,
+    time::Duration,
+};
+
+mod common;
+
+use common::{trojan_request, Server};
+
+const PAYLOAD_LEN: usize = 8 * 1024 * 1024;
+
+fn payload(i: usize) -> u8 {
+    (i % 251) as u8
+}
+
+#[test]
+fn origin_closes_after_large_write() {
+    let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = origin.accept().unwrap();
+        let data: Vec<u8> = (0..PAYLOAD_LEN).map(payload).collect();
+        stream.write_all(data.as_slice()).unwrap();
+    });
+
+    let server = Server::start(&["-L", "5"], &[]);
+    let mut tls = server.connect();
+    tls.write_all(trojan_request(origin_addr.ip(), origin_addr.port()).as_slice())
+        .unwrap();
+    tls.flush().unwrap();
+    // let the origin finish and close while the server still buffers data for us
+    thread::sleep(Duration::from_millis(500));
+
+    let mut received = Vec::new();
+    tls.read_to_end(&mut received).unwrap();
+    assert_eq!(received.len(), PAYLOAD_LEN);
+    assert!(received
+        .iter()
+        .enumerate()
+        .all(|(i, byte)| *byte == payload(i)));
+}
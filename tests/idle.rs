@@ -0,0 +1,51 @@
+//! Runs an idle proxy, its poll loop has to sleep without waking up.
+#![cfg(target_os = "linux")]
+use std::{
+    fs,
+    net::TcpListener,
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+/// Times the main thread went to sleep, epoll waits included.
+fn sleeps(pid: u32) -> usize {
+    let status = fs::read_to_string(format!("/proc/{}/task/{}/status", pid, pid)).unwrap();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("voluntary_ctxt_switches:"))
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn idle_proxy_does_not_wake_up() {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_trojan"))
+        .args([
+            "-a",
+            &format!("127.0.0.1:{}", port),
+            "-p",
+            "idle",
+            "-L",
+            "5",
+        ])
+        .args(["proxy", "-H", "127.0.0.1", "-o", "1", "--pool-size", "1"])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    // the startup self test takes a second
+    thread::sleep(Duration::from_secs(3));
+    let before = sleeps(child.id());
+    thread::sleep(Duration::from_secs(10));
+    let after = sleeps(child.id());
+    let _ = child.kill();
+    let _ = child.wait();
+    assert_eq!(after, before);
+}
@@ -0,0 +1,35 @@
+//! Runs the server with a listen backlog and defer accept, the ClientHello
+//! has to wake it for the connection like without.
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    thread,
+};
+
+mod common;
+
+use common::{trojan_request, Server};
+
+#[test]
+fn deferred_listener_serves_clients() {
+    let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = origin.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = origin.accept().unwrap();
+        let mut ping = [0u8; 4];
+        stream.read_exact(&mut ping).unwrap();
+        stream.write_all(b"pong").unwrap();
+    });
+    let server = Server::start(
+        &["-L", "5", "--listen-backlog", "16"],
+        &["--defer-accept-secs", "5"],
+    );
+    let mut tls = server.connect();
+    let mut request = trojan_request(addr.ip(), addr.port());
+    request.extend_from_slice(b"ping");
+    tls.write_all(request.as_slice()).unwrap();
+    tls.flush().unwrap();
+    let mut pong = [0u8; 4];
+    tls.read_exact(&mut pong).unwrap();
+    assert_eq!(&pong, b"pong");
+}
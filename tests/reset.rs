@@ -0,0 +1,130 @@
+//! Runs the server between a client playing the proxy and a raw origin,
+//! a FIN and an RST of either side must arrive as such with the reset
+//! flag, and both as a FIN without it.
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+use rustls::{ClientConnection, StreamOwned};
+use socket2::SockRef;
+
+mod common;
+
+use common::{trojan_command, Server};
+
+const CONNECT: u8 = 0x01;
+const RESET: u8 = 0x08;
+
+/// How a peer saw its connection end
+#[derive(Debug, PartialEq, Eq)]
+enum Close {
+    Fin,
+    Rst,
+}
+
+fn observe(stream: &mut impl Read) -> Close {
+    let mut buffer = [0u8; 64];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => return Close::Fin,
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::ConnectionReset => return Close::Rst,
+            Err(err) => panic!("read failed:{}", err),
+        }
+    }
+}
+
+/// Starts an origin which reads the ping and reports how the client side
+/// closed, or with `close` writes a pong and closes itself.
+fn origin(close: Option<Close>) -> (SocketAddr, Receiver<Close>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut ping = [0u8; 4];
+        stream.read_exact(&mut ping).unwrap();
+        match close {
+            None => sender.send(observe(&mut stream)).unwrap(),
+            Some(close) => {
+                stream.write_all(b"pong").unwrap();
+                thread::sleep(Duration::from_millis(200));
+                if close == Close::Rst {
+                    SockRef::from(&stream)
+                        .set_linger(Some(Duration::ZERO))
+                        .unwrap();
+                } else {
+                    stream.shutdown(std::net::Shutdown::Write).unwrap();
+                    // keep reading until the server closed too
+                    let _ = observe(&mut stream);
+                }
+            }
+        }
+    });
+    (addr, receiver)
+}
+
+fn open(
+    server: &Server,
+    command: u8,
+    addr: SocketAddr,
+) -> StreamOwned<ClientConnection, TcpStream> {
+    let mut tls = server.connect();
+    let mut request = trojan_command(command, addr.ip(), addr.port());
+    request.extend_from_slice(b"ping");
+    tls.write_all(request.as_slice()).unwrap();
+    tls.flush().unwrap();
+    tls
+}
+
+/// Closes like the proxy does after a FIN or an RST of its client.
+fn close(mut tls: StreamOwned<ClientConnection, TcpStream>, close: Close) {
+    thread::sleep(Duration::from_millis(200));
+    if close == Close::Fin {
+        tls.conn.send_close_notify();
+        tls.flush().unwrap();
+    }
+    tls.sock.shutdown(std::net::Shutdown::Both).unwrap();
+}
+
+fn client_close(server: &Server, command: u8, how: Close) -> Close {
+    let (addr, observed) = origin(None);
+    close(open(server, command, addr), how);
+    observed.recv_timeout(Duration::from_secs(5)).unwrap()
+}
+
+fn origin_close(server: &Server, command: u8, how: Close) -> Close {
+    let (addr, _) = origin(Some(how));
+    let mut tls = open(server, command, addr);
+    let mut pong = [0u8; 4];
+    tls.read_exact(&mut pong).unwrap();
+    assert_eq!(&pong, b"pong");
+    assert_eq!(observe(&mut tls), Close::Fin);
+    // the stream hides it, the session tells whether close_notify came
+    match tls.conn.reader().read(&mut pong) {
+        Ok(0) => Close::Fin,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Close::Rst,
+        result => panic!("unexpected read:{:?}", result),
+    }
+}
+
+#[test]
+fn resets_pass_through() {
+    let server = Server::start(&["-L", "5"], &[]);
+    let command = CONNECT | RESET;
+    assert_eq!(client_close(&server, command, Close::Fin), Close::Fin);
+    assert_eq!(client_close(&server, command, Close::Rst), Close::Rst);
+    assert_eq!(origin_close(&server, command, Close::Fin), Close::Fin);
+    assert_eq!(origin_close(&server, command, Close::Rst), Close::Rst);
+}
+
+#[test]
+fn resets_close_gracefully_without_the_flag() {
+    let server = Server::start(&["-L", "5"], &[]);
+    assert_eq!(client_close(&server, CONNECT, Close::Rst), Close::Fin);
+    assert_eq!(origin_close(&server, CONNECT, Close::Rst), Close::Fin);
+}
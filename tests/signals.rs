@@ -0,0 +1,111 @@
+//! Sends the signals the server handles by flags, each one has to leave
+//! it running and take effect.
+#![cfg(unix)]
+use std::{
+    fs,
+    io::{Read, Write},
+    net::TcpListener,
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+mod common;
+
+use common::{trojan_request, Server};
+
+fn signal(server: &Server, signal: i32) {
+    unsafe {
+        libc::kill(server.child.id() as i32, signal);
+    }
+    thread::sleep(Duration::from_millis(500));
+}
+
+fn dumps(path: &Path) -> usize {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .matches("state dump at")
+        .count()
+}
+
+/// Relays a ping to a local origin and back.
+fn ping(server: &Server) {
+    let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = origin.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = origin.accept().unwrap();
+        let mut ping = [0u8; 4];
+        stream.read_exact(&mut ping).unwrap();
+        stream.write_all(b"pong").unwrap();
+    });
+    let mut tls = server.connect();
+    let mut request = trojan_request(addr.ip(), addr.port());
+    request.extend_from_slice(b"ping");
+    tls.write_all(request.as_slice()).unwrap();
+    let mut pong = [0u8; 4];
+    tls.read_exact(&mut pong).unwrap();
+    assert_eq!(&pong, b"pong");
+}
+
+#[test]
+fn dump_signal() {
+    let dir = std::env::temp_dir();
+    let log_file = dir.join(format!("trojan-signals-{}.log", std::process::id()));
+    let dump_file = dir.join(format!("trojan-signals-{}.dump", std::process::id()));
+    let _ = fs::remove_file(&dump_file);
+    let mut server = Server::start(
+        &[
+            "-L",
+            "2",
+            "-l",
+            log_file.to_str().unwrap(),
+            "--dump-file",
+            dump_file.to_str().unwrap(),
+        ],
+        &[],
+    );
+    signal(&server, libc::SIGUSR2);
+    assert!(server.child.try_wait().unwrap().is_none());
+    assert_eq!(dumps(&dump_file), 1);
+
+    // a rotated log file takes the signal for reopening only
+    let rotated = dir.join(format!("trojan-signals-{}.log.1", std::process::id()));
+    fs::rename(&log_file, &rotated).unwrap();
+    fs::write(&log_file, b"").unwrap();
+    signal(&server, libc::SIGUSR2);
+    assert!(server.child.try_wait().unwrap().is_none());
+    assert_eq!(dumps(&dump_file), 1);
+    signal(&server, libc::SIGUSR2);
+    assert_eq!(dumps(&dump_file), 2);
+    ping(&server);
+
+    for path in [&log_file, &rotated, &dump_file] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[test]
+fn reload_signal() {
+    let dir = std::env::temp_dir();
+    let log_file = dir.join(format!("trojan-reload-{}.log", std::process::id()));
+    let key_file = dir.join(format!("trojan-reload-{}.key", std::process::id()));
+    fs::write(&key_file, [1u8; 32]).unwrap();
+    let mut server = Server::start(
+        &["-L", "2", "-l", log_file.to_str().unwrap()],
+        &[
+            "--session-ticket",
+            "--ticket-key-file",
+            key_file.to_str().unwrap(),
+        ],
+    );
+    fs::write(&key_file, [2u8; 32]).unwrap();
+    signal(&server, libc::SIGHUP);
+    assert!(server.child.try_wait().unwrap().is_none());
+    let log = fs::read_to_string(&log_file).unwrap();
+    assert!(log.contains("ticket key reloaded"), "{}", log);
+    ping(&server);
+
+    for path in [&log_file, &key_file] {
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,37 @@
+//! Sends the trojan request one byte per tls record, the server has to
+//! wait for all of it before connecting the origin.
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    thread,
+    time::Duration,
+};
+
+mod common;
+
+use common::{trojan_request, Server};
+
+#[test]
+fn request_split_into_single_bytes() {
+    let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = origin.accept().unwrap();
+        let mut ping = [0u8; 4];
+        stream.read_exact(&mut ping).unwrap();
+        assert_eq!(&ping, b"ping");
+        stream.write_all(b"pong").unwrap();
+    });
+
+    let server = Server::start(&["-L", "5"], &[]);
+    let mut tls = server.connect();
+    for byte in trojan_request(origin_addr.ip(), origin_addr.port()) {
+        tls.write_all(&[byte]).unwrap();
+        tls.flush().unwrap();
+        thread::sleep(Duration::from_millis(2));
+    }
+    tls.write_all(b"ping").unwrap();
+    let mut pong = [0u8; 4];
+    tls.read_exact(&mut pong).unwrap();
+    assert_eq!(&pong, b"pong");
+}
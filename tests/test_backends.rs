@@ -0,0 +1,52 @@
+//! Runs the echo and discard test commands against a server with and
+//! without --test-backends, neither reaches a target.
+use std::{
+    io::{Read, Write},
+    net::Ipv4Addr,
+    time::Duration,
+};
+
+mod common;
+
+use common::{trojan_command, Server};
+
+const ECHO: u8 = 0x11;
+const DISCARD: u8 = 0x12;
+
+#[test]
+fn echo_and_discard() {
+    let server = Server::start(&["-L", "5"], &["--test-backends"]);
+    let mut tls = server.connect();
+    let mut request = trojan_command(ECHO, Ipv4Addr::UNSPECIFIED.into(), 0);
+    request.extend_from_slice(b"ping");
+    tls.write_all(request.as_slice()).unwrap();
+    let mut ping = [0u8; 4];
+    tls.read_exact(&mut ping).unwrap();
+    assert_eq!(&ping, b"ping");
+    let payload: Vec<u8> = (0..16384).map(|n| n as u8).collect();
+    tls.write_all(payload.as_slice()).unwrap();
+    let mut echoed = vec![0u8; payload.len()];
+    tls.read_exact(echoed.as_mut_slice()).unwrap();
+    assert_eq!(echoed, payload);
+
+    let mut tls = server.connect();
+    let mut request = trojan_command(DISCARD, Ipv4Addr::UNSPECIFIED.into(), 0);
+    request.extend_from_slice(b"ping");
+    tls.write_all(request.as_slice()).unwrap();
+    tls.sock
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    let mut buffer = [0u8; 4];
+    assert!(tls.read(&mut buffer).is_err());
+}
+
+#[test]
+fn test_backends_off() {
+    let server = Server::start(&["-L", "5"], &[]);
+    let mut tls = server.connect();
+    let mut request = trojan_command(ECHO, Ipv4Addr::UNSPECIFIED.into(), 0);
+    request.extend_from_slice(b"ping");
+    tls.write_all(request.as_slice()).unwrap();
+    let mut buffer = [0u8; 4];
+    assert!(!matches!(tls.read(&mut buffer), Ok(len) if len > 0));
+}
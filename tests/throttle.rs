@@ -0,0 +1,45 @@
+//! A fast origin sends to a tunnel read slowly, the server has to hold its
+//! reads from the origin instead of closing the tunnel.
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    thread,
+    time::Duration,
+};
+
+mod common;
+
+use common::{trojan_request, Server};
+
+const TOTAL: usize = 8 << 20;
+
+#[test]
+fn slow_tunnel_throttles_origin() {
+    let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = origin.accept().unwrap();
+        let data: Vec<u8> = (0..TOTAL).map(|i| (i % 251) as u8).collect();
+        stream.write_all(data.as_slice()).unwrap();
+    });
+
+    let server = Server::start(&[], &[]);
+    let mut tls = server.connect();
+    tls.write_all(&trojan_request(origin_addr.ip(), origin_addr.port()))
+        .unwrap();
+    tls.flush().unwrap();
+
+    let mut chunk = vec![0u8; 16 * 1024];
+    let mut received = 0;
+    while received < TOTAL {
+        let size = tls.read(chunk.as_mut_slice()).unwrap();
+        assert_ne!(size, 0, "tunnel closed after {} bytes", received);
+        for (i, byte) in chunk[..size].iter().enumerate() {
+            assert_eq!(*byte, ((received + i) % 251) as u8);
+        }
+        received += size;
+        // about 4MB/s, far below what the origin sends
+        thread::sleep(Duration::from_millis(4));
+    }
+    assert_eq!(received, TOTAL);
+}
@@ -0,0 +1,246 @@
+//! Upgrades a running server with SIGUSR1. A download started before the
+//! upgrade has to finish on the old process, connections made after it
+//! are served by the new one. The same build stands in for the new binary.
+#![cfg(target_os = "linux")]
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener},
+    os::unix::fs::PermissionsExt,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+mod common;
+
+use common::{trojan_request, Server, PASSWORD};
+
+const CHUNK_LEN: usize = 64 * 1024;
+const CHUNKS: usize = 40;
+
+/// Kills the new process, which is no child of the test.
+struct Kill(u32);
+
+impl Drop for Kill {
+    fn drop(&mut self) {
+        unsafe {
+            libc::kill(self.0 as i32, libc::SIGTERM);
+        }
+    }
+}
+
+/// Pids of the processes started with `arg`.
+fn pids_with(arg: &str) -> Vec<u32> {
+    fs::read_dir("/proc")
+        .unwrap()
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|pid: &u32| {
+            fs::read(format!("/proc/{}/cmdline", pid))
+                .map(|cmdline| {
+                    cmdline
+                        .split(|byte| *byte == 0)
+                        .any(|part| part == arg.as_bytes())
+                })
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+fn echo(server: &Server, origin: SocketAddr) {
+    let mut tls = server.connect();
+    tls.write_all(trojan_request(origin.ip(), origin.port()).as_slice())
+        .unwrap();
+    tls.write_all(b"ping").unwrap();
+    tls.flush().unwrap();
+    let mut answer = [0u8; 4];
+    tls.read_exact(&mut answer).unwrap();
+    assert_eq!(&answer, b"ping");
+}
+
+#[test]
+fn upgrade_keeps_running_downloads() {
+    // slow download, takes about four seconds
+    let download = TcpListener::bind("127.0.0.1:0").unwrap();
+    let download_addr = download.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = download.accept().unwrap();
+        for i in 0..CHUNKS {
+            stream.write_all(&[i as u8; CHUNK_LEN]).unwrap();
+            thread::sleep(Duration::from_millis(100));
+        }
+    });
+    let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in origin.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut buffer = [0u8; 4];
+                if stream.read_exact(&mut buffer).is_ok() {
+                    let _ = stream.write_all(&buffer);
+                }
+            });
+        }
+    });
+
+    let socket = std::env::temp_dir().join(format!("trojan-upgrade-{}.sock", std::process::id()));
+    let socket = socket.to_str().unwrap().to_owned();
+    // connections closed by their target linger for the drain timeout
+    let mut old = Server::start(
+        &["-L", "5", "--drain-timeout", "1"],
+        &["--upgrade-socket", socket.as_str()],
+    );
+    echo(&old, origin_addr);
+    let mut tls = old.connect();
+    tls.write_all(trojan_request(download_addr.ip(), download_addr.port()).as_slice())
+        .unwrap();
+    tls.flush().unwrap();
+    let mut received = vec![0u8; CHUNK_LEN];
+    tls.read_exact(received.as_mut_slice()).unwrap();
+
+    unsafe {
+        libc::kill(old.child.id() as i32, libc::SIGUSR1);
+    }
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let new = loop {
+        let pids: Vec<_> = pids_with(socket.as_str())
+            .into_iter()
+            .filter(|pid| *pid != old.child.id())
+            .collect();
+        if let [pid] = pids.as_slice() {
+            break Kill(*pid);
+        }
+        assert!(Instant::now() < deadline, "new process not started");
+        thread::sleep(Duration::from_millis(50));
+    };
+    // connections keep working through the handoff
+    for _ in 0..10 {
+        echo(&old, origin_addr);
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    tls.read_to_end(&mut received).unwrap();
+    assert_eq!(received.len(), CHUNK_LEN * CHUNKS);
+    assert!(received
+        .chunks(CHUNK_LEN)
+        .enumerate()
+        .all(|(i, chunk)| chunk.iter().all(|byte| *byte == i as u8)));
+    drop(tls);
+
+    // the old process exits once drained, the new one goes on
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = old.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "old process not drained");
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success());
+    echo(&old, origin_addr);
+    drop(new);
+}
+
+/// Waits up to 10 seconds for `child` to exit.
+fn exited(child: &mut Child) -> Option<std::process::ExitStatus> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait().unwrap() {
+            return Some(status);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    None
+}
+
+#[test]
+fn draining_process_stops_on_sigterm() {
+    // a download which outlasts the test
+    let download = TcpListener::bind("127.0.0.1:0").unwrap();
+    let download_addr = download.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = download.accept().unwrap();
+        while stream.write_all(&[0u8; 1024]).is_ok() {
+            thread::sleep(Duration::from_millis(100));
+        }
+    });
+    let dir = std::env::temp_dir();
+    let id = std::process::id();
+    let socket = dir.join(format!("trojan-stop-{}.sock", id));
+    let socket = socket.to_str().unwrap().to_owned();
+    let usage = dir.join(format!("trojan-stop-{}.usage", id));
+    let log = dir.join(format!("trojan-stop-{}.log", id));
+    let mut old = Server::start(
+        &["-L", "2", "-l", log.to_str().unwrap()],
+        &[
+            "--upgrade-socket",
+            socket.as_str(),
+            "--usage-file",
+            usage.to_str().unwrap(),
+        ],
+    );
+    let mut tls = old.connect();
+    tls.write_all(trojan_request(download_addr.ip(), download_addr.port()).as_slice())
+        .unwrap();
+    let mut received = [0u8; 1024];
+    tls.read_exact(&mut received).unwrap();
+
+    unsafe {
+        libc::kill(old.child.id() as i32, libc::SIGUSR1);
+    }
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !fs::read_to_string(&log)
+        .unwrap_or_default()
+        .contains("draining connections")
+    {
+        assert!(Instant::now() < deadline, "old process not draining");
+        thread::sleep(Duration::from_millis(50));
+    }
+    let new = pids_with(socket.as_str())
+        .into_iter()
+        .find(|pid| *pid != old.child.id())
+        .map(Kill)
+        .expect("new process not started");
+    unsafe {
+        libc::kill(old.child.id() as i32, libc::SIGTERM);
+    }
+    let status = exited(&mut old.child).expect("draining process ignored SIGTERM");
+    assert!(status.success());
+    assert!(fs::read_to_string(&log)
+        .unwrap()
+        .contains("draining server stopped"));
+    drop(new);
+    for path in [&usage, &log] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[test]
+fn upgrades_need_a_readable_key() {
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    // a key only root may read, like in /etc/ssl/private
+    let key = std::env::temp_dir().join(format!("trojan-key-{}.key", std::process::id()));
+    fs::copy("tests/certs/server.key", &key).unwrap();
+    fs::set_permissions(&key, fs::Permissions::from_mode(0o600)).unwrap();
+    let socket = std::env::temp_dir().join(format!("trojan-key-{}.sock", std::process::id()));
+    let mut child = Command::new(env!("CARGO_BIN_EXE_trojan"))
+        .args(["-a", "127.0.0.1:0", "-p", PASSWORD, "-L", "5", "server"])
+        .args(["-c", "tests/certs/server.pem", "-k", key.to_str().unwrap()])
+        .args([
+            "--user",
+            "nobody",
+            "--upgrade-socket",
+            socket.to_str().unwrap(),
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let status = exited(&mut child);
+    let _ = child.kill();
+    let _ = fs::remove_file(&key);
+    assert!(!status.expect("server started").success());
+}